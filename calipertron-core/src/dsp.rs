@@ -0,0 +1,775 @@
+//! Signal processing for the demodulation loop.
+//! The Cortex-M3 has no FPU, so anything running per sample should avoid softfloat where it can.
+
+#[cfg(not(test))]
+use num_traits::Float;
+
+/// Angles are represented as an `i32` fraction of a full turn, so 2π maps to 2^32 and wraparound comes for free with wrapping arithmetic.
+pub const QUARTER_TURN: i32 = 1 << 30;
+
+pub const CORDIC_STAGES: usize = 16;
+
+/// `atan(2^-i)` in turn units.
+const CORDIC_ATAN_TABLE: [i32; CORDIC_STAGES] = [
+    536870912, 316933406, 167458907, 85004756, 42667331, 21354465, 10679838, 5340245, 2670163,
+    1335087, 667544, 333772, 166886, 83443, 41722, 20861,
+];
+
+/// Four-quadrant arctangent of `y / x` via CORDIC vectoring, returning an angle in turn units (see `QUARTER_TURN`).
+///
+/// Worst-case error is about 20,900 units (just under 2^-17 of a turn, 3.1e-5 rad), set by the final stage's `atan(2^-15)`.
+/// Returns 0 for `(0, 0)`.
+pub fn cordic_atan2(y: i32, x: i32) -> i32 {
+    // Work in i64 so there's headroom for the ~1.65 CORDIC gain.
+    let (mut x, mut y) = (x as i64, y as i64);
+
+    let magnitude = x.unsigned_abs() | y.unsigned_abs();
+    if magnitude == 0 {
+        return 0;
+    }
+
+    // Rotate into the right half-plane, where the CORDIC iterations converge.
+    let mut angle: i32 = 0;
+    if x < 0 {
+        if y >= 0 {
+            (x, y) = (y, -x);
+            angle = QUARTER_TURN;
+        } else {
+            (x, y) = (-y, x);
+            angle = -QUARTER_TURN;
+        }
+    }
+
+    // Normalize to 40 bits so small inputs don't lose precision to the shifts below.
+    let shift = magnitude.leading_zeros() - 24;
+    x <<= shift;
+    y <<= shift;
+
+    for (i, atan) in CORDIC_ATAN_TABLE.iter().enumerate() {
+        let (dx, dy) = (x >> i, y >> i);
+        if y > 0 {
+            x += dy;
+            y -= dx;
+            angle = angle.wrapping_add(*atan);
+        } else {
+            x -= dy;
+            y += dx;
+            angle = angle.wrapping_sub(*atan);
+        }
+    }
+
+    angle
+}
+
+/// Converts a turn-unit angle to radians in `[-π, π)`, for logging.
+pub fn angle_to_radians(angle: i32) -> f32 {
+    angle as f32 * (core::f32::consts::PI / (1u32 << 31) as f32)
+}
+
+/// Converts radians in `[-π, π]` to a turn-unit angle.
+pub fn radians_to_angle(radians: f32) -> i32 {
+    (radians * ((1u32 << 31) as f32 / core::f32::consts::PI)) as i32
+}
+
+/// Right shift from a `correlate` sum back to sample scale, undoing the Q15 table's factor of `i16::MAX` to within an LSB.
+pub const Q15_SHIFT: u32 = 15;
+
+/// Correlates `samples` against a Q15 sine/cosine table, as build.rs generates, from sample `first` on, returning `(Σ x·sin, Σ x·cos)` per channel, still in Q15; see `Q15_SHIFT`.
+/// Channels are interleaved in scan order, so sample `i` goes to channel `i % CHANNELS`; the tables don't care, since the ADC converts one sample per period whichever channel it's on.
+/// Integer multiply-accumulate (SMLAL on the M3): each 12-bit sample times a Q15 coefficient fits in an i32, and the i64 sum can't lose precision the way f32 did.
+pub fn correlate<const CHANNELS: usize>(
+    table: &[(i16, i16)],
+    samples: &[u16],
+    first: usize,
+) -> [(i64, i64); CHANNELS] {
+    let mut sums = [(0i64, 0i64); CHANNELS];
+    for (i, (&x, &(sine, cosine))) in samples.iter().zip(table).enumerate().skip(first) {
+        let (sum_sine, sum_cosine) = &mut sums[i % CHANNELS];
+        *sum_sine += (x as i32 * sine as i32) as i64;
+        *sum_cosine += (x as i32 * cosine as i32) as i64;
+    }
+    sums
+}
+
+/// Single-bin DFT via the Goertzel recurrence.
+/// Equivalent to correlating against a sine/cosine table, but needs only two state variables and one multiply per sample.
+/// The bin index doesn't need to be an integer, so it can track an excitation frequency that doesn't line up with the window.
+pub struct Goertzel {
+    coefficient: f32,
+    cosine: f32,
+    sine: f32,
+    // rotation by -ω(N - 1), which refers the phase back to the first sample of the window
+    rotation_cosine: f32,
+    rotation_sine: f32,
+    s1: f32,
+    s2: f32,
+}
+
+impl Goertzel {
+    /// `bin` is the number of signal cycles spanned by `num_samples`.
+    pub fn new(num_samples: usize, bin: f32) -> Self {
+        let omega = 2.0 * core::f32::consts::PI * bin / num_samples as f32;
+        let rotation = -omega * (num_samples - 1) as f32;
+        Goertzel {
+            coefficient: 2.0 * omega.cos(),
+            cosine: omega.cos(),
+            sine: omega.sin(),
+            rotation_cosine: rotation.cos(),
+            rotation_sine: rotation.sin(),
+            s1: 0.0,
+            s2: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+
+    pub fn push(&mut self, sample: i16) {
+        let s0 = sample as f32 + self.coefficient * self.s1 - self.s2;
+        self.s2 = self.s1;
+        self.s1 = s0;
+    }
+
+    /// Returns the equivalent of the table method's `(Σ x sin, Σ x cos)` correlation sums over the samples pushed since the last reset.
+    pub fn iq(&self) -> (f32, f32) {
+        let re = self.s1 - self.s2 * self.cosine;
+        let im = self.s2 * self.sine;
+
+        let re_rotated = re * self.rotation_cosine - im * self.rotation_sine;
+        let im_rotated = re * self.rotation_sine + im * self.rotation_cosine;
+
+        (-im_rotated, re_rotated)
+    }
+
+    /// Returns the magnitude and phase (radians) of the bin over the samples pushed since the last reset.
+    /// Uses the same convention as the table method, `atan2(Σ x sin, Σ x cos)`, so the two are interchangeable.
+    pub fn magnitude_phase(&self) -> (f32, f32) {
+        let (sine, cosine) = self.iq();
+        ((sine * sine + cosine * cosine).sqrt(), sine.atan2(cosine))
+    }
+}
+
+/// Amplitudes of `BINS` consecutive DFT bins from `first_bin` on, at sample scale: a sinusoid of amplitude A in a bin reads A, and DC in bin 0 reads its level.
+/// One Goertzel pass per bin, so it's `BINS` times the cost of demodulating; meant for an occasional look at interference and harmonics around the excitation, not every window.
+pub fn spectrum<const BINS: usize>(samples: &[u16], first_bin: u32) -> [f32; BINS] {
+    let n = samples.len();
+    core::array::from_fn(|k| {
+        let bin = first_bin + k as u32;
+        let mut goertzel = Goertzel::new(n, bin as f32);
+        for x in samples {
+            goertzel.push(*x as i16);
+        }
+        let (magnitude, _) = goertzel.magnitude_phase();
+        // a sinusoid splits between the bin and its mirror; DC has no mirror
+        let scale = if bin == 0 { 1.0 } else { 2.0 };
+        magnitude * scale / n as f32
+    })
+}
+
+/// Harmonics of the excitation checked by `aliased_harmonic`; `Square` excitation's odd harmonics taper off as 1/h, and `Sine`'s PDM puts next to nothing at low multiples.
+pub const MAX_ALIASED_HARMONIC: u32 = 7;
+
+/// The lowest harmonic of an excitation at `bin` cycles per `num_samples` window that aliases to within a bin of the excitation itself, if any up to `MAX_ALIASED_HARMONIC` does.
+/// Such a harmonic correlates with the demodulation like the fundamental does, so the phase comes out plausible but off by up to the harmonic's relative amplitude, and nothing in the window gives it away.
+///
+/// At r = num_samples / bin samples per excitation cycle, harmonic h lands exactly on the fundamental when (h - 1) / r or (h + 1) / r is a whole number:
+///
+/// ```text
+/// samples per cycle       harmonics aliasing onto the fundamental
+/// under 2                 the fundamental itself aliases; callers check that separately
+/// 2 to 8                  at r = k / m for k up to 8, i.e., 2, 7/3, 5/2, 8/3, 3, 7/2, 4, 5, 6, 7 and 8, and within a bin of them
+/// 8 + 1 / bin and over    none
+/// ```
+///
+/// So anything over 8 samples per cycle, by at least a bin, is safe regardless; below that, pick a ratio away from the small fractions.
+pub fn aliased_harmonic(bin: f64, num_samples: usize) -> Option<u32> {
+    let n = num_samples as f64;
+    (2..=MAX_ALIASED_HARMONIC).find(|&h| {
+        // below Nyquist a harmonic stays (h - 1) * bin bins away, which the window takes care of like any other tone
+        let harmonic = h as f64 * bin;
+        let wrapped = harmonic % n;
+        harmonic > n / 2.0 && (wrapped.min(n - wrapped) - bin).abs() < 1.0
+    })
+}
+
+/// Vector-averages the I/Q correlation sums of consecutive windows, trading update rate for resolution.
+/// Averaging before `atan2` rather than after avoids artifacts when the phase wraps.
+pub struct IqAverager {
+    n: u32,
+    count: u32,
+    sum_sine: i64,
+    sum_cosine: i64,
+}
+
+impl IqAverager {
+    pub fn new(n: u32) -> Self {
+        assert!(n > 0);
+        IqAverager {
+            n,
+            count: 0,
+            sum_sine: 0,
+            sum_cosine: 0,
+        }
+    }
+
+    /// Returns the average once every `n` windows.
+    /// Accumulates in i64, so any i32 window sums and any `n` are safe; the average itself is back in i32 range.
+    pub fn push(&mut self, sum_sine: i32, sum_cosine: i32) -> Option<(i32, i32)> {
+        self.sum_sine += sum_sine as i64;
+        self.sum_cosine += sum_cosine as i64;
+        self.count += 1;
+
+        if self.count < self.n {
+            return None;
+        }
+
+        let average = (
+            (self.sum_sine / self.n as i64) as i32,
+            (self.sum_cosine / self.n as i64) as i32,
+        );
+        self.count = 0;
+        self.sum_sine = 0;
+        self.sum_cosine = 0;
+        Some(average)
+    }
+}
+
+/// Single-pole IIR low-pass, `y += alpha * (x - y)`.
+/// The step response reaches 63% after `-1 / ln(1 - alpha)` samples, roughly `1 / alpha` for small alpha.
+pub struct OnePole {
+    alpha: f32,
+    y: Option<f32>,
+}
+
+impl OnePole {
+    /// `alpha` must be in `(0, 1]`; 1 passes the input through unfiltered.
+    pub fn new(alpha: f32) -> Self {
+        let mut filter = OnePole {
+            alpha: 1.0,
+            y: None,
+        };
+        filter.set_alpha(alpha);
+        filter
+    }
+
+    pub fn set_alpha(&mut self, alpha: f32) {
+        assert!(alpha > 0.0 && alpha <= 1.0);
+        self.alpha = alpha;
+    }
+
+    /// Forgets the output, so the next sample initializes it again.
+    pub fn reset(&mut self) {
+        self.y = None;
+    }
+
+    /// The first sample initializes the output, so there's no startup transient from zero.
+    pub fn filter(&mut self, x: f32) -> f32 {
+        let y = match self.y {
+            Some(y) => y + self.alpha * (x - y),
+            None => x,
+        };
+        self.y = Some(y);
+        y
+    }
+}
+
+/// Replaces each sample with the median of the `N` samples centered on it, rejecting single-sample spikes without shifting the signal in time.
+/// `N` must be odd; 1 leaves the samples alone.
+/// The `N / 2` samples at each end don't have a full window around them and are left as is.
+pub fn median_filter<const N: usize>(samples: &mut [u16]) {
+    assert!(N % 2 == 1);
+    let half = N / 2;
+    if half == 0 || samples.len() < N {
+        return;
+    }
+
+    // Unfiltered values around the current sample, as a ring indexed by sample index mod N, since samples before it have already been overwritten.
+    let mut window = [0u16; N];
+    window.copy_from_slice(&samples[..N]);
+
+    for i in half..samples.len() - half {
+        // insertion sort, which for a handful of values beats anything cleverer
+        let mut sorted = window;
+        for j in 1..N {
+            let mut k = j;
+            while k > 0 && sorted[k - 1] > sorted[k] {
+                sorted.swap(k - 1, k);
+                k -= 1;
+            }
+        }
+        samples[i] = sorted[half];
+
+        if let Some(&next) = samples.get(i + half + 1) {
+            window[(i - half) % N] = next;
+        }
+    }
+}
+
+/// Sums each run of `K` consecutive samples into one, so `out[i]` is the sum of `samples[i * K..(i + 1) * K]`.
+/// Summing rather than averaging keeps the fractional bits the extra conversions are for; 12-bit samples fit a u16 for `K` up to 16.
+pub fn sum_groups<const K: usize>(samples: &[u16], out: &mut [u16]) {
+    assert_eq!(samples.len(), out.len() * K);
+    for (group, x) in samples.chunks_exact(K).zip(out.iter_mut()) {
+        *x = group.iter().sum();
+    }
+}
+
+/// Fractional sample delays are an `i32` fraction of a sample period, so one period is 2^16.
+pub const SAMPLE_PERIOD: i32 = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interpolation {
+    /// Between the two samples either side, which attenuates a little but adds next to no phase error at many samples per cycle.
+    Linear,
+    /// Catmull-Rom through the four nearest samples, flatter in amplitude for the cost of two more multiplies per sample.
+    Cubic,
+}
+
+/// Moves samples taken `delay` after the points of the table's phase grid back onto them, so a window demodulates as if the ADC had sampled exactly on the grid.
+/// A window spans a whole number of excitation cycles, so it's treated as periodic and the samples needed past either end come from the other.
+/// The delay is the same for every sample in a window, so the interpolation weights are worked out once up front, leaving an integer multiply-accumulate per tap per sample.
+pub struct Resampler {
+    // whole samples of delay, plus one for the first tap
+    offset: isize,
+    // Q16, summing to 1
+    weights: [i32; 4],
+    taps: usize,
+}
+
+impl Resampler {
+    /// `delay` in `SAMPLE_PERIOD` units, and of either sign.
+    pub fn new(delay: i32, interpolation: Interpolation) -> Self {
+        // Sample n was taken at (n + delay), so grid point i falls a fraction t of the way from sample i - whole - 1 to sample i - whole.
+        let whole = delay.div_euclid(SAMPLE_PERIOD) as isize;
+        let t = (SAMPLE_PERIOD - delay.rem_euclid(SAMPLE_PERIOD)) as i64;
+        let one = SAMPLE_PERIOD as i64;
+        match interpolation {
+            Interpolation::Linear => Resampler {
+                offset: whole + 1,
+                weights: [(one - t) as i32, t as i32, 0, 0],
+                taps: 2,
+            },
+            Interpolation::Cubic => {
+                let t2 = t * t / one;
+                let t3 = t2 * t / one;
+                let weights = [
+                    (-t3 + 2 * t2 - t) / 2,
+                    (3 * t3 - 5 * t2 + 2 * one) / 2,
+                    (-3 * t3 + 4 * t2 + t) / 2,
+                    (t3 - t2) / 2,
+                ];
+                Resampler {
+                    offset: whole + 2,
+                    weights: weights.map(|w| w as i32),
+                    taps: 4,
+                }
+            }
+        }
+    }
+
+    /// Writes `samples` resampled onto the grid into `out`, which has to be the same length.
+    /// Cubic can overshoot a step, so results are clamped to the u16 range.
+    pub fn resample(&self, samples: &[u16], out: &mut [u16]) {
+        assert_eq!(samples.len(), out.len());
+        let n = samples.len() as isize;
+        for (i, y) in out.iter_mut().enumerate() {
+            let first = i as isize - self.offset;
+            let mut sum: i64 = 0;
+            for (k, w) in self.weights[..self.taps].iter().enumerate() {
+                let x = samples[(first + k as isize).rem_euclid(n) as usize];
+                sum += *w as i64 * x as i64;
+            }
+            *y = ((sum + (SAMPLE_PERIOD as i64 >> 1)) >> 16).clamp(0, u16::MAX as i64) as u16;
+        }
+    }
+}
+
+/// Nominal internal reference voltage, in mV, as `embassy_stm32::adc::VREF_INT` for the F1.
+/// The F103 has no factory-calibrated VREFINT, unlike the later families' VREFINT_CAL, so parts vary over the datasheet's 1.16--1.24V (section 5.3.4) and the nominal is all there is to go on.
+pub const VREFINT_MV: u32 = 1200;
+
+/// Plausible raw conversions of VREFINT, 1.16 to 1.24V (datasheet section 5.3.4) against a supply of 2.4 to 3.6V.
+/// One below the range, zero included, means the reference wasn't enabled or its read failed, so there's nothing to scale by.
+pub const MIN_VREFINT_SAMPLE: u16 = 1320;
+pub const MAX_VREFINT_SAMPLE: u16 = 2116;
+
+/// Converts a raw 12-bit sample to millivolts, against a conversion of VREFINT at the same supply; every binary converts through here so their millivolts agree.
+/// All integer; `None` if `vrefint_sample` is below `MIN_VREFINT_SAMPLE`, rather than dividing by nothing, and saturated to u16 for samples past 12 bits, such as oversampled sums.
+/// Above `MAX_VREFINT_SAMPLE` still converts, if low: that's a brownout rather than a failed read.
+pub fn adc_to_millivolts(sample: u16, vrefint_sample: u16) -> Option<u16> {
+    if vrefint_sample < MIN_VREFINT_SAMPLE {
+        return None;
+    }
+    let millivolts = sample as u32 * VREFINT_MV / vrefint_sample as u32;
+    Some(u16::try_from(millivolts).unwrap_or(u16::MAX))
+}
+
+/// The scale of `adc_to_millivolts` as a float, for sums and statistics over raw samples; `None` on the same readings.
+pub fn millivolts_per_count(vrefint_sample: u16) -> Option<f32> {
+    (vrefint_sample >= MIN_VREFINT_SAMPLE).then(|| VREFINT_MV as f32 / vrefint_sample as f32)
+}
+
+/// Bits in the samples everything from here on works with: 0..=4095, i.e., the F103's ADC right-aligned, as it comes out of reset.
+/// Data in any other format must go through `AdcFormat::normalize` first, or the demodulation and every threshold against a rail scale wrong.
+pub const ADC_BITS: u32 = 12;
+
+/// Which end of the 16-bit data register the ADC puts its result at; CR2's ALIGN bit on the F103 (reference manual section 11.5).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AdcAlignment {
+    Right,
+    Left,
+}
+
+impl AdcAlignment {
+    pub fn from_align_bit(align: bool) -> Self {
+        if align {
+            AdcAlignment::Left
+        } else {
+            AdcAlignment::Right
+        }
+    }
+
+    pub fn align_bit(self) -> bool {
+        self == AdcAlignment::Left
+    }
+}
+
+/// Resolution and alignment of raw ADC data, for bringing it to `ADC_BITS` right-aligned.
+/// The F103's ADC is 12 bits only, but families with a RES field convert at fewer; those come out scaled up to the same full scale, with their missing low bits zero.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AdcFormat {
+    pub bits: u32,
+    pub alignment: AdcAlignment,
+}
+
+impl AdcFormat {
+    pub const fn new(bits: u32, alignment: AdcAlignment) -> Self {
+        assert!(bits >= 1 && bits <= ADC_BITS);
+        AdcFormat { bits, alignment }
+    }
+
+    /// One data register value at `ADC_BITS` right-aligned, or `None` if it has bits set that this format always leaves clear, i.e., it wasn't converted in this format.
+    pub fn normalize_sample(self, raw: u16) -> Option<u16> {
+        match self.alignment {
+            AdcAlignment::Right => (raw >> self.bits == 0).then_some(raw << (ADC_BITS - self.bits)),
+            AdcAlignment::Left => {
+                (raw & ((1 << (16 - self.bits)) - 1) == 0).then_some(raw >> (16 - ADC_BITS))
+            }
+        }
+    }
+
+    /// Normalizes `samples` in place; false if any of them couldn't have come from this format, which are left as they were.
+    /// A misconfigured ADC shows up on nearly every window this way, rather than as a bias in the phase.
+    pub fn normalize(self, samples: &mut [u16]) -> bool {
+        let mut consistent = true;
+        for x in samples.iter_mut() {
+            match self.normalize_sample(*x) {
+                Some(sample) => *x = sample,
+                None => consistent = false,
+            }
+        }
+        consistent
+    }
+}
+
+/// Whether a raw 12-bit sample sits on either rail, i.e., the input is clipping.
+pub fn is_saturated(sample: u16) -> bool {
+    sample == 0 || sample >= 4095
+}
+
+pub fn count_saturated(samples: &[u16]) -> u32 {
+    samples.iter().filter(|x| is_saturated(**x)).count() as u32
+}
+
+/// Corrects ADC nonlinearity (DNL/INL) by adding a per-code offset, measured in a calibration run, to each raw 12-bit sample.
+/// `N` is a power of two up to 4096; below 4096 each entry covers a run of `4096 / N` codes, picked by the sample's top bits.
+/// Costs `2 * N` bytes: 512 for 256 entries, 8 KB for all 4096 codes.
+pub struct AdcLut<const N: usize> {
+    corrections: [i16; N],
+}
+
+impl<const N: usize> AdcLut<N> {
+    const SHIFT: u32 = 12 - N.trailing_zeros();
+
+    pub const fn new(corrections: [i16; N]) -> Self {
+        assert!(N.is_power_of_two() && N <= 4096);
+        AdcLut { corrections }
+    }
+
+    /// Overwrites the entries from `offset` on, e.g., as a table arrives in chunks.
+    pub fn set(&mut self, offset: usize, corrections: &[i16]) {
+        self.corrections[offset..offset + corrections.len()].copy_from_slice(corrections);
+    }
+
+    pub fn apply(&self, samples: &mut [u16]) {
+        for x in samples.iter_mut() {
+            let correction = self.corrections[(*x >> Self::SHIFT) as usize];
+            *x = x.saturating_add_signed(correction).min(4095);
+        }
+    }
+}
+
+/// Standard deviation of a turn-unit angle over the last `N` updates, for judging measurement noise.
+/// Successive angles are unwrapped against each other, so noise straddling ±π doesn't count as a whole turn of spread.
+/// Welford's algorithm, extended to drop the oldest angle as each new one arrives once the window is full.
+/// Runs once per window rather than per sample, so it can afford f64: the unwrapped angle grows without bound as the slider moves.
+pub struct PhaseStdDev<const N: usize> {
+    /// Unwrapped angles in turn units, oldest at `next` once full.
+    window: [f64; N],
+    len: usize,
+    next: usize,
+    last_angle: Option<i32>,
+    unwrapped: i64,
+    mean: f64,
+    /// Sum of squared differences from the mean.
+    m2: f64,
+}
+
+impl<const N: usize> PhaseStdDev<N> {
+    pub fn new() -> Self {
+        assert!(N >= 2);
+        PhaseStdDev {
+            window: [0.0; N],
+            len: 0,
+            next: 0,
+            last_angle: None,
+            unwrapped: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn push(&mut self, angle: i32) {
+        if let Some(last) = self.last_angle {
+            self.unwrapped += angle.wrapping_sub(last) as i64;
+        }
+        self.last_angle = Some(angle);
+        let x = self.unwrapped as f64;
+
+        if self.len < N {
+            self.len += 1;
+            let delta = x - self.mean;
+            self.mean += delta / self.len as f64;
+            self.m2 += delta * (x - self.mean);
+        } else {
+            let oldest = self.window[self.next];
+            let mean = self.mean + (x - oldest) / N as f64;
+            self.m2 += (x - oldest) * (x - mean + oldest - self.mean);
+            self.mean = mean;
+        }
+        // rounding can leave a hair below zero when the angle is constant
+        self.m2 = self.m2.max(0.0);
+
+        self.window[self.next] = x;
+        self.next = (self.next + 1) % N;
+    }
+
+    /// Sample standard deviation in radians, 0 until there are two angles.
+    pub fn std_dev(&self) -> f32 {
+        if self.len < 2 {
+            return 0.0;
+        }
+        let variance = self.m2 / (self.len - 1) as f64;
+        (variance as f32).sqrt() * (core::f32::consts::TAU / 4_294_967_296.0)
+    }
+}
+
+impl<const N: usize> Default for PhaseStdDev<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts of wrapped turn-unit angles in `N` equal bins across a turn, bin 0 starting at -π, for looking at the shape of the phase noise where a standard deviation only gives its width.
+/// Gaussian noise makes one hump; interference that comes and goes makes two, and the CORDIC's quantization shows up as bins that are always empty.
+/// `N` is a power of two, at least 2; counts saturate rather than wrap.
+pub struct PhaseHistogram<const N: usize> {
+    bins: [u32; N],
+    /// Angles added since the last reset, saturating.
+    pub total: u32,
+}
+
+impl<const N: usize> PhaseHistogram<N> {
+    const SHIFT: u32 = 32 - N.trailing_zeros();
+
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two() && N >= 2);
+        PhaseHistogram {
+            bins: [0; N],
+            total: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The bin `angle` lands in: `-π + i * 2π / N` and up.
+    pub fn bin(angle: i32) -> usize {
+        ((angle as u32).wrapping_add(1 << 31) >> Self::SHIFT) as usize
+    }
+
+    pub fn add(&mut self, angle: i32) {
+        let bin = &mut self.bins[Self::bin(angle)];
+        *bin = bin.saturating_add(1);
+        self.total = self.total.saturating_add(1);
+    }
+
+    pub fn bins(&self) -> &[u32; N] {
+        &self.bins
+    }
+}
+
+impl<const N: usize> Default for PhaseHistogram<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Second-order phase-locked loop over per-window phases, for reading a still slider below the per-window noise.
+/// A proportional-integral loop filter tracks a constant phase rate with no steady-state error, so slow thermal or mechanical drift is followed too; anything faster than the loop bandwidth is averaged away.
+/// The output lags a real move by around `1 / bandwidth` windows, so it's only worth using while `locked`.
+pub struct PhaseLockedLoop {
+    /// Proportional and integral gains, per window.
+    kp: f32,
+    ki: f32,
+    /// Smoothing for the lock detector's error level.
+    lock_alpha: f32,
+    lock_threshold: f32,
+    /// Loop output, in turn units.
+    phase: i32,
+    /// Phase rate, in turn units per window.
+    frequency: f32,
+    /// Smoothed magnitude of the phase error, in turn units.
+    error_level: f32,
+    started: bool,
+}
+
+impl PhaseLockedLoop {
+    /// A phase error beyond this is a step the loop can't follow without slipping, so it jumps to the input and starts over.
+    pub const MAX_ERROR: u32 = (QUARTER_TURN >> 2) as u32;
+
+    /// `bandwidth` is the loop's noise bandwidth as a fraction of the window rate, in `(0, 0.25]`; output variance is about `2 * bandwidth` times the input's.
+    /// Locked means the smoothed phase error has dropped below `lock_threshold`, in turn units; the error is mostly the input's own noise once the loop has caught up, so set it a few times the per-window noise, and lag from a move will push it over.
+    pub fn new(bandwidth: f32, lock_threshold: u32) -> Self {
+        let mut pll = PhaseLockedLoop {
+            kp: 0.0,
+            ki: 0.0,
+            lock_alpha: 0.0,
+            lock_threshold: lock_threshold as f32,
+            phase: 0,
+            frequency: 0.0,
+            error_level: 0.0,
+            started: false,
+        };
+        pll.set_bandwidth(bandwidth);
+        pll
+    }
+
+    /// Critically damped (ζ = 1/√2) gains for noise bandwidth `bandwidth`, from `B = ωn (ζ + 1/(4ζ)) / 2`.
+    pub fn set_bandwidth(&mut self, bandwidth: f32) {
+        assert!(bandwidth > 0.0 && bandwidth <= 0.25);
+        let zeta = core::f32::consts::FRAC_1_SQRT_2;
+        let natural_frequency = 2.0 * bandwidth / (zeta + 1.0 / (4.0 * zeta));
+        self.kp = 2.0 * zeta * natural_frequency;
+        self.ki = natural_frequency * natural_frequency;
+        self.lock_alpha = bandwidth;
+    }
+
+    /// Forgets the phase and rate, so the next window starts the loop over unlocked.
+    pub fn reset(&mut self) {
+        self.started = false;
+    }
+
+    pub fn phase(&self) -> i32 {
+        self.phase
+    }
+
+    pub fn locked(&self) -> bool {
+        self.started && self.error_level < self.lock_threshold
+    }
+
+    /// Takes a window's phase in turn units and returns the loop's.
+    pub fn update(&mut self, phase: i32) -> i32 {
+        // where the last window's phase and rate put this one
+        let predicted = self.phase.wrapping_add(self.frequency.round() as i32);
+        let error = phase.wrapping_sub(predicted);
+        if !self.started || error.unsigned_abs() > Self::MAX_ERROR {
+            self.phase = phase;
+            self.frequency = 0.0;
+            // start from the worst case, so lock waits for the error level to fall rather than being claimed on the first window
+            self.error_level = Self::MAX_ERROR as f32;
+            self.started = true;
+            return self.phase;
+        }
+
+        let error = error as f32;
+        self.error_level += self.lock_alpha * (error.abs() - self.error_level);
+        self.frequency += self.ki * error;
+        self.phase = predicted.wrapping_add((self.kp * error).round() as i32);
+        self.phase
+    }
+}
+
+/// Mean, RMS and peak-to-peak of a run of raw samples, for measuring the front end's noise floor with the excitation off.
+/// Sums are kept exactly in integers, so the variance doesn't lose the noise to cancellation against a large DC level.
+#[derive(Clone, Copy, Default)]
+pub struct NoiseStats {
+    count: u32,
+    sum: u64,
+    sum_squares: u64,
+    range: Option<(u16, u16)>,
+}
+
+impl NoiseStats {
+    pub fn new() -> Self {
+        NoiseStats {
+            count: 0,
+            sum: 0,
+            sum_squares: 0,
+            range: None,
+        }
+    }
+
+    pub fn push(&mut self, sample: u16) {
+        self.count += 1;
+        self.sum += sample as u64;
+        self.sum_squares += sample as u64 * sample as u64;
+        let (min, max) = self.range.get_or_insert((sample, sample));
+        *min = (*min).min(sample);
+        *max = (*max).max(sample);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// DC level, 0 with no samples.
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.sum as f64 / self.count as f64) as f32
+    }
+
+    /// RMS about the mean (i.e., the population standard deviation), which with the excitation off is the noise plus any interference.
+    pub fn rms(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let n = self.count as f64;
+        let sum = self.sum as f64;
+        let variance = (self.sum_squares as f64 - sum * sum / n) / n;
+        (variance.max(0.0) as f32).sqrt()
+    }
+
+    pub fn peak_to_peak(&self) -> u16 {
+        self.range.map_or(0, |(min, max)| max - min)
+    }
+}
@@ -1,7 +1,11 @@
 #![no_std]
 
+pub mod dsp;
+pub mod pipeline;
+
 use core::f32::consts::PI;
 
+#[cfg(not(test))]
 use num_traits::Float;
 pub struct PhaseAccumulator {
     pub unwrapped_phase: f32,
@@ -35,3 +39,853 @@ impl PhaseAccumulator {
         }
     }
 }
+
+/// Position counts per electrode pitch, i.e., per full 2π cycle of the demodulated phase.
+pub const COUNTS_PER_PITCH: i64 = 4096;
+
+/// A move of more than half a pitch between two measurements unwraps to a step in the wrong direction, and there's no way to tell from the phase alone.
+/// Steps within an eighth of a pitch of that limit get flagged so the caller knows the slider may be moving too fast to track.
+pub const ALIASING_THRESHOLD: i64 = COUNTS_PER_PITCH / 2 - COUNTS_PER_PITCH / 8;
+
+/// Fractional bits of `PositionTracker::position_fine`, i.e., it counts in 1/65536 of a count.
+/// That's 2^-28 of a pitch, 35 pm on the default pitch, so the tracker never throws away anything the phase measurement resolves: the CORDIC is good to about 2^-17 of a turn and the turn-unit angle it returns to 2^-32.
+pub const POSITION_FRACTION_BITS: u32 = 16;
+
+/// Electrode pitch of the v1.1 PCB Mitko sent me, 9.4mm across all 8 emission pads, in micrometers.
+pub const DEFAULT_PITCH_UM: u32 = 9_400;
+
+/// Converts position counts to micrometers, rounded to nearest, for a scale with `pitch_um` micrometers per electrode pitch.
+/// Works on the integer count rather than through a float scale factor, so the result doesn't lose resolution far from zero.
+pub fn counts_to_um(counts: i64, pitch_um: u32) -> i64 {
+    (counts * pitch_um as i64 + COUNTS_PER_PITCH / 2).div_euclid(COUNTS_PER_PITCH)
+}
+
+/// Micrometers per inch, exactly: the inch has been defined as 25.4mm since 1959.
+pub const UM_PER_INCH: i64 = 25_400;
+
+/// Converts position counts to ten-thousandths of an inch, the last digit calipers show in inch mode, rounded to nearest.
+/// Scales by the exact ratio in one step rather than going through rounded micrometers, so there's a single rounding however far from zero.
+/// The product is taken in i128, since in i64 it overflows at about 1e11 counts, well inside `PositionTracker::MAX_WRAPS`; a result past i64 saturates.
+pub fn counts_to_ten_thousandths_inch(counts: i64, pitch_um: u32) -> i64 {
+    let per_pitch = (COUNTS_PER_PITCH * UM_PER_INCH) as i128;
+    let ten_thousandths =
+        (counts as i128 * pitch_um as i128 * 10_000 + per_pitch / 2).div_euclid(per_pitch);
+    ten_thousandths.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Micrometers to ten-thousandths of an inch, rounded to nearest; back again with `ten_thousandths_inch_to_um`, that's within 1um, since a ten-thousandth is 2.54um.
+pub fn um_to_ten_thousandths_inch(um: i64) -> i64 {
+    (um * 10_000 + UM_PER_INCH / 2).div_euclid(UM_PER_INCH)
+}
+
+/// Ten-thousandths of an inch to micrometers, rounded to nearest; exact back again with `um_to_ten_thousandths_inch`.
+pub fn ten_thousandths_inch_to_um(ten_thousandths: i64) -> i64 {
+    (ten_thousandths * UM_PER_INCH + 5_000).div_euclid(10_000)
+}
+
+/// Largest number of counts the slider can move in `update_period` seconds at `max_speed_um_per_s`, rounded up, for `PositionTracker::set_max_step`.
+pub fn max_step_counts(max_speed_um_per_s: f32, update_period: f32, pitch_um: u32) -> i64 {
+    (max_speed_um_per_s * update_period * COUNTS_PER_PITCH as f32 / pitch_um as f32).ceil() as i64
+}
+
+/// Tracks absolute position by unwrapping successive wrapped phase measurements.
+/// Unwraps in fixed point with `POSITION_FRACTION_BITS` below the count, so the sub-count part of each phase carries through to `position_fine` rather than being rounded away every window.
+pub struct PositionTracker {
+    /// Position within the current pitch in fixed-point counts, in `0..ONE_PITCH`.
+    last_fine: Option<i64>,
+    wraps: i64,
+    /// Set if the most recent accepted update's step was too large to unwrap reliably.
+    pub aliased: bool,
+    max_step: i64,
+    /// Updates rejected since the last accepted one.
+    rejected_run: u32,
+    /// Updates rejected as impossibly fast since construction, see `set_max_step`.
+    pub rejected: u32,
+    /// Set once the accumulated position is more than halfway to where it saturates, see `MAX_WRAPS`; re-zero with `recenter` before it gets there.
+    pub near_limit: bool,
+}
+
+impl PositionTracker {
+    const ONE: i64 = 1 << POSITION_FRACTION_BITS;
+    const ONE_PITCH: i64 = COUNTS_PER_PITCH << POSITION_FRACTION_BITS;
+    /// Whole pitches either side of zero the position can accumulate, leaving room for the fraction and `position`'s rounding within i64.
+    /// Past it, whole pitches saturate rather than wrap: a wrapped position flips sign, indistinguishable from a real reading, while a saturated one only goes wrong by the pitches it's missed, after `near_limit` has been set for as long again.
+    /// That's 2^35 pitches, over 300,000 km on the default pitch: a decade of moving at a meter a second.
+    pub const MAX_WRAPS: i64 = i64::MAX / Self::ONE_PITCH - 1;
+
+    pub fn new() -> Self {
+        PositionTracker {
+            last_fine: None,
+            wraps: 0,
+            aliased: false,
+            max_step: COUNTS_PER_PITCH / 2,
+            rejected_run: 0,
+            rejected: 0,
+            near_limit: false,
+        }
+    }
+
+    /// Rejects later steps bigger than `max_step` counts as glitches, e.g., a noisy window: the position holds where it was and `rejected` counts up instead.
+    /// The limit doubles with each rejection in a row, since the last accepted measurement keeps getting older, so a real move faster than it stalls for a few updates rather than for good, as long as it catches up within half a pitch.
+    /// So pick a limit comfortably above any real speed, see `max_step_counts`; the default of half a pitch is the most any step can unwrap to, i.e., no limit.
+    pub fn set_max_step(&mut self, max_step: i64) {
+        self.max_step = max_step.clamp(1, COUNTS_PER_PITCH / 2);
+    }
+
+    /// Takes a wrapped phase in radians and returns the accumulated position in counts.
+    /// An f32 phase near ±π only resolves about 2^-22 of a turn, so its fractional counts are coarser than `update_angle`'s.
+    pub fn update(&mut self, phase: f32) -> i64 {
+        let fine = (phase * (Self::ONE_PITCH as f32 / (2.0 * PI))).round() as i64;
+        self.update_fine(fine)
+    }
+
+    /// Takes a wrapped phase as a turn-unit angle (see `dsp::QUARTER_TURN`) and returns the accumulated position in counts.
+    pub fn update_angle(&mut self, angle: i32) -> i64 {
+        // exact: an angle's 2^-32 of a turn is 2^-4 of a fixed-point count, so this only drops what's below the fraction
+        let fine = (angle as i64 * Self::ONE_PITCH) >> 32;
+        self.update_fine(fine)
+    }
+
+    fn update_fine(&mut self, fine: i64) -> i64 {
+        let fine = fine.rem_euclid(Self::ONE_PITCH);
+
+        if let Some(last_fine) = self.last_fine {
+            // shortest step between the two measurements, handling the wrap at ±half a pitch
+            let mut delta = fine - last_fine;
+            let mut wrap = 0;
+            if delta > Self::ONE_PITCH / 2 {
+                delta -= Self::ONE_PITCH;
+                wrap = -1;
+            } else if delta < -Self::ONE_PITCH / 2 {
+                delta += Self::ONE_PITCH;
+                wrap = 1;
+            }
+
+            // max_step is at most half a pitch, 2^27 fixed-point counts, so any step fits long before the shift could overflow
+            if delta.abs() > (self.max_step * Self::ONE) << self.rejected_run.min(32) {
+                self.rejected_run += 1;
+                self.rejected = self.rejected.wrapping_add(1);
+                return self.position();
+            }
+            self.rejected_run = 0;
+            self.wraps = (self.wraps + wrap).clamp(-Self::MAX_WRAPS, Self::MAX_WRAPS);
+            self.near_limit = self.wraps.abs() > Self::MAX_WRAPS / 2;
+            self.aliased = delta.abs() > ALIASING_THRESHOLD * Self::ONE;
+        }
+
+        self.last_fine = Some(fine);
+        self.position()
+    }
+
+    /// Accumulated position in counts, rounded to nearest.
+    pub fn position(&self) -> i64 {
+        (self.position_fine() + Self::ONE / 2) >> POSITION_FRACTION_BITS
+    }
+
+    /// Accumulated position in fixed-point counts, with `POSITION_FRACTION_BITS` fractional bits.
+    pub fn position_fine(&self) -> i64 {
+        self.wraps * Self::ONE_PITCH + self.last_fine.unwrap_or(0)
+    }
+
+    /// Drops the whole pitches accumulated so far, leaving the position within the current pitch, and returns the counts that took off it.
+    /// For re-zeroing: subtract the return value from anything held against `position`, e.g., a tare, so it still lines up.
+    pub fn recenter(&mut self) -> i64 {
+        let removed = self.wraps * COUNTS_PER_PITCH;
+        self.wraps = 0;
+        self.near_limit = false;
+        removed
+    }
+
+    /// Starts the accumulator `wraps` whole pitches from zero, clamped like an update, as though it had moved there; for exercising the limits.
+    pub fn set_wraps(&mut self, wraps: i64) {
+        self.wraps = wraps.clamp(-Self::MAX_WRAPS, Self::MAX_WRAPS);
+        self.near_limit = self.wraps.abs() > Self::MAX_WRAPS / 2;
+    }
+}
+
+impl Default for PositionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Corrects the periodic distortion in phase vs. position of a real scale, whose coupling isn't perfectly sinusoidal, ahead of `PositionTracker`.
+/// Holds a correction in counts for each of `N` evenly spaced measured phases across a pitch, starting at 0, and interpolates linearly between them, wrapping from the last entry back to the first.
+/// `N` is a power of two, at least 2.
+///
+/// To make a table, sweep the slider slowly over a few pitches against a reference, e.g., a DRO or a micrometer stage, logging measured phase and reference position in counts.
+/// Offset the reference so the two agree on average, then for each measured phase `i * 2^32 / N`, the correction is the reference position within the pitch minus the measured phase in counts (`phase * COUNTS_PER_PITCH >> 32`), wrapped to ±half a pitch and averaged over every pass through that phase.
+pub struct PhaseCorrection<const N: usize> {
+    corrections: [i16; N],
+}
+
+impl<const N: usize> PhaseCorrection<N> {
+    /// Shift down from a turn-unit phase to its entry below.
+    const SHIFT: u32 = 32 - N.trailing_zeros();
+
+    pub const fn new(corrections: [i16; N]) -> Self {
+        assert!(N.is_power_of_two() && N >= 2);
+        PhaseCorrection { corrections }
+    }
+
+    /// Overwrites the entries from `offset` on, e.g., as a table arrives in chunks.
+    pub fn set(&mut self, offset: usize, corrections: &[i16]) {
+        self.corrections[offset..offset + corrections.len()].copy_from_slice(corrections);
+    }
+
+    /// Takes a wrapped turn-unit phase (see `dsp::QUARTER_TURN`) and returns it corrected, still wrapped.
+    pub fn apply(&self, phase: i32) -> i32 {
+        let turns = phase as u32;
+        let i = (turns >> Self::SHIFT) as usize;
+        let below = self.corrections[i] as i64;
+        let above = self.corrections[(i + 1) % N] as i64;
+        // how far past entry i, as a fraction of 2^32
+        let fraction = (turns << (32 - Self::SHIFT)) as i64;
+        let correction = (below << 32) + (above - below) * fraction;
+        // counts * 2^32 to turn units; corrections past half a pitch wrap, same as the phase
+        phase.wrapping_add((correction / COUNTS_PER_PITCH) as i32)
+    }
+}
+
+/// Fine track pitches over the full travel of a two-track vernier scale.
+/// The coarse track has one pitch fewer over the same travel, so the phase difference between the tracks goes through exactly one cycle end to end.
+pub const VERNIER_FINE_PITCHES: i64 = 16;
+
+/// Resolves absolute position from the wrapped phases (turn units, see `dsp::QUARTER_TURN`) of the two tracks of a vernier scale.
+/// Returns counts of the fine track from the start of travel, in `0..VERNIER_FINE_PITCHES * COUNTS_PER_PITCH`.
+///
+/// The beat phase only locates the fine cycle to within its own error times `VERNIER_FINE_PITCHES`, so that combined error must stay under half a fine pitch.
+pub fn combine_vernier(phase_coarse: i32, phase_fine: i32) -> i64 {
+    const TURN: i64 = 1 << 32;
+
+    // fraction of the travel, as a turn
+    let beat = phase_fine.wrapping_sub(phase_coarse) as u32 as i64;
+    let fine = phase_fine as u32 as i64;
+
+    // Where the beat says the fine phase should be, minus where it is, is a whole number of fine cycles plus noise.
+    // Rounding rather than truncating keeps a fine phase just either side of its wrap in the right cycle, and rem_euclid catches the beat itself wrapping at the ends of travel.
+    let cycle = ((beat * VERNIER_FINE_PITCHES - fine + TURN / 2).div_euclid(TURN))
+        .rem_euclid(VERNIER_FINE_PITCHES);
+
+    cycle * COUNTS_PER_PITCH + ((fine * COUNTS_PER_PITCH) >> 32)
+}
+
+/// Estimates velocity from positions sampled a fixed period apart.
+/// At a few hundred updates per second a single count of quantization is already hundreds of counts per second, so the differences are smoothed.
+pub struct VelocityEstimator {
+    period: f32,
+    last_position: Option<i64>,
+    filter: dsp::OnePole,
+}
+
+impl VelocityEstimator {
+    /// `period` is the time between updates in seconds; `alpha` sets the smoothing as in `dsp::OnePole`.
+    pub fn new(period: f32, alpha: f32) -> Self {
+        VelocityEstimator {
+            period,
+            last_position: None,
+            filter: dsp::OnePole::new(alpha),
+        }
+    }
+
+    pub fn set_period(&mut self, period: f32) {
+        self.period = period;
+    }
+
+    /// Forget the previous position, e.g., after a gap in updates.
+    pub fn reset(&mut self) {
+        self.last_position = None;
+        self.filter.reset();
+    }
+
+    /// Takes a position in counts and returns the smoothed velocity in counts per second.
+    /// The first update after construction or `reset` has nothing to difference against and returns 0.
+    pub fn update(&mut self, position: i64) -> f32 {
+        let Some(last_position) = self.last_position.replace(position) else {
+            return 0.0;
+        };
+        self.filter
+            .filter((position - last_position) as f32 / self.period)
+    }
+}
+
+/// Which way the slider is moving; forward is increasing position.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Stationary,
+    Forward,
+    Backward,
+}
+
+/// Direction of motion from a smoothed velocity, with hysteresis so noise around zero doesn't flicker it.
+/// Moving starts once the speed is above `deadband` and stops once it's back under half that; a reversal has to clear `deadband` the other way.
+pub struct DirectionDetector {
+    deadband: f32,
+    direction: Direction,
+}
+
+impl DirectionDetector {
+    /// `deadband` is in the same units as the velocities passed to `update`.
+    pub fn new(deadband: f32) -> Self {
+        DirectionDetector {
+            deadband,
+            direction: Direction::Stationary,
+        }
+    }
+
+    pub fn set_deadband(&mut self, deadband: f32) {
+        self.deadband = deadband;
+    }
+
+    /// Back to stationary, e.g., when something else has decided the slider is still.
+    pub fn reset(&mut self) {
+        self.direction = Direction::Stationary;
+    }
+
+    pub fn update(&mut self, velocity: f32) -> Direction {
+        self.direction = if velocity > self.deadband {
+            Direction::Forward
+        } else if velocity < -self.deadband {
+            Direction::Backward
+        } else if velocity.abs() < self.deadband / 2.0 {
+            Direction::Stationary
+        } else {
+            self.direction
+        };
+        self.direction
+    }
+}
+
+/// Converts position into incremental A/B quadrature, one Gray-code transition per count.
+/// Position can jump by many counts between updates; pending transitions are held until `step` emits them one at a time.
+pub struct QuadratureEncoder {
+    emitted: Option<i64>,
+    target: i64,
+    /// Total transitions emitted, for checking against what the receiving controller counted.
+    pub edges: u32,
+}
+
+impl QuadratureEncoder {
+    pub fn new() -> Self {
+        QuadratureEncoder {
+            emitted: None,
+            target: 0,
+            edges: 0,
+        }
+    }
+
+    /// The first target just sets the starting point, so the output doesn't sweep in from zero on startup.
+    pub fn set_target(&mut self, position: i64) {
+        self.emitted.get_or_insert(position);
+        self.target = position;
+    }
+
+    /// Transitions not yet emitted.
+    pub fn pending(&self) -> i64 {
+        self.emitted.map_or(0, |emitted| self.target - emitted)
+    }
+
+    /// Moves the output one count towards the target, returning the new (A, B) levels, or `None` if already caught up.
+    pub fn step(&mut self) -> Option<(bool, bool)> {
+        let emitted = self.emitted.as_mut()?;
+        if *emitted == self.target {
+            return None;
+        }
+        *emitted += (self.target - *emitted).signum();
+        self.edges = self.edges.wrapping_add(1);
+
+        // A leads B when counting up
+        Some(match emitted.rem_euclid(4) {
+            0 => (false, false),
+            1 => (true, false),
+            2 => (true, true),
+            _ => (false, true),
+        })
+    }
+}
+
+impl Default for QuadratureEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Steps excitation strength between discrete levels to keep the received magnitude within `low..=high`.
+/// Levels are indices, weakest first; how much each step changes the magnitude is up to the caller.
+/// For hysteresis the band must be wider than the ratio between adjacent levels, otherwise a step out of one side overshoots the other and the level oscillates.
+pub struct GainControl {
+    level: usize,
+    levels: usize,
+    low: f32,
+    high: f32,
+    /// Updates still to ignore after a change, while the measurement catches up with the new level.
+    holdoff: u32,
+    settle_updates: u32,
+}
+
+impl GainControl {
+    /// Starts at the strongest level, since a weak signal is the usual case and an overdriven one shows up straight away.
+    pub fn new(levels: usize, low: f32, high: f32, settle_updates: u32) -> Self {
+        assert!(levels > 0 && low < high);
+        GainControl {
+            level: levels - 1,
+            levels,
+            low,
+            high,
+            holdoff: 0,
+            settle_updates,
+        }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Takes the latest magnitude and returns the new level if it changed.
+    pub fn update(&mut self, magnitude: f32) -> Option<usize> {
+        if self.holdoff > 0 {
+            self.holdoff -= 1;
+            return None;
+        }
+
+        let level = if magnitude > self.high && self.level > 0 {
+            self.level - 1
+        } else if magnitude < self.low && self.level + 1 < self.levels {
+            self.level + 1
+        } else {
+            return None;
+        };
+        self.level = level;
+        self.holdoff = self.settle_updates;
+        Some(level)
+    }
+}
+
+/// Soft start and stop for the excitation: steps the drive through the gain levels one at a time, holding each for a while, instead of jumping between off and full strength.
+/// The front end's coupling and filtering then see a few small steps rather than one big one, which rings for less time and radiates less.
+/// The drive is `None` for off, or a level index, weakest first as for `GainControl`.
+#[derive(Clone, Copy)]
+pub struct DriveRamp {
+    drive: Option<usize>,
+    target: Option<usize>,
+    /// Samples to hold each step for; 0 jumps straight to the target.
+    hold: u32,
+    countdown: u32,
+}
+
+impl DriveRamp {
+    /// Starts off, with nothing to ramp to.
+    pub const fn new(hold: u32) -> Self {
+        DriveRamp {
+            drive: None,
+            target: None,
+            hold,
+            countdown: 0,
+        }
+    }
+
+    pub fn set_hold(&mut self, hold: u32) {
+        self.hold = hold;
+    }
+
+    pub fn drive(&self) -> Option<usize> {
+        self.drive
+    }
+
+    pub fn target(&self) -> Option<usize> {
+        self.target
+    }
+
+    pub fn ramping(&self) -> bool {
+        self.drive != self.target
+    }
+
+    /// Ramps from wherever the drive is now, so retargeting partway through carries on from the current step.
+    pub fn set_target(&mut self, target: Option<usize>) {
+        if !self.ramping() {
+            // the first step goes out on the next advance, and each one after holds
+            self.countdown = 0;
+        }
+        self.target = target;
+    }
+
+    /// Moves straight to `drive` with no ramp, e.g., for a gain step, which is small enough already.
+    pub fn jump(&mut self, drive: Option<usize>) {
+        self.drive = drive;
+        self.target = drive;
+    }
+
+    /// Takes the number of samples since the last call and returns whether the drive changed.
+    pub fn advance(&mut self, samples: u32) -> bool {
+        if !self.ramping() {
+            return false;
+        }
+        if self.countdown > samples {
+            self.countdown -= samples;
+            return false;
+        }
+        if self.hold == 0 {
+            self.drive = self.target;
+            return true;
+        }
+        // off sits one step below level 0
+        let step = |drive: Option<usize>| drive.map_or(0, |level| level + 1);
+        let drive = step(self.drive);
+        let next = if step(self.target) > drive {
+            drive + 1
+        } else {
+            drive - 1
+        };
+        self.drive = next.checked_sub(1);
+        self.countdown = self.hold;
+        true
+    }
+}
+
+/// Decides when the analog front end has settled after startup or a reconfiguration, by waiting for consecutive windows to agree.
+/// Stays settled until `reset`, so moving the slider afterwards doesn't count as unsettling.
+pub struct SettlingDetector {
+    required_windows: u32,
+    /// Largest relative change in magnitude between consecutive windows that still counts as stable.
+    max_magnitude_change: f32,
+    /// Largest phase step between consecutive windows that still counts as stable, in turn units.
+    max_phase_step: u32,
+    last: Option<(f32, i32)>,
+    stable_windows: u32,
+}
+
+impl SettlingDetector {
+    pub fn new(required_windows: u32, max_magnitude_change: f32, max_phase_step: u32) -> Self {
+        SettlingDetector {
+            required_windows,
+            max_magnitude_change,
+            max_phase_step,
+            last: None,
+            stable_windows: 0,
+        }
+    }
+
+    /// Start over, e.g., after the excitation changed.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.stable_windows = 0;
+    }
+
+    pub fn settled(&self) -> bool {
+        self.stable_windows >= self.required_windows
+    }
+
+    /// Takes a window's magnitude and phase (turn units, see `dsp::QUARTER_TURN`) and returns whether the signal has settled.
+    pub fn update(&mut self, magnitude: f32, phase: i32) -> bool {
+        if self.settled() {
+            return true;
+        }
+
+        if let Some((last_magnitude, last_phase)) = self.last {
+            let magnitude_change = (magnitude - last_magnitude).abs() / last_magnitude.max(1.0);
+            let phase_step = phase.wrapping_sub(last_phase).unsigned_abs();
+            if magnitude_change <= self.max_magnitude_change && phase_step <= self.max_phase_step {
+                self.stable_windows += 1;
+            } else {
+                self.stable_windows = 0;
+            }
+        }
+        self.last = Some((magnitude, phase));
+        self.settled()
+    }
+}
+
+/// Running minimum and maximum of position since construction or `reset`, for measuring travel or runout.
+#[derive(Clone, Copy, Default)]
+pub struct PeakHold {
+    range: Option<(i64, i64)>,
+}
+
+impl PeakHold {
+    pub fn new() -> Self {
+        PeakHold { range: None }
+    }
+
+    pub fn reset(&mut self) {
+        self.range = None;
+    }
+
+    pub fn update(&mut self, position: i64) {
+        let (min, max) = self.range.get_or_insert((position, position));
+        *min = (*min).min(position);
+        *max = (*max).max(position);
+    }
+
+    /// `(min, max)` of the positions so far, or `None` if there haven't been any since `reset`.
+    pub fn range(&self) -> Option<(i64, i64)> {
+        self.range
+    }
+}
+
+/// Holds a displayed position still until the real one leaves a dead zone around it, so noise doesn't flicker the last digit.
+/// Once it has left, the output follows the input exactly until the input stays within `width` of one spot for `still_updates` in a row, and is held there again; so slow motion doesn't step by `width` at a time, and trails nothing while moving.
+/// Motion slower than `width` per `still_updates` updates looks still too, and moves the output in steps of `width`.
+/// Unlike `dsp::OnePole`, this doesn't reduce noise, only hides it while the slider sits still.
+pub struct DeadZone {
+    width: i64,
+    still_updates: u32,
+    output: Option<i64>,
+    moving: bool,
+    // where the input was when it last moved more than width, and how many updates it's stayed within width of it since
+    anchor: i64,
+    still_run: u32,
+}
+
+impl DeadZone {
+    /// `width` is in the same units as the positions passed to `update`; 0 passes them straight through.
+    pub fn new(width: i64, still_updates: u32) -> Self {
+        DeadZone {
+            width,
+            still_updates,
+            output: None,
+            moving: false,
+            anchor: 0,
+            still_run: 0,
+        }
+    }
+
+    pub fn set_width(&mut self, width: i64) {
+        self.width = width;
+    }
+
+    pub fn set_still_updates(&mut self, still_updates: u32) {
+        self.still_updates = still_updates;
+    }
+
+    pub fn update(&mut self, position: i64) -> i64 {
+        let Some(output) = self.output else {
+            self.output = Some(position);
+            self.anchor = position;
+            return position;
+        };
+        if self.moving {
+            if (position - self.anchor).abs() <= self.width {
+                self.still_run += 1;
+                self.moving = self.still_run < self.still_updates;
+            } else {
+                self.anchor = position;
+                self.still_run = 0;
+            }
+            self.output = Some(position);
+        } else if (position - output).abs() > self.width {
+            self.moving = true;
+            self.anchor = position;
+            self.still_run = 0;
+            self.output = Some(position);
+        }
+        self.output.unwrap()
+    }
+}
+
+/// Decides whether the slider has moved, for dropping into a low-power idle while it sits still.
+/// Windows are compared against the one where motion was last seen rather than the previous window, so a slow creep still adds up to motion eventually.
+pub struct MotionDetector {
+    /// Smallest phase change from the reference that counts as motion, in turn units.
+    min_phase_step: u32,
+    /// Smallest relative change in magnitude from the reference that counts as motion, e.g., the slider being lifted off the scale.
+    min_magnitude_change: f32,
+    reference: Option<(f32, i32)>,
+}
+
+impl MotionDetector {
+    pub fn new(min_phase_step: u32, min_magnitude_change: f32) -> Self {
+        MotionDetector {
+            min_phase_step,
+            min_magnitude_change,
+            reference: None,
+        }
+    }
+
+    /// Takes a window's magnitude and phase (turn units, see `dsp::QUARTER_TURN`) and returns whether it moved.
+    /// The first window after construction just sets the reference, and counts as motion.
+    pub fn update(&mut self, magnitude: f32, phase: i32) -> bool {
+        let moved = match self.reference {
+            None => true,
+            Some((reference_magnitude, reference_phase)) => {
+                let magnitude_change =
+                    (magnitude - reference_magnitude).abs() / reference_magnitude.max(1.0);
+                let phase_step = phase.wrapping_sub(reference_phase).unsigned_abs();
+                magnitude_change >= self.min_magnitude_change || phase_step >= self.min_phase_step
+            }
+        };
+        if moved {
+            self.reference = Some((magnitude, phase));
+        }
+        moved
+    }
+}
+
+/// Pins whose set and reset bits are both 1 in GPIO BSRR word `word`.
+/// BSRR resolves that as "set", so in a generated drive table it's a bug rather than anything intended.
+pub fn bsrr_conflicts(word: u32) -> u16 {
+    (word & (word >> 16)) as u16
+}
+
+/// Packs `samples` two to three bytes, for streaming 12-bit ADC data in three quarters of the bandwidth; see `schema::SampleWidth::Packed12` for the layout.
+/// Samples above 4095 saturate, and an odd one out is paired with 0.
+/// Returns the number of bytes written, stopping at whichever of `samples` or `out` runs out first.
+pub fn pack_12(samples: &[u16], big_endian: bool, out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for (pair, bytes) in samples.chunks(2).zip(out.chunks_exact_mut(3)) {
+        let a = pair[0].min(0xFFF) as u32;
+        let b = pair.get(1).map_or(0, |b| (*b).min(0xFFF)) as u32;
+        // either way the pair is one 24-bit integer with `a` in the first bytes sent
+        if big_endian {
+            bytes.copy_from_slice(&(a << 12 | b).to_be_bytes()[1..]);
+        } else {
+            bytes.copy_from_slice(&(a | b << 12).to_le_bytes()[..3]);
+        }
+        len += 3;
+    }
+    len
+}
+
+/// Inverse of `pack_12`, returning the number of samples written: two per whole three bytes, including any padding.
+pub fn unpack_12(bytes: &[u8], big_endian: bool, out: &mut [u16]) -> usize {
+    let mut len = 0;
+    for (bytes, pair) in bytes.chunks_exact(3).zip(out.chunks_exact_mut(2)) {
+        let (a, b) = if big_endian {
+            let x = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            (x >> 12, x & 0xFFF)
+        } else {
+            let x = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+            (x & 0xFFF, x >> 12)
+        };
+        pair[0] = a as u16;
+        pair[1] = b as u16;
+        len += 2;
+    }
+    len
+}
+
+/// Debounces a polled input such as a button: a new level only counts once it's read the same `stable_polls` times in a row.
+pub struct Debouncer {
+    stable_polls: u32,
+    level: bool,
+    /// Polls in a row that have disagreed with `level`.
+    count: u32,
+}
+
+impl Debouncer {
+    pub fn new(level: bool, stable_polls: u32) -> Self {
+        Debouncer {
+            stable_polls,
+            level,
+            count: 0,
+        }
+    }
+
+    /// Takes the latest raw reading and returns the debounced level if it just changed.
+    pub fn update(&mut self, raw: bool) -> Option<bool> {
+        if raw == self.level {
+            self.count = 0;
+            return None;
+        }
+        self.count += 1;
+        if self.count < self.stable_polls {
+            return None;
+        }
+        self.level = raw;
+        self.count = 0;
+        Some(raw)
+    }
+}
+
+/// Catches an ADC/DMA that's still handing over windows but has stopped converting, so each one is the same stale data: a timeout never fires, since the transfers keep coming.
+/// A window is stale once it's been identical to the last for `stale_windows` in a row, and the ADC hasn't started a conversion since the last window either.
+/// Real input always has a few LSB of noise, but one pinned to a rail reads the same every window, which is why identical alone isn't enough.
+pub struct StallDetector {
+    stale_windows: u32,
+    last_hash: Option<u32>,
+    /// Windows in a row that have been identical to the one before.
+    identical: u32,
+    /// Stalls detected since construction.
+    pub stalls: u32,
+}
+
+impl StallDetector {
+    pub fn new(stale_windows: u32) -> Self {
+        StallDetector {
+            stale_windows,
+            last_hash: None,
+            identical: 0,
+            stalls: 0,
+        }
+    }
+
+    /// Takes each window along with whether the ADC has started a conversion since the last one, and returns whether it's stalled.
+    /// After a stall it starts over, so the windows after a restart have to go stale again to count as another.
+    pub fn update(&mut self, samples: &[u16], converting: bool) -> bool {
+        let hash = window_hash(samples);
+        if self.last_hash == Some(hash) {
+            self.identical += 1;
+        } else {
+            self.identical = 0;
+        }
+        self.last_hash = Some(hash);
+
+        if self.identical < self.stale_windows || converting {
+            return false;
+        }
+        self.last_hash = None;
+        self.identical = 0;
+        self.stalls += 1;
+        true
+    }
+}
+
+/// FNV-1a over the samples' bytes, which unlike a plain sum also tells apart windows with the same samples in a different order.
+pub fn window_hash(samples: &[u16]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in samples.iter().flat_map(|x| x.to_le_bytes()) {
+        hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Gain and I/Q offset of one channel of a differential pair, applied to its correlation sums before the pair is subtracted: `(sums - offset) * gain`.
+/// Two channels rarely have quite the same gain or DC bias (which leaks into the sums when the window isn't a whole number of cycles), and either leaves a common-mode signal some residue in the difference.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelCalibration {
+    pub gain: f32,
+    pub offset_sine: i32,
+    pub offset_cosine: i32,
+}
+
+impl ChannelCalibration {
+    pub const IDENTITY: Self = ChannelCalibration {
+        gain: 1.0,
+        offset_sine: 0,
+        offset_cosine: 0,
+    };
+
+    /// From the channel's mean sums with no input, which are its offset, and its mean magnitude over that offset (see `magnitude_over`) with an input known to read `known` applied.
+    /// The known input's phase doesn't matter, so a slider can sit anywhere, but it has to be the same on both channels for them to come out balanced.
+    /// Returns `None` if the gain comes out unusable, e.g., with nothing over the offset.
+    pub fn new(zero: (i32, i32), measured: f32, known: f32) -> Option<Self> {
+        let gain = known / measured;
+        (gain.is_finite() && gain > 0.0).then_some(ChannelCalibration {
+            gain,
+            offset_sine: zero.0,
+            offset_cosine: zero.1,
+        })
+    }
+
+    /// Magnitude of `sums` less an offset of `zero`.
+    pub fn magnitude_over(zero: (i32, i32), (sum_sine, sum_cosine): (i32, i32)) -> f32 {
+        let (sine, cosine) = ((sum_sine - zero.0) as f32, (sum_cosine - zero.1) as f32);
+        (sine * sine + cosine * cosine).sqrt()
+    }
+
+    pub fn apply(&self, (sum_sine, sum_cosine): (i32, i32)) -> (i32, i32) {
+        (
+            ((sum_sine - self.offset_sine) as f32 * self.gain).round() as i32,
+            ((sum_cosine - self.offset_cosine) as f32 * self.gain).round() as i32,
+        )
+    }
+}
@@ -0,0 +1,160 @@
+//! local's demodulation chain, from a window of ADC samples to a position, with nothing hardware about it.
+//! local runs its windows through these steps, usb_custom takes its magnitude and window phase advance from them, and build.rs generates its tables with them, so windows can be replayed through `Pipeline` on the host and checked against the phase they were taken at.
+//! `tests/pipeline.rs` does that with synthetic windows, a regression check of the math rather than of the analog front end; a recording from `sample-dump` can go in the same fixture format.
+
+use crate::dsp::{cordic_atan2, correlate, OnePole, Q15_SHIFT};
+use crate::PositionTracker;
+#[cfg(not(test))]
+use num_traits::Float;
+
+/// One window's worth of output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    /// Slider phase in turn units, referred to the excitation's phase at the start of the first window.
+    pub phase: i32,
+    /// At sample scale, see local's MIN_MAGNITUDE.
+    pub magnitude: f32,
+    /// Unwrapped, in counts.
+    pub raw_position: i64,
+    /// `raw_position` through the position filter.
+    pub position: f32,
+}
+
+/// Demodulates single-channel, single-tone windows the way local does with its defaults: correlate against the table, atan2, advance by the window's share of the excitation, unwrap, filter.
+/// local's loop runs the same steps, `channel_sums`, `phase_magnitude`, `WindowPhase` and `PositionStage`, with its options around them.
+pub struct Pipeline<'a> {
+    table: &'a [(i16, i16)],
+    min_magnitude: f32,
+    window_phase: WindowPhase,
+    position: PositionStage,
+}
+
+impl<'a> Pipeline<'a> {
+    /// `table` and `window_phase_advance` are build.rs's SINE_COSINE_TABLE and WINDOW_PHASE_ADVANCE for the timing the windows were taken at; `sine_cosine_table` makes the former.
+    pub fn new(
+        table: &'a [(i16, i16)],
+        window_phase_advance: i32,
+        min_magnitude: f32,
+        filter_alpha: f32,
+    ) -> Self {
+        Pipeline {
+            table,
+            min_magnitude,
+            window_phase: WindowPhase::new(window_phase_advance),
+            position: PositionStage::new(filter_alpha),
+        }
+    }
+
+    /// Takes the next window, which has to follow the last one without a gap, since the excitation's phase is carried from one to the next.
+    /// Returns `None` for a window below the minimum magnitude, whose phase is noise; it still counts towards the excitation's phase.
+    pub fn process(&mut self, samples: &[u16]) -> Option<Reading> {
+        let window_start_phase = self.window_phase.advance();
+        let [sums] = channel_sums::<1>(self.table, samples, 0);
+        let (phase, magnitude) = phase_magnitude(sums, window_start_phase);
+        if magnitude < self.min_magnitude {
+            return None;
+        }
+
+        let (raw_position, position) = self.position.update(phase);
+        Some(Reading {
+            phase,
+            magnitude,
+            raw_position,
+            position,
+        })
+    }
+}
+
+/// Each of `CHANNELS` interleaved channels' correlation sums against `table`, from sample `first` on, back at sample scale.
+/// Samples line up with the table one-for-one, so each channel correlates against exactly the times it was sampled at.
+pub fn channel_sums<const CHANNELS: usize>(
+    table: &[(i16, i16)],
+    samples: &[u16],
+    first: usize,
+) -> [(i32, i32); CHANNELS] {
+    correlate::<CHANNELS>(table, samples, first).map(|(sum_sine, sum_cosine)| {
+        (
+            (sum_sine >> Q15_SHIFT) as i32,
+            (sum_cosine >> Q15_SHIFT) as i32,
+        )
+    })
+}
+
+/// Phase of a window's `(Σ x sin, Σ x cos)` sums in turn units, referred to the excitation's phase at the start of the window, and their magnitude.
+pub fn phase_magnitude((sum_sine, sum_cosine): (i32, i32), window_start_phase: i32) -> (i32, f32) {
+    let phase = cordic_atan2(sum_sine, sum_cosine).wrapping_add(window_start_phase);
+    let magnitude = ((sum_sine as f32).powi(2) + (sum_cosine as f32).powi(2)).sqrt();
+    (phase, magnitude)
+}
+
+/// The excitation's phase at the start of each window, in turn units from 0 at the first.
+/// Windows follow one another without a gap, so each starts `window_phase_advance` on from the last.
+pub struct WindowPhase {
+    phase: i32,
+    step: i32,
+}
+
+impl WindowPhase {
+    pub const fn new(window_phase_advance: i32) -> Self {
+        WindowPhase {
+            phase: 0,
+            step: window_phase_advance,
+        }
+    }
+
+    /// Returns the phase at the start of the window just taken, and moves on to the next one.
+    /// Call it for every window, including ones that get dropped, since each still took its share of the excitation.
+    pub fn advance(&mut self) -> i32 {
+        let phase = self.phase;
+        self.phase = self.phase.wrapping_add(self.step);
+        phase
+    }
+}
+
+/// The end of the chain: unwraps each window's phase into counts, then filters them.
+pub struct PositionStage {
+    pub tracker: PositionTracker,
+    pub filter: OnePole,
+}
+
+impl PositionStage {
+    pub fn new(filter_alpha: f32) -> Self {
+        PositionStage {
+            tracker: PositionTracker::new(),
+            filter: OnePole::new(filter_alpha),
+        }
+    }
+
+    /// Returns the unwrapped position and the filtered one.
+    pub fn update(&mut self, phase: i32) -> (i64, f32) {
+        let raw_position = self.tracker.update_angle(phase);
+        (raw_position, self.filter.filter(raw_position as f32))
+    }
+}
+
+/// Scale of a sine/cosine table's Q15 coefficients, i.e., what 1.0 comes out as.
+pub const Q15_ONE: f64 = i16::MAX as f64;
+
+/// The excitation's phase in radians at sample `i` of a window spanning `bin` cycles over `num_samples`, from 0 at the window's start.
+pub fn sample_angle(bin: f64, i: usize, num_samples: usize) -> f64 {
+    2.0 * core::f64::consts::PI * bin * i as f64 / num_samples as f64
+}
+
+/// Sample `i`'s entry of a sine/cosine table at `bin`, as `sample_angle`'s sine and cosine times `weight`, in Q15.
+pub fn sine_cosine_entry(bin: f64, i: usize, num_samples: usize, weight: f64) -> (i16, i16) {
+    let angle = sample_angle(bin, i, num_samples);
+    (
+        (weight * angle.sin() * Q15_ONE).round() as i16,
+        (weight * angle.cos() * Q15_ONE).round() as i16,
+    )
+}
+
+/// build.rs's SINE_COSINE_TABLE with a rectangular window and no gate, for `bin` cycles of the excitation per `N` samples.
+pub fn sine_cosine_table<const N: usize>(bin: f64) -> [(i16, i16); N] {
+    core::array::from_fn(|i| sine_cosine_entry(bin, i, N, 1.0))
+}
+
+/// build.rs's WINDOW_PHASE_ADVANCE, how far the excitation turns over a window of `bin` cycles, in turn units; the whole cycles wrap away in the cast.
+pub fn window_phase_advance(bin: f64) -> i32 {
+    (bin * 4_294_967_296.0) as i64 as i32
+}
@@ -0,0 +1,577 @@
+use calipertron_core::dsp::{
+    adc_to_millivolts, aliased_harmonic, cordic_atan2, count_saturated, median_filter,
+    millivolts_per_count, radians_to_angle, spectrum, sum_groups, AdcAlignment, AdcFormat, AdcLut,
+    Goertzel, Interpolation, NoiseStats, OnePole, PhaseHistogram, PhaseLockedLoop, PhaseStdDev,
+    Resampler, ADC_BITS, MAX_ALIASED_HARMONIC, MIN_VREFINT_SAMPLE, QUARTER_TURN, SAMPLE_PERIOD,
+};
+use core::f32::consts::PI;
+
+// Against floating-point atan2 all the way round, on the axes, and from tiny to full-scale vectors.
+#[test]
+fn cordic() {
+    let turn = 4_294_967_296.0;
+    let error = |y: i32, x: i32| {
+        let expected = (f64::atan2(y as f64, x as f64) / core::f64::consts::TAU * turn) as i64;
+        // ±π is one angle, i32::MIN
+        (cordic_atan2(y, x) as i64 - expected + (1 << 31)).rem_euclid(1 << 32) - (1 << 31)
+    };
+
+    let mut worst = 0;
+    for magnitude in [10.0, 1_000.0, 100_000.0, 2_000_000_000.0] {
+        for step in 0..4096 {
+            let theta = core::f64::consts::TAU * (step as f64 + 0.3) / 4096.0;
+            let (y, x) = (
+                (magnitude * theta.sin()) as i32,
+                (magnitude * theta.cos()) as i32,
+            );
+            worst = worst.max(error(y, x).abs());
+        }
+        let m = magnitude as i32;
+        for (y, x) in [
+            (0, m),
+            (m, 0),
+            (0, -m),
+            (-m, 0),
+            (m, m),
+            (m, -m),
+            (-m, -m),
+            (-m, m),
+        ] {
+            worst = worst.max(error(y, x).abs());
+        }
+    }
+    // as documented, just under 2^-17 of a turn
+    assert!(worst < 21_000, "off by up to {worst}");
+    assert_eq!(cordic_atan2(0, 0), 0);
+}
+
+// A pure tone at the bin reads back its amplitude and phase, in the table method's convention, whether or not the window spans whole cycles.
+#[test]
+fn goertzel() {
+    let n = 128;
+    let amplitude = 1000.0;
+    for bin in [1.0, 3.0, 10.0, 2.5, 10.37] {
+        for phase in [0.0, 1.0, -2.0, PI - 0.01] {
+            let mut goertzel = Goertzel::new(n, bin);
+            let samples: Vec<i16> = (0..n)
+                .map(|i| {
+                    let angle = 2.0 * PI * bin * i as f32 / n as f32;
+                    (amplitude * (angle - phase).cos()).round() as i16
+                })
+                .collect();
+            for x in &samples {
+                goertzel.push(*x);
+            }
+            let (magnitude, measured) = goertzel.magnitude_phase();
+
+            // the same sums the correlation tables would give, to f32 rounding
+            let (mut sine, mut cosine) = (0.0f64, 0.0f64);
+            for (i, x) in samples.iter().enumerate() {
+                let angle = core::f64::consts::TAU * bin as f64 * i as f64 / n as f64;
+                sine += *x as f64 * angle.sin();
+                cosine += *x as f64 * angle.cos();
+            }
+            let (goertzel_sine, goertzel_cosine) = goertzel.iq();
+            let tolerance = 1e-4 * (amplitude * n as f32 / 2.0) as f64;
+            assert!(
+                (goertzel_sine as f64 - sine).abs() < tolerance
+                    && (goertzel_cosine as f64 - cosine).abs() < tolerance,
+                "bin {bin}, phase {phase}: iq ({goertzel_sine}, {goertzel_cosine}), expected ({sine}, {cosine})"
+            );
+
+            // in whole or half cycles the tone's negative frequency image is orthogonal to the bin; otherwise a little of it leaks in
+            let tolerance = if (2.0 * bin).fract() == 0.0 {
+                0.001
+            } else {
+                0.02
+            };
+            let magnitude_error = magnitude / (amplitude * n as f32 / 2.0) - 1.0;
+            let phase_error = (measured - phase + PI).rem_euclid(2.0 * PI) - PI;
+            assert!(
+                magnitude_error.abs() < tolerance,
+                "bin {bin}, phase {phase}: magnitude {magnitude}"
+            );
+            assert!(
+                phase_error.abs() < tolerance,
+                "bin {bin}, phase {phase}: phase {measured}"
+            );
+        }
+    }
+}
+
+// Unit step response should cross 63% (1 - 1/e) at sample ceil(-1 / ln(1 - alpha)) and not before.
+#[test]
+fn one_pole() {
+    for alpha in [0.05f32, 0.1, 0.3, 1.0] {
+        let mut filter = OnePole::new(alpha);
+        filter.filter(0.0);
+
+        let expected_samples = (-1.0 / (1.0 - alpha).ln()).ceil().max(1.0) as usize;
+        let samples = (1..).find(|_| filter.filter(1.0) >= 1.0 - (-1.0f32).exp());
+        assert_eq!(samples, Some(expected_samples), "alpha {alpha}");
+    }
+}
+
+// A single full-scale spike in a clean window should barely move the phase once median filtered.
+#[test]
+fn median() {
+    let correlate = |samples: &[u16]| {
+        let (mut sum_sine, mut sum_cosine) = (0.0, 0.0);
+        for (i, x) in samples.iter().enumerate() {
+            let angle = 2.0 * core::f64::consts::PI * i as f64 / samples.len() as f64;
+            sum_sine += *x as f64 * angle.sin();
+            sum_cosine += *x as f64 * angle.cos();
+        }
+        f64::atan2(sum_sine, sum_cosine)
+    };
+
+    let phase = 1.0;
+    let mut clean = [0u16; 128];
+    for (i, x) in clean.iter_mut().enumerate() {
+        let angle = 2.0 * core::f64::consts::PI * i as f64 / 128.0;
+        *x = (2048.0 + 1024.0 * (angle - phase).cos()).round() as u16;
+    }
+    let expected = correlate(&clean);
+
+    for spike_at in [2, 40, 97] {
+        let mut samples = clean;
+        samples[spike_at] = 4095;
+        let spiked_error = (correlate(&samples) - expected).abs();
+
+        median_filter::<3>(&mut samples);
+        let filtered_error = (correlate(&samples) - expected).abs();
+        assert!(
+            filtered_error < 0.001 && filtered_error < spiked_error / 10.0,
+            "spike at {spike_at}: phase error {spiked_error} unfiltered, {filtered_error} filtered"
+        );
+    }
+
+    let mut samples = clean;
+    median_filter::<5>(&mut samples);
+    assert!((correlate(&samples) - expected).abs() < 0.001);
+}
+
+#[test]
+fn oversampling() {
+    // xorshift32, so runs are repeatable
+    let mut state = 0x1234_5678u32;
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f64 / u32::MAX as f64
+    };
+    // roughly gaussian, unit variance
+    let mut noise = move || (0..12).map(|_| uniform()).sum::<f64>() - 6.0;
+
+    // phase jitter of one window summed over K conversions per slot, with conversion noise of a few counts
+    let mut jitter = |k: usize| {
+        let trials = 400;
+        let phases: Vec<f64> = (0..trials)
+            .map(|_| {
+                let conversions: Vec<u16> = (0..128 * k)
+                    .map(|j| {
+                        let angle = 2.0 * core::f64::consts::PI * j as f64 / (128 * k) as f64;
+                        (2048.0 + 200.0 * (angle - 1.0).cos() + 8.0 * noise()).round() as u16
+                    })
+                    .collect();
+                let mut slots = [0u16; 128];
+                match k {
+                    1 => sum_groups::<1>(&conversions, &mut slots),
+                    4 => sum_groups::<4>(&conversions, &mut slots),
+                    _ => unreachable!(),
+                }
+
+                let (mut sum_sine, mut sum_cosine) = (0.0, 0.0);
+                for (i, x) in slots.iter().enumerate() {
+                    let angle = 2.0 * core::f64::consts::PI * i as f64 / 128.0;
+                    sum_sine += *x as f64 * angle.sin();
+                    sum_cosine += *x as f64 * angle.cos();
+                }
+                f64::atan2(sum_sine, sum_cosine)
+            })
+            .collect();
+        let mean = phases.iter().sum::<f64>() / trials as f64;
+        (phases.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / trials as f64).sqrt()
+    };
+
+    let (single, oversampled) = (jitter(1), jitter(4));
+    let improvement = single / oversampled;
+    assert!(
+        (1.7..2.3).contains(&improvement),
+        "4x oversampling cut jitter by {improvement:.2}, should be about 2"
+    );
+}
+
+#[test]
+fn phase_std_dev() {
+    let mut state = 0x9e37_79b9u32;
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f64 / u32::MAX as f64
+    };
+    let mut noise = move || (0..12).map(|_| uniform()).sum::<f64>() - 6.0;
+
+    let sigma = 0.05;
+    // well clear of the wrap, then right at it, where naive deltas would spread over a whole turn
+    for phase in [0.3, core::f64::consts::PI - 0.01] {
+        let mut std_dev = PhaseStdDev::<64>::new();
+        let (mut sum, mut count, mut worst) = (0.0, 0, 0.0f32);
+        for i in 0..2000 {
+            let x = phase + sigma * noise();
+            let x = (x + core::f64::consts::PI).rem_euclid(core::f64::consts::TAU)
+                - core::f64::consts::PI;
+            std_dev.push(radians_to_angle(x as f32));
+            if i >= 64 {
+                let estimate = std_dev.std_dev();
+                sum += estimate;
+                count += 1;
+                worst = worst.max((estimate / sigma as f32 - 1.0).abs());
+            }
+        }
+        // each 64-sample estimate is only good to about 1 / sqrt(2 * 63), i.e., 9%, but on average it should be close
+        let mean = sum / count as f32;
+        assert!(
+            (mean / sigma as f32 - 1.0).abs() < 0.05,
+            "phase {phase}: std dev averages {mean}"
+        );
+        assert!(worst < 0.4, "phase {phase}: std dev off by up to {worst}");
+    }
+
+    let mut std_dev = PhaseStdDev::<64>::new();
+    for _ in 0..200 {
+        std_dev.push(12345);
+    }
+    assert_eq!(std_dev.std_dev(), 0.0);
+}
+
+#[test]
+fn adc_lut() {
+    // an ADC that reads codes in its upper half 3 high, and a table that undoes it
+    let distort = |x: u16| if x >= 2048 { x + 3 } else { x };
+    let mut full = AdcLut::new([0i16; 4096]);
+    full.set(2048, &[-3; 2048]);
+    let mut samples: Vec<u16> = (0..4093).step_by(7).map(distort).collect();
+    full.apply(&mut samples);
+    for (x, expected) in samples.iter().zip((0..4093).step_by(7)) {
+        assert_eq!(*x, expected);
+    }
+
+    // 256 entries of 16 codes each, indexed by the top 8 bits
+    let mut coarse = AdcLut::new([0i16; 256]);
+    coarse.set(255, &[100]);
+    let mut samples = [0, 15, 4079, 4080, 4095];
+    coarse.apply(&mut samples);
+    assert_eq!(samples, [0, 15, 4079, 4095, 4095]);
+
+    // corrections saturate at the ends of the range
+    let low = AdcLut::new([-5i16; 256]);
+    let mut samples = [2, 100];
+    low.apply(&mut samples);
+    assert_eq!(samples, [0, 95]);
+}
+
+#[test]
+fn saturation() {
+    // a sinusoid driven past both rails clips for the same stretch at the top and bottom
+    let samples: Vec<u16> = (0..128)
+        .map(|i| {
+            let angle = 2.0 * PI * i as f32 / 128.0;
+            (2048.0 + 2400.0 * angle.cos()).round().clamp(0.0, 4095.0) as u16
+        })
+        .collect();
+    let clipped = count_saturated(&samples);
+    // |cos(angle)| > 2047/2400 for 2 * acos(0.853) / 2π of the cycle at each end, about 22 samples
+    assert!((42..=48).contains(&clipped), "{clipped} samples clipped");
+    assert_eq!(count_saturated(&[1, 2048, 4094]), 0);
+}
+
+#[test]
+fn noise_stats() {
+    // a ±3 count square wave on a large DC level has an RMS of exactly 3
+    let mut stats = NoiseStats::new();
+    for i in 0..4096 {
+        stats.push(if i % 2 == 0 { 3000 - 3 } else { 3000 + 3 });
+    }
+    assert_eq!(stats.count(), 4096);
+    assert_eq!(stats.mean(), 3000.0);
+    assert!((stats.rms() - 3.0).abs() < 1e-4, "rms {}", stats.rms());
+    assert_eq!(stats.peak_to_peak(), 6);
+
+    // a constant has none
+    let mut stats = NoiseStats::new();
+    for _ in 0..1000 {
+        stats.push(4095);
+    }
+    assert_eq!(stats.rms(), 0.0);
+    assert_eq!(stats.peak_to_peak(), 0);
+    assert_eq!(NoiseStats::new().rms(), 0.0);
+}
+
+#[test]
+fn pll() {
+    let mut state = 0x2545_f491u32;
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f64 / u32::MAX as f64
+    };
+    let mut noise = move || (0..12).map(|_| uniform()).sum::<f64>() - 6.0;
+
+    // 0.01 rad of noise, about 1.6e-3 of a turn, on a phase right at the wrap
+    let sigma = 0.01 * 4_294_967_296.0 / core::f64::consts::TAU;
+    let phase = radians_to_angle(PI - 0.001);
+    let mut pll = PhaseLockedLoop::new(0.01, (4.0 * sigma) as u32);
+    let (mut input_m2, mut output_m2) = (0.0, 0.0);
+    for i in 0..5000 {
+        let x = phase.wrapping_add((sigma * noise()) as i32);
+        let y = pll.update(x);
+        if i >= 1000 {
+            assert!(pll.locked(), "window {i}: lost lock on a still phase");
+            input_m2 += (x.wrapping_sub(phase) as f64).powi(2);
+            output_m2 += (y.wrapping_sub(phase) as f64).powi(2);
+        }
+    }
+    let ratio = output_m2 / input_m2;
+    // 2 * bandwidth in theory
+    assert!(
+        ratio < 0.04,
+        "PLL output variance is {ratio} of the input's"
+    );
+
+    // a step the loop can't follow snaps to it, unlocked
+    let jumped = phase.wrapping_add(QUARTER_TURN);
+    assert_eq!(pll.update(jumped), jumped);
+    assert!(!pll.locked());
+
+    // a slow ramp is tracked with no steady-state lag
+    let mut pll = PhaseLockedLoop::new(0.01, (4.0 * sigma) as u32);
+    let rate = 100_000;
+    let mut x = 0i32;
+    for _ in 0..3000 {
+        x = x.wrapping_add(rate);
+        pll.update(x);
+    }
+    let lag = x.wrapping_sub(pll.phase()).unsigned_abs();
+    assert!(lag < 1000, "PLL lags a ramp by {lag}");
+    assert!(pll.locked());
+}
+
+#[test]
+fn millivolts() {
+    // VREFINT reading 1200mV on a 3.3V supply is 1489 counts
+    let vrefint_sample = 1489;
+    for (sample, expected) in [(0, 0), (1489, 1200), (2048, 1650), (4095, 3300)] {
+        let mv = adc_to_millivolts(sample, vrefint_sample).unwrap();
+        assert!(
+            mv.abs_diff(expected) <= 1,
+            "{sample} counts is {mv}mV, expected {expected}"
+        );
+        let scaled = sample as f32 * millivolts_per_count(vrefint_sample).unwrap();
+        assert!(
+            (scaled - mv as f32).abs() < 1.0,
+            "{sample} counts: {scaled} vs {mv}"
+        );
+    }
+    // a low supply reads VREFINT higher, so the same sample is fewer millivolts
+    assert_eq!(adc_to_millivolts(2048, 2048), Some(1200));
+    // a failed or never-enabled reference read doesn't get divided by
+    assert_eq!(adc_to_millivolts(4095, 0), None);
+    assert_eq!(adc_to_millivolts(4095, MIN_VREFINT_SAMPLE - 1), None);
+    assert_eq!(millivolts_per_count(0), None);
+    assert_eq!(millivolts_per_count(MIN_VREFINT_SAMPLE - 1), None);
+    // at the floor, with the reference low and the supply at 3.6V, even 16 bits of oversampled sum fits a u16
+    assert_eq!(adc_to_millivolts(4095, MIN_VREFINT_SAMPLE), Some(3722));
+    assert_eq!(
+        adc_to_millivolts(u16::MAX, MIN_VREFINT_SAMPLE),
+        Some(59_577)
+    );
+    // a brownout reads VREFINT high, and millivolts low, but still converts
+    assert_eq!(adc_to_millivolts(4095, 4095), Some(1200));
+}
+
+#[test]
+fn two_tone_spectrum() {
+    // quarter-scale 3 cycles per window and a weaker 6th, on mid-scale DC
+    let n = 128;
+    let samples: Vec<u16> = (0..n)
+        .map(|i| {
+            let t = 2.0 * PI * i as f32 / n as f32;
+            (2048.0 + 1024.0 * (3.0 * t).sin() + 200.0 * (6.0 * t + 1.0).cos()).round() as u16
+        })
+        .collect();
+    let amplitudes = spectrum::<8>(&samples, 0);
+    for (bin, amplitude) in amplitudes.iter().enumerate() {
+        let expected = match bin {
+            0 => 2048.0,
+            3 => 1024.0,
+            6 => 200.0,
+            _ => 0.0,
+        };
+        // rounding the samples to whole counts leaves a fraction of a count spread across the bins
+        assert!(
+            (amplitude - expected).abs() < 1.0,
+            "bin {bin} reads {amplitude}, expected {expected}"
+        );
+    }
+    // starting partway up shifts the bins without changing them
+    let shifted = spectrum::<4>(&samples, 3);
+    assert!((shifted[0] - amplitudes[3]).abs() < 0.01 && (shifted[3] - amplitudes[6]).abs() < 0.01);
+}
+
+#[test]
+fn resampling() {
+    // 3 cycles per window like local's default, with a harmonic so the waveform isn't a pure tone
+    let n = 128;
+    let cycles = 3.0;
+    let window = |delay: f32| -> Vec<u16> {
+        (0..n)
+            .map(|i| {
+                let t = 2.0 * PI * cycles * (i as f32 + delay) / n as f32 + 0.5;
+                (2048.0 + 1500.0 * t.sin() + 150.0 * (3.0 * t).sin()).round() as u16
+            })
+            .collect()
+    };
+    let phase = |samples: &[u16]| {
+        let mut goertzel = Goertzel::new(n, cycles);
+        for x in samples {
+            goertzel.push(*x as i16);
+        }
+        goertzel.magnitude_phase().1
+    };
+    let on_grid = phase(&window(0.0));
+
+    for delay in [0.37, -0.8, 1.5] {
+        let samples = window(delay);
+        let uncorrected = phase(&samples) - on_grid;
+        // atan2(Σ x sin, Σ x cos) reads a later sample as less phase
+        let expected = -2.0 * PI * cycles * delay / n as f32;
+        assert!((uncorrected - expected).abs() < 1e-3);
+
+        for interpolation in [Interpolation::Linear, Interpolation::Cubic] {
+            let resampler = Resampler::new((delay * SAMPLE_PERIOD as f32) as i32, interpolation);
+            let mut resampled = vec![0; n];
+            resampler.resample(&samples, &mut resampled);
+            let bias = phase(&resampled) - on_grid;
+            assert!(
+                bias.abs() < uncorrected.abs() / 50.0,
+                "{interpolation:?} at {delay} samples left {bias} rad of {uncorrected}"
+            );
+        }
+    }
+
+    // no delay is no change
+    let samples = window(0.0);
+    let mut resampled = vec![0; n];
+    Resampler::new(0, Interpolation::Cubic).resample(&samples, &mut resampled);
+    assert_eq!(samples, resampled);
+}
+
+#[test]
+fn harmonic_aliasing() {
+    // Against brute force on whole-cycle windows, where a harmonic either lands in the excitation's bin or is orthogonal to it.
+    let n: usize = 128;
+    for bin in 1..n / 2 {
+        let correlating = (2..=MAX_ALIASED_HARMONIC).find(|&h| {
+            let (mut sine, mut cosine) = (0.0, 0.0);
+            for i in 0..n {
+                let t = i as f64 / n as f64;
+                let x = (std::f64::consts::TAU * (h as usize * bin) as f64 * t + 0.3).cos();
+                sine += x * (std::f64::consts::TAU * bin as f64 * t).sin();
+                cosine += x * (std::f64::consts::TAU * bin as f64 * t).cos();
+            }
+            (sine * sine + cosine * cosine).sqrt() > n as f64 / 4.0
+        });
+        assert_eq!(
+            aliased_harmonic(bin as f64, n),
+            correlating,
+            "{bin} cycles per {n} samples"
+        );
+    }
+    // 4 samples per cycle: the 3rd harmonic folds back onto the fundamental, and 8: the 7th
+    assert_eq!(aliased_harmonic(32.0, 128), Some(3));
+    assert_eq!(aliased_harmonic(16.0, 128), Some(7));
+    // just over 8 samples per cycle, the 7th lands between bins but within one of the fundamental still
+    assert_eq!(aliased_harmonic(16.2, 130), Some(7));
+    assert_eq!(aliased_harmonic(16.0, 145), None);
+    // 5/2 samples per cycle, where it's the 4th
+    assert_eq!(aliased_harmonic(2.0, 5), Some(4));
+    // a single cycle per window puts the 2nd harmonic a bin away, but that's leakage, not aliasing
+    assert_eq!(aliased_harmonic(0.999, 128), None);
+    // usb_custom's power-on excitation and sample time, about 61 samples per cycle
+    assert_eq!(
+        aliased_harmonic(100_000.0 / 128.0 * 128.0 / (12e6 / 252.0), 128),
+        None
+    );
+}
+
+#[test]
+fn adc_format() {
+    let right = AdcFormat::new(ADC_BITS, AdcAlignment::Right);
+    let left = AdcFormat::new(ADC_BITS, AdcAlignment::Left);
+    let converted = [0u16, 1, 2048, 4094, 4095];
+
+    // right-aligned is already what the rest expects
+    let mut samples = converted;
+    assert!(right.normalize(&mut samples));
+    assert_eq!(samples, converted);
+    // left-aligned comes back to the same full scale
+    let mut samples = converted.map(|x| x << 4);
+    assert!(left.normalize(&mut samples));
+    assert_eq!(samples, converted);
+
+    // and so does a lower resolution, in both alignments, with its missing low bits zero
+    let ten_bit = [0u16, 1, 512, 1023];
+    let expected = ten_bit.map(|x| x << 2);
+    let mut samples = ten_bit;
+    assert!(AdcFormat::new(10, AdcAlignment::Right).normalize(&mut samples));
+    assert_eq!(samples, expected);
+    let mut samples = ten_bit.map(|x| x << 6);
+    assert!(AdcFormat::new(10, AdcAlignment::Left).normalize(&mut samples));
+    assert_eq!(samples, expected);
+
+    // data in the other alignment from the one configured is caught rather than scaled
+    let mut samples = converted.map(|x| x << 4);
+    assert!(!right.normalize(&mut samples));
+    let mut samples = converted;
+    assert!(!left.normalize(&mut samples));
+    assert_eq!(samples[0], 0, "samples that fit are still normalized");
+    assert_eq!(right.normalize_sample(0x1000), None);
+    assert_eq!(left.normalize_sample(0x0008), None);
+    assert_eq!(left.normalize_sample(0xfff0), Some(4095));
+
+    assert_eq!(AdcAlignment::from_align_bit(true), AdcAlignment::Left);
+    assert!(!AdcAlignment::Right.align_bit());
+}
+
+#[test]
+fn phase_histogram() {
+    let mut histogram = PhaseHistogram::<64>::new();
+    // bin edges: -π starts bin 0, 0 starts bin 32, and just short of π is the last
+    assert_eq!(PhaseHistogram::<64>::bin(i32::MIN), 0);
+    assert_eq!(PhaseHistogram::<64>::bin(-1), 31);
+    assert_eq!(PhaseHistogram::<64>::bin(0), 32);
+    assert_eq!(PhaseHistogram::<64>::bin(i32::MAX), 63);
+    assert_eq!(PhaseHistogram::<64>::bin(radians_to_angle(PI / 2.0)), 48);
+
+    // noise around a phase near π lands either side of the wrap, not in the middle
+    let center = radians_to_angle(PI - 0.01);
+    let mut seed: u32 = 1;
+    for _ in 0..10_000 {
+        seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let noise = (seed >> 16) as i32 - (1 << 15);
+        histogram.add(center.wrapping_add(noise * 1024));
+    }
+    let bins = histogram.bins();
+    assert_eq!(histogram.total, 10_000);
+    assert_eq!(bins.iter().sum::<u32>(), 10_000);
+    assert!(bins[63] > 0 && bins[0] > 0);
+    assert_eq!(bins[16..48].iter().sum::<u32>(), 0);
+
+    histogram.reset();
+    assert_eq!(histogram.total, 0);
+    assert!(histogram.bins().iter().all(|c| *c == 0));
+}
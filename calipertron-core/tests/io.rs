@@ -0,0 +1,63 @@
+use calipertron_core::*;
+
+#[test]
+fn bsrr() {
+    // PA0 set, PA1 reset
+    assert_eq!(bsrr_conflicts(0b10 << 16 | 0b01), 0);
+    // PA3 both set and reset, PA0 only set
+    assert_eq!(bsrr_conflicts(0b1000 << 16 | 0b1001), 0b1000);
+    assert_eq!(bsrr_conflicts(0xFFFF_FFFF), 0xFFFF);
+}
+
+#[test]
+fn packing() {
+    // layouts as documented on schema::SampleWidth::Packed12
+    let mut bytes = [0u8; 3];
+    assert_eq!(pack_12(&[0xABC, 0x123], false, &mut bytes), 3);
+    assert_eq!(bytes, [0xBC, 0x3A, 0x12]);
+    assert_eq!(pack_12(&[0xABC, 0x123], true, &mut bytes), 3);
+    assert_eq!(bytes, [0xAB, 0xC1, 0x23]);
+
+    // round trip every code, shuffled, in odd-length runs so the padding gets exercised too
+    let samples: Vec<u16> = (0..4096).map(|i| (i * 2711 % 4096) as u16).collect();
+    for big_endian in [false, true] {
+        for chunk in samples.chunks(37) {
+            let mut bytes = [0u8; 57];
+            let len = pack_12(chunk, big_endian, &mut bytes);
+            assert_eq!(len, chunk.len().div_ceil(2) * 3);
+            let mut unpacked = [0u16; 38];
+            let n = unpack_12(&bytes[..len], big_endian, &mut unpacked);
+            assert_eq!(&unpacked[..chunk.len()], chunk);
+            // an odd sample out comes back with its zero padding
+            assert_eq!(n, chunk.len().div_ceil(2) * 2);
+        }
+    }
+
+    // too big for 12 bits saturates rather than wrapping
+    pack_12(&[5000, 4095], false, &mut bytes);
+    let mut unpacked = [0u16; 2];
+    unpack_12(&bytes, false, &mut unpacked);
+    assert_eq!(unpacked, [4095, 4095]);
+}
+
+#[test]
+fn debounce() {
+    let mut button = Debouncer::new(false, 4);
+    // contact bounce on press, then held
+    let presses: Vec<_> = [
+        true, false, true, true, false, true, true, true, true, true, true,
+    ]
+    .into_iter()
+    .map(|raw| button.update(raw))
+    .collect();
+    assert_eq!(presses.iter().filter(|p| p.is_some()).count(), 1);
+    assert_eq!(presses[8], Some(true));
+    // a glitch shorter than the window while held doesn't release it
+    for raw in [false, false, false, true] {
+        assert_eq!(button.update(raw), None);
+    }
+    for _ in 0..3 {
+        assert_eq!(button.update(false), None);
+    }
+    assert_eq!(button.update(false), Some(false));
+}
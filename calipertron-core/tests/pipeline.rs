@@ -0,0 +1,71 @@
+use calipertron_core::dsp::{angle_to_radians, correlate, radians_to_angle};
+use calipertron_core::pipeline::{sine_cosine_table, window_phase_advance, Pipeline};
+
+// Simulated windows through local's demodulation steps, checked against the phase and travel they were generated with; see the fixture's header.
+// A regression check on the math only: the fixture isn't a hardware recording, so it says nothing about real electrodes.
+#[test]
+fn replay() {
+    let fixture = include_str!("../fixtures/moving_slider_synthetic.txt");
+    let mut keys = std::collections::HashMap::new();
+    let mut windows: Vec<Vec<u16>> = vec![];
+    for line in fixture
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+    {
+        match line.split_once(' ') {
+            Some((key, value)) if key.starts_with(|c: char| c.is_alphabetic()) => {
+                keys.insert(key, value.parse::<f64>().unwrap());
+            }
+            _ => windows.push(line.split(' ').map(|x| x.parse().unwrap()).collect()),
+        }
+    }
+    const N: usize = 128;
+    assert_eq!(keys["samples_per_window"], N as f64);
+    assert!(windows.iter().all(|w| w.len() == N));
+
+    let bin = keys["window_cycles"];
+    let table = sine_cosine_table::<N>(bin);
+    let alpha = 0.5;
+    let mut pipeline = Pipeline::new(&table, window_phase_advance(bin), 1000.0, alpha);
+    let readings: Vec<_> = windows
+        .iter()
+        .map(|w| {
+            pipeline
+                .process(w)
+                .expect("every window is well above the minimum magnitude")
+        })
+        .collect();
+
+    // Within a window of a single cycle the slider's own DC leaks into the bin a little; 0.03 rad is 20 counts.
+    let first = readings[0];
+    let phase_error = angle_to_radians(
+        first
+            .phase
+            .wrapping_sub(radians_to_angle(keys["first_phase_rad"] as f32)),
+    );
+    assert!(
+        phase_error.abs() < 0.03,
+        "first phase off by {phase_error} rad"
+    );
+    let last = *readings.last().unwrap();
+    // as much again at either end
+    let travel = (last.raw_position - first.raw_position) as f64;
+    assert!(
+        (travel - keys["travel_counts"]).abs() < 40.0,
+        "travelled {travel} counts, expected {}",
+        keys["travel_counts"]
+    );
+    // a one-pole filter trails a ramp by step (1 - alpha) / alpha once it's settled
+    let step = keys["travel_counts"] / (readings.len() - 1) as f64;
+    let lag = last.raw_position as f64 - last.position as f64;
+    let expected_lag = step * (1.0 - alpha as f64) / alpha as f64;
+    assert!(
+        (lag - expected_lag).abs() < 20.0,
+        "filter lags by {lag} counts, expected {expected_lag}"
+    );
+
+    // interleaved channels split the same sums between them
+    let single = correlate::<1>(&table, &windows[0], 3)[0];
+    let [a, b] = correlate::<2>(&table, &windows[0], 3);
+    assert_eq!(single, (a.0 + b.0, a.1 + b.1));
+}
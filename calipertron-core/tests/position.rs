@@ -0,0 +1,397 @@
+use calipertron_core::*;
+use core::f32::consts::PI;
+
+// A monotonically increasing phase, wrapping 5 times, unwraps in even steps.
+#[test]
+fn unwrap() {
+    let mut tracker = PositionTracker::new();
+    let mut last_position = None;
+
+    for step in 0..100 {
+        let angle = (step as f32 * 0.1 * PI + PI) % (2.0 * PI) - PI;
+        let position = tracker.update(angle);
+
+        assert!(!tracker.aliased);
+        if let Some(last_position) = last_position {
+            let delta: i64 = position - last_position;
+            assert!(
+                (delta - COUNTS_PER_PITCH / 20).abs() <= 1,
+                "position should increase smoothly, but stepped by {delta}"
+            );
+        }
+        last_position = Some(position);
+    }
+}
+
+// Synthetic track phases across the whole travel, including fine phases either side of their wraps.
+// Each track also gets a phase error, sized so the beat's error is 0.4 of a fine pitch: close to, but within, what combine_vernier can resolve.
+#[test]
+fn vernier() {
+    let travel = VERNIER_FINE_PITCHES * COUNTS_PER_PITCH;
+    let turn = (1i64 << 32) as f64;
+    let max_error = 0.2 / VERNIER_FINE_PITCHES as f64;
+    let phase = |cycles: f64| ((cycles.rem_euclid(1.0) * turn) as i64) as i32;
+
+    for counts in (0..travel).step_by(7).chain([1, travel - 1]) {
+        let x = counts as f64 / travel as f64;
+        for (error_coarse, error_fine) in
+            [(0.0, 0.0), (max_error, -max_error), (-max_error, max_error)]
+        {
+            let phase_fine = phase(x * VERNIER_FINE_PITCHES as f64 + error_fine);
+            let phase_coarse = phase(x * (VERNIER_FINE_PITCHES - 1) as f64 + error_coarse);
+
+            let position = combine_vernier(phase_coarse, phase_fine);
+            let error = (position - counts + travel / 2).rem_euclid(travel) - travel / 2;
+            assert!(
+                error.abs() as f64 <= max_error * COUNTS_PER_PITCH as f64 + 1.0,
+                "vernier at {counts} resolved to {position}"
+            );
+        }
+    }
+}
+
+// Constant velocity ramp, quantized to whole counts, at about usb_custom's window rate.
+#[test]
+fn velocity() {
+    let period = 128.0 / 47_619.0;
+    let velocity = 1234.5;
+    let mut estimator = VelocityEstimator::new(period, 0.1);
+
+    assert_eq!(
+        estimator.update(100),
+        0.0,
+        "first update has no previous position"
+    );
+    for step in 1..200 {
+        let estimate = estimator.update(100 + (step as f32 * period * velocity).round() as i64);
+        if step > 60 {
+            assert!(
+                (estimate - velocity).abs() < 0.03 * velocity,
+                "velocity estimate {estimate} at step {step}"
+            );
+        }
+    }
+}
+
+#[test]
+fn millimeters() {
+    let c = COUNTS_PER_PITCH;
+    for (counts, expected_um) in [
+        (0, 0),
+        (c, DEFAULT_PITCH_UM as i64),
+        (-c, -(DEFAULT_PITCH_UM as i64)),
+        (c / 2, 4_700),
+        (-c / 4, -2_350),
+        // 2.29um per count rounds to nearest
+        (1, 2),
+        (-1, -2),
+        (1_000 * c + 1, 9_400_002),
+        (-1_000 * c - 1, -9_400_002),
+    ] {
+        let um = counts_to_um(counts, DEFAULT_PITCH_UM);
+        assert_eq!(um, expected_um, "{counts} counts");
+    }
+    // a finer scale
+    assert_eq!(counts_to_um(3 * c, 2_000), 6_000);
+}
+
+#[test]
+fn inches() {
+    let c = COUNTS_PER_PITCH;
+    for (counts, expected) in [
+        (0, 0),
+        // 9.4mm is 0.370079 inch
+        (c, 3_701),
+        (-c, -3_701),
+        // 2.29um per count is under a ten-thousandth, which is 2.54um
+        (1, 1),
+        (-1, -1),
+        // a meter of travel, which would be off by 0.0023 inch going through a rounded 0.3701 per pitch
+        (106 * c + 1_569, 393_701),
+        (-106 * c - 1_569, -393_701),
+    ] {
+        assert_eq!(
+            counts_to_ten_thousandths_inch(counts, DEFAULT_PITCH_UM),
+            expected,
+            "{counts} counts"
+        );
+    }
+    // whole inches on a pitch that divides one exactly
+    assert_eq!(counts_to_ten_thousandths_inch(10 * c, 2_540), 10_000);
+    assert_eq!(counts_to_ten_thousandths_inch(-10 * c, 2_540), -10_000);
+    // as far as the tracker goes, where the product no longer fits an i64
+    let far = PositionTracker::MAX_WRAPS * c;
+    let through_um = um_to_ten_thousandths_inch(counts_to_um(far, DEFAULT_PITCH_UM));
+    assert!((counts_to_ten_thousandths_inch(far, DEFAULT_PITCH_UM) - through_um).abs() <= 1);
+    assert_eq!(
+        counts_to_ten_thousandths_inch(-far, DEFAULT_PITCH_UM),
+        -counts_to_ten_thousandths_inch(far, DEFAULT_PITCH_UM)
+    );
+    assert_eq!(counts_to_ten_thousandths_inch(i64::MAX, u32::MAX), i64::MAX);
+    assert_eq!(counts_to_ten_thousandths_inch(i64::MIN, u32::MAX), i64::MIN);
+
+    for um in (-2_000_000..=2_000_000).step_by(997).chain(-3_000..=3_000) {
+        let inch = um_to_ten_thousandths_inch(um);
+        let back = ten_thousandths_inch_to_um(inch);
+        assert!(
+            (back - um).abs() <= 1,
+            "{um}um went to {inch} and back to {back}um"
+        );
+        assert_eq!(
+            um_to_ten_thousandths_inch(ten_thousandths_inch_to_um(inch)),
+            inch,
+            "{inch} ten-thousandths"
+        );
+    }
+    assert_eq!(um_to_ten_thousandths_inch(25_400), 10_000);
+    assert_eq!(um_to_ten_thousandths_inch(-25_400), -10_000);
+    assert_eq!(ten_thousandths_inch_to_um(-10_000), -25_400);
+}
+
+#[test]
+fn max_step() {
+    // 1 m/s for 10ms is 10mm, a little over a pitch
+    let max_step = max_step_counts(1_000_000.0, 0.01, DEFAULT_PITCH_UM);
+    assert_eq!(max_step, 4358);
+    // at 1ms, 436 counts
+    let max_step = max_step_counts(1_000_000.0, 0.001, DEFAULT_PITCH_UM);
+    assert_eq!(max_step, 436);
+
+    let angle = |counts: i64| (counts * (1 << 32) / COUNTS_PER_PITCH) as i32;
+
+    // a single bad window half a pitch away is held, and the next good one carries on
+    let mut tracker = PositionTracker::new();
+    tracker.set_max_step(max_step);
+    assert_eq!(tracker.update_angle(angle(100)), 100);
+    assert_eq!(tracker.update_angle(angle(110)), 110);
+    assert_eq!(tracker.update_angle(angle(110 + 1800)), 110);
+    assert_eq!(tracker.rejected, 1);
+    assert_eq!(tracker.update_angle(angle(120)), 120);
+    assert_eq!(tracker.rejected, 1);
+
+    // a fast but legitimate move, just under the limit every window, wrapping through several pitches
+    let mut tracker = PositionTracker::new();
+    tracker.set_max_step(max_step);
+    let mut position: i64 = 0;
+    for _ in 0..40 {
+        position += max_step - 1;
+        assert_eq!(
+            tracker.update_angle(angle(position.rem_euclid(COUNTS_PER_PITCH))),
+            position
+        );
+    }
+    assert_eq!(tracker.rejected, 0);
+
+    // a move faster than the limit stalls, but catches up once the doubling limit lets the step through; as long as that's within half a pitch
+    let mut tracker = PositionTracker::new();
+    tracker.set_max_step(200);
+    tracker.update_angle(angle(0));
+    let mut position: i64 = 0;
+    let mut stalled = 0;
+    for _ in 0..4 {
+        position += 300;
+        if tracker.update_angle(angle(position.rem_euclid(COUNTS_PER_PITCH))) != position {
+            stalled += 1;
+        }
+    }
+    assert_eq!(tracker.position(), position);
+    assert!(stalled >= 1, "never stalled");
+}
+
+#[test]
+fn phase_correction() {
+    use std::f64::consts::TAU;
+    let pitch = COUNTS_PER_PITCH as f64;
+    let to_angle = |counts: f64| (counts * 4_294_967_296.0 / pitch).round() as i64 as i32;
+    let to_counts = |angle: i32| angle as f64 * pitch / 4_294_967_296.0;
+    let wrap = |counts: f64| (counts + pitch / 2.0).rem_euclid(pitch) - pitch / 2.0;
+    // a scale that reads up to 40 counts off, twice per pitch
+    let distort = |position: f64| position + 40.0 * (2.0 * TAU * position / pitch).sin();
+
+    let identity = PhaseCorrection::new([0; 64]);
+    for angle in [0, 1, -1, dsp::QUARTER_TURN, i32::MIN, i32::MAX] {
+        assert_eq!(identity.apply(angle), angle);
+    }
+
+    // the table for that distortion, as from a calibration sweep: at each measured phase, the true position that reads as it, less the phase
+    const N: usize = 64;
+    let mut table = [0i16; N];
+    for (i, correction) in table.iter_mut().enumerate() {
+        let measured = i as f64 * pitch / N as f64;
+        let mut position = measured;
+        for _ in 0..20 {
+            position = measured - (distort(position) - position);
+        }
+        *correction = (position - measured).round() as i16;
+    }
+    let correction = PhaseCorrection::new(table);
+
+    let (mut uncorrected, mut corrected) = (0.0f64, 0.0f64);
+    for step in 0..4096 {
+        // a couple of pitches, off the table's grid
+        let position = step as f64 * 2.0 + 0.3;
+        let measured = to_angle(distort(position));
+        uncorrected = uncorrected.max(wrap(to_counts(measured) - position).abs());
+        corrected = corrected.max(wrap(to_counts(correction.apply(measured)) - position).abs());
+    }
+    assert!(uncorrected > 39.0, "uncorrected error {uncorrected:.2}");
+    // rounding the table to whole counts leaves up to half a count
+    assert!(corrected < 1.0, "corrected error {corrected:.2}");
+}
+
+#[test]
+fn direction() {
+    let mut detector = DirectionDetector::new(100.0);
+    // noise inside the deadband never starts motion
+    for i in 0..100 {
+        let velocity = if i % 2 == 0 { 90.0 } else { -90.0 };
+        assert_eq!(detector.update(velocity), Direction::Stationary);
+    }
+    assert_eq!(detector.update(150.0), Direction::Forward);
+    // slowing into the hysteresis band holds, and only dropping below half the deadband stops
+    assert_eq!(detector.update(60.0), Direction::Forward);
+    assert_eq!(detector.update(-90.0), Direction::Forward);
+    assert_eq!(detector.update(40.0), Direction::Stationary);
+    assert_eq!(detector.update(-150.0), Direction::Backward);
+    // reversing straight through zero switches without a stationary in between
+    assert_eq!(detector.update(150.0), Direction::Forward);
+}
+
+#[test]
+fn fractional_position() {
+    // 1/16 of a fixed-point count is one turn unit, so steps in multiples of 16 accumulate exactly
+    let per_fine = (1i64 << 32) / (COUNTS_PER_PITCH << POSITION_FRACTION_BITS);
+    assert_eq!(per_fine, 16);
+
+    // about 1.14 counts a window, forwards through several pitches and then back to zero, never a whole number of counts
+    let step: i64 = 0x12_3450;
+    let mut tracker = PositionTracker::new();
+    let mut angle: i64 = 0;
+    for direction in [1, -1] {
+        for _ in 0..20_000 {
+            angle += direction * step;
+            let position = tracker.update_angle(angle as i32);
+            let expected_fine = angle / per_fine;
+            assert_eq!(tracker.position_fine(), expected_fine);
+            // counts round to nearest rather than truncating the fraction
+            let expected = (expected_fine as f64 / (1 << POSITION_FRACTION_BITS) as f64).round();
+            assert_eq!(position, expected as i64, "at angle {angle}");
+        }
+    }
+    assert_eq!(tracker.position_fine(), 0);
+
+    // a quarter count, from a phase in radians
+    let mut tracker = PositionTracker::new();
+    tracker.update(0.25 * 2.0 * PI / COUNTS_PER_PITCH as f32);
+    let fine = tracker.position_fine();
+    assert!(
+        (fine - (1 << POSITION_FRACTION_BITS) / 4).abs() <= 1,
+        "quarter count read as {fine}"
+    );
+    assert_eq!(tracker.position(), 0);
+}
+
+#[test]
+fn accumulator_limit() {
+    let max = PositionTracker::MAX_WRAPS;
+    let quarter = 1i32 << 30;
+
+    // walk forwards a quarter pitch a window from a few pitches short of the limit
+    let mut tracker = PositionTracker::new();
+    tracker.update_angle(0);
+    tracker.set_wraps(max - 3);
+    assert!(tracker.near_limit);
+    let mut angle = 0i32;
+    let mut last = tracker.position();
+    for _ in 0..4 * 6 {
+        angle = angle.wrapping_add(quarter);
+        let position = tracker.update_angle(angle);
+        // never wraps round to negative, however far past the limit it's pushed
+        assert!(position > 0, "wrapped to {position}");
+        assert!(
+            position >= last - COUNTS_PER_PITCH,
+            "jumped back to {position}"
+        );
+        last = position;
+    }
+    // saturated on the last pitch, with the fraction and rounding still inside i64
+    assert_eq!(
+        tracker.position_fine() >> POSITION_FRACTION_BITS,
+        max * COUNTS_PER_PITCH
+    );
+    assert!(tracker.near_limit);
+
+    // and backwards onto the negative limit
+    tracker.set_wraps(-max + 1);
+    for _ in 0..4 * 4 {
+        angle = angle.wrapping_sub(quarter);
+        assert!(tracker.update_angle(angle) < 0);
+    }
+    assert_eq!(tracker.position() / COUNTS_PER_PITCH, -max);
+
+    // the flag comes on past halfway, and recentering clears it, handing back what it took off
+    let mut tracker = PositionTracker::new();
+    tracker.update_angle(0);
+    tracker.set_wraps(max / 2);
+    assert!(!tracker.near_limit);
+    tracker.update_angle(quarter);
+    tracker.update_angle(i32::MIN);
+    tracker.update_angle(-quarter);
+    tracker.update_angle(0);
+    assert!(tracker.near_limit);
+    let before = tracker.position();
+    let removed = tracker.recenter();
+    assert!(!tracker.near_limit);
+    assert_eq!(before - removed, tracker.position());
+    assert_eq!(tracker.position(), 0);
+}
+
+#[test]
+fn dead_zone() {
+    let mut dead_zone = DeadZone::new(3, 10);
+    assert_eq!(dead_zone.update(100), 100);
+    // flicker inside the zone holds the first position
+    for i in 0..50 {
+        assert_eq!(dead_zone.update(100 + [1, -2, 3, 0, -3][i % 5]), 100);
+    }
+
+    // a slow ramp of 1 count per update, under the width, is followed with no lag once it's left the zone
+    let mut lag = 0;
+    for i in 1..=200 {
+        let position = 100 + i;
+        lag = lag.max(position - dead_zone.update(position));
+    }
+    assert!(lag <= 3, "trailed a slow ramp by {lag}");
+    assert_eq!(dead_zone.update(300), 300);
+
+    // stopping, it keeps following the noise until it's been within the zone long enough, then holds
+    let mut last = 0;
+    for i in 0..20 {
+        last = dead_zone.update(300 + [2, -1][i % 2]);
+    }
+    for _ in 0..20 {
+        assert_eq!(dead_zone.update(301), last);
+    }
+
+    // zero width is no dead zone
+    let mut off = DeadZone::new(0, 10);
+    off.update(0);
+    assert_eq!(off.update(1), 1);
+}
+
+#[test]
+fn peak_hold() {
+    let mut peak = PeakHold::new();
+    assert_eq!(peak.range(), None);
+
+    // sweep out and back, either side of the start
+    for position in [100, 250, -40, 90, 300, 120] {
+        peak.update(position);
+    }
+    assert_eq!(peak.range(), Some((-40, 300)));
+
+    peak.reset();
+    assert_eq!(peak.range(), None);
+    peak.update(-7);
+    assert_eq!(peak.range(), Some((-7, -7)));
+}
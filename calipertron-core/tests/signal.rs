@@ -0,0 +1,237 @@
+use calipertron_core::dsp::{correlate, Q15_SHIFT};
+use calipertron_core::pipeline::sine_cosine_table;
+use calipertron_core::*;
+use core::f32::consts::PI;
+
+#[test]
+fn gain_control() {
+    let depths = [0.125, 0.25, 0.5, 1.0];
+    let (low, high) = (4_000.0, 32_000.0);
+
+    // coupling sweeping from far gap to close and back, magnitude proportional to drive
+    let couplings = (0..2000).map(|i| {
+        let t = i as f32 / 1000.0;
+        let t = if t > 1.0 { 2.0 - t } else { t };
+        2_000.0 * (100.0f32).powf(t)
+    });
+
+    let mut gain = GainControl::new(depths.len(), low, high, 4);
+    let mut changes = 0;
+    for coupling in couplings {
+        let magnitude = coupling * depths[gain.level()];
+        if let Some(level) = gain.update(magnitude) {
+            changes += 1;
+            let magnitude = coupling * depths[level];
+            // one level halves or doubles magnitude, which the band is wide enough to land inside
+            assert!(
+                (low..=high).contains(&magnitude),
+                "stepped to level {level} with magnitude {magnitude}"
+            );
+        }
+    }
+    // three steps down on the way close, three back up on the way out
+    assert_eq!(changes, 6);
+    assert_eq!(gain.level(), depths.len() - 1);
+}
+
+#[test]
+fn settling() {
+    let mut detector = SettlingDetector::new(8, 0.05, (dsp::QUARTER_TURN >> 4) as u32);
+
+    // magnitude ringing up towards its final value, with the phase swinging
+    let mut windows = 0;
+    for i in 0..100 {
+        let decay = (-(i as f32) / 5.0).exp();
+        let magnitude = 10_000.0 * (1.0 - decay);
+        let phase = dsp::radians_to_angle(1.0 + 2.0 * decay * (i as f32).sin());
+        windows += 1;
+        if detector.update(magnitude, phase) {
+            break;
+        }
+    }
+    // 5% per window at a time constant of 5 windows is reached after about 15; then 8 more to confirm
+    assert!(
+        (20..40).contains(&windows),
+        "settled after {windows} windows"
+    );
+
+    // stays settled through motion, until reset
+    assert!(detector.update(10_000.0, dsp::radians_to_angle(-2.0)));
+    detector.reset();
+    assert!(!detector.update(10_000.0, 0));
+}
+
+#[test]
+fn motion() {
+    let step = (dsp::QUARTER_TURN >> 6) as u32;
+    let mut detector = MotionDetector::new(step, 0.2);
+    assert!(detector.update(10_000.0, 0));
+
+    // noise well under the threshold either way, around the wrap too
+    let jitter = (step / 4) as i32;
+    for i in 0..100 {
+        let phase = if i % 2 == 0 { jitter } else { -jitter };
+        assert!(!detector.update(10_000.0 + (i % 3) as f32 * 100.0, phase));
+    }
+
+    // a slow creep of an eighth of the threshold per window is compared against where motion was last seen, so it trips eventually
+    let mut windows = 0;
+    let mut phase = 0i32;
+    loop {
+        windows += 1;
+        phase = phase.wrapping_add((step / 8) as i32);
+        if detector.update(10_000.0, phase) {
+            break;
+        }
+    }
+    assert_eq!(windows, 8, "creep detected after {windows} windows");
+
+    // lifting the slider off drops the magnitude without moving the phase
+    assert!(detector.update(5_000.0, phase));
+}
+
+#[test]
+fn drive_ramp() {
+    // runs the ramp in 32 sample reads until it's done, returning each drive it stepped through and the samples spent getting to the last
+    let run = |ramp: &mut DriveRamp| {
+        let mut steps = vec![];
+        let mut samples = 0;
+        while ramp.ramping() {
+            if ramp.advance(32) {
+                steps.push(ramp.drive());
+            }
+            samples += 32;
+        }
+        (steps, samples)
+    };
+
+    let mut ramp = DriveRamp::new(128);
+    ramp.set_target(Some(3));
+    let (steps, samples) = run(&mut ramp);
+    assert_eq!(steps, [Some(0), Some(1), Some(2), Some(3)]);
+    // the first step straight away, then one per hold
+    assert_eq!(samples, 32 + 3 * 128);
+
+    ramp.set_target(None);
+    let (steps, _) = run(&mut ramp);
+    assert_eq!(steps, [Some(2), Some(1), Some(0), None]);
+
+    // turned around partway, it heads back from the step it's on
+    ramp.set_target(Some(3));
+    ramp.advance(32);
+    ramp.advance(128);
+    assert_eq!(ramp.drive(), Some(1));
+    ramp.set_target(None);
+    let (steps, _) = run(&mut ramp);
+    assert_eq!(steps, [Some(0), None]);
+
+    // a gain step jumps, and a hold of 0 is a hard start
+    ramp.jump(Some(2));
+    assert!(!ramp.ramping() && !ramp.advance(32));
+    let mut hard = DriveRamp::new(0);
+    hard.set_target(Some(3));
+    assert_eq!(run(&mut hard).0, [Some(3)]);
+}
+
+#[test]
+fn stall() {
+    let stale_windows = 8;
+    // a still slider: the same signal each window, give or take an LSB of noise
+    let mut still = StallDetector::new(stale_windows);
+    for window in 0..1000u32 {
+        let samples: Vec<u16> = (0..128u32)
+            .map(|i| (2048 + (i * 7 + window * 13) % 3) as u16)
+            .collect();
+        assert!(!still.update(&samples, false), "window {window}");
+    }
+
+    // pinned to a rail, every window is the same, but the ADC is still converting
+    let rail = [4095u16; 128];
+    let mut clipped = StallDetector::new(stale_windows);
+    assert!((0..1000).all(|_| !clipped.update(&rail, true)));
+
+    // stale data with no conversions behind it goes stale_windows windows past the first, then starts over
+    let samples: Vec<u16> = (0..128).map(|i| 2048 + i).collect();
+    let mut stalled = StallDetector::new(stale_windows);
+    let windows = (1..=100)
+        .filter(|_| stalled.update(&samples, false))
+        .count();
+    assert_eq!(windows, 100 / (stale_windows as usize + 1));
+    assert_eq!(stalled.stalls, windows as u32);
+
+    // a sum would miss a reordering
+    let mut swapped = samples.clone();
+    swapped.swap(3, 4);
+    assert_ne!(window_hash(&samples), window_hash(&swapped));
+}
+
+#[test]
+fn channel_calibration() {
+    // A differential pair sampled alternately, as local does, with the second channel 15% hotter and sitting lower.
+    const N: usize = 128;
+    let table = sine_cosine_table::<N>(0.999);
+    let (dc, gain) = ([2048.0f32, 1850.0], [1.0f32, 1.15]);
+    let amplitude = 800.0;
+    // `sign` -1 drives the second channel in antiphase, i.e., a differential input
+    let sums = |amplitude: f32, phase: f32, sign: f32| -> [(i32, i32); 2] {
+        let samples: Vec<u16> = (0..N)
+            .map(|i| {
+                let channel = i % 2;
+                let angle = 2.0 * PI * 0.999 * i as f32 / N as f32;
+                let drive = if channel == 0 { 1.0 } else { sign };
+                let x = dc[channel] + gain[channel] * drive * amplitude * (angle - phase).cos();
+                x.round() as u16
+            })
+            .collect();
+        correlate::<2>(&table, &samples, 0)
+            .map(|(sine, cosine)| ((sine >> Q15_SHIFT) as i32, (cosine >> Q15_SHIFT) as i32))
+    };
+    let difference = |[a, b]: [(i32, i32); 2]| {
+        let (sine, cosine) = ((a.0 - b.0) as f32, (a.1 - b.1) as f32);
+        (sine * sine + cosine * cosine).sqrt()
+    };
+    let phases: Vec<f32> = (0..16).map(|k| k as f32 * PI / 8.0).collect();
+
+    // each channel sees half the window, so an amplitude of A reads A * N/4
+    let known = amplitude * N as f32 / 4.0;
+    let zero = sums(0.0, 0.0, 1.0);
+    let calibration: Vec<ChannelCalibration> = (0..2)
+        .map(|channel| {
+            let measured = phases
+                .iter()
+                .map(|&phase| {
+                    ChannelCalibration::magnitude_over(
+                        zero[channel],
+                        sums(amplitude, phase, 1.0)[channel],
+                    )
+                })
+                .sum::<f32>()
+                / phases.len() as f32;
+            ChannelCalibration::new(zero[channel], measured, known).unwrap()
+        })
+        .collect();
+    assert!((calibration[1].gain * gain[1] / calibration[0].gain - 1.0).abs() < 0.001);
+
+    let apply = |s: [(i32, i32); 2]| [calibration[0].apply(s[0]), calibration[1].apply(s[1])];
+    let (mut before, mut after) = (0.0f32, 0.0f32);
+    for &phase in &phases {
+        // mid-way between the calibration's phases, and weaker
+        let common = sums(0.6 * amplitude, phase + PI / 16.0, 1.0);
+        before = before.max(difference(common));
+        after = after.max(difference(apply(common)));
+    }
+    assert!(
+        before > 0.05 * known,
+        "common mode leaves {before} uncalibrated"
+    );
+    assert!(
+        after < 0.01 * known,
+        "common mode leaves {after} calibrated"
+    );
+    // while a differential input comes through at twice the known magnitude
+    let differential = difference(apply(sums(amplitude, 0.3, -1.0)));
+    assert!(
+        (differential / (2.0 * known) - 1.0).abs() < 0.01,
+        "{differential}"
+    );
+}
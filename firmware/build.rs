@@ -1,70 +1,814 @@
+use calipertron_core::dsp::aliased_harmonic;
+use calipertron_core::pipeline::{sample_angle, sine_cosine_entry, window_phase_advance, Q15_ONE};
 use std::f64::consts::PI;
 use std::fs::File;
 use std::io::Write;
 
-fn generate_pdm_bsrr(n_samples: usize) -> String {
-    let mut output = String::new();
-    output.push_str("pub const PDM_SIGNAL: [u32; ");
-    output.push_str(&n_samples.to_string());
-    output.push_str("] = [\n");
+#[derive(Clone)]
+struct PdmConfig {
+    /// Number of electrode phases.
+    n_phases: usize,
+    /// Number of PDM ticks per signal cycle.
+    pdm_length: usize,
+    /// `(GPIOA pin, phase)` for each driven electrode.
+    pins: Vec<(usize, usize)>,
+    /// Peak-to-peak swing of the target waveforms as a fraction of full drive, centered on 50% duty.
+    modulation_depth: f64,
+    /// Offset of each phase's target sinusoid, in radians.
+    phase_offsets: Vec<f64>,
+    /// Multiple of the excitation frequency to drive a second tone at, alongside the first and at the same phase offsets, or `None` for a single tone.
+    /// Each tone gets half the modulation depth, so the sum never drives past full scale.
+    second_tone: Option<usize>,
+    /// `(GPIOA pin, phase)` of a logic analyzer test point that copies that phase's bits, or `None`; see `test_point_from_env`.
+    test_point: Option<(usize, usize)>,
+}
+
+/// Excitation strengths available to automatic gain control, as fractions of `PdmConfig::modulation_depth`, weakest first.
+/// Each is half the next, so magnitude moves by a factor of two per level.
+const PDM_GAIN_LEVELS: [f64; 4] = [0.125, 0.25, 0.5, 1.0];
+
+/// Largest difference between the amplitude of a pin's PDM output at the excitation frequency and its target's.
+const MAX_PDM_AMPLITUDE_ERROR: f64 = 0.02;
+
+/// Multiple of the excitation frequency the `dual-frequency` feature adds a second tone at.
+/// Odd, so the second tone doesn't land on the even harmonics an asymmetric front end makes of the first; mains and supply interference near one tone is then nowhere near the other.
+const SECOND_TONE_MULTIPLE: usize = 3;
+
+/// GPIOA pin the test point comes out on: PA10, which none of the binaries use for anything else.
+const TEST_POINT_PIN: usize = 10;
+
+impl PdmConfig {
+    /// Reproduces the original hardcoded table for the v1.1 PCB, where pins PA0--PA7 are wired up for signal idx 0,4, 1,5, 2,6, 3,7.
+    fn v1_1() -> Self {
+        let n_phases = 8;
+        PdmConfig {
+            n_phases,
+            pdm_length: 128,
+            pins: [0, 4, 1, 5, 2, 6, 3, 7].into_iter().enumerate().collect(),
+            modulation_depth: 1.0,
+            // evenly spaced around a full cycle
+            phase_offsets: (0..n_phases)
+                .map(|phase| 2.0 * PI * phase as f64 / n_phases as f64)
+                .collect(),
+            second_tone: None,
+            test_point: None,
+        }
+    }
+
+    /// Four electrodes in quadrature on PA0--PA3, for sensors laid out with 4 phases rather than 8.
+    /// Selected with the `quadrature` feature.
+    fn quadrature() -> Self {
+        let n_phases = 4;
+        PdmConfig {
+            n_phases,
+            pdm_length: 128,
+            pins: (0..n_phases).map(|phase| (phase, phase)).collect(),
+            modulation_depth: 1.0,
+            // 90° apart
+            phase_offsets: (0..n_phases)
+                .map(|phase| 2.0 * PI * phase as f64 / n_phases as f64)
+                .collect(),
+            second_tone: None,
+            test_point: None,
+        }
+    }
+
+    /// `(cycles per PDM cycle, fraction of the modulation depth)` of each tone in the target waveforms.
+    fn tones(&self) -> Vec<(usize, f64)> {
+        match self.second_tone {
+            None => vec![(1, 1.0)],
+            Some(multiple) => vec![(1, 0.5), (multiple, 0.5)],
+        }
+    }
+
+    /// Target analog drive level of `phase` at PDM tick `tick`, in `0..=1`.
+    fn target(&self, phase: usize, tick: usize) -> f64 {
+        let cosine: f64 = self
+            .tones()
+            .into_iter()
+            .map(|(cycles, weight)| {
+                let angle = 2.0 * PI * ((cycles * tick) as f64 / self.pdm_length as f64)
+                    + self.phase_offsets[phase];
+                weight * (angle.cos() as f32 as f64)
+            })
+            .sum();
+        0.5 + self.modulation_depth / 2.0 * cosine
+    }
+
+    fn pin_mask(&self) -> u32 {
+        self.pins.iter().fold(0, |mask, (pin, _)| mask | (1 << pin))
+    }
+
+    fn test_point_mask(&self) -> u32 {
+        self.test_point.map_or(0, |(pin, _)| 1 << pin)
+    }
+
+    /// Adds the test point's set or reset bit to `bsrr`, matching the first electrode pin on its phase.
+    fn mirror_test_point(&self, bsrr: u32) -> u32 {
+        let Some((test_pin, phase)) = self.test_point else {
+            return bsrr;
+        };
+        let (pin, _) = self.pins.iter().find(|(_, p)| *p == phase).unwrap();
+        if bsrr & (1 << pin) != 0 {
+            bsrr | (1 << test_pin)
+        } else {
+            bsrr | (1 << (test_pin + 16))
+        }
+    }
 
-    let n_waves = 8;
+    fn validate(&self) {
+        for (pin, phase) in &self.pins {
+            assert!(*pin < 16, "GPIOA only has 16 pins, can't drive PA{pin}");
+            assert!(
+                *phase < self.n_phases,
+                "PA{pin} is assigned to phase {phase}, but there are only {} phases",
+                self.n_phases
+            );
+        }
+        assert_eq!(
+            self.phase_offsets.len(),
+            self.n_phases,
+            "need one phase offset per phase"
+        );
+        assert!(
+            self.modulation_depth > 0.0 && self.modulation_depth <= 1.0,
+            "modulation depth must be in (0, 1]"
+        );
+        assert_eq!(
+            self.pin_mask().count_ones() as usize,
+            self.pins.len(),
+            "a pin is assigned more than one phase"
+        );
+        if let Some((pin, phase)) = self.test_point {
+            assert!(
+                pin < 16 && self.pin_mask() & (1 << pin) == 0,
+                "the test point PA{pin} has to be a GPIOA pin that isn't an electrode"
+            );
+            assert!(
+                self.pins.iter().any(|(_, p)| *p == phase),
+                "the test point mirrors phase {phase}, but no pin drives it"
+            );
+        }
+        if let Some(multiple) = self.second_tone {
+            // the sigma-delta needs several ticks per cycle of the faster tone to get its amplitude right
+            assert!(
+                multiple > 1 && multiple * 8 <= self.pdm_length,
+                "a second tone at {multiple}x needs a multiple above 1 and at least 8 PDM ticks per cycle"
+            );
+        }
+    }
 
-    // in PCB schematic v1.1 the pins PA0--PA7 are wired up for signal idx 0,4, 1,5, 2,6, 3,7
-    let mut wave_idx_to_pin = [0; 8];
-    for (pin_idx, wave_idx) in [0, 4, 1, 5, 2, 6, 3, 7].into_iter().enumerate() {
-        wave_idx_to_pin[wave_idx] = pin_idx;
+    /// Checks BSRR word `bsrr` for tick `sample` of a table driving this config's pins.
+    /// Every driven pin gets exactly one of its set/reset bits; both at once is a conflicting drive that BSRR would silently resolve as "set".
+    fn check_bsrr(&self, sample: usize, bsrr: u32) {
+        let set = bsrr & 0xFFFF;
+        let reset = bsrr >> 16;
+        assert_eq!(set & reset, 0, "sample {sample} both sets and resets a pin");
+        assert_eq!(
+            set | reset,
+            self.pin_mask() | self.test_point_mask(),
+            "sample {sample} doesn't drive every pin"
+        );
     }
+}
+
+fn generate_pdm_bsrr(config: &PdmConfig) -> String {
+    config.validate();
+
+    let n_samples = config.pdm_length;
+    let n_waves = config.n_phases;
 
-    let mut errors = vec![0.0; n_waves];
+    let mut output = String::new();
+    output.push_str(&format!("pub const NUM_PHASES: usize = {};\n", n_waves));
+    output.push_str(&format!(
+        "pub const PDM_PIN_MASK: u16 = {:#018b};\n",
+        config.pin_mask()
+    ));
+
+    output.push_str(&format!(
+        "pub const PDM_GAIN_DEPTHS: [f32; {}] = {:?};\n",
+        PDM_GAIN_LEVELS.len(),
+        PDM_GAIN_LEVELS.map(|level| (level * config.modulation_depth) as f32)
+    ));
+    output.push_str(&format!(
+        "pub const PDM_SIGNALS: [[u32; {n_samples}]; {}] = [\n",
+        PDM_GAIN_LEVELS.len()
+    ));
+    for level in PDM_GAIN_LEVELS {
+        let level_config = PdmConfig {
+            modulation_depth: level * config.modulation_depth,
+            ..config.clone()
+        };
+        output.push_str(&generate_pdm_table(&level_config));
+        output.push_str(",\n");
+    }
+    output.push_str("];\n");
+    // full strength, for drivers without gain control
+    output.push_str(&format!(
+        "pub const PDM_SIGNAL: [u32; {n_samples}] = PDM_SIGNALS[{}];\n",
+        PDM_GAIN_LEVELS.len() - 1
+    ));
+    output.push_str(&format!(
+        "pub const PDM_SQUARE_SIGNAL: [u32; {n_samples}] = {};\n",
+        generate_square_table(config)
+    ));
+    if let Some((pin, phase)) = config.test_point {
+        let pattern: String = pdm_words(config)
+            .iter()
+            .map(|bsrr| if bsrr & (1 << pin) != 0 { '1' } else { '0' })
+            .collect();
+        output.push_str(&format!(
+            "// PA{pin} mirrors phase {phase}; at full strength it reads, one bit per PDM tick from PDM_SIGNAL[0]:\n// {pattern}\n"
+        ));
+    }
+    output.push_str(&format!(
+        "pub const TEST_POINT: Option<(u8, usize)> = {:?};\n\
+         pub const TEST_POINT_MASK: u16 = {:#018b};\n",
+        config.test_point.map(|(pin, phase)| (pin as u8, phase)),
+        config.test_point_mask()
+    ));
+    output
+}
+
+/// Logic analyzer test point from `CALIPER_TEST_POINT`, the phase to copy onto `TEST_POINT_PIN`; `None` if it's unset.
+///
+/// Every word of every PDM table then also sets or resets the test point along with the first electrode on that phase, so the pin shows exactly the bits the DMA writes to BSRR, without probing the electrodes, which a probe's capacitance would load.
+/// To check a capture against the table:
+///
+/// - Each bit lasts one PDM tick, 1/PDM_FREQUENCY (4.5us at the default 222 kHz); a steady tick rate confirms TIM2 is pacing the DMA, and a stretched or missing tick shows where it wasn't.
+/// - The pattern repeats every PDM_SIGNAL.len() ticks, one excitation cycle. constants.rs spells out the full-strength cycle as 0s and 1s, starting from `PDM_SIGNAL[0]`; find it in the capture, starting anywhere, as the DMA's position when the capture starts is arbitrary.
+/// - The density of 1s follows the phase's sinusoid: mostly high around its peak, mostly low half a cycle later. The square excitation mode's half cycle high and half low, and usb_custom's weaker gain levels have the same rhythm at a shallower swing, so they won't match the spelled-out pattern.
+///
+/// The pin only follows the table in the binaries that set it up as an output, see `board::enable_test_point`.
+fn test_point_from_env(config: &PdmConfig) -> Option<(usize, usize)> {
+    println!("cargo:rerun-if-env-changed=CALIPER_TEST_POINT");
+    let phase = std::env::var("CALIPER_TEST_POINT").ok()?;
+    let phase = phase
+        .parse::<usize>()
+        .ok()
+        .filter(|&p| p < config.n_phases)
+        .unwrap_or_else(|| {
+            panic!(
+                "CALIPER_TEST_POINT must be a phase from 0 to {}, got {phase:?}",
+                config.n_phases - 1
+            )
+        });
+    Some((TEST_POINT_PIN, phase))
+}
+
+/// Square-wave counterpart of `generate_pdm_table` for debugging: each pin is fully on for the half cycle around its target's peak, and off for the rest.
+/// Worked out in whole ticks, so every pin gets exactly half a cycle either way however the float rounding at the edges goes.
+fn generate_square_table(config: &PdmConfig) -> String {
+    let n_samples = config.pdm_length;
+    let mut output = String::from("[\n");
     for sample in 0..n_samples {
         let mut bsrr = 0u32;
-        for wave in 0..n_waves {
-            let phase_offset = 2.0 * PI * (wave as f64) / (n_waves as f64);
-            let angle = 2.0 * PI * (sample as f64 / n_samples as f64) + phase_offset;
-            let cosine = angle.cos() as f32;
-            let normalized_signal = (cosine + 1.0) / 2.0;
-
-            // rescale
-            let scale = 1.0;
-            let normalized_signal = (1.0 - scale) / 2.0 + scale * normalized_signal;
-
-            if normalized_signal > errors[wave] {
-                bsrr |= 1 << wave_idx_to_pin[wave]; // set bit
-                errors[wave] += 1.0 - normalized_signal;
+        for (pin, wave) in &config.pins {
+            // ticks from the start of the on half cycle, which is centered on the peak
+            let offset =
+                (config.phase_offsets[*wave] / (2.0 * PI) * n_samples as f64).round() as usize;
+            if (sample + offset + n_samples / 4) % n_samples < n_samples / 2 {
+                bsrr |= 1 << pin;
             } else {
-                bsrr |= 1 << (wave_idx_to_pin[wave] + 16); // reset bit
-                errors[wave] -= normalized_signal;
+                bsrr |= 1 << (pin + 16);
             }
         }
+        let bsrr = config.mirror_test_point(bsrr);
+        config.check_bsrr(sample, bsrr);
         output.push_str(&format!("    {:#034b},\n", bsrr));
     }
+    output.push(']');
+    output
+}
 
-    output.push_str("];\n");
+/// One PDM cycle of GPIOA BSRR words as an array literal.
+fn generate_pdm_table(config: &PdmConfig) -> String {
+    let mut output = String::from("[\n");
+    for bsrr in pdm_words(config) {
+        output.push_str(&format!("    {:#034b},\n", bsrr));
+    }
+    output.push(']');
     output
 }
 
+/// One PDM cycle of GPIOA BSRR words, checked against the target amplitudes.
+fn pdm_words(config: &PdmConfig) -> Vec<u32> {
+    let n_samples = config.pdm_length;
+
+    let mut words = Vec::with_capacity(n_samples);
+
+    // first-order sigma-delta per pin: emit whichever level brings the running error back towards the target
+    let mut errors = vec![0.0; config.pins.len()];
+    // per pin, correlation of the emitted bits with each tone's frequency
+    let tones = config.tones();
+    let mut fundamentals = vec![vec![(0.0, 0.0); tones.len()]; config.pins.len()];
+    for sample in 0..n_samples {
+        let mut bsrr = 0u32;
+        for ((error, fundamental), (pin, wave)) in errors
+            .iter_mut()
+            .zip(fundamentals.iter_mut())
+            .zip(&config.pins)
+        {
+            let normalized_signal = config.target(*wave, sample);
+
+            if normalized_signal > *error {
+                for ((cycles, _), fundamental) in tones.iter().zip(fundamental.iter_mut()) {
+                    let angle = 2.0 * PI * ((cycles * sample) as f64 / n_samples as f64);
+                    fundamental.0 += angle.cos();
+                    fundamental.1 += angle.sin();
+                }
+                bsrr |= 1 << pin; // set bit
+                *error += 1.0 - normalized_signal;
+            } else {
+                bsrr |= 1 << (pin + 16); // reset bit
+                *error -= normalized_signal;
+            }
+        }
+
+        let bsrr = config.mirror_test_point(bsrr);
+        config.check_bsrr(sample, bsrr);
+
+        words.push(bsrr);
+    }
+
+    // The PDM pattern's component at the excitation frequency is what actually couples to the slider.
+    for ((pin, wave), fundamental) in config.pins.iter().zip(&fundamentals) {
+        for ((cycles, weight), (cosine, sine)) in tones.iter().zip(fundamental) {
+            let amplitude = 2.0 * (cosine * cosine + sine * sine).sqrt() / n_samples as f64;
+            let target_amplitude = weight * config.modulation_depth / 2.0;
+            assert!(
+                (amplitude - target_amplitude).abs() <= MAX_PDM_AMPLITUDE_ERROR,
+                "PA{pin} (phase {wave}) has amplitude {amplitude:.3} at {cycles}x, should be {target_amplitude:.3}"
+            );
+        }
+    }
+
+    words
+}
+
+/// ADC sample times selectable through the SMPx register fields, in ADC clock cycles, indexed by their encoding.
+const ADC_SAMPLE_CYCLES: [f64; 8] = [1.5, 7.5, 13.5, 28.5, 41.5, 55.5, 71.5, 239.5];
+
+/// Fixed cycles each conversion takes on top of its sample time; see reference manual section 11.6.
+const ADC_CONVERSION_OVERHEAD_CYCLES: f64 = 12.5;
+
+/// Everything that sets the excitation and sample timing, so the tables and the peripherals are configured from one place.
+/// Emitted as `SAMPLE_CONFIG` for the firmware to set the timer and ADC from.
+struct SampleConfig {
+    /// Timer rate stepping through the PDM table.
+    pdm_frequency: u32,
+    /// Number of PDM ticks per signal cycle.
+    pdm_length: usize,
+    /// Samples per demodulation window.
+    num_samples: usize,
+    /// ADC clock, as set up by the firmware's rcc config.
+    adc_frequency: f64,
+    /// ADC sample time in ADC clock cycles; one of `ADC_SAMPLE_CYCLES`.
+    adc_sample_cycles: f64,
+    /// PDM ticks per conversion when the PDM timer triggers the ADC, or 0 for the ADC to free-run at its own conversion rate.
+    /// Triggered, the samples keep a fixed phase against the excitation rather than relying on the two rates matching.
+    adc_trigger_ticks: usize,
+}
+
+impl SampleConfig {
+    fn signal_frequency(&self) -> f64 {
+        self.pdm_frequency as f64 / self.pdm_length as f64
+    }
+
+    fn conversion_time(&self) -> f64 {
+        (self.adc_sample_cycles + ADC_CONVERSION_OVERHEAD_CYCLES) / self.adc_frequency
+    }
+
+    fn sampling_frequency(&self) -> f64 {
+        if self.adc_trigger_ticks > 0 {
+            self.pdm_frequency as f64 / self.adc_trigger_ticks as f64
+        } else {
+            1.0 / self.conversion_time()
+        }
+    }
+
+    /// SMPx field encoding of the sample time.
+    fn adc_sample_time(&self) -> u8 {
+        ADC_SAMPLE_CYCLES
+            .iter()
+            .position(|cycles| *cycles == self.adc_sample_cycles)
+            .unwrap_or_else(|| {
+                panic!(
+                    "the ADC can't sample for {} cycles, pick one of {ADC_SAMPLE_CYCLES:?}",
+                    self.adc_sample_cycles
+                )
+            }) as u8
+    }
+
+    fn validate(&self) {
+        self.adc_sample_time();
+
+        // A trigger arriving mid-conversion is ignored, which would silently halve the sample rate.
+        let sample_period = 1.0 / self.sampling_frequency();
+        assert!(
+            self.conversion_time() <= sample_period,
+            "a conversion at {} cycles takes {:.2}us, longer than the {:.2}us between triggers every {} PDM ticks",
+            self.adc_sample_cycles,
+            self.conversion_time() * 1e6,
+            sample_period * 1e6,
+            self.adc_trigger_ticks
+        );
+
+        // anything under two samples per cycle aliases the excitation
+        let excitation_period = 1.0 / self.signal_frequency();
+        assert!(
+            2.0 * sample_period < excitation_period,
+            "sampling every {:.2}us is more than half the {:.2}us excitation period",
+            sample_period * 1e6,
+            excitation_period * 1e6
+        );
+    }
+
+    fn generate(&self) -> String {
+        self.validate();
+
+        let mut output = String::new();
+        output.push_str("/// Excitation and sample timing the tables below were generated for; configure the timer and ADC from this so they match.\n");
+        output.push_str("pub struct SampleConfig {\n");
+        output.push_str("    pub pdm_frequency_hz: u32,\n");
+        output.push_str("    pub pdm_length: usize,\n");
+        output.push_str("    pub num_samples: usize,\n");
+        output.push_str("    /// SMPx field encoding of the ADC sample time.\n");
+        output.push_str("    pub adc_sample_time: u8,\n");
+        output.push_str("    /// PDM ticks per ADC conversion when the PDM timer triggers the ADC, 0 when it free-runs.\n");
+        output.push_str("    pub adc_trigger_ticks: usize,\n");
+        output.push_str("}\n");
+
+        output.push_str("pub const SAMPLE_CONFIG: SampleConfig = SampleConfig {\n");
+        output.push_str(&format!("    pdm_frequency_hz: {},\n", self.pdm_frequency));
+        output.push_str(&format!("    pdm_length: {},\n", self.pdm_length));
+        output.push_str(&format!("    num_samples: {},\n", self.num_samples));
+        output.push_str(&format!(
+            "    adc_sample_time: {}, // {} cycles\n",
+            self.adc_sample_time(),
+            self.adc_sample_cycles
+        ));
+        output.push_str(&format!(
+            "    adc_trigger_ticks: {},\n",
+            self.adc_trigger_ticks
+        ));
+        output.push_str("};\n");
+        output
+    }
+}
+
+/// Largest fraction of a cycle the demodulation window may be off from a whole number of excitation cycles.
+/// Any leftover partial cycle leaks the signal's DC offset into the correlation sums, biasing the phase.
+const MAX_WINDOW_CYCLE_ERROR: f64 = 0.01;
+
+/// Samples at the start of each window left out of the demodulation, for an excitation with a transient there, such as the PDM DMA wrapping round its circular buffer.
+/// The window grows by as many, so the samples after the gate still span the whole cycles `window_samples` picks, and table entries keep their phase from the window's start.
+///
+/// To choose it, build local with the sample-dump feature, hold the slider still, and press the button for a few dumps.
+/// Overlay their samples against SINE_COSINE_TABLE's sine: a transient shows as the first samples of every dump departing the same way from the sinusoid the rest follow.
+/// Set this to the first sample after which they agree to within the noise, plus a couple for margin; leave it at 0 if they agree from the start.
+/// That only works if the transient sits at a fixed place in the window, so with the ADC free-running (`adc_trigger_ticks` 0) its position drifts window to window and no gate helps; trigger the ADC off the PDM timer first.
+const GATE_SAMPLES: usize = 0;
+
+/// Samples per demodulation window, from `CALIPER_NUM_SAMPLES` directly or as `CALIPER_CYCLES_PER_WINDOW` excitation cycles of `samples_per_cycle` each; 128 if neither is set.
+///
+/// A window of n cycles at the ~1.73 kHz excitation lasts n * 577us, and gives one reading per window, so it trades update rate for noise.
+/// Conversion noise sums incoherently, so the phase noise falls as 1/sqrt(n) while the noise bandwidth, about 1/window (see `Window::enbw`), falls as 1/n:
+///
+///     cycles  samples  window   readings/s  phase noise
+///     1       128      0.58ms   1733        1x
+///     2       256      1.15ms   867         0.71x
+///     4       513      2.31ms   433         0.5x
+///     8       1025     4.62ms   217         0.35x
+///
+/// Interference inside that bandwidth, e.g., from a switching supply near the excitation frequency, isn't averaged away at all, so past a point longer windows only slow the readings down.
+/// local.rs keeps a double-buffered ring of conversions plus the Q15 table in RAM, about 16 bytes per sample, so 8 cycles is as far as the F103's 20 KiB goes.
+fn window_samples(samples_per_cycle: f64) -> usize {
+    println!("cargo:rerun-if-env-changed=CALIPER_NUM_SAMPLES");
+    println!("cargo:rerun-if-env-changed=CALIPER_CYCLES_PER_WINDOW");
+    let num_samples = std::env::var("CALIPER_NUM_SAMPLES").ok().map(|s| {
+        s.parse::<usize>()
+            .unwrap_or_else(|_| panic!("CALIPER_NUM_SAMPLES must be an integer, got {s:?}"))
+    });
+    let cycles = std::env::var("CALIPER_CYCLES_PER_WINDOW").ok().map(|s| {
+        s.parse::<u32>().ok().filter(|&c| c >= 1).unwrap_or_else(|| {
+            panic!("CALIPER_CYCLES_PER_WINDOW must be a whole number of cycles, at least 1, got {s:?}")
+        })
+    });
+    match (num_samples, cycles) {
+        (Some(_), Some(_)) => {
+            panic!("set one of CALIPER_NUM_SAMPLES and CALIPER_CYCLES_PER_WINDOW, not both")
+        }
+        (Some(n), None) => n,
+        // rounded to the nearest sample; main checks what's left over against MAX_WINDOW_CYCLE_ERROR like for any other window
+        (None, Some(c)) => (c as f64 * samples_per_cycle).round() as usize,
+        (None, None) => 128,
+    }
+}
+
+/// How far after its point on the table's phase grid each slot's conversions actually sample, in slots, for resampling them back onto it (see `Resampler`).
+///
+/// The ADC holds its input at the end of the sample time rather than when the conversion starts, and a slot's conversions are centered (OVERSAMPLING - 1) / 2 after its first.
+/// `CALIPER_SAMPLE_DELAY`, in slots, overrides that with a calibrated value, e.g., one that also covers the front end's group delay.
+fn sample_delay(sample_config: &SampleConfig) -> f64 {
+    println!("cargo:rerun-if-env-changed=CALIPER_SAMPLE_DELAY");
+    match std::env::var("CALIPER_SAMPLE_DELAY") {
+        Ok(s) => s
+            .parse::<f64>()
+            .ok()
+            .filter(|delay| delay.abs() < 1e4)
+            .unwrap_or_else(|| {
+                panic!("CALIPER_SAMPLE_DELAY must be a number of samples, got {s:?}")
+            }),
+        Err(_) => {
+            let hold = sample_config.adc_sample_cycles / sample_config.adc_frequency
+                * sample_config.sampling_frequency();
+            ((OVERSAMPLING - 1) as f64 / 2.0 + hold) / OVERSAMPLING as f64
+        }
+    }
+}
+
+/// Back-to-back ADC conversions summed into each table slot.
+/// Conversion noise is uncorrelated, so the noise on a slot relative to its signal drops by sqrt(OVERSAMPLING), at the cost of OVERSAMPLING times fewer windows per second.
+/// A slot spans OVERSAMPLING conversions, so the tables below are generated at the slot rate, `sampling_frequency / OVERSAMPLING`.
+const OVERSAMPLING: usize = 1;
+
+/// Right shift that takes a sum of samples times Q15 coefficients back to sample scale, applied once after the whole window is accumulated.
+const SCALE_SHIFT: u32 = 15;
+
+/// Weighting applied across the demodulation window to the sine/cosine table, selected with `CALIPER_WINDOW`.
+///
+/// A window that doesn't span a whole number of excitation cycles leaks the signal's large DC offset, and its own negative-frequency image, into the correlation sums, biasing the phase by an amount that depends on the phase itself.
+/// Tapering the ends cuts that leakage, by about 4x at 2 cycles per window and 10x or more from 4, at the cost of a wider main lobe: the equivalent noise bandwidth is 1.5 bins for Hann and 1.36 for Hamming against the rectangular window's 1,
+/// so for the same window length, phase jitter from broadband noise goes up by sqrt(ENBW), about 22% and 17%.
+/// The wider main lobe also means DC one bin away isn't rejected at all, so a tapered window needs at least two excitation cycles per window.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+}
+
+impl Window {
+    fn from_env() -> Self {
+        println!("cargo:rerun-if-env-changed=CALIPER_WINDOW");
+        match std::env::var("CALIPER_WINDOW").as_deref() {
+            Err(_) | Ok("rectangular") => Window::Rectangular,
+            Ok("hann") => Window::Hann,
+            Ok("hamming") => Window::Hamming,
+            Ok(s) => panic!("CALIPER_WINDOW must be rectangular, hann or hamming, got {s:?}"),
+        }
+    }
+
+    /// Weight of sample `i` of an `n` sample window; the periodic form, so a whole number of cycles still lines up with the DFT bins.
+    fn weight(&self, i: usize, n: usize) -> f64 {
+        let cosine = (2.0 * PI * i as f64 / n as f64).cos();
+        match self {
+            Window::Rectangular => 1.0,
+            Window::Hann => 0.5 - 0.5 * cosine,
+            Window::Hamming => 0.54 - 0.46 * cosine,
+        }
+    }
+
+    /// Mean weight, i.e., how much the window scales a coherent signal's correlation sums.
+    fn coherent_gain(&self, n: usize) -> f64 {
+        (0..n).map(|i| self.weight(i, n)).sum::<f64>() / n as f64
+    }
+
+    /// Equivalent noise bandwidth in bins.
+    fn enbw(&self, n: usize) -> f64 {
+        let sum: f64 = (0..n).map(|i| self.weight(i, n)).sum();
+        let sum_squares: f64 = (0..n).map(|i| self.weight(i, n).powi(2)).sum();
+        n as f64 * sum_squares / (sum * sum)
+    }
+
+    /// Worst phase error, in radians, demodulating a mid-scale signal `cycles` excitation cycles long over `n` samples, across a sweep of signal phases.
+    /// The table is built for the signal's true frequency, so all the error is leakage from the window not spanning whole cycles.
+    fn leakage_phase_error(&self, cycles: f64, n: usize) -> f64 {
+        // like generate_self_test_signal: DC offset twice the amplitude, roughly what the electrodes give
+        let (offset, amplitude) = (2048.0, 1024.0);
+        (0..64)
+            .map(|k| {
+                let phase = 2.0 * PI * k as f64 / 64.0;
+                let (mut sum_sine, mut sum_cosine) = (0.0, 0.0);
+                for i in 0..n {
+                    let angle = 2.0 * PI * cycles * i as f64 / n as f64;
+                    let x = offset + amplitude * (angle - phase).cos();
+                    let w = self.weight(i, n);
+                    sum_sine += x * w * angle.sin();
+                    sum_cosine += x * w * angle.cos();
+                }
+                let error = f64::atan2(sum_sine, sum_cosine) - phase;
+                // wrap to ±π
+                (error + PI).rem_euclid(2.0 * PI) - PI
+            })
+            .fold(0.0, |worst, error: f64| worst.max(error.abs()))
+    }
+}
+
+/// Also emits `{name}_MAX_ACCUMULATOR`, the largest magnitude either of the demodulator's sums can reach with every slot between 0 and `max_sample`.
+/// That's `max_sample` times the coefficients' absolute sum, from a signal at full scale wherever the coefficient is positive and zero elsewhere.
+/// The entries are calipertron_core::pipeline's `sine_cosine_entry`, so the host replays windows against the same table.
 fn generate_sine_cosine_table(
+    name: &str,
+    window: Window,
+    bin: f64,
+    num_samples: usize,
+    gate_samples: usize,
+    max_sample: u16,
+) -> String {
+    let mut output = String::new();
+    output.push_str(&format!(
+        "// Q15 fixed point, i.e., scaled by i16::MAX, with a {window:?} window over all but the first {gate_samples} samples, which are 0\n"
+    ));
+    output.push_str(&format!("pub const {name}: [(i16, i16); "));
+    output.push_str(&num_samples.to_string());
+    output.push_str("] = [\n");
+
+    let mut table = Vec::with_capacity(num_samples);
+    let mut reference = Vec::with_capacity(num_samples);
+    for i in 0..num_samples {
+        // phase from the start of the window all the same, so the gate doesn't move the zero
+        let angle = sample_angle(bin, i, num_samples);
+        let w = if i < gate_samples {
+            0.0
+        } else {
+            window.weight(i - gate_samples, num_samples - gate_samples)
+        };
+        let (sine, cosine) = sine_cosine_entry(bin, i, num_samples, w);
+        // round trip should land within one LSB of the float value
+        for (q, x) in [(sine, w * angle.sin()), (cosine, w * angle.cos())] {
+            assert!(
+                (q as f64 / Q15_ONE - x).abs() <= 1.0 / Q15_ONE,
+                "{x} doesn't survive Q15 conversion"
+            );
+        }
+        output.push_str(&format!("    ({:?}, {:?}),\n", sine, cosine));
+        table.push((sine, cosine));
+        reference.push((w * angle.sin(), w * angle.cos()));
+    }
+
+    output.push_str("];\n");
+
+    let abs_sum = |coefficient: fn(&(i16, i16)) -> i16| -> i128 {
+        table
+            .iter()
+            .map(|c| coefficient(c).unsigned_abs() as i128)
+            .sum()
+    };
+    let max_accumulator = max_sample as i128 * abs_sum(|c| c.0).max(abs_sum(|c| c.1));
+    output.push_str(&format!(
+        "// {num_samples} slots of at most {max_sample} times a Q15 coefficient\n\
+         pub const {name}_MAX_ACCUMULATOR: i128 = {max_accumulator};\n"
+    ));
+
+    let error = fixed_point_round_trip_error(&table, &reference, max_sample);
+    // each coefficient is within half an LSB of the float, so each slot adds at most x / 2 LSB of error, plus one from the final shift rounding down
+    let bound = num_samples as f64 * max_sample as f64 / (2.0 * Q15_ONE) + 1.0;
+    assert!(
+        error <= bound,
+        "{name} demodulates {error:.2} away from the float reference, more than the {bound:.2} rounding allows"
+    );
+    output.push_str(&format!(
+        "// fixed point lands within {error:.2} of the float reference at sample scale, against a rounding bound of {bound:.2}\n"
+    ));
+    output
+}
+
+/// Worst difference, at sample scale, between the integer demodulation of a full-scale sinusoid against `table` and the float correlation against `reference`, across a sweep of signal phases.
+fn fixed_point_round_trip_error(
+    table: &[(i16, i16)],
+    reference: &[(f64, f64)],
+    max_sample: u16,
+) -> f64 {
+    let n = table.len();
+    let half_scale = max_sample as f64 / 2.0;
+    (0..16)
+        .map(|k| {
+            let phase = 2.0 * PI * k as f64 / 16.0;
+            let (mut sum_sine, mut sum_cosine) = (0i64, 0i64);
+            let (mut float_sine, mut float_cosine) = (0.0, 0.0);
+            for i in 0..n {
+                // one cycle per window whatever the table's tone, so the sweep covers the whole range of sample values
+                let x = (half_scale + half_scale * (2.0 * PI * i as f64 / n as f64 - phase).cos())
+                    .round() as u16;
+                sum_sine += (x as i32 * table[i].0 as i32) as i64;
+                sum_cosine += (x as i32 * table[i].1 as i32) as i64;
+                float_sine += x as f64 * reference[i].0;
+                float_cosine += x as f64 * reference[i].1;
+            }
+            let sine_error = ((sum_sine >> SCALE_SHIFT) as f64 - float_sine).abs();
+            let cosine_error = ((sum_cosine >> SCALE_SHIFT) as f64 - float_cosine).abs();
+            sine_error.max(cosine_error)
+        })
+        .fold(0.0, f64::max)
+}
+
+/// Synthetic ADC samples of the excitation with a known phase, for checking the demodulation path without the analog front end.
+/// The phase is in the demodulator's convention, i.e., what `atan2(Σ x sin, Σ x cos)` should come out to.
+fn generate_self_test_signal(
     signal_frequency: f64,
     sampling_frequency: f64,
     num_samples: usize,
+    phase: f64,
 ) -> String {
+    // mid-scale DC with a quarter-scale sinusoid, roughly what the electrodes give
+    let offset = 2048.0;
+    let amplitude = 1024.0;
+
     let mut output = String::new();
-    output.push_str("pub const SINE_COSINE_TABLE: [(f32, f32); ");
+    output.push_str(&format!(
+        "pub const SELF_TEST_PHASE: i32 = {:?};\n",
+        (phase / (2.0 * PI) * 4_294_967_296.0) as i64 as i32
+    ));
+    output.push_str("pub const SELF_TEST_SIGNAL: [u16; ");
     output.push_str(&num_samples.to_string());
     output.push_str("] = [\n");
 
     for i in 0..num_samples {
         let angle = 2.0 * PI * signal_frequency * (i as f64 * (1.0 / sampling_frequency));
-        let sine = angle.sin() as f32;
-        let cosine = angle.cos() as f32;
-        output.push_str(&format!("    ({:?}, {:?}),\n", sine, cosine));
+        let sample = (offset + amplitude * (angle - phase).cos()).round() as u16;
+        output.push_str(&format!("    {:?},\n", sample));
     }
 
     output.push_str("];\n");
     output
 }
 
+/// Per-code ADC corrections for the adc-lut feature, read from the file named by `CALIPER_ADC_LUT`: 256 or 4096 whitespace-separated integers, see `calipertron_core::dsp::AdcLut`.
+/// Without the file the table is 256 zeros, i.e., no correction.
+fn generate_adc_lut() -> String {
+    println!("cargo:rerun-if-env-changed=CALIPER_ADC_LUT");
+    let corrections: Vec<i16> = match std::env::var("CALIPER_ADC_LUT") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            let contents = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("can't read CALIPER_ADC_LUT {path:?}: {e}"));
+            contents
+                .split_whitespace()
+                .map(|s| {
+                    s.parse()
+                        .unwrap_or_else(|_| panic!("{path:?} has a non-integer correction {s:?}"))
+                })
+                .collect()
+        }
+        Err(_) => vec![0; 256],
+    };
+    assert!(
+        corrections.len() == 256 || corrections.len() == 4096,
+        "ADC LUT needs 256 or 4096 entries, got {}",
+        corrections.len()
+    );
+
+    format!(
+        "pub const ADC_LUT_CORRECTIONS: [i16; {}] = {:?};\n",
+        corrections.len(),
+        corrections
+    )
+}
+
+/// Version and commit for usb_custom's `DeviceInfo`, as `FIRMWARE_VERSION`, `GIT_HASH` and `GIT_DIRTY`.
+/// Outside a git checkout, e.g., from a source tarball, the hash is all zeros rather than failing the build.
+fn generate_build_info() -> String {
+    let version: Vec<u8> = ["MAJOR", "MINOR", "PATCH"]
+        .iter()
+        .map(|part| {
+            std::env::var(format!("CARGO_PKG_VERSION_{part}"))
+                .unwrap()
+                .parse()
+                .expect("version parts fit a u8")
+        })
+        .collect();
+
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    let mut git_hash = [0u8; 8];
+    if let Some(hash) = git(&["rev-parse", "--short=8", "HEAD"]) {
+        for (b, c) in git_hash.iter_mut().zip(hash.bytes()) {
+            *b = c;
+        }
+        // a new commit or checkout moves HEAD or the branch it points at
+        if let Some(dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+            println!("cargo:rerun-if-changed={dir}/HEAD");
+            println!("cargo:rerun-if-changed={dir}/refs");
+        }
+    }
+    // only as fresh as the last time this script ran, which a commit or checkout triggers but an edit to the sources doesn't
+    let git_dirty = git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+
+    format!(
+        "pub const FIRMWARE_VERSION: [u8; 3] = {version:?};\n\
+         pub const GIT_HASH: [u8; 8] = {git_hash:?};\n\
+         pub const GIT_DIRTY: bool = {git_dirty};\n"
+    )
+}
+
 fn main() {
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
@@ -74,29 +818,247 @@ fn main() {
     let dest_path = std::path::Path::new(&out_dir).join("constants.rs");
     let mut f = File::create(&dest_path).unwrap();
 
-    let pdm_frequency: u32 = 222_000;
+    let mut sample_config = SampleConfig {
+        pdm_frequency: 222_000,
+        pdm_length: 128,
+        // set from the timing below
+        num_samples: 0,
+        adc_frequency: 12_000_000.,
+        // adc_sample_cycles: 239.5,
+        // adc_sample_cycles: 71.5,
+        adc_sample_cycles: 41.5,
+        // e.g. 2 for 64 samples per excitation cycle, locked to the PDM
+        adc_trigger_ticks: 0,
+    };
+    sample_config.num_samples = window_samples(
+        sample_config.sampling_frequency() / OVERSAMPLING as f64 / sample_config.signal_frequency(),
+    ) + GATE_SAMPLES;
+    let num_samples = sample_config.num_samples;
+    f.write_all(sample_config.generate().as_bytes()).unwrap();
+
+    let pdm_frequency = sample_config.pdm_frequency;
     f.write_all(format!("pub const PDM_FREQUENCY: u32 = {:?};\n", pdm_frequency).as_bytes())
         .unwrap();
 
-    let pdm_length = 128;
-    let num_samples = 128;
+    let layout = if std::env::var("CARGO_FEATURE_QUADRATURE").is_ok() {
+        PdmConfig::quadrature()
+    } else {
+        PdmConfig::v1_1()
+    };
+    let dual_frequency = std::env::var("CARGO_FEATURE_DUAL_FREQUENCY").is_ok();
+    let pdm_config = PdmConfig {
+        pdm_length: sample_config.pdm_length,
+        second_tone: dual_frequency.then_some(SECOND_TONE_MULTIPLE),
+        test_point: test_point_from_env(&layout),
+        ..layout
+    };
+    // Generate (and so check) every layout's tables, single and dual tone, not just the selected one's, so a change that breaks another shows up on any build.
+    for layout in [PdmConfig::v1_1(), PdmConfig::quadrature()] {
+        for second_tone in [None, Some(SECOND_TONE_MULTIPLE)] {
+            for test_point in [None, Some((TEST_POINT_PIN, 0))] {
+                generate_pdm_bsrr(&PdmConfig {
+                    pdm_length: sample_config.pdm_length,
+                    second_tone,
+                    test_point,
+                    ..layout.clone()
+                });
+            }
+        }
+    }
+    let pdm_length = pdm_config.pdm_length;
+
+    let signal_frequency = sample_config.signal_frequency();
+    let sampling_frequency = sample_config.sampling_frequency();
+
+    f.write_all(
+        format!(
+            "pub const SAMPLING_FREQUENCY_HZ: u32 = {:?};\n",
+            sampling_frequency as u32
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    f.write_all(format!("pub const OVERSAMPLING: usize = {OVERSAMPLING};\n").as_bytes())
+        .unwrap();
+    f.write_all(format!("pub const SCALE_SHIFT: u32 = {SCALE_SHIFT};\n").as_bytes())
+        .unwrap();
+    // full scale of a slot, i.e., OVERSAMPLING 12-bit conversions summed
+    let max_sample =
+        u16::try_from(OVERSAMPLING * 4095).expect("OVERSAMPLING overflows a slot's u16 sum");
+
+    // Each slot's sum is centered (OVERSAMPLING - 1) / 2 conversions after the slot starts, which shifts the demodulated phase by a constant that zeroing removes.
+    let sampling_frequency = sampling_frequency / OVERSAMPLING as f64;
+
+    // number of signal cycles spanned by the window, which advances the excitation's phase from one window to the next
+    let demod_bin = signal_frequency * num_samples as f64 / sampling_frequency;
+    // and by the samples after the gate, i.e., the DFT bin the table correlates against
+    let gated_samples = num_samples - GATE_SAMPLES;
+    let gated_bin = signal_frequency * gated_samples as f64 / sampling_frequency;
+    let window_cycles = gated_bin.round();
+    assert!(
+        window_cycles >= 1.0 && (gated_bin - window_cycles).abs() <= MAX_WINDOW_CYCLE_ERROR,
+        "{gated_samples} samples after the gate span {gated_bin:.4} excitation cycles of {:.3} samples each; pick a multiple of the cycle length",
+        sampling_frequency / signal_frequency
+    );
+    if let Some(h) = aliased_harmonic(demod_bin, num_samples) {
+        panic!(
+            "at {:.3} samples per excitation cycle the {h}x harmonic aliases onto the excitation, biasing the phase; see aliased_harmonic for the ratios that work",
+            sampling_frequency / signal_frequency
+        );
+    }
+
+    f.write_all(
+        format!(
+            "// One excitation cycle is {pdm_length} PDM ticks at {pdm_frequency} Hz, i.e., {:.3} samples at {sampling_frequency:.1} Hz.\n\
+             // The table's {num_samples} samples span num_samples * (pdm_frequency / pdm_length) / sampling_frequency = {demod_bin:.4} cycles, {gated_bin:.4} after the first {GATE_SAMPLES}.\n\
+             pub const WINDOW_CYCLES: u32 = {};\n\
+             pub const GATE_SAMPLES: usize = {GATE_SAMPLES};\n",
+            sampling_frequency / signal_frequency,
+            window_cycles as u32
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    let sample_delay = sample_delay(&sample_config);
+    f.write_all(
+        format!(
+            "// {sample_delay:.3} slots, in 1/65536 of a slot as calipertron_core::dsp::SAMPLE_PERIOD.\n\
+             pub const ADC_SAMPLE_DELAY: i32 = {};\n",
+            (sample_delay * 65536.0).round() as i32
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    let window = Window::from_env();
+    assert!(
+        window == Window::Rectangular || window_cycles >= 2.0,
+        "a {window:?} window doesn't reject DC a bin away, so it needs at least 2 excitation cycles per window; raise CALIPER_CYCLES_PER_WINDOW"
+    );
+    if window != Window::Rectangular {
+        // The check this is all for: off a whole number of cycles, the taper should leak less than the rectangular window does.
+        let cycles = window_cycles + 0.1;
+        let windowed = window.leakage_phase_error(cycles, gated_samples);
+        let rectangular = Window::Rectangular.leakage_phase_error(cycles, gated_samples);
+        assert!(
+            windowed < rectangular,
+            "a {window:?} window leaks more than a rectangular one: {windowed:.2e} against {rectangular:.2e} rad"
+        );
+        f.write_all(
+            format!(
+                "// {window:?} window: ENBW {:.2} bins, coherent gain {:.3}; {cycles:.1} cycles per window leak {windowed:.2e} rad of phase error, against {rectangular:.2e} rectangular.\n",
+                window.enbw(gated_samples),
+                window.coherent_gain(gated_samples)
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    }
+    f.write_all(
+        format!(
+            "pub const WINDOW_COHERENT_GAIN: f32 = {:?};\n",
+            window.coherent_gain(gated_samples) as f32
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    f.write_all(
+        generate_sine_cosine_table(
+            "SINE_COSINE_TABLE",
+            window,
+            demod_bin,
+            num_samples,
+            GATE_SAMPLES,
+            max_sample,
+        )
+        .as_bytes(),
+    )
+    .unwrap();
 
-    let signal_frequency = pdm_frequency as f64 / pdm_length as f64;
-    let adc_frequency = 12_000_000.;
-    // let adc_sample_cycles = 239.5;
-    // let adc_sample_cycles = 71.5;
-    let adc_sample_cycles = 41.5;
-    let adc_sample_overhead_cycles = 12.5; // see reference manual section 11.6
-    let sampling_frequency = adc_frequency / (adc_sample_cycles + adc_sample_overhead_cycles);
+    // The second tone's counterparts of SINE_COSINE_TABLE, DEMOD_BIN and WINDOW_PHASE_ADVANCE, for the dual-frequency feature.
+    // Emitted either way so the firmware needn't cfg them out, but only checked when the excitation actually has the tone.
+    let second_frequency = signal_frequency * SECOND_TONE_MULTIPLE as f64;
+    let second_demod_bin = demod_bin * SECOND_TONE_MULTIPLE as f64;
+    if dual_frequency {
+        assert!(
+            2.0 * second_frequency < sampling_frequency,
+            "the second tone at {second_frequency:.0} Hz is above Nyquist for {sampling_frequency:.0} Hz sampling"
+        );
+        // the window's leftover partial cycle scales with the tone's frequency
+        let second_gated_bin = gated_bin * SECOND_TONE_MULTIPLE as f64;
+        assert!(
+            (second_gated_bin - second_gated_bin.round()).abs() <= MAX_WINDOW_CYCLE_ERROR,
+            "{gated_samples} samples after the gate span {second_gated_bin:.4} cycles of the second tone; pick a window closer to whole excitation cycles"
+        );
+    }
+    f.write_all(
+        format!("pub const SECOND_TONE_MULTIPLE: usize = {SECOND_TONE_MULTIPLE};\n").as_bytes(),
+    )
+    .unwrap();
+    f.write_all(
+        generate_sine_cosine_table(
+            "SECOND_SINE_COSINE_TABLE",
+            window,
+            second_demod_bin,
+            num_samples,
+            GATE_SAMPLES,
+            max_sample,
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    f.write_all(
+        format!(
+            "pub const SECOND_DEMOD_BIN: f32 = {:?};\n",
+            second_demod_bin as f32
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    f.write_all(
+        format!(
+            "pub const SECOND_WINDOW_PHASE_ADVANCE: i32 = {:?};\n",
+            window_phase_advance(second_demod_bin)
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+
+    // radians; anything away from 0 and ±π, so a flipped sign or swapped I/Q shows up
+    let self_test_phase = 1.0;
+    f.write_all(
+        generate_self_test_signal(
+            signal_frequency,
+            sampling_frequency,
+            num_samples,
+            self_test_phase,
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+
+    f.write_all(format!("pub const DEMOD_BIN: f32 = {:?};\n", demod_bin as f32).as_bytes())
+        .unwrap();
 
+    // Excitation phase gained from the start of one window to the next, in turn units.
+    // Computed here in f64 since sampling continuously accumulates any error every window.
     f.write_all(
-        generate_sine_cosine_table(signal_frequency, sampling_frequency, num_samples).as_bytes(),
+        format!(
+            "pub const WINDOW_PHASE_ADVANCE: i32 = {:?};\n",
+            window_phase_advance(demod_bin)
+        )
+        .as_bytes(),
     )
     .unwrap();
 
-    f.write_all(generate_pdm_bsrr(pdm_length).as_bytes())
+    f.write_all(generate_pdm_bsrr(&pdm_config).as_bytes())
         .unwrap();
 
+    if std::env::var("CARGO_FEATURE_ADC_LUT").is_ok() {
+        f.write_all(generate_adc_lut().as_bytes()).unwrap();
+    }
+
+    f.write_all(generate_build_info().as_bytes()).unwrap();
+
     // Tell Cargo to rerun this script if the source file changes
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -0,0 +1,334 @@
+#![no_std]
+#![no_main]
+
+// Standalone caliper: measures as flash_logger does and shows the position in millimeters on a 128x64 SSD1306 OLED, with a bar for signal strength.
+// The button on PB14 zeroes the display, as in usb_custom.
+//
+// Wiring, with SPI2 transmitting only; PB10 and PB11 are the I2C header local uses, free here:
+//
+//     PB13  SCK   -> D0
+//     PB15  MOSI  -> D1
+//     PB12  CS    -> CS
+//     PB10  DC    -> DC
+//     PB11  RESET -> RES
+//
+// Only the SSD1306: an ST7789's framebuffer, at 16 bits a pixel, is bigger than the F103's 20 KB of RAM.
+
+use calipertron_core::{
+    counts_to_um, DeadZone, Debouncer, PositionTracker, COUNTS_PER_PITCH, DEFAULT_PITCH_UM,
+};
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_stm32::adc::{self, Adc};
+use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
+use embassy_stm32::mode::Async;
+use embassy_stm32::spi::{self, Spi};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::Config;
+use embassy_time::{Duration, Ticker, Timer};
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Text};
+use num_traits::Float;
+
+use {defmt_rtt as _, panic_probe as _};
+
+include!(concat!(env!("OUT_DIR"), "/constants.rs"));
+
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../common.rs"]
+mod common;
+
+use common::{measure_phase, MEASURE_SAMPLES, MIN_MEASURE_MAGNITUDE, MIN_SIGNAL_AMPLITUDE};
+
+// As in flash_logger: about 470 mm/s of travel at most.
+const MEASURE_INTERVAL: Duration = Duration::from_millis(10);
+// The signal bar fills at this amplitude, on a log scale up from MIN_SIGNAL_AMPLITUDE since coupling varies by orders of magnitude with the gap.
+const FULL_SIGNAL_AMPLITUDE: f32 = 2048.0;
+
+// Redrawing takes a couple of milliseconds of DMA, off its own ticker, so it never holds up a measurement.
+const DISPLAY_REFRESH: Duration = Duration::from_millis(50);
+// The display shows hundredths of a millimeter, like a caliper's; the dead zone keeps the last of them from flickering on a still slider.
+const DISPLAY_DEADBAND_UM: i64 = 10;
+// Refreshes the position has to stay inside the dead zone for, once it's been moving, to be held again; about 0.3 s.
+const DISPLAY_STILL_REFRESHES: u32 = 6;
+// Panel controllers take up to 10 MHz; SPI2 is on the 36 MHz APB1, so this ends up at 4.5 MHz.
+const DISPLAY_SPI_FREQUENCY: Hertz = Hertz(8_000_000);
+const DISPLAY_WIDTH: usize = 128;
+const DISPLAY_HEIGHT: usize = 64;
+
+const ZERO_BUTTON_POLL_INTERVAL: Duration = Duration::from_millis(5);
+const ZERO_BUTTON_STABLE_MS: u64 = 20;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV1,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+    }
+    let p = embassy_stm32::init(config);
+    let board = take_board!(p);
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
+    // cycle counter times measurement windows, see measure_phase
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
+
+    info!("Hello World!");
+
+    ////////////////////////
+    // Signal emission setup
+
+    let _excitation =
+        common::start_excitation(board.electrodes, board.test_point, board.pdm_dma, p.TIM2);
+
+    ////////////////////////
+    // Display setup
+
+    let mut spi_config = spi::Config::default();
+    spi_config.frequency = DISPLAY_SPI_FREQUENCY;
+    let mut display = Ssd1306 {
+        spi: Spi::new_txonly(p.SPI2, p.PB13, p.PB15, p.DMA1_CH5, spi_config),
+        dc: Output::new(p.PB10, Level::Low, Speed::Low),
+        cs: Output::new(p.PB12, Level::High, Speed::Low),
+    };
+    let mut display_reset = Output::new(p.PB11, Level::High, Speed::Low);
+
+    let mut adc = Adc::new(p.ADC1);
+    adc.set_sample_time(adc::SampleTime::CYCLES71_5);
+    let mut pin = board.sense_pin;
+    let zero_button = Input::new(p.PB14, Pull::Up);
+
+    // untared position in counts and signal amplitude of the latest measurement, or None while the signal's too weak to trust
+    let reading: Cell<Option<(i64, f32)>> = Cell::new(None);
+    let tare = Cell::new(0i64);
+
+    let fut_measure = async {
+        let mut ticker = Ticker::every(MEASURE_INTERVAL);
+        let mut tracker = PositionTracker::new();
+        let mut first = true;
+
+        loop {
+            let (angle, magnitude) = measure_phase(&mut adc, &mut pin, PDM_FREQUENCY).await;
+            // below it, the display shows dashes
+            if magnitude >= MIN_MEASURE_MAGNITUDE {
+                let position = tracker.update_angle(angle);
+                if tracker.aliased {
+                    warn!(
+                        "Phase step too large to unwrap reliably, position may be off by a pitch"
+                    );
+                }
+                // read zero from wherever the slider was at power-on
+                if first {
+                    first = false;
+                    tare.set(position);
+                }
+                reading.set(Some((position, magnitude * 2.0 / MEASURE_SAMPLES as f32)));
+            } else {
+                reading.set(None);
+            }
+            ticker.next().await;
+        }
+    };
+
+    let fut_display = async {
+        display.init(&mut display_reset).await;
+        let mut framebuffer = Framebuffer([0; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8]);
+        let mut dead_zone = DeadZone::new(
+            DISPLAY_DEADBAND_UM * COUNTS_PER_PITCH / DEFAULT_PITCH_UM as i64,
+            DISPLAY_STILL_REFRESHES,
+        );
+        let mut ticker = Ticker::every(DISPLAY_REFRESH);
+        loop {
+            let shown = reading.get().map(|(position, amplitude)| {
+                let um = counts_to_um(dead_zone.update(position) - tare.get(), DEFAULT_PITCH_UM);
+                (um, amplitude)
+            });
+            draw(&mut framebuffer, shown);
+            display.flush(&framebuffer).await;
+            ticker.next().await;
+        }
+    };
+
+    let fut_zero_button = async {
+        let mut ticker = Ticker::every(ZERO_BUTTON_POLL_INTERVAL);
+        let mut button = Debouncer::new(
+            false,
+            (ZERO_BUTTON_STABLE_MS / ZERO_BUTTON_POLL_INTERVAL.as_millis()) as u32,
+        );
+        loop {
+            ticker.next().await;
+            if button.update(zero_button.is_low()) == Some(true) {
+                if let Some((position, _)) = reading.get() {
+                    tare.set(position);
+                    info!("Zero button pressed, tare {}", position);
+                }
+            }
+        }
+    };
+
+    embassy_futures::join::join3(fut_measure, fut_display, fut_zero_button).await;
+}
+
+/// Lays out a frame: position in millimeters, or dashes without a usable signal, and the signal bar along the bottom.
+fn draw(framebuffer: &mut Framebuffer, shown: Option<(i64, f32)>) {
+    framebuffer.0.fill(0);
+    let large = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+
+    let mut text: heapless::String<16> = heapless::String::new();
+    let bar_fraction = match shown {
+        Some((um, amplitude)) => {
+            // to the nearest hundredth of a millimeter, with the sign kept for readings just under zero
+            let hundredths = (um as f32 / 10.0).round() as i64;
+            let sign = if hundredths < 0 { "-" } else { "" };
+            let _ = write!(
+                text,
+                "{sign}{}.{:02}",
+                hundredths.abs() / 100,
+                hundredths.abs() % 100
+            );
+            ((amplitude / MIN_SIGNAL_AMPLITUDE).log2()
+                / (FULL_SIGNAL_AMPLITUDE / MIN_SIGNAL_AMPLITUDE).log2())
+            .clamp(0.0, 1.0)
+        }
+        None => {
+            let _ = text.push_str("--.--");
+            0.0
+        }
+    };
+    // Drawing into RAM can't fail.
+    let _ =
+        Text::with_alignment(&text, Point::new(104, 30), large, Alignment::Right).draw(framebuffer);
+    let _ = Text::new("mm", Point::new(108, 30), small).draw(framebuffer);
+
+    let _ = Text::new("SIG", Point::new(0, 61), small).draw(framebuffer);
+    let bar = Rectangle::new(Point::new(22, 53), Size::new(104, 9));
+    let _ = bar
+        .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+        .draw(framebuffer);
+    let fill = (bar_fraction * 100.0) as u32;
+    let _ = Rectangle::new(Point::new(24, 55), Size::new(fill, 5))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(framebuffer);
+}
+
+/// One bit per pixel in the SSD1306's own layout, so a frame goes out in one write: byte `x + (y / 8) * DISPLAY_WIDTH`, bit `y % 8`.
+struct Framebuffer([u8; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8]);
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x >= DISPLAY_WIDTH || y >= DISPLAY_HEIGHT {
+                continue;
+            }
+            let byte = &mut self.0[x + y / 8 * DISPLAY_WIDTH];
+            if color.is_on() {
+                *byte |= 1 << (y % 8);
+            } else {
+                *byte &= !(1 << (y % 8));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 128x64 SSD1306 on 4-wire SPI: DC low for commands, high for display data.
+struct Ssd1306<'d> {
+    spi: Spi<'d, Async>,
+    dc: Output<'d>,
+    cs: Output<'d>,
+}
+
+impl<'d> Ssd1306<'d> {
+    /// Resets the panel and brings it up as in the datasheet's application note: horizontal addressing, internal charge pump, scanning so (0, 0) is top left with the pins at the top.
+    async fn init(&mut self, reset: &mut Output<'d>) {
+        reset.set_low();
+        Timer::after_millis(1).await;
+        reset.set_high();
+        Timer::after_millis(1).await;
+        self.command(&[
+            0xAE, // display off
+            0xD5, 0x80, // clock divide ratio, oscillator frequency
+            0xA8, 0x3F, // multiplex ratio, 64 rows
+            0xD3, 0x00, // no display offset
+            0x40, // start line 0
+            0x8D, 0x14, // charge pump on
+            0x20, 0x00, // horizontal addressing
+            0xA1, // column 127 mapped to SEG0
+            0xC8, // scan COM63 to COM0
+            0xDA, 0x12, // alternative COM pin configuration
+            0x81, 0xCF, // contrast
+            0xD9, 0xF1, // pre-charge period
+            0xDB, 0x40, // VCOMH deselect level
+            0xA4, // show RAM contents
+            0xA6, // not inverted
+            0xAF, // display on
+        ])
+        .await;
+    }
+
+    async fn flush(&mut self, framebuffer: &Framebuffer) {
+        // whole screen, so every write starts back at the top left
+        self.command(&[
+            0x21,
+            0,
+            DISPLAY_WIDTH as u8 - 1,
+            0x22,
+            0,
+            (DISPLAY_HEIGHT / 8) as u8 - 1,
+        ])
+        .await;
+        self.dc.set_high();
+        self.write(&framebuffer.0).await;
+    }
+
+    async fn command(&mut self, bytes: &[u8]) {
+        self.dc.set_low();
+        self.write(bytes).await;
+    }
+
+    async fn write(&mut self, bytes: &[u8]) {
+        self.cs.set_low();
+        if let Err(e) = self.spi.write(bytes).await {
+            warn!("Display SPI error: {:?}", e);
+        }
+        self.cs.set_high();
+    }
+}
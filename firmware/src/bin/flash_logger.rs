@@ -0,0 +1,393 @@
+#![no_std]
+#![no_main]
+
+// Logs position to the top of internal flash for measuring away from a host, and hands the log over USB once one's attached.
+// Speaks the usb_custom protocol, but only EraseLog and DumpLog.
+
+use calipertron_core::PositionTracker;
+use schema::*;
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_stm32::adc::{self, Adc};
+use embassy_stm32::flash::{Blocking, Flash, FLASH_BASE, FLASH_SIZE};
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embassy_usb::driver::{Endpoint, EndpointIn, EndpointOut};
+use embassy_usb::Builder;
+
+use {defmt_rtt as _, panic_probe as _};
+
+include!(concat!(env!("OUT_DIR"), "/constants.rs"));
+
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../common.rs"]
+mod common;
+
+use common::{measure_phase, MIN_MEASURE_MAGNITUDE};
+
+bind_interrupts!(struct Irqs {
+    USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
+});
+
+const MAX_PACKET_SIZE: u8 = 64;
+const USB_CLASS_CUSTOM: u8 = 0xFF;
+const USB_SUBCLASS_CUSTOM: u8 = 0x00;
+const USB_PROTOCOL_CUSTOM: u8 = 0x00;
+
+// Pages at the top of flash given over to the log, checked at boot not to overlap the firmware image.
+// 16 pages hold 4032 records. Pages are reused round-robin, so each is erased once per 4032 records; at the F103's rated 10k erase cycles and the worst case of a record every measurement, that's about 4.7 days of continuous motion.
+const LOG_PAGES: usize = 16;
+const LOG_START: u32 = (FLASH_SIZE - LOG_PAGES * LOG_PAGE_SIZE) as u32;
+
+// Measurements this far apart keep up with about 470 mm/s of travel, i.e., half a 9.4 mm pitch per measurement.
+const MEASURE_INTERVAL: Duration = Duration::from_millis(10);
+// Smaller moves than this since the last record aren't logged, so noise on a still slider doesn't fill the log; about 37um on the v1.1 PCB.
+const LOG_MIN_STEP: i64 = 16;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV1,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+    }
+    let mut p = embassy_stm32::init(config);
+    let board = take_board!(p);
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
+    // cycle counter times measurement windows, see measure_phase
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
+
+    info!("Hello World!");
+
+    // The image ends where .data's initial values do, see cortex-m-rt's link.x.
+    extern "C" {
+        static __sidata: u8;
+        static __sdata: u8;
+        static __edata: u8;
+    }
+    let image_end = unsafe {
+        core::ptr::addr_of!(__sidata) as usize
+            + (core::ptr::addr_of!(__edata) as usize - core::ptr::addr_of!(__sdata) as usize)
+    };
+    assert!(
+        image_end <= FLASH_BASE + LOG_START as usize,
+        "firmware image ends at {:#x}, past the start of the log at {:#x}; lower LOG_PAGES",
+        image_end,
+        FLASH_BASE + LOG_START as usize
+    );
+
+    {
+        // Board has a pull-up resistor on the D+ line; pull it down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host will not reset your device when you upload new firmware.
+        let _dp = Output::new(&mut p.PA12, Level::Low, Speed::Low);
+        Timer::after_millis(10).await;
+    }
+
+    ////////////////////////
+    // Signal emission setup
+
+    let _excitation =
+        common::start_excitation(board.electrodes, board.test_point, board.pdm_dma, p.TIM2);
+
+    let log = RefCell::new(FlashLog::new(Flash::new_blocking(p.FLASH)));
+
+    ////////////////////////
+    // USB Setup
+
+    let driver = usb::Driver::new(p.USB, Irqs, p.PA12, p.PA11);
+    let (vid, pid) = (0xc0de, 0xcafe);
+    let mut config = embassy_usb::Config::new(vid, pid);
+    config.max_packet_size_0 = MAX_PACKET_SIZE;
+    config.product = Some("Calipertron logger");
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    let mut func = builder.function(USB_CLASS_CUSTOM, USB_SUBCLASS_CUSTOM, USB_PROTOCOL_CUSTOM);
+    let mut iface = func.interface();
+    let mut iface_alt = iface.alt_setting(
+        USB_CLASS_CUSTOM,
+        USB_SUBCLASS_CUSTOM,
+        USB_PROTOCOL_CUSTOM,
+        None,
+    );
+    let mut read_ep = iface_alt.endpoint_bulk_out(MAX_PACKET_SIZE as u16);
+    let mut write_ep = iface_alt.endpoint_bulk_in(MAX_PACKET_SIZE as u16);
+    drop(func);
+
+    let mut usb = builder.build();
+    let fut_usb = usb.run();
+
+    let mut adc = Adc::new(p.ADC1);
+    adc.set_sample_time(adc::SampleTime::CYCLES71_5);
+    let mut pin = board.sense_pin;
+
+    // Runs whether or not a host is connected; that's the point.
+    let fut_log = async {
+        let mut ticker = Ticker::every(MEASURE_INTERVAL);
+        let mut tracker = PositionTracker::new();
+        let mut logged: Option<i64> = None;
+
+        loop {
+            let (angle, magnitude) = measure_phase(&mut adc, &mut pin, PDM_FREQUENCY).await;
+            // below it, nothing is logged
+            if magnitude >= MIN_MEASURE_MAGNITUDE {
+                let position = tracker.update_angle(angle);
+                if tracker.aliased {
+                    warn!("Phase step too large to unwrap reliably, logged position may be off by a pitch");
+                }
+                if !logged.is_some_and(|logged| (position - logged).abs() < LOG_MIN_STEP) {
+                    // Erasing a page holds everything else up for about 20ms, every LOG_RECORDS_PER_PAGE records.
+                    log.borrow_mut()
+                        .append(Instant::now().as_millis() as u32, position);
+                    logged = Some(position);
+                }
+            }
+            ticker.next().await;
+        }
+    };
+
+    let fut_commands = async {
+        read_ep.wait_enabled().await;
+
+        loop {
+            let mut command_buf = [0u8; MAX_PACKET_SIZE as usize];
+            match read_ep.read(&mut command_buf).await {
+                Ok(size) => {
+                    let Some(command) = Command::deserialize(&command_buf[..size]) else {
+                        error!("Failed to deserialize command");
+                        continue;
+                    };
+                    info!("Received command: {:?}", command);
+                    match command {
+                        Command::EraseLog => {
+                            log.borrow_mut().erase();
+                            respond(&mut write_ep, &Response::Ack).await;
+                        }
+                        Command::DumpLog => {
+                            // Logging carries on during the dump, so on a full log the oldest pages may be reused before they go out; the sequence numbers show where.
+                            let pages = log.borrow_mut().pages_oldest_first();
+                            respond(
+                                &mut write_ep,
+                                &Response::Log {
+                                    pages: pages.len() as u16,
+                                },
+                            )
+                            .await;
+                            'dump: for page in pages {
+                                for start in (0..LOG_PAGE_SIZE).step_by(MAX_PACKET_SIZE as usize) {
+                                    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+                                    log.borrow_mut().read(page, start, &mut buf);
+                                    if let Err(e) = write_ep.write(&buf).await {
+                                        error!("USB Error: {:?}, abandoning log dump", e);
+                                        break 'dump;
+                                    }
+                                }
+                            }
+                        }
+                        x => {
+                            warn!("Can't handle: {}", x);
+                            respond(&mut write_ep, &Response::Error(CommandError::Unsupported))
+                                .await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to read USB packet: {:?}", e);
+                    // Reads fail straight away while disconnected, so wait for the host rather than spin.
+                    read_ep.wait_enabled().await;
+                }
+            }
+        }
+    };
+
+    embassy_futures::join::join3(fut_usb, fut_log, fut_commands).await;
+}
+
+/// Ring of `LOG_PAGES` pages at `LOG_START`, each a `LogPageHeader` followed by `LogRecord`s.
+///
+/// Flash only programs a halfword that's still erased, and only erases a page at a time, so records are appended and never rewritten; a full page moves on to the next one, erasing whatever it held.
+/// Power loss can cut a header or record short, but each one's marker field is written last, so what's left reads as unwritten rather than garbage.
+/// Every boot opens a fresh page rather than appending to a possibly damaged one.
+struct FlashLog<'d> {
+    flash: Flash<'d, Blocking>,
+    /// Page being appended to.
+    page: usize,
+    /// Sequence number for the next page opened.
+    next_sequence: u32,
+    /// Records in the current page, or `None` until the first append since boot or `erase` opens one.
+    records: Option<usize>,
+    /// What the next record is relative to.
+    last_timestamp_ms: u32,
+    last_position: i64,
+}
+
+impl<'d> FlashLog<'d> {
+    fn new(mut flash: Flash<'d, Blocking>) -> Self {
+        // Carry on after the newest page, so the ring keeps rotating across boots rather than always wearing the first page.
+        let mut newest: Option<(u32, usize)> = None;
+        for page in 0..LOG_PAGES {
+            if let Some(header) = read_header(&mut flash, page) {
+                if !newest.is_some_and(|(sequence, _)| sequence >= header.sequence) {
+                    newest = Some((header.sequence, page));
+                }
+            }
+        }
+        let (next_sequence, page) = match newest {
+            Some((sequence, page)) => (sequence + 1, page),
+            None => (0, LOG_PAGES - 1),
+        };
+        info!("Log resumes at page sequence {}", next_sequence);
+
+        FlashLog {
+            flash,
+            page,
+            next_sequence,
+            records: None,
+            last_timestamp_ms: 0,
+            last_position: 0,
+        }
+    }
+
+    fn append(&mut self, timestamp_ms: u32, position: i64) {
+        let Some(mut records) = self.records else {
+            self.open_page(timestamp_ms, position);
+            return;
+        };
+
+        let mut dt_ms = timestamp_ms.wrapping_sub(self.last_timestamp_ms);
+        let mut dposition = position - self.last_position;
+        while dt_ms > 0 || dposition != 0 {
+            if records == LOG_RECORDS_PER_PAGE {
+                // the new page's header has the absolute position, so nothing is lost by splitting here
+                self.open_page(timestamp_ms, position);
+                return;
+            }
+            let record = LogRecord {
+                dt_ms: dt_ms.min(LogRecord::MAX_DT_MS as u32) as u16,
+                dposition: dposition.clamp(i16::MIN as i64, i16::MAX as i64) as i16,
+            };
+            self.write_record(records, &record);
+            records += 1;
+            dt_ms -= record.dt_ms as u32;
+            dposition -= record.dposition as i64;
+        }
+
+        self.records = Some(records);
+        self.last_timestamp_ms = timestamp_ms;
+        self.last_position = position;
+    }
+
+    fn open_page(&mut self, timestamp_ms: u32, position: i64) {
+        self.page = (self.page + 1) % LOG_PAGES;
+        let offset = page_offset(self.page);
+        unwrap!(self
+            .flash
+            .blocking_erase(offset, offset + LOG_PAGE_SIZE as u32));
+
+        let mut header = [0u8; LogPageHeader::SIZE];
+        LogPageHeader {
+            sequence: self.next_sequence,
+            timestamp_ms,
+            position,
+        }
+        .write(&mut header);
+        // sequence last, see LogPageHeader
+        unwrap!(self.flash.blocking_write(offset + 4, &header[4..]));
+        unwrap!(self.flash.blocking_write(offset, &header[..4]));
+
+        self.next_sequence += 1;
+        self.records = Some(0);
+        self.last_timestamp_ms = timestamp_ms;
+        self.last_position = position;
+    }
+
+    fn write_record(&mut self, slot: usize, record: &LogRecord) {
+        let offset = page_offset(self.page) + (LogPageHeader::SIZE + slot * LogRecord::SIZE) as u32;
+        let mut bytes = [0u8; LogRecord::SIZE];
+        record.write(&mut bytes);
+        // dt_ms last, see LogRecord
+        unwrap!(self.flash.blocking_write(offset + 2, &bytes[2..]));
+        unwrap!(self.flash.blocking_write(offset, &bytes[..2]));
+    }
+
+    fn erase(&mut self) {
+        unwrap!(self
+            .flash
+            .blocking_erase(LOG_START, LOG_START + (LOG_PAGES * LOG_PAGE_SIZE) as u32));
+        self.page = LOG_PAGES - 1;
+        self.next_sequence = 0;
+        self.records = None;
+        info!("Log erased");
+    }
+
+    /// Pages with a valid header, by sequence number.
+    fn pages_oldest_first(&mut self) -> heapless::Vec<usize, LOG_PAGES> {
+        let mut pages = heapless::Vec::<(u32, usize), LOG_PAGES>::new();
+        for page in 0..LOG_PAGES {
+            if let Some(header) = read_header(&mut self.flash, page) {
+                unwrap!(pages.push((header.sequence, page)));
+            }
+        }
+        pages.sort_unstable();
+        pages.iter().map(|(_, page)| *page).collect()
+    }
+
+    fn read(&mut self, page: usize, start: usize, buf: &mut [u8]) {
+        unwrap!(self
+            .flash
+            .blocking_read(page_offset(page) + start as u32, buf));
+    }
+}
+
+fn page_offset(page: usize) -> u32 {
+    LOG_START + (page * LOG_PAGE_SIZE) as u32
+}
+
+fn read_header(flash: &mut Flash<'_, Blocking>, page: usize) -> Option<LogPageHeader> {
+    let mut header = [0u8; LogPageHeader::SIZE];
+    unwrap!(flash.blocking_read(page_offset(page), &mut header));
+    LogPageHeader::read(&header)
+}
+
+async fn respond(ep: &mut impl EndpointIn, response: &Response) {
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    match response.serialize(&mut buf) {
+        Ok(bs) => {
+            if let Err(e) = ep.write(bs).await {
+                error!("USB Error: {:?}", e);
+            }
+        }
+        Err(_) => error!("Failed to serialize response"),
+    }
+}
@@ -1,22 +1,445 @@
 #![no_std]
 #![no_main]
 
+use calipertron_core::dsp::*;
+use calipertron_core::pipeline::{channel_sums, phase_magnitude, PositionStage, WindowPhase};
 use calipertron_core::*;
+use schema::{I2cRegisters, IqOffset};
 
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::dma::*;
 use embassy_stm32::gpio::{Flex, Input, Level, Output, Speed};
 use embassy_stm32::time::Hertz;
-use embassy_stm32::{adc, Config};
+use embassy_stm32::{adc, interrupt, Config};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
 
-//use embassy_time::Duration;
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write as _;
 use num_traits::Float;
 
 use {defmt_rtt as _, panic_probe as _};
 
 include!(concat!(env!("OUT_DIR"), "/constants.rs"));
+
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../common.rs"]
+mod common;
+
 const NUM_SAMPLES: usize = SINE_COSINE_TABLE.len();
+const _: () = assert!(NUM_SAMPLES == SAMPLE_CONFIG.num_samples);
+const _: () = assert!(SECOND_SINE_COSINE_TABLE.len() == NUM_SAMPLES);
+const _: () = assert!(PDM_SIGNAL.len() == SAMPLE_CONFIG.pdm_length);
+// Conversions are started by the PDM timer rather than free-running; see adc_trigger_ticks in build.rs and the trigger setup in main.
+const ADC_TRIGGERED: bool = SAMPLE_CONFIG.adc_trigger_ticks > 0;
+// The tables assume this sample time, so every channel in the window has to use it.
+const ADC_SAMPLE_TIME: adc::SampleTime = adc::SampleTime::from_bits(SAMPLE_CONFIG.adc_sample_time);
+// How ADC1 is set up to leave conversions in DR; each window is normalized from it to the ADC_BITS right-aligned that everything downstream assumes.
+// The F103 only converts at 12 bits; the alignment is set below and read back from CR2 before any samples are used.
+const ADC_FORMAT: AdcFormat = AdcFormat::new(ADC_BITS, AdcAlignment::Right);
+// ADC conversions per window; see OVERSAMPLING in build.rs.
+const NUM_CONVERSIONS: usize = NUM_SAMPLES * OVERSAMPLING;
+// Goertzel takes slot sums as i16.
+const _: () = assert!(OVERSAMPLING * 4095 <= i16::MAX as usize);
+// demodulate accumulates into i64 and shifts each channel back to sample scale once at the end, which has to fit the i32 it returns.
+// A difference of two channels' sums is bounded by the whole window's, so this covers DIFFERENTIAL too, short of a channel_gain above 1.
+const _: () = assert!(SINE_COSINE_TABLE_MAX_ACCUMULATOR <= i64::MAX as i128);
+const _: () = assert!(SINE_COSINE_TABLE_MAX_ACCUMULATOR >> SCALE_SHIFT <= i32::MAX as i128);
+const _: () = assert!(SECOND_SINE_COSINE_TABLE_MAX_ACCUMULATOR <= i64::MAX as i128);
+const _: () = assert!(SECOND_SINE_COSINE_TABLE_MAX_ACCUMULATOR >> SCALE_SHIFT <= i32::MAX as i128);
+// channel_sums shifts by calipertron_core's Q15_SHIFT, build.rs scales the tables by SCALE_SHIFT.
+const _: () = assert!(SCALE_SHIFT == Q15_SHIFT);
+const _: () = assert!(PDM_PIN_MASK >> 8 == 0, "only PA0--PA7 can drive electrodes");
+
+// Two windows long, so one half is demodulated while DMA fills the other.
+// Reads are a whole window each, so as long as the buffer holds a whole number of windows every read lines up with the start of SINE_COSINE_TABLE.
+const ADC_BUFFER_LEN: usize = 2 * NUM_CONVERSIONS;
+const _: () = assert!(ADC_BUFFER_LEN % NUM_CONVERSIONS == 0);
+
+// Twice as long as a window of samples should take; anything past that means the ADC or its DMA has stalled.
+const ADC_TIMEOUT: Duration = Duration::from_micros(
+    2 * (NUM_CONVERSIONS as u64 * 1_000_000).div_ceil(SAMPLING_FREQUENCY_HZ as u64),
+);
+// Windows in a row identical to the last, with no conversion started in between (the ADC's STRT flag), before the ADC counts as stalled; see StallDetector.
+// Noise alone makes two real windows in a row identical vanishingly rare, so this only needs to be long enough to not act on a coincidence.
+const STALL_WINDOWS: u32 = 8;
+
+// Where TIM2 gets its clock, and so the excitation, and with ADC_TRIGGERED the sampling too.
+// Calipers side by side on their own crystals excite at frequencies a few ppm apart, and each picks up the other's excitation as a beat.
+// Clocking them all from one crystal keeps their excitations a fixed phase apart instead, and the fixed part demodulates out like any other coupling offset.
+//
+// Wiring: one board is the Master, and puts its 8 MHz crystal out on PA8 (MCO).
+// Run a short wire from it to PA15 (TIM2_ETR, with TIM2 remapped) on each External board, along with a ground wire; PA15 is JTDI, so External boards give up JTAG, but SWD keeps working.
+// To check, hold both sliders still and log phase on both for a minute: clocked together the difference between them holds to within the noise, on their own crystals it keeps walking.
+#[derive(PartialEq)]
+enum ClockReference {
+    // The board's own crystal, through the PLL.
+    Internal,
+    // As Internal, and also drive the crystal out on PA8 for External boards.
+    Master,
+    // Count REFERENCE_FREQUENCY_HZ edges on PA15, in TIM2's external clock mode 2.
+    External,
+}
+const CLOCK_REFERENCE: ClockReference = ClockReference::Internal;
+// The HSE crystal, what a Master puts out.
+const REFERENCE_FREQUENCY_HZ: u32 = 8_000_000;
+// TIM2 sits on APB1, which runs at half of SYSCLK, so its clock is doubled back up to 72 MHz (reference manual figure 8).
+const TIM2_CLOCK_HZ: u32 = 72_000_000;
+// TIM2 ticks per PDM tick, rounded down like Timer::set_frequency does.
+const PDM_TIMER_TICKS: u32 = TIM2_CLOCK_HZ / SAMPLE_CONFIG.pdm_frequency_hz;
+// An External board has to land on exactly the Master's PDM tick rate, or the two still beat.
+const REFERENCE_TICKS_PER_PDM_TICK: u32 =
+    PDM_TIMER_TICKS / (TIM2_CLOCK_HZ / REFERENCE_FREQUENCY_HZ);
+const _: () = assert!(
+    !matches!(CLOCK_REFERENCE, ClockReference::External)
+        || (PDM_TIMER_TICKS % (TIM2_CLOCK_HZ / REFERENCE_FREQUENCY_HZ) == 0
+            && REFERENCE_TICKS_PER_PDM_TICK >= 1
+            && REFERENCE_TICKS_PER_PDM_TICK <= 1 << 16),
+    "the PDM frequency isn't a whole number of reference clock cycles"
+);
+// ETR is sampled off the timer clock, so it can count at most a quarter of it (reference manual section 15.3.3).
+const _: () = assert!(REFERENCE_FREQUENCY_HZ <= TIM2_CLOCK_HZ / 4);
+// Sampling off this board's own crystal would beat against the excitation the same way.
+const _: () = assert!(
+    !matches!(CLOCK_REFERENCE, ClockReference::External) || ADC_TRIGGERED,
+    "an external clock needs the PDM timer to trigger the ADC; set adc_trigger_ticks in build.rs"
+);
+
+// Drive PB6/PB7 as an incremental A/B quadrature encoder, for machine controllers that don't speak anything else.
+const QUADRATURE_OUTPUT: bool = true;
+const ENCODER_COUNTS_PER_PITCH: i64 = 256;
+// 20k transitions per second, i.e., the output keeps up with about 730mm/s of travel.
+const ENCODER_EDGE_INTERVAL: Duration = Duration::from_micros(50);
+
+// Serve position as an I2C slave on I2C2 (PB10 SCL, PB11 SDA), for polling from another microcontroller; see schema::I2cRegisters.
+// Our embassy-stm32 only does I2C master, so the slave side is driven from the peripheral's interrupts directly.
+const I2C_OUTPUT: bool = false;
+const I2C_ADDRESS: u8 = 0x42;
+
+// Send position as a line of ASCII on USART1 (PA9 TX), for a PLC, display module or anything else with a serial input and no USB.
+// Each line is `<position in um>,<status>\r\n`: the position tared and filtered as on I2C, as a signed decimal, and the status as I2cRegisters flags in hex, e.g., `-12345,04\r\n`.
+// A line per reading, and at most one per UART_INTERVAL so a slow baud keeps up; none while there are no readings, e.g., with the signal too weak.
+// 8N1, no flow control; the line goes out over DMA, so the demodulation loop never waits on it.
+const UART_OUTPUT: bool = false;
+const UART_BAUD: u32 = 115_200;
+const UART_INTERVAL: Duration = Duration::from_millis(10);
+// a sign and 19 digits for an i64, a comma, up to 8 hex digits of status and the line ending
+const UART_LINE_LEN: usize = 32;
+
+// Number of windows to vector-average before computing phase.
+// Window noise is uncorrelated, so phase jitter drops by sqrt(N) (N = 4 roughly halves it) while the update rate drops by N.
+const IQ_AVERAGE_WINDOWS: u32 = 4;
+
+// Demodulate with a Goertzel filter rather than correlating against SINE_COSINE_TABLE, for A/B comparison of noise and timing.
+const USE_GOERTZEL: bool = false;
+
+// Sample a second electrode on PB0 as well as PB1 and demodulate their difference, rejecting common-mode interference.
+const DIFFERENTIAL: bool = false;
+const NUM_CHANNELS: usize = if DIFFERENTIAL { 2 } else { 1 };
+const _: () = assert!(NUM_SAMPLES % NUM_CHANNELS == 0);
+// Goertzel assumes evenly spaced samples from one channel.
+const _: () = assert!(!(DIFFERENTIAL && USE_GOERTZEL));
+// Windows each step of a channel calibration averages, a couple of seconds at the default timing; see I2cRegisters::CALIBRATE.
+const CALIBRATION_WINDOWS: u32 = 4096;
+// Goertzel runs over the whole window; the gate is built into SINE_COSINE_TABLE.
+const _: () = assert!(!(USE_GOERTZEL && GATE_SAMPLES > 0));
+// so the gate skips the same number of samples from every channel
+const _: () = assert!(GATE_SAMPLES % NUM_CHANNELS == 0);
+// oversampling would sum conversions from alternating channels
+const _: () = assert!(!(DIFFERENTIAL && OVERSAMPLING > 1));
+// each trigger converts the whole scan sequence back to back, so the channels' samples wouldn't be evenly spaced
+const _: () = assert!(!(DIFFERENTIAL && ADC_TRIGGERED));
+
+// Per-code corrections for the ADC's nonlinearity, from build.rs; see the adc-lut feature.
+#[cfg(feature = "adc-lut")]
+const ADC_LUT: AdcLut<{ ADC_LUT_CORRECTIONS.len() }> = AdcLut::new(ADC_LUT_CORRECTIONS);
+
+// Median filter raw samples over this many neighbours before demodulating, to reject single-sample ADC glitches; 1 turns it off.
+const MEDIAN_FILTER_WINDOW: usize = 3;
+// the filter would mix interleaved channels
+const _: () = assert!(!(DIFFERENTIAL && MEDIAN_FILTER_WINDOW > 1));
+
+// Below this received amplitude (ADC counts, peak) the scale is either dirty or too far from the slider for the phase to mean anything.
+const MIN_SIGNAL_AMPLITUDE: f32 = 16.0;
+// A sinusoid of amplitude A correlates to a magnitude of A * N/2 over an N-sample window, scaled by the window's coherent gain; see CALIPER_WINDOW in build.rs.
+// Goertzel doesn't use the table, so it isn't windowed.
+const WINDOW_GAIN: f32 = if USE_GOERTZEL {
+    1.0
+} else {
+    WINDOW_COHERENT_GAIN
+};
+// Only the samples after GATE_SAMPLES are demodulated.
+const MIN_MAGNITUDE: f32 =
+    MIN_SIGNAL_AMPLITUDE * (NUM_SAMPLES - GATE_SAMPLES) as f32 / 2.0 * WINDOW_GAIN;
+
+// The ADC's analog watchdog flags any conversion outside this window (raw 12-bit counts).
+// The electrodes sit around mid-scale, so a conversion near either rail means the front end is saturating or the input is floating.
+const ADC_WATCHDOG_LOW: u16 = 128;
+const ADC_WATCHDOG_HIGH: u16 = 4095 - 128;
+// More conversions than this on the rails in a window means the input is clipping, rather than one glitching conversion the median filter takes care of.
+const MAX_SATURATED_SAMPLES: u32 = 2;
+
+// Temperature is read from the internal sensor this often, squeezed in as an injected conversion.
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(1);
+// Thermal expansion of the FR4 scale: warm, each pitch covers more distance than its nominal length, so the raw position reads short.
+// Coefficient in ppm/°C; constant for now, but it belongs with the rest of the calibration once that's settable.
+const SCALE_EXPANSION_PPM_PER_C: f32 = 17.0;
+// Temperature the pitch is specified at, where no correction applies.
+const REFERENCE_TEMPERATURE_C: f32 = 20.0;
+
+// Demodulate SELF_TEST_SIGNAL at boot and check the phase comes out as injected, to separate DSP problems from analog ones.
+const SELF_TEST: bool = true;
+// 1/256 turn; the window's slight mismatch with a whole excitation cycle leaks enough DC to account for about a fifth of that.
+const SELF_TEST_TOLERANCE: u32 = (QUARTER_TURN >> 6) as u32;
+
+// Demodulate the second excitation tone from the dual-frequency feature too, and flag when the two tones' phases disagree; see I2cRegisters for what the host does with them.
+// Both tones are in every window, so they're demodulated simultaneously from the same samples with their own tables.
+const DUAL_FREQUENCY: bool = cfg!(feature = "dual-frequency");
+// 1/64 turn, i.e., 32 counts; well past the two tones' jitter against each other after averaging, but small next to what interference beating with one of them does.
+const MAX_FREQUENCY_DISAGREEMENT: u32 = (QUARTER_TURN >> 4) as u32;
+
+// Smoothing of the logged position; see OnePole for how alpha maps to settling time.
+const POSITION_FILTER_ALPHA: f32 = 0.3;
+
+// With the sample-dump feature, the button also dumps the window it was pressed on alongside SINE_COSINE_TABLE, for plotting the raw waveform against the demodulation weights.
+// Each dump is a few KB over RTT, so at most one per interval, however long the button is held.
+#[cfg(feature = "sample-dump")]
+const SAMPLE_DUMP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Log how fast the demodulated phase creeps, to check the clock tree before trusting readings; hold the slider still while it runs.
+// Sampling and excitation both run off the PLL set up at the top of main, and WINDOW_PHASE_ADVANCE assumes the ratio build.rs computed; a clock that's off makes a stationary slider rotate steadily.
+// With the excitation off there'd be no phase to measure, so this needs the real signal.
+const CLOCK_DRIFT_CHECK: bool = false;
+const CLOCK_DRIFT_INTERVAL: Duration = Duration::from_secs(5);
+// More creep than a still hand or thermal drift would explain.
+const MAX_CLOCK_DRIFT_DEG_PER_S: f32 = 1.0;
+
+// The per-window readings go out at most this often. defmt blocks once the RTT buffer fills, so with a probe attached that isn't draining fast enough, logging every window would hold up demodulation and drop samples.
+// One-off messages aren't throttled.
+const READING_LOG_INTERVAL: Duration = Duration::from_millis(100);
+// The same goes for warnings about a window, which a bad input trips on every one; each goes out at most this often, with a count of the windows it held back.
+const WINDOW_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+// Log the amplitudes of a few DFT bins around the excitation now and then, to spot interference and harmonics when picking an excitation frequency.
+// Each one costs SPECTRUM_BINS times a window's demodulation, hence the interval.
+const LOG_SPECTRUM: bool = false;
+const SPECTRUM_INTERVAL: Duration = Duration::from_secs(10);
+const SPECTRUM_BINS: usize = 8;
+// From a few bins below the excitation's, or DC if that's closer.
+const SPECTRUM_FIRST_BIN: u32 = WINDOW_CYCLES.saturating_sub(SPECTRUM_BINS as u32 / 2 - 1);
+// interleaved channels aren't one evenly sampled signal
+const _: () = assert!(!(LOG_SPECTRUM && DIFFERENTIAL));
+
+// Interpolate each window onto the exact phases SINE_COSINE_TABLE assumes, ADC_SAMPLE_DELAY earlier than the ADC sampled them, before demodulating.
+// A fixed delay only adds a constant to the phase that zeroing removes, but that constant scales with the excitation frequency and the ADC sample time, and differs between a tone and its harmonics; resampled, none of them move the zero.
+// Linear is plenty at tens of samples per cycle; either costs a pass over the window.
+const RESAMPLE: Option<Interpolation> = None;
+const _: () = assert!(!(RESAMPLE.is_some() && DIFFERENTIAL));
+
+// DMA1 channels, numbered from 0 as in the PAC.
+use board::{ADC_DMA_CHANNEL, PDM_DMA_CHANNEL};
+// transfer errors on them, polled rather than left to Embassy's interrupt
+use common::{mask_dma_error_interrupt, DmaErrors};
+
+// Written by the demodulation loop, latched and served by the I2C interrupts.
+static I2C_REGISTERS: Mutex<CriticalSectionRawMutex, Cell<I2cRegisters>> =
+    Mutex::new(Cell::new(I2cRegisters {
+        position: 0,
+        magnitude: 0.0,
+        status: 0,
+        phase: 0,
+        second_phase: 0,
+        stalls: 0,
+        channel_gain: [1.0; 2],
+        channel_offset: [IqOffset {
+            sum_sine: 0,
+            sum_cosine: 0,
+        }; 2],
+    }));
+
+// Set once either DMA channel is given up on; every status written after that carries I2cRegisters::DMA_FAILED.
+static DMA_FAILED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+// Set while the demodulation loop captures windows for a channel calibration, carried into every status like DMA_FAILED.
+static CALIBRATING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+fn update_i2c_registers(f: impl FnOnce(&mut I2cRegisters)) {
+    let sticky = sticky_status();
+    I2C_REGISTERS.lock(|r| {
+        let mut registers = r.get();
+        f(&mut registers);
+        registers.status |= sticky;
+        r.set(registers);
+    })
+}
+
+/// The status flags that outlast a reading, DMA_FAILED and CALIBRATING, for every status sent anywhere.
+fn sticky_status() -> u32 {
+    let mut status = 0;
+    if DMA_FAILED.lock(Cell::get) {
+        status |= I2cRegisters::DMA_FAILED;
+    }
+    if CALIBRATING.lock(Cell::get) {
+        status |= I2cRegisters::CALIBRATING;
+    }
+    status
+}
+
+fn set_dma_failed() {
+    DMA_FAILED.lock(|f| f.set(true));
+    update_i2c_registers(|_| {});
+}
+
+fn set_calibrating(calibrating: bool) {
+    CALIBRATING.lock(|c| c.set(calibrating));
+    update_i2c_registers(|r| r.status &= !I2cRegisters::CALIBRATING);
+}
+
+// Applied by demodulate in differential mode, see I2cRegisters::CALIBRATE; written over I2C or by the demodulation loop once a calibration's done.
+static CHANNEL_CALIBRATION: Mutex<CriticalSectionRawMutex, Cell<[ChannelCalibration; 2]>> =
+    Mutex::new(Cell::new([ChannelCalibration::IDENTITY; 2]));
+
+fn set_channel_calibration(calibration: [ChannelCalibration; 2]) {
+    CHANNEL_CALIBRATION.lock(|c| c.set(calibration));
+    update_i2c_registers(|r| {
+        for (channel, c) in calibration.iter().enumerate() {
+            r.channel_gain[channel] = c.gain;
+            r.channel_offset[channel] = IqOffset {
+                sum_sine: c.offset_sine,
+                sum_cosine: c.offset_cosine,
+            };
+        }
+    });
+}
+
+/// A step of a channel calibration, as written to I2cRegisters::CALIBRATE.
+#[derive(Clone, Copy)]
+enum CalibrationStep {
+    /// No input on either channel: capture their offsets.
+    Zero,
+    /// The same input on both, which each channel should read at this magnitude: capture their gains against the offsets.
+    Known(f32),
+}
+
+static CALIBRATION_REQUEST: Signal<CriticalSectionRawMutex, CalibrationStep> = Signal::new();
+
+/// One demodulation window's worth of output, as published to every consumer; local's counterpart to schema::Reading, never serialized.
+#[derive(Clone, Copy)]
+struct Reading {
+    /// Filtered, temperature compensated and zeroed, in counts.
+    position: f32,
+    /// Straight from the tracker, before filtering or zeroing.
+    raw_position: i64,
+    magnitude: f32,
+    phase: i32,
+    /// Phase of the second tone against its baseline, with the dual-frequency feature.
+    second_phase: Option<i32>,
+    aliased: bool,
+    frequency_disagreement: bool,
+    near_limit: bool,
+}
+
+impl Reading {
+    /// I2cRegisters status flags for this reading, short of sticky_status.
+    fn status(&self) -> u32 {
+        let mut status = 0;
+        if self.aliased {
+            status |= I2cRegisters::ALIASED;
+        }
+        if self.frequency_disagreement {
+            status |= I2cRegisters::FREQUENCY_DISAGREEMENT;
+        }
+        if self.near_limit {
+            status |= I2cRegisters::POSITION_NEAR_LIMIT;
+        }
+        status
+    }
+}
+
+/// Everything that waits on the demodulation loop's readings; to add an output, add a variant and await `next_position` with it.
+#[derive(Clone, Copy)]
+enum Consumer {
+    I2c,
+    Encoder,
+    Uart,
+}
+const NUM_CONSUMERS: usize = 3;
+
+// A latest-value slot per consumer: a Signal only holds one waiter, and a shared one would hand each reading to whichever consumer took it first.
+static READINGS: [Signal<CriticalSectionRawMutex, Reading>; NUM_CONSUMERS] =
+    [const { Signal::new() }; NUM_CONSUMERS];
+
+/// Hands `reading` to every consumer, replacing any they haven't picked up yet, so the loop never waits on them.
+fn publish(reading: Reading) {
+    for slot in &READINGS {
+        slot.signal(reading);
+    }
+}
+
+/// Waits for a reading newer than the last one `consumer` got; a consumer that falls behind skips straight to the latest.
+async fn next_position(consumer: Consumer) -> Reading {
+    READINGS[consumer as usize].wait().await
+}
+
+/// Rate limits a warning about a window to one per WINDOW_WARNING_INTERVAL.
+struct WindowWarning {
+    last: Option<Instant>,
+    held_back: u32,
+}
+
+impl WindowWarning {
+    const fn new() -> Self {
+        Self {
+            last: None,
+            held_back: 0,
+        }
+    }
+
+    /// Call on each window the warning applies to; returns how many it held back since the last one it let through, or None to hold this one back too.
+    fn due(&mut self) -> Option<u32> {
+        if self
+            .last
+            .is_some_and(|last| last.elapsed() < WINDOW_WARNING_INTERVAL)
+        {
+            self.held_back += 1;
+            return None;
+        }
+        self.last = Some(Instant::now());
+        Some(core::mem::take(&mut self.held_back))
+    }
+}
+
+struct I2cSlave {
+    /// Copy of I2C_REGISTERS taken when the current read was addressed.
+    latched: [u8; I2cRegisters::SIZE],
+    /// Next register byte to send; set by the first byte of a write, incremented by reads.
+    pointer: usize,
+    /// Whether the next byte written is a register address.
+    expect_pointer: bool,
+    /// Bytes written to the writable registers, from I2cRegisters::CHANNEL_CALIBRATION on, applied at the STOP.
+    written: [u8; I2C_WRITABLE_LEN],
+    /// Which of `written` this write has set.
+    written_mask: u32,
+}
+
+// the six calibration registers, then CALIBRATE
+const I2C_WRITABLE_LEN: usize = I2cRegisters::CALIBRATE + 4 - I2cRegisters::CHANNEL_CALIBRATION;
+const _: () = assert!(I2C_WRITABLE_LEN <= u32::BITS as usize);
+
+static I2C_SLAVE: Mutex<CriticalSectionRawMutex, RefCell<I2cSlave>> =
+    Mutex::new(RefCell::new(I2cSlave {
+        latched: [0; I2cRegisters::SIZE],
+        pointer: 0,
+        expect_pointer: false,
+        written: [0; I2C_WRITABLE_LEN],
+        written_mask: 0,
+    }));
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
@@ -38,28 +461,37 @@ async fn main(_spawner: Spawner) {
         config.rcc.apb2_pre = APBPrescaler::DIV1;
     }
     let p = embassy_stm32::init(config);
+    let board = take_board!(p);
 
     info!("Hello World!");
 
     ////////////////////////
     // Signal emission setup
 
-    let _pins = [
-        Output::new(p.PA0, Level::Low, Speed::Low),
-        Output::new(p.PA1, Level::Low, Speed::Low),
-        Output::new(p.PA2, Level::Low, Speed::Low),
-        Output::new(p.PA3, Level::Low, Speed::Low),
-        Output::new(p.PA4, Level::Low, Speed::Low),
-        Output::new(p.PA5, Level::Low, Speed::Low),
-        Output::new(p.PA6, Level::Low, Speed::Low),
-        Output::new(p.PA7, Level::Low, Speed::Low),
-    ];
+    // build.rs decides which of the board's electrodes PDM_SIGNAL actually drives.
+    let _pins: heapless::Vec<Output, 8> = board
+        .electrodes
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| PDM_PIN_MASK & (1 << idx) != 0)
+        .map(|(_, pin)| Output::new(pin, Level::Low, Speed::Low))
+        .collect();
+    let _test_point = board::test_point(board.test_point, TEST_POINT);
+    info!("Driving {} electrode phases", NUM_PHASES);
+    info!(
+        "Demodulating {} samples per window, {} excitation cycles",
+        NUM_SAMPLES, WINDOW_CYCLES
+    );
+    // build.rs already checks this; cheap to check again in debug builds before it gets to the pins
+    debug_assert!(PDM_SIGNAL.iter().all(|word| bsrr_conflicts(*word) == 0));
 
     let tim = embassy_stm32::timer::low_level::Timer::new(p.TIM2);
     let timer_registers = tim.regs_gp16();
-    timer_registers
-        .cr2()
-        .modify(|w| w.set_ccds(embassy_stm32::pac::timer::vals::Ccds::ONUPDATE));
+    timer_registers.cr2().modify(|w| {
+        w.set_ccds(embassy_stm32::pac::timer::vals::Ccds::ONUPDATE);
+        // TRGO on every PDM tick, for the ADC trigger below
+        w.set_mms(embassy_stm32::pac::timer::vals::Mms::UPDATE);
+    });
     timer_registers.dier().modify(|w| {
         // Enable update DMA request
         w.set_ude(true);
@@ -67,13 +499,61 @@ async fn main(_spawner: Spawner) {
         w.set_uie(true);
     });
 
-    tim.set_frequency(Hertz(PDM_FREQUENCY));
+    let _mco = (CLOCK_REFERENCE == ClockReference::Master)
+        .then(|| embassy_stm32::rcc::Mco::new(p.MCO, p.PA8, embassy_stm32::rcc::McoSource::HSE));
+    // PA15 comes out of reset as JTDI rather than a GPIO
+    let _reference_input = (CLOCK_REFERENCE == ClockReference::External)
+        .then(|| Input::new(p.PA15, embassy_stm32::gpio::Pull::None));
+    if CLOCK_REFERENCE == ClockReference::External {
+        use embassy_stm32::pac::{AFIO, RCC};
+        RCC.apb2enr().modify(|w| w.set_afioen(true));
+        AFIO.mapr().modify(|w| {
+            // SW-DP only, which frees PA15
+            w.set_swj_cfg(0b010);
+            // partial remap 1, TIM2_CH1_ETR on PA15 (reference manual section 9.3.7)
+            w.set_tim2_remap(0b01);
+        });
+        // ETR counts rising edges with no prescaler or filter, as after reset
+        timer_registers.smcr().modify(|w| w.set_ece(true));
+        timer_registers.psc().write(|w| w.set_psc(0));
+        timer_registers
+            .arr()
+            .write(|w| w.set_arr((REFERENCE_TICKS_PER_PDM_TICK - 1) as u16));
+        // load the prescaler straight away, like set_frequency does
+        timer_registers.egr().write(|w| w.set_ug(true));
+        info!(
+            "Counting a {} Hz reference on PA15, {} cycles per PDM tick",
+            REFERENCE_FREQUENCY_HZ, REFERENCE_TICKS_PER_PDM_TICK
+        );
+    } else {
+        tim.set_frequency(Hertz(SAMPLE_CONFIG.pdm_frequency_hz));
+    }
+
+    // With ADC_TRIGGERED, the same update event that steps the PDM DMA also paces the ADC:
+    // TIM2 TRGO (update) -> ITR1 -> TIM3 in external clock mode 1, counting PDM ticks -> TIM3 TRGO (update, every adc_trigger_ticks ticks) -> ADC1 EXTSEL regular trigger.
+    // TIM3 is running at zero before start_pdm resets TIM2 and points the DMA at the top of PDM_SIGNAL, so every conversion lands on the same PDM tick, boot after boot.
+    // To check, hold the slider still and compare the "first window" phase logged below across resets: triggered, it repeats to within the noise; free-running, it doesn't.
+    let adc_trigger = embassy_stm32::timer::low_level::Timer::new(p.TIM3);
+    if ADC_TRIGGERED {
+        use embassy_stm32::pac::timer::vals::{Mms, Sms, Ts};
+        let trigger_registers = adc_trigger.regs_gp16();
+        trigger_registers.psc().write(|w| w.set_psc(0));
+        trigger_registers
+            .arr()
+            .write(|w| w.set_arr(SAMPLE_CONFIG.adc_trigger_ticks as u16 - 1));
+        trigger_registers.smcr().modify(|w| {
+            w.set_ts(Ts::ITR1); // TIM2, reference manual table 86
+            w.set_sms(Sms::EXT_CLOCK_MODE);
+        });
+        trigger_registers.cr2().modify(|w| w.set_mms(Mms::UPDATE));
+        adc_trigger.start();
+    }
 
     let start_pdm = || unsafe {
         let mut opts = TransferOptions::default();
         opts.circular = true;
 
-        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&p.DMA1_CH2);
+        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&board.pdm_dma);
         let request = embassy_stm32::timer::UpDma::request(&dma_ch);
 
         tim.reset();
@@ -82,7 +562,7 @@ async fn main(_spawner: Spawner) {
             dma_ch,
             request,
             &PDM_SIGNAL,
-            embassy_stm32::pac::GPIOA.bsrr().as_ptr() as *mut u32,
+            board::ELECTRODE_PORT.bsrr().as_ptr() as *mut u32,
             opts,
         );
 
@@ -93,26 +573,36 @@ async fn main(_spawner: Spawner) {
     ////////////////////////
     // ADC + DMA setup
 
-    let start_adc = |sample_buf| unsafe {
-        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&p.DMA1_CH1);
-        let request = embassy_stm32::adc::RxDma::request(&dma_ch);
-        let opts = TransferOptions::default();
-
-        let t = Transfer::new_read(
-            dma_ch,
+    let mut adc_buffer = [0u16; ADC_BUFFER_LEN];
+    let request = embassy_stm32::adc::RxDma::request(&board.adc_dma);
+    let mut opts = TransferOptions::default();
+    opts.half_transfer_ir = true;
+    let mut adc_rb = unsafe {
+        ReadableRingBuffer::new(
+            board.adc_dma,
             request,
             embassy_stm32::pac::ADC1.dr().as_ptr() as *mut u16,
-            sample_buf,
+            &mut adc_buffer,
             opts,
-        );
-
-        // Start ADC conversions
-        embassy_stm32::pac::ADC1.cr2().modify(|w| w.set_adon(true));
-        t
+        )
     };
 
-    // just need this to power on ADC
-    let _adc = adc::Adc::new(p.ADC1);
+    // just need this to power on ADC, and for a reading of VREFINT to take the temperature sensor's millivolts against
+    let mut embassy_adc = adc::Adc::new(p.ADC1);
+    let vrefint_sample = {
+        let mut vrefint = embassy_adc.enable_vref();
+
+        // give vref some time to warm up
+        Timer::after_millis(100).await;
+
+        embassy_adc.read(&mut vrefint).await
+    };
+    if vrefint_sample < MIN_VREFINT_SAMPLE {
+        error!(
+            "VREFINT sample {} below {}, so no temperature compensation or millivolts",
+            vrefint_sample, MIN_VREFINT_SAMPLE
+        );
+    }
 
     // Configure ADC for continuous conversion with DMA
     let adc = embassy_stm32::pac::ADC1;
@@ -124,73 +614,848 @@ async fn main(_spawner: Spawner) {
 
     adc.cr2().modify(|w| {
         w.set_dma(true);
-        w.set_cont(true);
+        w.set_align(ADC_FORMAT.alignment.align_bit());
+        if ADC_TRIGGERED {
+            w.set_extsel(embassy_stm32::pac::adc::vals::Extsel::TIM3_TRGO);
+            w.set_exttrig(true);
+        } else {
+            w.set_cont(true);
+        }
     });
 
     // Configure channel and sampling time
-    adc.sqr1().modify(|w| w.set_l(0)); // one conversion.
+    adc.sqr1().modify(|w| w.set_l(NUM_CHANNELS as u8 - 1)); // one conversion per channel.
 
     // TODO: this may not be necessary
-    let mut pb1 = Flex::new(p.PB1);
-    pb1.set_as_analog();
+    let mut sense_pin = Flex::new(board.sense_pin);
+    sense_pin.set_as_analog();
 
-    const PIN_CHANNEL: u8 = 9; // PB1 is on channel 9 for STM32F103
-    adc.sqr3().modify(|w| w.set_sq(0, PIN_CHANNEL));
+    adc.sqr3().modify(|w| w.set_sq(0, board::SENSE_ADC_CHANNEL));
     adc.smpr2()
-        .modify(|w| w.set_smp(PIN_CHANNEL as usize, adc::SampleTime::CYCLES41_5));
+        .modify(|w| w.set_smp(board::SENSE_ADC_CHANNEL as usize, ADC_SAMPLE_TIME));
+
+    let mut return_pin = Flex::new(board.return_pin);
+    if DIFFERENTIAL {
+        return_pin.set_as_analog();
+
+        adc.sqr3()
+            .modify(|w| w.set_sq(1, board::RETURN_ADC_CHANNEL));
+        adc.smpr2()
+            .modify(|w| w.set_smp(board::RETURN_ADC_CHANNEL as usize, ADC_SAMPLE_TIME));
+    }
+
+    // Analog watchdog on every regular conversion, polled once per window rather than interrupting (reference manual section 11.3.7).
+    adc.htr().write(|w| w.set_ht(ADC_WATCHDOG_HIGH));
+    adc.ltr().write(|w| w.set_lt(ADC_WATCHDOG_LOW));
+    adc.cr1().modify(|w| {
+        if DIFFERENTIAL {
+            w.set_awdsgl(false); // both channels
+        } else {
+            w.set_awdsgl(true);
+            w.set_awdch(board::SENSE_ADC_CHANNEL);
+        }
+        w.set_awden(true);
+    });
+
+    // The temperature sensor (channel 16) is an injected conversion, started in software between windows.
+    // It slots in after whichever regular conversion is running, delaying the rest of that window by one conversion, about 20us.
+    const TEMPERATURE_CHANNEL: u8 = 16;
+    adc.smpr1().modify(|w| {
+        // the sensor needs at least 17.1us of sampling (datasheet section 5.3.19)
+        w.set_smp(
+            TEMPERATURE_CHANNEL as usize - 10,
+            adc::SampleTime::CYCLES239_5,
+        )
+    });
+    adc.jsqr().modify(|w| {
+        w.set_jl(0);
+        w.set_jsq(3, TEMPERATURE_CHANNEL); // a single injected conversion comes from JSQ4
+    });
+    adc.cr2().modify(|w| {
+        w.set_tsvrefe(true);
+        w.set_jextsel(embassy_stm32::pac::adc::vals::Jextsel::JSWSTART);
+        w.set_jexttrig(true);
+    });
+
+    // the hardware's word over the constant's, so a mismatch costs a log line rather than scaling every window wrong
+    let adc_format = AdcFormat {
+        alignment: AdcAlignment::from_align_bit(adc.cr2().read().align()),
+        ..ADC_FORMAT
+    };
+    let alignment_name = |alignment| match alignment {
+        AdcAlignment::Right => "right",
+        AdcAlignment::Left => "left",
+    };
+    if adc_format != ADC_FORMAT {
+        error!(
+            "ADC reads back {} aligned, configured for {}; normalizing from what it reads back",
+            alignment_name(adc_format.alignment),
+            alignment_name(ADC_FORMAT.alignment)
+        );
+    }
 
     let user_button = Input::new(p.PB14, embassy_stm32::gpio::Pull::None);
 
-    let mut phase_accumulator = PhaseAccumulator::new(0.0, 0.1);
+    if I2C_OUTPUT {
+        let _i2c_peripherals = (p.I2C2, p.PB10, p.PB11); // driven through the PAC in start_i2c_slave
+        start_i2c_slave();
+        info!("Serving position as I2C slave at {:#x}", I2C_ADDRESS);
+    }
+
+    let encoder = RefCell::new(QuadratureEncoder::new());
 
-    // 9.4mm spacing across all 8 emission pads on the v1.1 PCB Mitko sent me.
-    let distance_per_phase_cycle = 9.4;
+    let mut goertzel = Goertzel::new(NUM_SAMPLES, DEMOD_BIN);
+    let mut iq_averager = IqAverager::new(IQ_AVERAGE_WINDOWS);
+    let mut second_goertzel = Goertzel::new(NUM_SAMPLES, SECOND_DEMOD_BIN);
+    let mut second_iq_averager = IqAverager::new(IQ_AVERAGE_WINDOWS);
+    // unwrap and filter, one of the steps calipertron_core::pipeline's Pipeline replays windows through on the host
+    let mut position_stage = PositionStage::new(POSITION_FILTER_ALPHA);
+    let mut zero_position = 0;
+
+    if SELF_TEST {
+        if DIFFERENTIAL {
+            // the synthetic signal is the same on both channels, so their difference is zero
+            warn!("Self-test skipped, it needs single-ended mode");
+        } else {
+            let (sum_sine, sum_cosine) =
+                demodulate(&mut goertzel, &SINE_COSINE_TABLE, &SELF_TEST_SIGNAL);
+            let angle = cordic_atan2(sum_sine, sum_cosine);
+            let error = angle.wrapping_sub(SELF_TEST_PHASE).unsigned_abs();
+            if error <= SELF_TEST_TOLERANCE {
+                info!("Self-test passed, phase error {} turn units", error);
+            } else {
+                error!(
+                    "Self-test FAILED: injected phase {}, demodulated {}",
+                    angle_to_radians(SELF_TEST_PHASE),
+                    angle_to_radians(angle)
+                );
+            }
+        }
+    }
 
     let fut_main = async {
+        // Sample and drive continuously, so there's no gap between windows.
+        adc_rb.start();
+        adc.cr2().modify(|w| w.set_adon(true)); // start ADC conversions
+        let _pdm_transfer = start_pdm();
+        let mut adc_dma_errors = DmaErrors::new("ADC", ADC_DMA_CHANNEL, ADC_BUFFER_LEN);
+        let mut pdm_dma_errors = DmaErrors::new("PDM", PDM_DMA_CHANNEL, PDM_SIGNAL.len());
+        mask_dma_error_interrupt(ADC_DMA_CHANNEL);
+        mask_dma_error_interrupt(PDM_DMA_CHANNEL);
+
+        // Read back from the registers now everything's running, for comparing a board in the field against a known-good one.
+        let clocks = common::clocks();
+        common::log_clocks(&clocks);
+        common::log_adc(vrefint_sample, board::SENSE_ADC_CHANNEL);
+        common::log_dma_channel("ADC", ADC_DMA_CHANNEL);
+        common::log_dma_channel("PDM", PDM_DMA_CHANNEL);
+        common::log_excitation(&clocks, PDM_SIGNAL.len());
+        info!(
+            "Startup: {} samples per window over {} channels",
+            NUM_SAMPLES, NUM_CHANNELS
+        );
+
+        let mut stall_detector = StallDetector::new(STALL_WINDOWS);
+
+        let mut conversions = [0u16; NUM_CONVERSIONS];
+        let mut adc_buf = [0u16; NUM_SAMPLES];
+        let resampler =
+            RESAMPLE.map(|interpolation| Resampler::new(ADC_SAMPLE_DELAY, interpolation));
+        let mut resampled = [0u16; NUM_SAMPLES];
+        // Excitation phase at the start of the current window; PDM_FREQUENCY doesn't quite match the ADC, so windows creep through the excitation cycle.
+        let mut window_phase = WindowPhase::new(WINDOW_PHASE_ADVANCE);
+        let mut second_window_phase = WindowPhase::new(SECOND_WINDOW_PHASE_ADVANCE);
+        // second tone's phase minus the first's on the first good window; the tones' electrical lags differ, so that's what agreement looks like
+        let mut frequency_baseline: Option<i32> = None;
+        // uncorrected until the first reading comes in
+        let mut temperature_c = REFERENCE_TEMPERATURE_C;
+        let mut last_temperature = Instant::now();
+        // phase change accumulated since drift_start, in turn units
+        let mut drift_start = Instant::now();
+        let mut drift_last_angle: Option<i32> = None;
+        let mut drift_total: i64 = 0;
+        #[cfg(feature = "sample-dump")]
+        let mut last_dump: Option<Instant> = None;
+        let mut last_spectrum = Instant::now();
+        let mut last_reading_log = Instant::now();
+        let mut out_of_range_warning = WindowWarning::new();
+        let mut low_magnitude_warning = WindowWarning::new();
+        let mut aliased_warning = WindowWarning::new();
+        let mut first_window = true;
+        // The F103's first conversion after the ADC powers up is often off, a known behavior of its ADC; cleared whenever ADON is cycled below, so the window holding it gets dropped.
+        let mut primed = false;
+        // each channel's mean sums with no input, from the first step of a channel calibration
+        let mut channel_zero: Option<[(i32, i32); NUM_CHANNELS]> = None;
+        // the calibration step being captured, windows so far, and each channel's sums and magnitudes over channel_zero summed across them
+        let mut calibration_capture: Option<(
+            CalibrationStep,
+            u32,
+            [(i64, i64); NUM_CHANNELS],
+            [f32; NUM_CHANNELS],
+        )> = None;
+
         loop {
-            // TODO: I'd rather this be local, but Transfer requires the buffer have the same lifetime as the DMA channel for some reason.
-            static mut ADC_BUF: [u16; NUM_SAMPLES] = [0u16; NUM_SAMPLES];
-
-            let adc_buf = unsafe { &mut ADC_BUF[..] };
-            let adc_transfer = start_adc(adc_buf);
-            let mut pdm_transfer = start_pdm();
-            // wait for all of the samples to be taken
-            adc_transfer.await;
-            pdm_transfer.request_stop();
-
-            let mut sum_sine: f32 = 0.0;
-            let mut sum_cosine: f32 = 0.0;
-
-            let adc_buf = unsafe { &ADC_BUF[..] };
-
-            for i in 0..NUM_SAMPLES {
-                let (sine, cosine) = SINE_COSINE_TABLE[i];
-                sum_sine += adc_buf[i] as f32 * sine;
-                sum_cosine += adc_buf[i] as f32 * cosine;
-            }
-            let phase = sum_sine.atan2(sum_cosine);
-
-            phase_accumulator.update(phase);
-            info!(
-                //"Phase: {:06.2} Position: {:06.2}",
-                "Position: {}mm, Phase: {} ",
-                phase_accumulator.unwrapped_phase
-                    * (distance_per_phase_cycle / (2.0 * core::f32::consts::PI)),
-                phase,
+            match with_timeout(ADC_TIMEOUT, adc_rb.read_exact(&mut conversions)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    // Dropped samples also drop the link between window and excitation phase, so position jumps.
+                    warn!("ADC_RB error: {:?}, position may jump", e);
+                    adc_rb.clear();
+                    continue;
+                }
+                Err(_) => {
+                    // A transfer error stops the DMA with the ADC still converting, which looks just like a stalled ADC from here.
+                    if adc_dma_errors.poll() {
+                        if adc_dma_errors.failed {
+                            set_dma_failed();
+                        }
+                        adc_rb.clear();
+                        continue;
+                    }
+                    if adc_dma_errors.failed {
+                        // nothing more is coming; resetting the ADC won't change that
+                        continue;
+                    }
+                    error!(
+                        "ADC didn't deliver a window within {}us, resetting ADC",
+                        ADC_TIMEOUT.as_micros()
+                    );
+                    if CLOCK_REFERENCE == ClockReference::External {
+                        // the trigger comes off the reference, so a loose wire stops the ADC too
+                        warn!("Check the reference clock on PA15");
+                    }
+                    restart_adc().await;
+                    adc_rb.clear();
+                    primed = false;
+                    continue;
+                }
+            }
+            if pdm_dma_errors.poll() && pdm_dma_errors.failed {
+                set_dma_failed();
+            }
+            // Every conversion start sets STRT and only this clears it, so it tells stale data apart from a slider that's still or an input on a rail.
+            let converting = adc.sr().read().strt();
+            adc.sr().modify(|w| w.set_strt(false)); // rc_w0
+            if stall_detector.update(&conversions, converting) {
+                let stalls = stall_detector.stalls;
+                error!(
+                    "ADC delivered {} identical windows with no conversions behind them, restarting ADC and DMA, {} stalls since boot; position may jump",
+                    STALL_WINDOWS + 1,
+                    stalls
+                );
+                update_i2c_registers(|r| r.stalls = stalls);
+                adc_dma_errors.restart();
+                restart_adc().await;
+                adc_rb.clear();
+                primed = false;
+                continue;
+            }
+
+            let window_start_phase = window_phase.advance();
+            let second_window_start_phase = second_window_phase.advance();
+
+            // after the phase bookkeeping, since the window still took its share of the excitation
+            if !primed {
+                primed = true;
+                debug!("Dropping the first window after the ADC powered up");
+                continue;
+            }
+            if !adc_format.normalize(&mut conversions) {
+                error!(
+                    "ADC data doesn't fit {} bits {} aligned, window dropped; check CR2's ALIGN against ADC_FORMAT",
+                    adc_format.bits,
+                    alignment_name(adc_format.alignment)
+                );
+                continue;
+            }
+
+            // Pick up the temperature conversion started on an earlier window, and start the next one when it's due; neither waits on the ADC.
+            if adc.sr().read().jeoc() {
+                adc.sr().modify(|w| w.set_jeoc(false)); // rc_w0
+
+                // without a reference, compensation stays at REFERENCE_TEMPERATURE_C, i.e., off
+                // injected data is aligned by the same bit, and can't be off it (no JOFR offset's set)
+                if let Some(millivolts) = adc_format
+                    .normalize_sample(adc.jdr(0).read().jdata())
+                    .and_then(|sample| adc_to_millivolts(sample, vrefint_sample))
+                {
+                    temperature_c = common::sensor_temperature_c(millivolts);
+                    info!("Temperature: {}C", temperature_c);
+                }
+            }
+            if last_temperature.elapsed() >= TEMPERATURE_INTERVAL {
+                last_temperature = Instant::now();
+                adc.cr2().modify(|w| w.set_jswstart(true));
+            }
+
+            // The flag covers everything converted since the last check, so it can also blame this window for the start of the next one.
+            if adc.sr().read().awd() {
+                adc.sr().modify(|w| w.set_awd(false)); // rc_w0
+
+                // Clipped conversions tell saturation apart from a floating input that's merely wandered near a rail.
+                let saturated = count_saturated(&conversions);
+                update_i2c_registers(|r| {
+                    r.status = if saturated > MAX_SATURATED_SAMPLES {
+                        I2cRegisters::ADC_OUT_OF_RANGE | I2cRegisters::SATURATED
+                    } else {
+                        I2cRegisters::ADC_OUT_OF_RANGE
+                    }
+                });
+                if let Some(held_back) = out_of_range_warning.due() {
+                    warn!(
+                        "ADC input outside {}..={} with {} conversions clipped, window invalid, {} more since the last; check for saturation or a disconnected electrode",
+                        ADC_WATCHDOG_LOW, ADC_WATCHDOG_HIGH, saturated, held_back
+                    );
+                }
+                continue;
+            }
+
+            #[cfg(feature = "adc-lut")]
+            ADC_LUT.apply(&mut conversions);
+            sum_groups::<OVERSAMPLING>(&conversions, &mut adc_buf);
+            median_filter::<MEDIAN_FILTER_WINDOW>(&mut adc_buf);
+
+            // ahead of the magnitude check, since weak windows are often the interesting ones
+            #[cfg(feature = "sample-dump")]
+            if user_button.is_low()
+                && !last_dump.is_some_and(|t| t.elapsed() < SAMPLE_DUMP_INTERVAL)
+            {
+                last_dump = Some(Instant::now());
+                // one message, so the window and table can't be split by other logging
+                info!(
+                    "Sample dump (after median filter, window phase {}): samples {} table {}",
+                    window_start_phase, adc_buf, SINE_COSINE_TABLE
+                );
+            }
+
+            // nothing to log in without a reference
+            if let (true, Some(mv_per_count)) = (
+                LOG_SPECTRUM && last_spectrum.elapsed() >= SPECTRUM_INTERVAL,
+                millivolts_per_count(vrefint_sample),
+            ) {
+                last_spectrum = Instant::now();
+                // slots are OVERSAMPLING conversions summed
+                let mv_per_count = mv_per_count / OVERSAMPLING as f32;
+                let amplitudes_mv = spectrum::<SPECTRUM_BINS>(&adc_buf, SPECTRUM_FIRST_BIN)
+                    .map(|amplitude| amplitude * mv_per_count);
+                let bin_hz =
+                    SAMPLING_FREQUENCY_HZ as f32 / OVERSAMPLING as f32 / NUM_SAMPLES as f32;
+                info!(
+                    "Spectrum from {} Hz in steps of {} Hz, excitation in bin {}: {} mV",
+                    SPECTRUM_FIRST_BIN as f32 * bin_hz,
+                    bin_hz,
+                    WINDOW_CYCLES - SPECTRUM_FIRST_BIN,
+                    amplitudes_mv
+                );
+            }
+
+            if let Some(resampler) = &resampler {
+                resampler.resample(&adc_buf, &mut resampled);
+                adc_buf = resampled;
+            }
+
+            if let Some(step) = CALIBRATION_REQUEST.try_take() {
+                match (DIFFERENTIAL, step, channel_zero) {
+                    (false, _, _) => {
+                        warn!("Channel calibration is only for differential mode, ignoring it")
+                    }
+                    (true, CalibrationStep::Known(_), None) => {
+                        warn!("Capture the channels' offsets, with no input, before their gains")
+                    }
+                    (true, step, _) => {
+                        info!(
+                            "Capturing {} windows for channel calibration; hold the input steady",
+                            CALIBRATION_WINDOWS
+                        );
+                        set_calibrating(true);
+                        calibration_capture =
+                            Some((step, 0, [(0, 0); NUM_CHANNELS], [0.0; NUM_CHANNELS]));
+                    }
+                }
+            }
+            let mut calibration_done = false;
+            if let Some((step, windows, sums, magnitudes)) = &mut calibration_capture {
+                for (channel, s) in
+                    channel_sums::<NUM_CHANNELS>(&SINE_COSINE_TABLE, &adc_buf, GATE_SAMPLES)
+                        .into_iter()
+                        .enumerate()
+                {
+                    sums[channel].0 += s.0 as i64;
+                    sums[channel].1 += s.1 as i64;
+                    // the known input's phase moves with the window's, so it's magnitudes that average rather than the sums
+                    if let (CalibrationStep::Known(_), Some(zero)) = (*step, channel_zero) {
+                        magnitudes[channel] += ChannelCalibration::magnitude_over(zero[channel], s);
+                    }
+                }
+                *windows += 1;
+                calibration_done = *windows == CALIBRATION_WINDOWS;
+                if calibration_done {
+                    let n = CALIBRATION_WINDOWS as i64;
+                    match (*step, channel_zero) {
+                        (CalibrationStep::Zero, _) => {
+                            let zero =
+                                sums.map(|(sine, cosine)| ((sine / n) as i32, (cosine / n) as i32));
+                            info!("Channel offsets: {}", zero);
+                            channel_zero = Some(zero);
+                        }
+                        (CalibrationStep::Known(known), Some(zero)) => {
+                            let mut calibration = CHANNEL_CALIBRATION.lock(Cell::get);
+                            let mut usable = true;
+                            for channel in 0..NUM_CHANNELS {
+                                let measured = magnitudes[channel] / CALIBRATION_WINDOWS as f32;
+                                match ChannelCalibration::new(zero[channel], measured, known) {
+                                    Some(c) => calibration[channel] = c,
+                                    None => usable = false,
+                                }
+                            }
+                            if usable {
+                                info!(
+                                    "Channel gains: {}, {}",
+                                    calibration[0].gain, calibration[1].gain
+                                );
+                                set_channel_calibration(calibration);
+                            } else {
+                                error!("A channel read nothing over its offset, keeping the old calibration; check the known input reaches both");
+                            }
+                        }
+                        // ruled out when the capture started
+                        (CalibrationStep::Known(_), None) => {}
+                    }
+                }
+            }
+            if calibration_done {
+                calibration_capture = None;
+                set_calibrating(false);
+            }
+
+            let (sum_sine, sum_cosine) = demodulate(&mut goertzel, &SINE_COSINE_TABLE, &adc_buf);
+            // back to the scale of a single conversion, so MIN_MAGNITUDE holds whatever the oversampling
+            let (sum_sine, sum_cosine) = (
+                sum_sine / OVERSAMPLING as i32,
+                sum_cosine / OVERSAMPLING as i32,
             );
 
-            // make sure everything is reset before we continue
-            pdm_transfer.await;
+            // pushed every window like the first tone's, so both averagers complete on the same one
+            let second_sums = if DUAL_FREQUENCY {
+                let (sum_sine, sum_cosine) =
+                    demodulate(&mut second_goertzel, &SECOND_SINE_COSINE_TABLE, &adc_buf);
+                second_iq_averager.push(
+                    sum_sine / OVERSAMPLING as i32,
+                    sum_cosine / OVERSAMPLING as i32,
+                )
+            } else {
+                None
+            };
+
+            // at sample scale the sums are bounded by NUM_SAMPLES * 4095, so they fit comfortably in an i32
+            let Some((sum_sine, sum_cosine)) = iq_averager.push(sum_sine, sum_cosine) else {
+                continue;
+            };
+            // Averaged windows span a few steps of window_phase, which only adds a constant offset.
+            let (angle, magnitude) = phase_magnitude((sum_sine, sum_cosine), window_start_phase);
+            let second_angle =
+                second_sums.map(|sums| phase_magnitude(sums, second_window_start_phase).0);
+
+            if magnitude < MIN_MAGNITUDE {
+                update_i2c_registers(|r| {
+                    r.magnitude = magnitude;
+                    r.status = I2cRegisters::LOW_MAGNITUDE;
+                });
+                if let Some(held_back) = low_magnitude_warning.due() {
+                    warn!(
+                        "Magnitude: {} below {}, phase invalid, {} more since the last; check electrode coupling",
+                        magnitude, MIN_MAGNITUDE, held_back
+                    );
+                }
+                continue;
+            }
+
+            if first_window {
+                first_window = false;
+                info!(
+                    "First window phase: {} rad, {}",
+                    angle_to_radians(angle),
+                    if ADC_TRIGGERED {
+                        "ADC triggered by the PDM timer"
+                    } else {
+                        "ADC free-running"
+                    }
+                );
+            }
+
+            if CLOCK_DRIFT_CHECK {
+                if let Some(last) = drift_last_angle {
+                    drift_total += angle.wrapping_sub(last) as i64;
+                }
+                drift_last_angle = Some(angle);
+
+                let elapsed = drift_start.elapsed();
+                if elapsed >= CLOCK_DRIFT_INTERVAL {
+                    let degrees = drift_total as f32 * 360.0 / 4_294_967_296.0;
+                    let rate = degrees / (elapsed.as_micros() as f32 / 1e6);
+                    if rate.abs() > MAX_CLOCK_DRIFT_DEG_PER_S {
+                        warn!(
+                            "Phase creeping {} deg/s with the slider still; check the rcc config and PDM_FREQUENCY",
+                            rate
+                        );
+                    } else {
+                        info!("Phase creep: {} deg/s", rate);
+                    }
+                    drift_start = Instant::now();
+                    drift_total = 0;
+                }
+            }
+
+            // Both tones see the same slider, so after the baseline they should agree; interference near one pulls only that one.
+            let second_phase = second_angle.map(|second_angle| {
+                let difference = second_angle.wrapping_sub(angle);
+                second_angle.wrapping_sub(*frequency_baseline.get_or_insert(difference))
+            });
+            let frequency_disagreement = second_phase.is_some_and(|second| {
+                second.wrapping_sub(angle).unsigned_abs() > MAX_FREQUENCY_DISAGREEMENT
+            });
+            let log_reading = last_reading_log.elapsed() >= READING_LOG_INTERVAL;
+            if log_reading {
+                last_reading_log = Instant::now();
+            }
+            if let (true, Some(second_phase)) = (log_reading, second_phase) {
+                info!(
+                    "Tone phases: {} rad, {} rad{}",
+                    angle_to_radians(angle),
+                    angle_to_radians(second_phase),
+                    if frequency_disagreement {
+                        ", disagreeing; check for interference near one of the excitation frequencies"
+                    } else {
+                        ""
+                    }
+                );
+            }
+
+            // filter the untared position so zeroing takes effect immediately rather than settling
+            let position = position_stage.update(angle).1 - zero_position as f32;
+            let position =
+                temperature_compensate(position, temperature_c, SCALE_EXPANSION_PPM_PER_C);
+            if position_stage.tracker.aliased {
+                if let Some(held_back) = aliased_warning.due() {
+                    warn!(
+                        "Phase step too large to unwrap reliably, position may be off by a pitch, {} more since the last",
+                        held_back
+                    );
+                }
+            }
+            publish(Reading {
+                position,
+                raw_position: position_stage.tracker.position(),
+                magnitude,
+                phase: angle,
+                second_phase,
+                aliased: position_stage.tracker.aliased,
+                frequency_disagreement,
+                near_limit: position_stage.tracker.near_limit,
+            });
+
+            if QUADRATURE_OUTPUT && log_reading {
+                let encoder = encoder.borrow();
+                info!(
+                    "Encoder edges: {}, pending: {}",
+                    encoder.edges,
+                    encoder.pending()
+                );
+            }
+
+            if log_reading {
+                info!(
+                    //"Phase: {:06.2} Position: {:06.2}",
+                    "Position: {}mm, Phase: {}, Magnitude: {}",
+                    counts_to_um(position.round() as i64, DEFAULT_PITCH_UM) as f32 / 1000.0,
+                    angle_to_radians(angle),
+                    magnitude,
+                );
+            }
 
             ///////////////////////
             // handle button press
 
             if user_button.is_low() {
                 info!("Button pressed, zeroing");
-                phase_accumulator.unwrapped_phase = 0.;
+                // Near the limit, take the whole pitches off the accumulator too, which clears it; the filter restarts rather than slewing across them.
+                // Only then, since the encoder follows the untared position and sees it as a move.
+                if position_stage.tracker.near_limit {
+                    warn!("Position near the accumulator's limit, recentering");
+                    position_stage.tracker.recenter();
+                    position_stage.filter.reset();
+                }
+                zero_position = position_stage.tracker.position();
             }
         }
     };
 
-    fut_main.await
+    let fut_i2c = async {
+        loop {
+            let reading = next_position(Consumer::I2c).await;
+            update_i2c_registers(|r| {
+                *r = I2cRegisters {
+                    position: reading.position.round() as i64,
+                    magnitude: reading.magnitude,
+                    status: reading.status(),
+                    phase: reading.phase,
+                    second_phase: reading.second_phase.unwrap_or(0),
+                    // kept up to date by the demodulation loop and I2C writes rather than per reading
+                    ..*r
+                }
+            });
+        }
+    };
+
+    let fut_encoder_target = async {
+        if !QUADRATURE_OUTPUT {
+            return;
+        }
+        loop {
+            let reading = next_position(Consumer::Encoder).await;
+            // Encoder follows the untared position; the controller on the other end does its own zeroing.
+            encoder.borrow_mut().set_target(
+                (reading.raw_position * ENCODER_COUNTS_PER_PITCH).div_euclid(COUNTS_PER_PITCH),
+            );
+        }
+    };
+
+    // USART1_TX is wired to DMA1 channel 4 (reference manual table 78)
+    let mut uart = UART_OUTPUT.then(|| {
+        let mut config = embassy_stm32::usart::Config::default();
+        config.baudrate = UART_BAUD;
+        unwrap!(embassy_stm32::usart::UartTx::new(
+            p.USART1, p.PA9, p.DMA1_CH4, config
+        ))
+    });
+    if UART_OUTPUT {
+        info!("Sending position on USART1 at {} baud", UART_BAUD);
+    }
+
+    let fut_uart = async {
+        let Some(uart) = uart.as_mut() else {
+            return;
+        };
+        loop {
+            let reading = next_position(Consumer::Uart).await;
+            let mut line: heapless::String<UART_LINE_LEN> = heapless::String::new();
+            // can't overflow UART_LINE_LEN
+            let _ = core::write!(
+                line,
+                "{},{:02x}\r\n",
+                counts_to_um(reading.position.round() as i64, DEFAULT_PITCH_UM),
+                reading.status() | sticky_status()
+            );
+            if let Err(e) = uart.write(line.as_bytes()).await {
+                warn!("UART write failed: {:?}", e);
+            }
+            // readings that come in meanwhile replace each other in the slot, so the next line is the latest
+            Timer::after(UART_INTERVAL).await;
+        }
+    };
+
+    let mut pin_a = Output::new(p.PB6, Level::Low, Speed::Low);
+    let mut pin_b = Output::new(p.PB7, Level::Low, Speed::Low);
+
+    let fut_encoder = async {
+        if !QUADRATURE_OUTPUT {
+            return;
+        }
+
+        let mut ticker = Ticker::every(ENCODER_EDGE_INTERVAL);
+        loop {
+            ticker.next().await;
+
+            if let Some((a, b)) = encoder.borrow_mut().step() {
+                pin_a.set_level(a.into());
+                pin_b.set_level(b.into());
+            }
+        }
+    };
+
+    embassy_futures::join::join5(fut_main, fut_i2c, fut_encoder_target, fut_encoder, fut_uart)
+        .await;
+}
+
+/// Returns the window's `(Σ x sin, Σ x cos)` at sample scale, from either demodulator.
+/// `goertzel` and `table` have to be for the same tone.
+fn demodulate(
+    goertzel: &mut Goertzel,
+    table: &[(i16, i16); NUM_SAMPLES],
+    samples: &[u16; NUM_SAMPLES],
+) -> (i32, i32) {
+    if USE_GOERTZEL {
+        goertzel.reset();
+        for x in samples.iter() {
+            goertzel.push(*x as i16);
+        }
+        let (sum_sine, sum_cosine) = goertzel.iq();
+        (sum_sine as i32, sum_cosine as i32)
+    } else {
+        // the table is 0 up to GATE_SAMPLES anyway; see build.rs
+        let sums = channel_sums::<NUM_CHANNELS>(table, samples, GATE_SAMPLES);
+        if DIFFERENTIAL {
+            // each normalized first, so that a common-mode input cancels
+            let calibration = CHANNEL_CALIBRATION.lock(Cell::get);
+            let (a, b) = (calibration[0].apply(sums[0]), calibration[1].apply(sums[1]));
+            (a.0 - b.0, a.1 - b.1)
+        } else {
+            sums[0]
+        }
+    }
+}
+
+/// Power cycles the ADC; conversions only start on the ADON write after the tSTAB wait (reference manual section 11.3.1).
+async fn restart_adc() {
+    let adc = embassy_stm32::pac::ADC1;
+    adc.cr2().modify(|w| w.set_adon(false));
+    adc.cr2().modify(|w| w.set_adon(true));
+    Timer::after_micros(1).await;
+    adc.cr2().modify(|w| w.set_adon(true));
+}
+
+/// Scales position to what it would read at REFERENCE_TEMPERATURE_C, for a scale that expands by `ppm_per_c`.
+fn temperature_compensate(position: f32, temperature_c: f32, ppm_per_c: f32) -> f32 {
+    position * (1.0 + ppm_per_c * 1e-6 * (temperature_c - REFERENCE_TEMPERATURE_C))
+}
+
+/// Sets up I2C2 as a slave at I2C_ADDRESS, served by the I2C2_EV and I2C2_ER interrupts (reference manual section 26.3.3).
+fn start_i2c_slave() {
+    use embassy_stm32::pac;
+    use embassy_stm32::pac::gpio::vals::{CnfOut, Mode};
+
+    pac::RCC.apb1enr().modify(|w| w.set_i2c2en(true));
+
+    // PB10 and PB11 are pins 2 and 3 of CRH, as alternate function open drain
+    pac::GPIOB.cr(1).modify(|w| {
+        for pin in [2, 3] {
+            w.set_mode(pin, Mode::OUTPUT2MHZ);
+            w.set_cnf_out(pin, CnfOut::ALTOPENDRAIN);
+        }
+    });
+
+    let i2c = pac::I2C2;
+    i2c.cr2().modify(|w| {
+        w.set_freq(36); // APB1 in MHz
+        w.set_itevten(true);
+        w.set_itbufen(true);
+        w.set_iterren(true);
+    });
+    i2c.oar1().write(|w| w.set_add((I2C_ADDRESS as u16) << 1)); // 7-bit address in bits 7:1
+    i2c.cr1().modify(|w| w.set_pe(true));
+    // ACK is cleared by hardware while PE is off, so it has to come after
+    i2c.cr1().modify(|w| w.set_ack(true));
+
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::I2C2_EV);
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::I2C2_ER);
+    }
+}
+
+#[interrupt]
+fn I2C2_EV() {
+    let i2c = embassy_stm32::pac::I2C2;
+    let sr1 = i2c.sr1().read();
+
+    I2C_SLAVE.lock(|slave| {
+        let mut slave = slave.borrow_mut();
+
+        if sr1.addr() {
+            // reading SR2 after SR1 clears ADDR
+            if i2c.sr2().read().tra() {
+                slave.latched = I2C_REGISTERS.lock(Cell::get).to_bytes();
+            } else {
+                slave.expect_pointer = true;
+            }
+        }
+
+        if sr1.rxne() {
+            let byte = i2c.dr().read().dr();
+            if slave.expect_pointer {
+                slave.pointer = byte as usize;
+                slave.expect_pointer = false;
+            } else {
+                // anything outside the writable registers is ignored
+                if let Some(offset) = slave
+                    .pointer
+                    .checked_sub(I2cRegisters::CHANNEL_CALIBRATION)
+                    .filter(|&offset| offset < I2C_WRITABLE_LEN)
+                {
+                    slave.written[offset] = byte;
+                    slave.written_mask |= 1 << offset;
+                }
+                slave.pointer += 1;
+            }
+        }
+
+        if sr1.txe() {
+            // reading past the end gives 0xFF, like an undriven bus
+            let byte = slave.latched.get(slave.pointer).copied().unwrap_or(0xFF);
+            slave.pointer += 1;
+            i2c.dr().write(|w| w.set_dr(byte));
+        }
+
+        if sr1.stopf() {
+            // cleared by reading SR1, done above, then writing CR1
+            i2c.cr1().modify(|_| {});
+            if slave.written_mask != 0 {
+                apply_i2c_write(&slave.written, slave.written_mask);
+                slave.written_mask = 0;
+            }
+        }
+    });
+}
+
+/// Takes a write to the writable registers, `written` from I2cRegisters::CHANNEL_CALIBRATION on with `mask` saying which bytes the master sent.
+fn apply_i2c_write(written: &[u8; I2C_WRITABLE_LEN], mask: u32) {
+    const CALIBRATION_LEN: usize = 2 * I2cRegisters::CHANNEL_CALIBRATION_SIZE;
+    if mask & ((1 << CALIBRATION_LEN) - 1) != 0 {
+        // bytes the master didn't send keep their current values
+        let mut bytes = I2C_REGISTERS.lock(Cell::get).to_bytes();
+        for (offset, &byte) in written[..CALIBRATION_LEN].iter().enumerate() {
+            if mask & 1 << offset != 0 {
+                bytes[I2cRegisters::CHANNEL_CALIBRATION + offset] = byte;
+            }
+        }
+        let word = |at: usize| [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        let calibration = [0, 1].map(|channel| {
+            let at = I2cRegisters::CHANNEL_CALIBRATION
+                + channel * I2cRegisters::CHANNEL_CALIBRATION_SIZE;
+            ChannelCalibration {
+                gain: f32::from_le_bytes(word(at)),
+                offset_sine: i32::from_le_bytes(word(at + 4)),
+                offset_cosine: i32::from_le_bytes(word(at + 8)),
+            }
+        });
+        if calibration
+            .iter()
+            .all(|c| c.gain.is_finite() && c.gain > 0.0)
+        {
+            set_channel_calibration(calibration);
+        } else {
+            warn!("Ignoring a channel calibration write with a gain that isn't positive");
+        }
+    }
+
+    let calibrate = I2cRegisters::CALIBRATE - I2cRegisters::CHANNEL_CALIBRATION;
+    if mask >> calibrate & 0xF == 0xF {
+        let known = f32::from_le_bytes(written[calibrate..calibrate + 4].try_into().unwrap());
+        if known == 0.0 {
+            CALIBRATION_REQUEST.signal(CalibrationStep::Zero);
+        } else if known.is_finite() && known > 0.0 {
+            CALIBRATION_REQUEST.signal(CalibrationStep::Known(known));
+        } else {
+            warn!("Ignoring a calibration with a known magnitude of {}", known);
+        }
+    }
+}
+
+#[interrupt]
+fn I2C2_ER() {
+    // The master NACKs the last byte it reads, which is how every read ends; bus errors and overruns just drop the transfer.
+    embassy_stm32::pac::I2C2.sr1().modify(|w| {
+        w.set_af(false);
+        w.set_berr(false);
+        w.set_ovr(false);
+    });
 }
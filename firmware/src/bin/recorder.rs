@@ -12,10 +12,15 @@ use embassy_stm32::{adc, bind_interrupts, peripherals, usb, Config};
 use embassy_time::Timer;
 use embassy_usb::driver::{Endpoint, EndpointIn, EndpointOut};
 use embassy_usb::Builder;
+use static_cell::ConstStaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
 include!(concat!(env!("OUT_DIR"), "/constants.rs"));
 
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+
 bind_interrupts!(struct Irqs {
     USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
 });
@@ -24,6 +29,9 @@ const MAX_PACKET_SIZE: u8 = 64;
 const SAMPLES_PER_PACKET: usize = (MAX_PACKET_SIZE as usize) / 2; // 2 bytes per sample
 const NUM_SAMPLES: usize = SAMPLES_PER_PACKET * 128;
 
+// 8 KB, so it lives here rather than in the command future; taken once in main, so that's the only reference to it.
+static ADC_BUF: ConstStaticCell<[u16; NUM_SAMPLES]> = ConstStaticCell::new([0; NUM_SAMPLES]);
+
 pub const USB_CLASS_CUSTOM: u8 = 0xFF;
 const USB_SUBCLASS_CUSTOM: u8 = 0x00;
 const USB_PROTOCOL_CUSTOM: u8 = 0x00;
@@ -48,6 +56,7 @@ async fn main(_spawner: Spawner) {
         config.rcc.apb2_pre = APBPrescaler::DIV1;
     }
     let mut p = embassy_stm32::init(config);
+    let mut board = take_board!(p);
 
     info!("Hello World!");
 
@@ -61,16 +70,10 @@ async fn main(_spawner: Spawner) {
     ////////////////////////
     // Signal emission setup
 
-    let _pins = [
-        Output::new(p.PA0, Level::Low, Speed::Low),
-        Output::new(p.PA1, Level::Low, Speed::Low),
-        Output::new(p.PA2, Level::Low, Speed::Low),
-        Output::new(p.PA3, Level::Low, Speed::Low),
-        Output::new(p.PA4, Level::Low, Speed::Low),
-        Output::new(p.PA5, Level::Low, Speed::Low),
-        Output::new(p.PA6, Level::Low, Speed::Low),
-        Output::new(p.PA7, Level::Low, Speed::Low),
-    ];
+    let _pins = board
+        .electrodes
+        .map(|pin| Output::new(pin, Level::Low, Speed::Low));
+    let _test_point = board::test_point(board.test_point, TEST_POINT);
 
     let tim = embassy_stm32::timer::low_level::Timer::new(p.TIM2);
     let timer_registers = tim.regs_gp16();
@@ -90,7 +93,7 @@ async fn main(_spawner: Spawner) {
         let mut opts = TransferOptions::default();
         opts.circular = true;
 
-        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&p.DMA1_CH2);
+        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&board.pdm_dma);
         let request = embassy_stm32::timer::UpDma::request(&dma_ch);
 
         tim.reset();
@@ -99,7 +102,7 @@ async fn main(_spawner: Spawner) {
             dma_ch,
             request,
             &PDM_SIGNAL,
-            embassy_stm32::pac::GPIOA.bsrr().as_ptr() as *mut u32,
+            board::ELECTRODE_PORT.bsrr().as_ptr() as *mut u32,
             opts,
         );
 
@@ -149,23 +152,7 @@ async fn main(_spawner: Spawner) {
     ////////////////////////
     // ADC + DMA setup
 
-    let start_adc = |sample_buf| unsafe {
-        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&p.DMA1_CH1);
-        let request = embassy_stm32::adc::RxDma::request(&dma_ch);
-        let opts = TransferOptions::default();
-
-        let t = Transfer::new_read(
-            dma_ch,
-            request,
-            embassy_stm32::pac::ADC1.dr().as_ptr() as *mut u16,
-            sample_buf,
-            opts,
-        );
-
-        // Start ADC conversions
-        embassy_stm32::pac::ADC1.cr2().modify(|w| w.set_adon(true));
-        t
-    };
+    let adc_buf = ADC_BUF.take();
 
     let mut adc = Adc::new(p.ADC1);
 
@@ -175,11 +162,11 @@ async fn main(_spawner: Spawner) {
         // give vref some time to warm up
         Timer::after_millis(100).await;
 
-        adc.read(&mut vrefint).await as u32
+        adc.read(&mut vrefint).await
     };
     info!("VREFINT: {}", vrefint_sample);
 
-    //let convert_to_millivolts = |sample| (sample as u32 * adc::VREF_INT / vrefint_sample) as u16;
+    //let convert_to_millivolts = |sample| calipertron_core::dsp::adc_to_millivolts(sample, vrefint_sample).unwrap_or(INVALID_MILLIVOLTS);
 
     // Configure ADC for continuous conversion with DMA
     let adc = embassy_stm32::pac::ADC1;
@@ -198,13 +185,16 @@ async fn main(_spawner: Spawner) {
     adc.sqr1().modify(|w| w.set_l(0)); // one conversion.
 
     // TODO: this may not be necessary
-    let mut pb1 = Flex::new(p.PB1);
-    pb1.set_as_analog();
-
-    const PIN_CHANNEL: u8 = 9; // PB1 is on channel 9 for STM32F103
-    adc.sqr3().modify(|w| w.set_sq(0, PIN_CHANNEL));
-    adc.smpr2()
-        .modify(|w| w.set_smp(PIN_CHANNEL as usize, adc::SampleTime::CYCLES239_5));
+    let mut sense_pin = Flex::new(board.sense_pin);
+    sense_pin.set_as_analog();
+
+    adc.sqr3().modify(|w| w.set_sq(0, board::SENSE_ADC_CHANNEL));
+    adc.smpr2().modify(|w| {
+        w.set_smp(
+            board::SENSE_ADC_CHANNEL as usize,
+            adc::SampleTime::CYCLES239_5,
+        )
+    });
 
     //////////////////////////
     // handle commands from host
@@ -234,7 +224,7 @@ async fn main(_spawner: Spawner) {
 
                                 adc.smpr2().modify(|w| {
                                     w.set_smp(
-                                        PIN_CHANNEL as usize,
+                                        board::SENSE_ADC_CHANNEL as usize,
                                         match adc_sampling_period {
                                             AdcSamplingPeriod::CYCLES1_5 => {
                                                 adc::SampleTime::CYCLES1_5
@@ -267,21 +257,14 @@ async fn main(_spawner: Spawner) {
 
                             // would be nice to extract this, but async closures aren't stable yet and no way in hell I'm going to write out the types.
                             Record => {
-                                // TODO: I'd rather this be local, but Transfer requires the buffer have the same lifetime as the DMA channel for some reason.
-                                static mut ADC_BUF: [u16; NUM_SAMPLES] = [0u16; NUM_SAMPLES];
-
-                                let buf = unsafe { &mut ADC_BUF[..] };
-
                                 // start ADC
-                                let adc_transfer = start_adc(buf);
+                                let adc_transfer = start_adc(&mut board.adc_dma, adc_buf);
 
                                 // start PDM
                                 let mut pdm_transfer = start_pdm();
 
-                                // wait for all of the samples to be taken
+                                // wait for all of the samples to be taken, which hands the buffer back
                                 adc_transfer.await;
-                                // TODO: why am I getting errors about multiple mutable borrows --- shouldn't awaiting the adc_transfer above end the borrow?
-                                let buf = unsafe { &mut ADC_BUF[..] };
 
                                 pdm_transfer.request_stop();
 
@@ -290,7 +273,7 @@ async fn main(_spawner: Spawner) {
                                 // for x in buf.iter_mut() {
                                 //     *x = convert_to_millivolts(*x);
                                 // }
-                                for c in buf.chunks(SAMPLES_PER_PACKET) {
+                                for c in adc_buf.chunks(SAMPLES_PER_PACKET) {
                                     let r = write_ep.write(bytemuck::cast_slice(c)).await;
                                     if r.is_err() {
                                         error!("USB Error: {:?}", r);
@@ -306,7 +289,11 @@ async fn main(_spawner: Spawner) {
                         error!("Failed to deserialize command");
                     }
                 }
-                Err(e) => error!("Failed to read USB packet: {:?}", e),
+                Err(e) => {
+                    error!("Failed to read USB packet: {:?}", e);
+                    // Reads fail straight away while disconnected, so wait for the host rather than spin.
+                    read_ep.wait_enabled().await;
+                }
             }
         }
     };
@@ -321,3 +308,25 @@ async fn main(_spawner: Spawner) {
         [fut_usb, fut_commands];
     embassy_futures::join::join_array(futures).await;
 }
+
+/// Starts ADC conversions, with DMA copying one into `buf` each until it's full.
+/// The transfer keeps `dma` and `buf` borrowed until it's awaited or dropped (which stops the DMA), so nothing else can touch the buffer while DMA is writing it.
+/// A closure can't say that: its argument gets one lifetime for every call, so each Record's borrow would have to outlive the next one's.
+fn start_adc<'a>(dma: &'a mut board::AdcDma, buf: &'a mut [u16]) -> Transfer<'a> {
+    let request = embassy_stm32::adc::RxDma::request(&*dma);
+
+    // ADC1's data register is where the conversions come out, and the borrows above cover the rest
+    let t = unsafe {
+        Transfer::new_read(
+            dma,
+            request,
+            embassy_stm32::pac::ADC1.dr().as_ptr() as *mut u16,
+            buf,
+            TransferOptions::default(),
+        )
+    };
+
+    // Start ADC conversions
+    embassy_stm32::pac::ADC1.cr2().modify(|w| w.set_adon(true));
+    t
+}
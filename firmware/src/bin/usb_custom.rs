@@ -1,29 +1,430 @@
 #![no_std]
 #![no_main]
+use calipertron_core::dsp::*;
+use calipertron_core::pipeline::{phase_magnitude, window_phase_advance};
+use calipertron_core::{
+    bsrr_conflicts, counts_to_ten_thousandths_inch, counts_to_um, max_step_counts, pack_12,
+    DeadZone, Debouncer, DirectionDetector, DriveRamp, GainControl, MotionDetector, PeakHold,
+    PhaseCorrection, PositionTracker, SettlingDetector, VelocityEstimator, COUNTS_PER_PITCH,
+    DEFAULT_PITCH_UM, POSITION_FRACTION_BITS,
+};
 use schema::*;
 
+use core::cell::{Cell, RefCell};
+
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::adc::Adc;
-use embassy_stm32::gpio::{Flex, Level, Output, Speed};
+use embassy_stm32::gpio::{Flex, Input, Level, Output, Pull, Speed};
 use embassy_stm32::time::Hertz;
 use embassy_stm32::{adc, bind_interrupts, interrupt, peripherals, usb, Config};
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+use embassy_usb::control::{InResponse, Request, RequestType};
 use embassy_usb::driver::{Endpoint, EndpointIn, EndpointOut};
-use embassy_usb::Builder;
+use embassy_usb::{Builder, Handler};
+use num_traits::Float;
 
 use {defmt_rtt as _, panic_probe as _};
 
+include!(concat!(env!("OUT_DIR"), "/constants.rs"));
+
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../common.rs"]
+mod common;
+
 bind_interrupts!(struct Irqs {
     USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
 });
 
 const MAX_PACKET_SIZE: u8 = 64;
-const SAMPLES_PER_PACKET: usize = (MAX_PACKET_SIZE as usize) / 2; // 2 bytes per sample
+// See SamplePacketHeader for the packet layout.
+const SAMPLES_PER_PACKET: usize =
+    (MAX_PACKET_SIZE as usize - SamplePacketHeader::SIZE - SAMPLE_PACKET_CRC_SIZE) / 2; // 2 bytes per sample
+const SAMPLE_PACKET_SIZE: usize =
+    SamplePacketHeader::SIZE + 2 * SAMPLES_PER_PACKET + SAMPLE_PACKET_CRC_SIZE;
+// Packets queued for the host; once full, new packets are dropped (see Status::dropped_packets) rather than stalling demodulation.
+// This is what pipelines the stream: fut_demodulate builds the next packet while fut_stream_adc has one in flight, and the driver's write returns once a packet is in USB packet memory, so it only ever waits on the one before.
+// At 29 samples per packet, streaming takes 1.6k packets/s at CYCLES239_5 up to 29.6k at CYCLES1_5, against a full-speed bulk ceiling of 19 packets per 1ms frame.
+// To find the real limit on hardware, step the sampling period down while streaming and watch for dropped_packets or adc_overruns climbing.
+const SAMPLE_QUEUE_DEPTH: usize = 4;
+const IQ_PACKET_SIZE: usize =
+    SamplePacketHeader::SIZE + 8 * IQ_PAIRS_PER_PACKET + SAMPLE_PACKET_CRC_SIZE;
+// both kinds go through the same queue, sized for the bigger one
+const _: () = assert!(IQ_PACKET_SIZE <= SAMPLE_PACKET_SIZE);
+// Most samples a packet takes in any SampleFormat; smaller formats accumulate across ring buffer halves.
+const MAX_STREAM_SAMPLES: usize = SampleFormat {
+    width: SampleWidth::Packed12,
+    endianness: Endianness::Little,
+}
+.samples_per_packet();
+const _: () = assert!(SampleFormat::DEFAULT.samples_per_packet() == SAMPLES_PER_PACKET);
+const POSITION_PACKET_SIZE: usize =
+    SamplePacketHeader::SIZE + PositionSample::SIZE + SAMPLE_PACKET_CRC_SIZE;
+const _: () = assert!(POSITION_PACKET_SIZE <= SAMPLE_PACKET_SIZE);
+const POSITION_RUN_PACKET_SIZE: usize =
+    SamplePacketHeader::SIZE + PositionRun::SIZE + SAMPLE_PACKET_CRC_SIZE;
+const _: () = assert!(POSITION_RUN_PACKET_SIZE <= SAMPLE_PACKET_SIZE);
+// How soon fut_stream_positions notices a switch into StreamMode::Positions or PositionRuns; once streaming, it checks every tick.
+const POSITION_STREAM_POLL_INTERVAL: Duration = Duration::from_millis(50);
 pub const USB_CLASS_CUSTOM: u8 = 0xFF;
 const USB_SUBCLASS_CUSTOM: u8 = 0x00;
 const USB_PROTOCOL_CUSTOM: u8 = 0x00;
 
+// Range of PDM tick rates the host is allowed to request.
+// Below this the drive signal drops into line-noise territory, above it the DMA to BSRR can't keep up.
+const MIN_PDM_FREQUENCY_HZ: u32 = 1_000;
+const MAX_PDM_FREQUENCY_HZ: u32 = 500_000;
+
+// Samples per demodulation window.
+const NUM_SAMPLES: usize = 128;
+
+// Power-on configuration: excitation until the host sends SetFrequency, no tare or I/Q offset, unfiltered position, and the v1.1 PCB's pitch.
+const DEFAULT_CONFIG: DeviceConfig = DeviceConfig {
+    pdm_frequency_hz: 100_000,
+    adc_sampling_period: AdcSamplingPeriod::CYCLES239_5,
+    tare: 0,
+    filter_alpha: 1.0,
+    iq_offset: IqOffset {
+        sum_sine: 0,
+        sum_cosine: 0,
+    },
+    pitch_um: DEFAULT_PITCH_UM,
+    stream_mode: StreamMode::Samples,
+    sample_format: SampleFormat::DEFAULT,
+    excitation_mode: ExcitationMode::Sine,
+    idle_timeout_ms: 0,
+    idle_poll_interval_ms: 250,
+    position_rate_hz: 1000,
+    display_deadband_um: 0,
+    unit: Unit::Millimeters,
+};
+
+const _: () = assert!(VREFINT_MV == adc::VREF_INT);
+
+// Internal temperature sensor is read this often, as an injected conversion between packets.
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keeps velocity within a few percent of a steady ramp despite whole-count quantization.
+const VELOCITY_FILTER_ALPHA: f32 = 0.1;
+// About as fast as a caliper gets slid by hand; steps implying more are noise, and held rather than tracked.
+// At the default sampling period that's about 590 counts per window, a seventh of a pitch.
+const MAX_SLEW_UM_PER_S: f32 = 500_000.0;
+// Slowest speed reported as a direction in Status, well above what phase noise makes of a still slider after velocity smoothing.
+const DIRECTION_DEADBAND_UM_PER_S: f32 = 2000.0;
+// How long the position has to stay inside the display dead zone, once it's been moving, before position_um holds again.
+// Long enough that slow hand motion isn't mistaken for still, short enough that the display settles soon after letting go.
+const DISPLAY_STILL_TIME: f32 = 0.3;
+// A dead zone wider than this would hide real moves a user expects to see.
+const MAX_DISPLAY_DEADBAND_UM: u32 = 100;
+
+// Windows averaged by CalibrateIqOffset, about 3 seconds at the default excitation.
+const CALIBRATION_WINDOWS: u32 = 1024;
+// Stray coupling and ADC bias amount to a few counts of amplitude at most, which correlates to A * N/2 over a window.
+// An offset much bigger than that is a real signal.
+const MAX_IQ_OFFSET_MAGNITUDE: f32 = 8.0 * NUM_SAMPLES as f32 / 2.0;
+
+// Samples gathered by MeasureNoise, about 90 ms at the default sampling period, after discarding enough for the front end to settle once the drive goes off.
+const NOISE_SAMPLES: u32 = 4096;
+const NOISE_SETTLE_SAMPLES: u32 = 256;
+
+// 1/256 turn, a few times the error due to the synthetic window not spanning a whole excitation cycle.
+const SELF_TEST_TOLERANCE: u32 = (QUARTER_TURN >> 6) as u32;
+
+// Well over a packet's worth of conversions at the longest sample time, so only a stopped DMA trips it.
+const ADC_TIMEOUT: Duration = Duration::from_millis(10);
+// DMA1 channels, numbered from 0 as in the PAC.
+use board::{ADC_DMA_CHANNEL, PDM_DMA_CHANNEL};
+// transfer errors on them, polled rather than left to Embassy's interrupt
+use common::{mask_dma_error_interrupt, DmaErrors};
+
+// Lives outside main's futures so that nothing about a USB disconnect can reset it.
+static DEVICE_CONFIG: Mutex<CriticalSectionRawMutex, Cell<DeviceConfig>> =
+    Mutex::new(Cell::new(DEFAULT_CONFIG));
+
+fn device_config() -> DeviceConfig {
+    DEVICE_CONFIG.lock(Cell::get)
+}
+
+// Reported in DeviceInfo::features.
+const FEATURES: u32 = if cfg!(feature = "adc-lut") {
+    DeviceInfo::FEATURE_ADC_LUT
+} else {
+    0
+} | if cfg!(feature = "quadrature") {
+    DeviceInfo::FEATURE_QUADRATURE
+} else {
+    0
+} | if cfg!(feature = "dual-frequency") {
+    DeviceInfo::FEATURE_DUAL_FREQUENCY
+} else {
+    0
+} | if cfg!(feature = "demod-dump") {
+    DeviceInfo::FEATURE_DEMOD_DUMP
+} else {
+    0
+};
+const _: () = assert!(DeviceInfo::MAX_SIZE <= MAX_PACKET_SIZE as usize);
+// after the Response variant, in respond's buffer
+const _: () = assert!(1 + Status::MAX_SIZE <= MAX_PACKET_SIZE as usize);
+
+/// Answers the vendor control request for `DeviceInfo`; runs inside fut_usb, so it never waits on the command loop.
+struct DeviceInfoHandler;
+
+impl Handler for DeviceInfoHandler {
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Vendor || req.request != DeviceInfo::REQUEST {
+            return None;
+        }
+        let config = device_config();
+        let info = DeviceInfo {
+            version: FIRMWARE_VERSION,
+            git_hash: GIT_HASH,
+            git_dirty: GIT_DIRTY,
+            sampling_frequency_hz: config.adc_sampling_period.to_Hz() as f32,
+            pitch_um: config.pitch_um,
+            features: FEATURES,
+        };
+        // a host asking for fewer bytes gets the response truncated to its wLength
+        match info.serialize(buf) {
+            Ok(bs) => Some(InResponse::Accepted(bs)),
+            Err(_) => Some(InResponse::Rejected),
+        }
+    }
+}
+
+fn update_device_config(f: impl FnOnce(&mut DeviceConfig)) {
+    DEVICE_CONFIG.lock(|c| {
+        let mut config = c.get();
+        f(&mut config);
+        c.set(config);
+    })
+}
+
+// Automatic gain keeps window magnitude between these by stepping through PDM_SIGNALS, i.e., amplitudes of about 64 to 1024 ADC counts.
+// The upper end leaves headroom before clipping; the band spans 16x against 2x per level, so a step never lands outside it.
+const GAIN_LOW_MAGNITUDE: f32 = 64.0 * NUM_SAMPLES as f32 / 2.0;
+const GAIN_HIGH_MAGNITUDE: f32 = 1024.0 * NUM_SAMPLES as f32 / 2.0;
+// Windows to ignore after a gain change, so ones that straddle the switch don't trigger another step.
+const GAIN_SETTLE_WINDOWS: u32 = 16;
+
+// pdm_signal(mode, gain level), or PDM_OFF while idle, in RAM so the circular DMA can keep reading it while set_drive rewrites it.
+// Swapping the contents rather than restarting the transfer keeps the excitation phase continuous.
+// Off until fut_demodulate's soft start ramps it up.
+static mut PDM_BUFFER: [u32; PDM_SIGNAL.len()] = PDM_OFF;
+
+// Every pin driven low on every tick, to save power while idle; the test point too, if there is one.
+const PDM_OFF: [u32; PDM_SIGNAL.len()] =
+    [((PDM_PIN_MASK | TEST_POINT_MASK) as u32) << 16; PDM_SIGNAL.len()];
+
+// The tables build.rs generated for each excitation mode, by gain level.
+// A square wave has no depth to step through, so it's the same at every level.
+fn pdm_signal(mode: ExcitationMode, level: usize) -> &'static [u32; PDM_SIGNAL.len()] {
+    match mode {
+        ExcitationMode::Sine => &PDM_SIGNALS[level],
+        ExcitationMode::Square => &PDM_SQUARE_SIGNAL,
+    }
+}
+
+fn set_gain_level(level: usize) {
+    set_drive(pdm_signal(device_config().excitation_mode, level));
+}
+
+// A stopped transfer leaves the pins at whatever tick it got to, so without this some sit high until the next start.
+fn drive_pins_low() {
+    board::ELECTRODE_PORT
+        .bsrr()
+        .write(|w| w.0 = ((PDM_PIN_MASK | TEST_POINT_MASK) as u32) << 16);
+}
+
+fn set_drive_off() {
+    set_drive(&PDM_OFF);
+}
+
+// Excitation cycles each step of a soft start or stop holds for, see DriveRamp; off to full strength is then PDM_GAIN_DEPTHS.len() - 1 holds, about 1.7 ms at the default excitation.
+// Set to 0 for hard starts and stops, e.g., to compare how many windows Reading::settling stays set for after SetFrequency.
+const RAMP_CYCLES_PER_STEP: u32 = 1;
+
+// DriveRamp's hold in samples; the window spans `bin` excitation cycles.
+fn ramp_hold(bin: f64) -> u32 {
+    (RAMP_CYCLES_PER_STEP as f64 * NUM_SAMPLES as f64 / bin) as u32
+}
+
+fn set_drive_ramped(drive: Option<usize>) {
+    match drive {
+        Some(level) => set_gain_level(level),
+        None => set_drive_off(),
+    }
+}
+
+/// Ramps the drive off ahead of stopping the PDM transfer, waiting on fut_demodulate to step it down.
+/// The ramp only moves as samples come in, so if they've stopped, the drive is cut rather than waited on forever.
+async fn soft_stop(drive_ramp: &Cell<DriveRamp>) {
+    let mut ramp = drive_ramp.get();
+    ramp.set_target(None);
+    drive_ramp.set(ramp);
+    let step = Duration::from_micros(
+        RAMP_CYCLES_PER_STEP.max(1) as u64 * PDM_SIGNAL.len() as u64 * 1_000_000
+            / device_config().pdm_frequency_hz as u64,
+    );
+    for _ in 0..2 * (PDM_SIGNALS.len() + 1) {
+        if !drive_ramp.get().ramping() {
+            return;
+        }
+        Timer::after(step).await;
+    }
+    let mut ramp = drive_ramp.get();
+    ramp.jump(None);
+    drive_ramp.set(ramp);
+    set_drive_off();
+}
+
+fn set_drive(signal: &[u32; PDM_SIGNAL.len()]) {
+    for (i, word) in signal.iter().enumerate() {
+        debug_assert!(
+            bsrr_conflicts(*word) == 0,
+            "PDM tick {} both sets and resets pins {:#x}",
+            i,
+            bsrr_conflicts(*word)
+        );
+        // Word writes are atomic, so the DMA only ever sees a mix of old and new ticks for the rest of one cycle.
+        unsafe { core::ptr::addr_of_mut!(PDM_BUFFER[i]).write_volatile(*word) };
+    }
+}
+
+// Any change from the window where the slider last moved bigger than these counts as motion, for idling; 1/256 turn is about 40um on the v1.1 PCB.
+const IDLE_MOTION_PHASE_STEP: u32 = (QUARTER_TURN >> 6) as u32;
+const IDLE_MOTION_MAGNITUDE_CHANGE: f32 = 0.2;
+// Windows discarded after the drive comes back on for an idle poll, while the front end catches up; about 10 ms at the default excitation.
+const IDLE_POLL_SETTLE_WINDOWS: u32 = 4;
+// Longest SetIdle poll interval, so a stationary slider still gets checked now and then.
+const MAX_IDLE_POLL_INTERVAL_MS: u32 = 10_000;
+
+// Button that zeroes like Command::Tare, for use without a host: to ground, with the internal pull-up.
+// PB14, the same as local's button.
+type ZeroButtonPin = peripherals::PB14;
+// A press counts once the pin has read low this long, which rides out contact bounce.
+const ZERO_BUTTON_POLL_INTERVAL: Duration = Duration::from_millis(5);
+const ZERO_BUTTON_STABLE_MS: u64 = 20;
+
+// Status LED showing measurement quality at a glance, for running without a host: the Blue Pill's PC13 LED, lit by pulling the pin low.
+type StatusLedPin = peripherals::PC13;
+const STATUS_LED_ACTIVE_LOW: bool = true;
+const STATUS_LED_TICK: Duration = Duration::from_millis(50);
+
+/// Lit for the first `on_ms` of every `period_ms`; solid if they're equal.
+struct BlinkPattern {
+    on_ms: u64,
+    period_ms: u64,
+}
+
+// Settled, unclipped readings with the magnitude inside the gain control's band.
+const LED_GOOD: BlinkPattern = BlinkPattern {
+    on_ms: 1000,
+    period_ms: 1000,
+};
+// Readings still settling, clipping, or weak even at full drive.
+const LED_DEGRADED: BlinkPattern = BlinkPattern {
+    on_ms: 500,
+    period_ms: 1000,
+};
+// No more signal than stray coupling gives with the slider off the scale, or DMA given up on: the position means nothing.
+const LED_INVALID: BlinkPattern = BlinkPattern {
+    on_ms: 100,
+    period_ms: 200,
+};
+// Drive off while still, see PowerState::Idle; readings are held, so there's no quality to show.
+const LED_IDLE: BlinkPattern = BlinkPattern {
+    on_ms: 50,
+    period_ms: 2000,
+};
+
+// Corrections for the ADC's nonlinearity, applied to raw samples ahead of everything else; zero until the host uploads a table.
+#[cfg(feature = "adc-lut")]
+static ADC_LUT: Mutex<CriticalSectionRawMutex, RefCell<AdcLut<ADC_LUT_LEN>>> =
+    Mutex::new(RefCell::new(AdcLut::new([0; ADC_LUT_LEN])));
+
+// Corrections for the scale's own distortion, applied to each window's phase on the way into the position tracker; zero until the host uploads a table.
+static PHASE_CORRECTION: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<PhaseCorrection<PHASE_CORRECTION_LEN>>,
+> = Mutex::new(RefCell::new(PhaseCorrection::new(
+    [0; PHASE_CORRECTION_LEN],
+)));
+
+// Readings are flagged as settling until this many consecutive windows agree to within these, after boot or any reconfiguration.
+// At the default excitation that's about 40 ms, and a slider moving faster than about 25 mm/s holds it off.
+const SETTLING_WINDOWS: u32 = 16;
+const SETTLING_MAX_MAGNITUDE_CHANGE: f32 = 0.05;
+const SETTLING_MAX_PHASE_STEP: u32 = (QUARTER_TURN >> 5) as u32; // 1/128 turn
+
+// Readings with more samples than this clipping are flagged saturated; one or two is an ADC glitch rather than the signal.
+const MAX_SATURATED_SAMPLES: u32 = 2;
+
+// Windows in the phase noise estimate reported by GetStatus.
+const PHASE_NOISE_WINDOWS: usize = 64;
+
+// Phase-locked loop reported by GetStatus, as a fraction of the window rate: around 100 windows of averaging, for a still slider.
+const PLL_BANDWIDTH: f32 = 0.01;
+// 1/256 turn, a few times the per-window phase noise with good coupling.
+const PLL_LOCK_THRESHOLD: u32 = (QUARTER_TURN >> 6) as u32;
+
+// Most points a ScanFrequency sweep can have.
+const MAX_SCAN_POINTS: usize = 32;
+// Longest to wait for a scan point to settle and fill the phase noise estimate, before recording it unsettled and moving on.
+const SCAN_POINT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Most recent windows kept for DumpHistory.
+const HISTORY_LEN: usize = 256;
+
+struct PositionHistory {
+    entries: [HistoryEntry; HISTORY_LEN],
+    /// Entries written so far, wrapping; the next one goes at `head % HISTORY_LEN`.
+    head: usize,
+}
+
+// The demodulation loop records every window here, and DumpHistory reads it out a packet at a time without holding up the writer.
+static POSITION_HISTORY: Mutex<CriticalSectionRawMutex, RefCell<PositionHistory>> =
+    Mutex::new(RefCell::new(PositionHistory {
+        entries: [HistoryEntry {
+            timestamp_us: 0,
+            position: 0,
+        }; HISTORY_LEN],
+        head: 0,
+    }));
+
+// Every demodulated window's wrapped phase, for DumpPhaseHistogram, and when ResetPhaseHistogram last started it over.
+static PHASE_HISTOGRAM: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<(PhaseHistogram<PHASE_HISTOGRAM_BINS>, Instant)>,
+> = Mutex::new(RefCell::new((
+    PhaseHistogram::new(),
+    Instant::from_ticks(0),
+)));
+
+/// One window's samples as the demodulator saw them, for DumpDemodulation.
+#[cfg(feature = "demod-dump")]
+struct DemodCapture {
+    samples: [u16; NUM_SAMPLES],
+    /// Excitation phase at the first sample, as in fut_demodulate's window_phase.
+    window_phase: i32,
+    bin: f64,
+}
+
+fn record_history(entry: HistoryEntry) {
+    POSITION_HISTORY.lock(|h| {
+        let mut h = h.borrow_mut();
+        let i = h.head % HISTORY_LEN;
+        h.entries[i] = entry;
+        h.head = h.head.wrapping_add(1);
+    })
+}
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let mut config = Config::default();
@@ -44,6 +445,7 @@ async fn main(_spawner: Spawner) {
         config.rcc.apb2_pre = APBPrescaler::DIV1;
     }
     let mut p = embassy_stm32::init(config);
+    let board = take_board!(p);
 
     info!("Hello World!");
 
@@ -57,16 +459,10 @@ async fn main(_spawner: Spawner) {
     ////////////////////////
     // Signal emission setup
 
-    let _pins = [
-        Output::new(p.PA0, Level::Low, Speed::Low),
-        Output::new(p.PA1, Level::Low, Speed::Low),
-        Output::new(p.PA2, Level::Low, Speed::Low),
-        Output::new(p.PA3, Level::Low, Speed::Low),
-        Output::new(p.PA4, Level::Low, Speed::Low),
-        Output::new(p.PA5, Level::Low, Speed::Low),
-        Output::new(p.PA6, Level::Low, Speed::Low),
-        Output::new(p.PA7, Level::Low, Speed::Low),
-    ];
+    let _pins = board
+        .electrodes
+        .map(|pin| Output::new(pin, Level::Low, Speed::Low));
+    let _test_point = board::test_point(board.test_point, TEST_POINT);
 
     let tim = embassy_stm32::timer::low_level::Timer::new(p.TIM2);
     let timer_registers = tim.regs_gp16();
@@ -80,10 +476,21 @@ async fn main(_spawner: Spawner) {
         w.set_uie(true);
     });
 
-    tim.set_frequency(Hertz(100_000));
-    //tim.start();
+    tim.set_frequency(Hertz(device_config().pdm_frequency_hz));
 
     let _debug_pin = Output::new(p.PB7, Level::Low, Speed::Low); // use SDA as debug pin for scope
+    let zero_button_pin: ZeroButtonPin = p.PB14;
+    let zero_button = Input::new(zero_button_pin, Pull::Up);
+    let status_led_pin: StatusLedPin = p.PC13;
+    let mut status_led = Output::new(
+        status_led_pin,
+        if STATUS_LED_ACTIVE_LOW {
+            Level::High
+        } else {
+            Level::Low
+        },
+        Speed::Low,
+    );
     unsafe { cortex_m::peripheral::NVIC::unmask(embassy_stm32::pac::Interrupt::TIM2) };
 
     static mut DRIVE_N: usize = 0;
@@ -91,7 +498,7 @@ async fn main(_spawner: Spawner) {
     unsafe fn TIM2() {
         embassy_stm32::pac::TIM2.sr().modify(|w| w.set_uif(false));
         DRIVE_N += 1;
-        if 0 == DRIVE_N % SIGNAL.len() {
+        if 0 == DRIVE_N % PDM_SIGNAL.len() {
             embassy_stm32::pac::GPIOB
                 .bsrr()
                 .write(|w| w.set_bs(7, true))
@@ -103,20 +510,27 @@ async fn main(_spawner: Spawner) {
     }
 
     use embassy_stm32::dma::*;
-    let gpioa = embassy_stm32::pac::GPIOA;
 
-    let mut opts = TransferOptions::default();
-    opts.circular = true;
+    let start_pdm = || unsafe {
+        let mut opts = TransferOptions::default();
+        opts.circular = true;
+
+        let dma_ch = embassy_stm32::Peripheral::clone_unchecked(&board.pdm_dma);
+        let request = embassy_stm32::timer::UpDma::request(&dma_ch);
+
+        tim.reset();
 
-    let request = embassy_stm32::timer::UpDma::request(&p.DMA1_CH2);
-    let _transfer = unsafe {
-        Transfer::new_write(
-            p.DMA1_CH2,
+        let t = Transfer::new_write(
+            dma_ch,
             request,
-            &SIGNAL,
-            gpioa.bsrr().as_ptr() as *mut u32,
+            &*core::ptr::addr_of!(PDM_BUFFER),
+            board::ELECTRODE_PORT.bsrr().as_ptr() as *mut u32,
             opts,
-        )
+        );
+        mask_dma_error_interrupt(PDM_DMA_CHANNEL);
+
+        tim.start();
+        t
     };
 
     ////////////////////////
@@ -131,6 +545,7 @@ async fn main(_spawner: Spawner) {
     let mut config_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
     let mut control_buf = [0; 64];
+    let mut device_info_handler = DeviceInfoHandler;
 
     let mut builder = Builder::new(
         driver,
@@ -141,6 +556,8 @@ async fn main(_spawner: Spawner) {
         &mut control_buf,
     );
 
+    builder.handler(&mut device_info_handler);
+
     let mut func = builder.function(USB_CLASS_CUSTOM, USB_SUBCLASS_CUSTOM, USB_PROTOCOL_CUSTOM);
     let mut iface = func.interface();
 
@@ -152,6 +569,7 @@ async fn main(_spawner: Spawner) {
     );
     let mut read_ep = iface_alt.endpoint_bulk_out(MAX_PACKET_SIZE as u16);
     let mut write_ep = iface_alt.endpoint_bulk_in(MAX_PACKET_SIZE as u16);
+    let mut response_ep = iface_alt.endpoint_bulk_in(MAX_PACKET_SIZE as u16);
     drop(func);
 
     let mut usb = builder.build();
@@ -162,12 +580,12 @@ async fn main(_spawner: Spawner) {
     // ADC + DMA setup
 
     let mut adc_buffer = [0; 2 * SAMPLES_PER_PACKET];
-    let request = embassy_stm32::adc::RxDma::request(&p.DMA1_CH1);
+    let request = embassy_stm32::adc::RxDma::request(&board.adc_dma);
     let mut opts = TransferOptions::default();
     opts.half_transfer_ir = true;
     let mut adc_rb = unsafe {
         ReadableRingBuffer::new(
-            p.DMA1_CH1,
+            board.adc_dma,
             request,
             embassy_stm32::pac::ADC1.dr().as_ptr() as *mut u16,
             &mut adc_buffer,
@@ -183,11 +601,21 @@ async fn main(_spawner: Spawner) {
         // give vref some time to warm up
         Timer::after_millis(100).await;
 
-        adc.read(&mut vrefint).await as u32
+        adc.read(&mut vrefint).await
     };
-    info!("VREFINT: {}", vrefint_sample);
+    let adc_calibration = AdcCalibration {
+        vrefint_sample,
+        vref_int_mv: VREFINT_MV as u16,
+        plausible: (MIN_VREFINT_SAMPLE..=MAX_VREFINT_SAMPLE).contains(&vrefint_sample),
+    };
+    if !adc_calibration.plausible {
+        error!(
+            "VREFINT sample {} outside {}..={}, millivolts will be off",
+            vrefint_sample, MIN_VREFINT_SAMPLE, MAX_VREFINT_SAMPLE
+        );
+    }
 
-    let convert_to_millivolts = |sample| (sample as u32 * adc::VREF_INT / vrefint_sample) as u16;
+    let convert_to_millivolts = |sample| adc_to_millivolts(sample, vrefint_sample);
 
     // Configure ADC for continuous conversion with DMA
     let adc = embassy_stm32::pac::ADC1;
@@ -206,62 +634,749 @@ async fn main(_spawner: Spawner) {
     adc.sqr1().modify(|w| w.set_l(0)); // one conversion.
 
     // TODO: this may not be necessary
-    let mut pb1 = Flex::new(p.PB1);
-    pb1.set_as_analog();
+    let mut sense_pin = Flex::new(board.sense_pin);
+    sense_pin.set_as_analog();
 
-    const PIN_CHANNEL: u8 = 9; // PB1 is on channel 9 for STM32F103
-    adc.sqr3().modify(|w| w.set_sq(0, PIN_CHANNEL));
+    adc.sqr3().modify(|w| w.set_sq(0, board::SENSE_ADC_CHANNEL));
     adc.smpr2().modify(|w| {
         w.set_smp(
-            PIN_CHANNEL as usize,
+            board::SENSE_ADC_CHANNEL as usize,
+            sample_time(&device_config().adc_sampling_period),
+        )
+    });
+
+    // Temperature sensor (channel 16) as a single injected conversion, see local.rs.
+    const TEMPERATURE_CHANNEL: u8 = 16;
+    adc.smpr1().modify(|w| {
+        w.set_smp(
+            TEMPERATURE_CHANNEL as usize - 10,
             adc::SampleTime::CYCLES239_5,
-            //adc::SampleTime::CYCLES71_5,
         )
     });
+    adc.jsqr().modify(|w| {
+        w.set_jl(0);
+        w.set_jsq(3, TEMPERATURE_CHANNEL);
+    });
+    adc.cr2().modify(|w| {
+        w.set_tsvrefe(true);
+        w.set_jextsel(embassy_stm32::pac::adc::vals::Jextsel::JSWSTART);
+        w.set_jexttrig(true);
+    });
 
     // Start ADC conversions
     adc.cr2().modify(|w| w.set_adon(true));
 
     ////////////////////////
-    // Stream ADC data to host
+    // Demodulate ADC data and queue it for the host
 
-    let fut_stream_adc = async {
-        // Wait for USB to connect
-        write_ep.wait_enabled().await;
+    let adc_overruns = Cell::new(0u32);
+    let temperature_c = Cell::new(0.0f32);
+    let gain_level = Cell::new(PDM_SIGNALS.len() - 1);
+    let phase_std_dev = Cell::new(0.0f32);
+    let pll_phase = Cell::new(0.0f32);
+    let pll_locked = Cell::new(false);
+    let power_state = Cell::new(PowerState::Active);
+    // in the latest window
+    let saturated_samples = Cell::new(0u32);
+    let rejected_steps = Cell::new(0u32);
+    let direction = Cell::new(Direction::Stationary);
+    // Written from fut_commands as well, but only fut_demodulate advances it, in step with the samples.
+    let drive_ramp = Cell::new(DriveRamp::new(0));
+    let ramp_drive_to = |target: Option<usize>| {
+        let mut ramp = drive_ramp.get();
+        ramp.set_target(target);
+        drive_ramp.set(ramp);
+    };
+    let jump_drive = |drive: Option<usize>| {
+        let mut ramp = drive_ramp.get();
+        ramp.jump(drive);
+        drive_ramp.set(ramp);
+        set_drive_ramped(drive);
+    };
+    let dma_errors = Cell::new(0u32);
+    let dma_failed = Cell::new(false);
+    // since the host last connected
+    let dropped_packets = Cell::new(0u32);
+    // None until the host first grants credits, after which each queued stream packet spends one.
+    let stream_credits = Cell::new(None::<u32>);
+    let throttled_packets = Cell::new(0u32);
+
+    // SELF_TEST_SIGNAL is generated for the build-time excitation, not whatever the host picks, so this checks the Goertzel + CORDIC path rather than the live configuration.
+    let self_test_passed = {
+        let mut goertzel = Goertzel::new(SELF_TEST_SIGNAL.len(), DEMOD_BIN);
+        for x in SELF_TEST_SIGNAL.iter() {
+            goertzel.push(*x as i16);
+        }
+        let (sum_sine, sum_cosine) = goertzel.iq();
+        let angle = cordic_atan2(sum_sine as i32, sum_cosine as i32);
+        let error = angle.wrapping_sub(SELF_TEST_PHASE).unsigned_abs();
+        if error <= SELF_TEST_TOLERANCE {
+            info!("Self-test passed, phase error {} turn units", error);
+            true
+        } else {
+            error!(
+                "Self-test FAILED: injected phase {}, demodulated {}",
+                angle_to_radians(SELF_TEST_PHASE),
+                angle_to_radians(angle)
+            );
+            false
+        }
+    };
+    // untared; tare is applied when reporting rather than to the tracker, so the tracker keeps its wrap count
+    let reading = Cell::new(Reading::default());
+    // when the window behind reading started, as in HistoryEntry
+    let reading_timestamp_us = Cell::new(0u32);
+    // untared too, like reading
+    let peak_hold = Cell::new(PeakHold::new());
+    // reading.position through the display dead zone
+    let display_position = Cell::new(0i64);
+    // packet and its length
+    let samples =
+        Channel::<NoopRawMutex, ([u8; SAMPLE_PACKET_SIZE], usize), SAMPLE_QUEUE_DEPTH>::new();
+    // Queues a finished stream packet for fut_stream_adc, unless the host is out of credits or the queue is full.
+    // A host that only polls GetReading never drains the stream; the sequence gap tells a streaming host what it missed.
+    let queue_packet = |packet: [u8; SAMPLE_PACKET_SIZE], len: usize| {
+        let credits = stream_credits.get();
+        if credits == Some(0) {
+            throttled_packets.set(throttled_packets.get().wrapping_add(1));
+        } else if samples.try_send((packet, len)).is_err() {
+            dropped_packets.set(dropped_packets.get().wrapping_add(1));
+        } else {
+            stream_credits.set(credits.map(|c| c - 1));
+        }
+    };
+    let calibration_request = Signal::<NoopRawMutex, ()>::new();
+    let calibration_result = Signal::<NoopRawMutex, IqOffset>::new();
+    let noise_request = Signal::<NoopRawMutex, ()>::new();
+    let noise_result = Signal::<NoopRawMutex, NoiseStats>::new();
+    let scan_request = Signal::<NoopRawMutex, ()>::new();
+    let scan_result = Signal::<NoopRawMutex, ScanPoint>::new();
+    #[cfg(feature = "demod-dump")]
+    let demod_dump_request = Signal::<NoopRawMutex, ()>::new();
+    #[cfg(feature = "demod-dump")]
+    let demod_dump_result = Signal::<NoopRawMutex, DemodCapture>::new();
 
+    // Runs whether or not a host is connected, so the latest reading is always current.
+    let fut_demodulate = async {
         // Start handling DMA requests from ADC
         adc_rb.start();
+        mask_dma_error_interrupt(ADC_DMA_CHANNEL);
+        // PDM transfers are started and stopped by fut_commands, but it's parked on the endpoint most of the time, so they're watched from here.
+        let mut adc_dma_errors = DmaErrors::new("ADC", ADC_DMA_CHANNEL, 2 * SAMPLES_PER_PACKET);
+        let mut pdm_dma_errors = DmaErrors::new("PDM", PDM_DMA_CHANNEL, PDM_SIGNAL.len());
+
+        // Read back from the registers, for comparing a board in the field against a known-good one; fut_commands is polled first, so the PDM transfer's already running.
+        let clocks = common::clocks();
+        common::log_clocks(&clocks);
+        common::log_adc(vrefint_sample, board::SENSE_ADC_CHANNEL);
+        common::log_dma_channel("ADC", ADC_DMA_CHANNEL);
+        common::log_dma_channel("PDM", PDM_DMA_CHANNEL);
+        common::log_excitation(&clocks, PDM_SIGNAL.len());
+        info!(
+            "Startup: {} samples per window, {} per packet",
+            NUM_SAMPLES, SAMPLES_PER_PACKET
+        );
 
         let mut buf = [0; SAMPLES_PER_PACKET];
+        let mut packet = [0u8; SAMPLE_PACKET_SIZE];
+        let mut sequence: u16 = 0;
+        // in StreamMode::IqWindows, windows so far in iq_packet
+        let mut iq_packet = [0u8; SAMPLE_PACKET_SIZE];
+        let mut iq_pairs = 0;
+        let mut iq_packet_start_us: u32 = 0;
+        // in StreamMode::Samples, millivolt samples so far towards the next packet in stream_format
+        let mut stream_samples = [0u16; MAX_STREAM_SAMPLES];
+        let mut stream_len = 0;
+        let mut stream_start_us: u32 = 0;
+
+        let config = device_config();
+        let mut stream_format = config.sample_format;
+        let mut excitation = (
+            config.pdm_frequency_hz,
+            config.adc_sampling_period,
+            config.excitation_mode,
+        );
+        let mut bin = excitation_bin(excitation.0, &excitation.1);
+        let mut goertzel = Goertzel::new(NUM_SAMPLES, bin as f32);
+        let mut window_len = 0;
+        let mut window_saturated = 0;
+        // when the current window's first sample was converted, see sample_time_us below
+        let mut window_start_us: u32 = 0;
+        // Excitation phase at the first sample of the current window.
+        // The bin is generally not an integer, so each window starts at a different point in the excitation cycle.
+        let mut window_phase: i32 = 0;
+        let mut position_tracker = PositionTracker::new();
+        let mut position_filter = OnePole::new(config.filter_alpha);
+        let mut velocity_estimator =
+            VelocityEstimator::new(window_period(&excitation.1), VELOCITY_FILTER_ALPHA);
+        // running while a CalibrateIqOffset command waits on it
+        let mut calibration: Option<IqAverager> = None;
+        // running while a MeasureNoise command waits on it: samples still to discard, and the statistics so far
+        let mut noise: Option<(u32, NoiseStats)> = None;
+        // running while a ScanFrequency point waits on it: settled windows so far, and their magnitudes summed
+        let mut scan: Option<(usize, f32)> = None;
+        // filling while a DumpDemodulation command waits on it; always starts on a window boundary
+        #[cfg(feature = "demod-dump")]
+        let mut demod_capture: Option<DemodCapture> = None;
+        let mut last_temperature = Instant::now();
+        let mut phase_noise = PhaseStdDev::<PHASE_NOISE_WINDOWS>::new();
+        let mut pll = PhaseLockedLoop::new(PLL_BANDWIDTH, PLL_LOCK_THRESHOLD);
+        let mut settling = SettlingDetector::new(
+            SETTLING_WINDOWS,
+            SETTLING_MAX_MAGNITUDE_CHANGE,
+            SETTLING_MAX_PHASE_STEP,
+        );
+        let mut gain = GainControl::new(
+            PDM_SIGNALS.len(),
+            GAIN_LOW_MAGNITUDE,
+            GAIN_HIGH_MAGNITUDE,
+            GAIN_SETTLE_WINDOWS,
+        );
+        let mut motion = MotionDetector::new(IDLE_MOTION_PHASE_STEP, IDLE_MOTION_MAGNITUDE_CHANGE);
+        let mut direction_detector = DirectionDetector::new(0.0);
+        let mut display_dead_zone = DeadZone::new(0, 0);
+        let mut last_motion = Instant::now();
+        // While idle: when the drive went off between polls, or if it's on for one, how many windows are left to discard.
+        // The timer and DMA keep running with the drive off, so window_phase still tracks the excitation.
+        let mut drive_off_since: Option<Instant> = None;
+        let mut poll_settle_windows = 0;
+
+        // soft start
+        let mut ramp = drive_ramp.get();
+        ramp.set_hold(ramp_hold(bin));
+        drive_ramp.set(ramp);
+        ramp_drive_to(Some(gain.level()));
+
         loop {
-            loop {
-                let r = adc_rb.read_exact(&mut buf).await;
+            // Overrun, the DMA lapping us before we drained the buffer, loses samples but the stream is still fine, so note it and keep going.
+            // The phase reference is lost with them, though, so position jumps by an arbitrary amount.
+            let read = with_timeout(ADC_TIMEOUT, adc_rb.read_exact(&mut buf)).await;
+            pdm_dma_errors.poll();
+            // samples already converted after buf's, still waiting in the ring buffer
+            let mut backlog = 0;
+            let lost_samples = match read {
+                Ok(Ok(remaining)) => {
+                    backlog = remaining;
+                    false
+                }
+                Ok(Err(e)) => {
+                    adc_overruns.set(adc_overruns.get() + 1);
+                    warn!(
+                        "ADC_RB error: {:?}, {} overruns total",
+                        e,
+                        adc_overruns.get()
+                    );
+                    true
+                }
+                // A read that never finishes is the DMA stopped on a transfer error, or left off after too many; the ring buffer starts over either way.
+                Err(_) => {
+                    adc_dma_errors.poll();
+                    true
+                }
+            };
+            dma_errors.set(adc_dma_errors.total + pdm_dma_errors.total);
+            dma_failed.set(adc_dma_errors.failed || pdm_dma_errors.failed);
+            if lost_samples {
+                adc_rb.clear();
+                goertzel.reset();
+                window_len = 0;
+                window_saturated = 0;
+                velocity_estimator.reset();
+                phase_noise.reset();
+                pll.reset();
+                settling.reset();
+                continue;
+            }
+            // Timestamps go back to when the ADC converted each sample rather than when this loop got round to it, so USB and the other futures add no jitter.
+            // buf's last sample came `backlog` samples before now; the rest follow at the sample rate, which is only off by the rate changing mid-buffer.
+            let sample_us = 1e6 / excitation.1.to_Hz();
+            let last_sample_us = (Instant::now().as_micros() as u32)
+                .wrapping_sub((backlog as f64 * sample_us) as u32);
+            let sample_time_us = move |i: usize| {
+                last_sample_us
+                    .wrapping_sub(((SAMPLES_PER_PACKET - 1 - i) as f64 * sample_us) as u32)
+            };
+            let mut ramp = drive_ramp.get();
+            if ramp.advance(buf.len() as u32) {
+                set_drive_ramped(ramp.drive());
+            }
+            drive_ramp.set(ramp);
+            #[cfg(feature = "adc-lut")]
+            ADC_LUT.lock(|lut| lut.borrow().apply(&mut buf));
 
-                if r.is_err() {
-                    error!("ADC_RB error: {:?}", r);
-                    break;
+            if adc.sr().read().jeoc() {
+                adc.sr().modify(|w| w.set_jeoc(false)); // rc_w0
+
+                // without a reference, the temperature just stays at whatever it last read
+                if let Some(millivolts) = convert_to_millivolts(adc.jdr(0).read().jdata()) {
+                    temperature_c.set(common::sensor_temperature_c(millivolts));
                 }
+            }
+            if last_temperature.elapsed() >= TEMPERATURE_INTERVAL {
+                last_temperature = Instant::now();
+                adc.cr2().modify(|w| w.set_jswstart(true));
+            }
 
-                for x in buf.iter_mut() {
-                    *x = convert_to_millivolts(*x);
+            // Host changed the excitation or the sample time, so start over from a fresh window at the new bin; the phase reference is lost either way.
+            let config = device_config();
+            let new_excitation = (
+                config.pdm_frequency_hz,
+                config.adc_sampling_period,
+                config.excitation_mode,
+            );
+            if new_excitation != excitation {
+                excitation = new_excitation;
+                bin = excitation_bin(excitation.0, &excitation.1);
+                goertzel = Goertzel::new(NUM_SAMPLES, bin as f32);
+                window_len = 0;
+                window_saturated = 0;
+                window_phase = 0;
+                // a scan point measured across the change would mix the two; fut_commands asks again after it retunes
+                scan = None;
+                // the window it was part way through is gone, so go again with the next one
+                #[cfg(feature = "demod-dump")]
+                if demod_capture.take().is_some() {
+                    demod_dump_request.signal(());
                 }
+                velocity_estimator.set_period(window_period(&excitation.1));
+                let mut ramp = drive_ramp.get();
+                ramp.set_hold(ramp_hold(bin));
+                drive_ramp.set(ramp);
+                velocity_estimator.reset();
+                phase_noise.reset();
+                pll.reset();
+                settling.reset();
+            }
 
-                let r = write_ep.write(bytemuck::cast_slice(&buf)).await;
+            if noise.is_none() && noise_request.try_take().is_some() {
+                // straight off, so the ramp down isn't in the noise
+                jump_drive(None);
+                noise = Some((NOISE_SETTLE_SAMPLES, NoiseStats::new()));
+            }
+            if let Some((settle, stats)) = noise.as_mut() {
+                for x in buf.iter() {
+                    if *settle > 0 {
+                        *settle -= 1;
+                    } else {
+                        stats.push(*x);
+                    }
+                }
+                if stats.count() >= NOISE_SAMPLES {
+                    noise_result.signal(*stats);
+                    noise = None;
+                    if power_state.get() == PowerState::Active {
+                        ramp_drive_to(Some(gain.level()));
+                    } else {
+                        drive_off_since = Some(Instant::now());
+                    }
+                    velocity_estimator.reset();
+                    settling.reset();
+                }
+            }
+
+            for (i, x) in buf.iter().enumerate() {
+                if window_len == 0 {
+                    window_start_us = sample_time_us(i);
+                }
+                #[cfg(feature = "demod-dump")]
+                {
+                    if window_len == 0
+                        && demod_capture.is_none()
+                        && demod_dump_request.try_take().is_some()
+                    {
+                        demod_capture = Some(DemodCapture {
+                            samples: [0; NUM_SAMPLES],
+                            window_phase,
+                            bin,
+                        });
+                    }
+                    if let Some(capture) = demod_capture.as_mut() {
+                        capture.samples[window_len] = *x;
+                    }
+                }
+                goertzel.push(*x as i16);
+                window_len += 1;
+                window_saturated += is_saturated(*x) as u32;
+
+                if window_len == NUM_SAMPLES {
+                    // Rotate the window's sums into the excitation's frame, so offsets from coupling that's synchronous with the excitation stay put from window to window.
+                    let (sum_sine, sum_cosine) = goertzel.iq();
+                    let (sine, cosine) = angle_to_radians(window_phase).sin_cos();
+                    let (sum_sine, sum_cosine) = (
+                        (sum_cosine * sine + sum_sine * cosine) as i32,
+                        (sum_cosine * cosine - sum_sine * sine) as i32,
+                    );
+                    goertzel.reset();
+                    window_len = 0;
+                    window_phase = window_phase.wrapping_add(window_phase_advance(bin));
+                    let saturated = core::mem::take(&mut window_saturated);
+                    #[cfg(feature = "demod-dump")]
+                    if let Some(capture) = demod_capture.take() {
+                        demod_dump_result.signal(capture);
+                    }
+
+                    // Windows still get demodulated, keeping window_phase in step with the excitation, but there's nothing to measure with the drive off, and idle polls wait too.
+                    if noise.is_some() {
+                        continue;
+                    }
+
+                    if power_state.get() == PowerState::Idle && config.idle_timeout_ms == 0 {
+                        info!("Idling turned off, waking up");
+                        ramp_drive_to(Some(gain.level()));
+                        drive_off_since = None;
+                        power_state.set(PowerState::Active);
+                    }
+                    if power_state.get() == PowerState::Idle {
+                        if let Some(since) = drive_off_since {
+                            if since.elapsed()
+                                < Duration::from_millis(config.idle_poll_interval_ms as u64)
+                            {
+                                continue;
+                            }
+                            ramp_drive_to(Some(gain.level()));
+                            drive_off_since = None;
+                            poll_settle_windows = IDLE_POLL_SETTLE_WINDOWS;
+                            // a poll apart rather than a window
+                            velocity_estimator.reset();
+                            continue;
+                        }
+                        // the settle windows count from when the drive reaches full strength
+                        if drive_ramp.get().ramping() {
+                            continue;
+                        }
+                        if poll_settle_windows > 0 {
+                            poll_settle_windows -= 1;
+                            continue;
+                        }
+                        // otherwise this window is the poll
+                    }
+
+                    if calibration_request.try_take().is_some() {
+                        calibration = Some(IqAverager::new(CALIBRATION_WINDOWS));
+                    }
+                    if let Some(averager) = calibration.as_mut() {
+                        if let Some((sum_sine, sum_cosine)) = averager.push(sum_sine, sum_cosine) {
+                            calibration_result.signal(IqOffset {
+                                sum_sine,
+                                sum_cosine,
+                            });
+                            calibration = None;
+                        }
+                    }
+
+                    let offset = config.iq_offset;
+                    let (sum_sine, sum_cosine) =
+                        (sum_sine - offset.sum_sine, sum_cosine - offset.sum_cosine);
+
+                    if config.stream_mode == StreamMode::IqWindows {
+                        if iq_pairs == 0 {
+                            iq_packet_start_us = window_start_us;
+                        }
+                        let offset = SamplePacketHeader::SIZE + 8 * iq_pairs;
+                        iq_packet[offset..offset + 4].copy_from_slice(&sum_sine.to_le_bytes());
+                        iq_packet[offset + 4..offset + 8]
+                            .copy_from_slice(&sum_cosine.to_le_bytes());
+                        iq_pairs += 1;
+                        if iq_pairs == IQ_PAIRS_PER_PACKET {
+                            finish_packet(
+                                &mut iq_packet,
+                                IQ_PACKET_SIZE,
+                                sequence,
+                                iq_packet_start_us,
+                            );
+                            sequence = sequence.wrapping_add(1);
+                            queue_packet(iq_packet, IQ_PACKET_SIZE);
+                            iq_pairs = 0;
+                        }
+                    }
+
+                    // already rotated to the window's start, above
+                    let (phase, magnitude) = phase_magnitude((sum_sine, sum_cosine), 0);
+                    // The sigma-delta patterns differ a little between depths, so position can shift by a few counts when this steps.
+                    // Mid-ramp the magnitude is low on purpose, so it's no guide to the gain.
+                    if !drive_ramp.get().ramping() {
+                        if let Some(level) = gain.update(magnitude) {
+                            info!(
+                                "Magnitude {}, switching PDM depth to {}",
+                                magnitude, PDM_GAIN_DEPTHS[level]
+                            );
+                            jump_drive(Some(level));
+                            gain_level.set(level);
+                            settling.reset();
+                        }
+                    }
+
+                    phase_noise.push(phase);
+                    PHASE_HISTOGRAM.lock(|h| h.borrow_mut().0.add(phase));
+                    let settled = settling.update(magnitude, phase);
+                    phase_std_dev.set(phase_noise.std_dev());
+                    if let Some((windows, magnitude_sum)) = scan.as_mut() {
+                        if settled && !drive_ramp.get().ramping() {
+                            *windows += 1;
+                            *magnitude_sum += magnitude;
+                        } else {
+                            // a gain change or a bump, so start the phase noise over from the next settled window
+                            *windows = 0;
+                            *magnitude_sum = 0.0;
+                            phase_noise.reset();
+                        }
+                        if *windows == PHASE_NOISE_WINDOWS {
+                            scan_result.signal(ScanPoint {
+                                pdm_frequency_hz: excitation.0,
+                                magnitude: *magnitude_sum / *windows as f32,
+                                phase_std_dev: phase_noise.std_dev(),
+                                gain_level: gain.level() as u8,
+                                settled: true,
+                            });
+                            scan = None;
+                        }
+                        // the slider is meant to be still, so don't let that idle the drive mid-scan
+                        last_motion = Instant::now();
+                    } else if scan_request.try_take().is_some() {
+                        scan = Some((0, 0.0));
+                        phase_noise.reset();
+                    }
+                    position_tracker.set_max_step(max_step_counts(
+                        MAX_SLEW_UM_PER_S,
+                        window_period(&excitation.1),
+                        config.pitch_um,
+                    ));
+                    let corrected_phase = PHASE_CORRECTION.lock(|c| c.borrow().apply(phase));
+                    pll_phase.set(angle_to_radians(pll.update(corrected_phase)));
+                    pll_locked.set(pll.locked());
+                    let position = position_tracker.update_angle(corrected_phase);
+                    rejected_steps.set(position_tracker.rejected);
+                    position_filter.set_alpha(config.filter_alpha);
+                    let filtered_position = position_filter.filter(position as f32).round() as i64;
+                    let mut peak = peak_hold.get();
+                    peak.update(filtered_position);
+                    peak_hold.set(peak);
+                    display_dead_zone.set_width(
+                        (config.display_deadband_um as u64 * COUNTS_PER_PITCH as u64)
+                            .div_ceil(config.pitch_um as u64) as i64,
+                    );
+                    display_dead_zone.set_still_updates(
+                        (DISPLAY_STILL_TIME / window_period(&excitation.1)).ceil() as u32,
+                    );
+                    display_position.set(display_dead_zone.update(filtered_position));
+                    record_history(HistoryEntry {
+                        timestamp_us: window_start_us,
+                        position: filtered_position,
+                    });
+                    let velocity = velocity_estimator.update(position);
+                    reading.set(Reading {
+                        position: filtered_position,
+                        phase,
+                        magnitude,
+                        velocity,
+                        // converted on the way out, once the tare is applied
+                        position_um: 0,
+                        value: 0,
+                        unit: Unit::Millimeters,
+                        position_fine: position_tracker.position_fine(),
+                        settling: !settled,
+                        saturated: saturated > MAX_SATURATED_SAMPLES,
+                        tracked_phase: corrected_phase,
+                    });
+                    reading_timestamp_us.set(window_start_us);
+                    saturated_samples.set(saturated);
+
+                    if motion.update(magnitude, phase) {
+                        last_motion = Instant::now();
+                        if power_state.get() == PowerState::Idle {
+                            info!("Motion, waking up");
+                            power_state.set(PowerState::Active);
+                        }
+                    } else if power_state.get() == PowerState::Idle {
+                        // poll found nothing
+                        ramp_drive_to(None);
+                        drive_off_since = Some(Instant::now());
+                    } else if config.idle_timeout_ms > 0
+                        && last_motion.elapsed()
+                            >= Duration::from_millis(config.idle_timeout_ms as u64)
+                    {
+                        info!("Still for {}ms, idling", config.idle_timeout_ms);
+                        power_state.set(PowerState::Idle);
+                        ramp_drive_to(None);
+                        drive_off_since = Some(Instant::now());
+                    }
+
+                    // Idle has already decided the slider is still, and velocity is reset across every poll anyway.
+                    if power_state.get() == PowerState::Idle {
+                        direction_detector.reset();
+                        direction.set(Direction::Stationary);
+                    } else {
+                        direction_detector.set_deadband(
+                            DIRECTION_DEADBAND_UM_PER_S * COUNTS_PER_PITCH as f32
+                                / config.pitch_um as f32,
+                        );
+                        direction.set(match direction_detector.update(velocity) {
+                            calipertron_core::Direction::Stationary => Direction::Stationary,
+                            calipertron_core::Direction::Forward => Direction::Forward,
+                            calipertron_core::Direction::Backward => Direction::Backward,
+                        });
+                    }
+                }
+            }
+
+            if config.stream_mode != StreamMode::IqWindows {
+                // drop any partial I/Q packet from before switching away
+                iq_pairs = 0;
+            }
+            if config.stream_mode != StreamMode::Samples {
+                continue;
+            }
+
+            if config.sample_format != stream_format {
+                // drop the partial packet in the old format
+                stream_format = config.sample_format;
+                stream_len = 0;
+            }
+
+            let packet_samples = stream_format.samples_per_packet();
+            for (i, x) in buf.iter().enumerate() {
+                if stream_len == 0 {
+                    stream_start_us = sample_time_us(i);
+                }
+                stream_samples[stream_len] =
+                    convert_to_millivolts(*x).unwrap_or(INVALID_MILLIVOLTS);
+                stream_len += 1;
+                if stream_len < packet_samples {
+                    continue;
+                }
+                stream_len = 0;
+
+                let body = &mut packet[SamplePacketHeader::SIZE..];
+                let big_endian = stream_format.endianness == Endianness::Big;
+                match stream_format.width {
+                    SampleWidth::Bits16 => {
+                        for (x, bytes) in stream_samples[..packet_samples]
+                            .iter()
+                            .zip(body.chunks_exact_mut(2))
+                        {
+                            let x = if big_endian {
+                                x.to_be_bytes()
+                            } else {
+                                x.to_le_bytes()
+                            };
+                            bytes.copy_from_slice(&x);
+                        }
+                    }
+                    SampleWidth::Packed12 => {
+                        pack_12(&stream_samples[..packet_samples], big_endian, body);
+                    }
+                }
+                let len =
+                    SamplePacketHeader::SIZE + stream_format.body_len() + SAMPLE_PACKET_CRC_SIZE;
+                finish_packet(&mut packet, len, sequence, stream_start_us);
+                sequence = sequence.wrapping_add(1);
+                queue_packet(packet, len);
+            }
+        }
+    };
+
+    let fut_stream_adc = async {
+        loop {
+            // Wait for USB to connect, then discard whatever piled up while we were disconnected
+            write_ep.wait_enabled().await;
+            while samples.try_receive().is_ok() {}
+            dropped_packets.set(0);
+            // a new host may not know about credits, so start unpaced
+            stream_credits.set(None);
+            throttled_packets.set(0);
+
+            loop {
+                let (packet, len) = samples.receive().await;
+                let r = write_ep.write(&packet[..len]).await;
                 if r.is_err() {
                     error!("USB Error: {:?}", r);
                     break;
                 }
             }
+        }
+    };
 
-            adc_rb.clear();
+    // Decimates to the host's rate by sending whatever the latest reading is on each tick, rather than every window.
+    let fut_stream_positions = async {
+        let mut sequence: u16 = 0;
+        loop {
+            let config = device_config();
+            let stream_mode = config.stream_mode;
+            let run_threshold = match stream_mode {
+                StreamMode::Positions => None,
+                StreamMode::PositionRuns { threshold } => Some(threshold as i64),
+                _ => {
+                    Timer::after(POSITION_STREAM_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            // in StreamMode::PositionRuns, the last sample sent and the ticks held at it since; a new run starts with each switch into it
+            let mut held: Option<(PositionSample, u16)> = None;
+
+            let interval = position_interval(config.position_rate_hz, &config.adc_sampling_period);
+            let mut ticker = Ticker::every(interval);
+            loop {
+                ticker.next().await;
+                let config = device_config();
+                // a new rate, sample time or threshold starts a new ticker
+                if config.stream_mode != stream_mode
+                    || position_interval(config.position_rate_hz, &config.adc_sampling_period)
+                        != interval
+                {
+                    break;
+                }
+
+                let reading = reading.get();
+                let sample = PositionSample {
+                    position: reading.position - config.tare,
+                    settling: reading.settling,
+                    saturated: reading.saturated,
+                    phase: reading.tracked_phase,
+                    position_fine: reading.position_fine - (config.tare << POSITION_FRACTION_BITS),
+                };
+                let mut packet = [0u8; SAMPLE_PACKET_SIZE];
+                let len = match run_threshold {
+                    None => {
+                        sample.write(&mut packet[SamplePacketHeader::SIZE..]);
+                        POSITION_PACKET_SIZE
+                    }
+                    Some(threshold) => {
+                        if let Some((last, repeats)) = &mut held {
+                            // against the last one sent rather than the last tick, so a slow creep still gets through once it adds up to the threshold
+                            if *repeats < u16::MAX
+                                && (sample.position - last.position).abs() <= threshold
+                                && (sample.settling, sample.saturated)
+                                    == (last.settling, last.saturated)
+                            {
+                                *repeats += 1;
+                                continue;
+                            }
+                        }
+                        PositionRun {
+                            sample,
+                            repeats: held.map_or(0, |(_, repeats)| repeats),
+                        }
+                        .write(&mut packet[SamplePacketHeader::SIZE..]);
+                        held = Some((sample, 0));
+                        POSITION_RUN_PACKET_SIZE
+                    }
+                };
+                finish_packet(&mut packet, len, sequence, reading_timestamp_us.get());
+                sequence = sequence.wrapping_add(1);
+                queue_packet(packet, len);
+            }
         }
     };
 
     //////////////////////////
     // handle commands from host
     let fut_commands = async {
+        // Drive from boot so there's a position to report before the host picks a frequency
+        let mut pdm_transfer = Some(start_pdm());
+
         // Wait for USB to connect
         read_ep.wait_enabled().await;
+        respond(&mut response_ep, &Response::Handshake(adc_calibration)).await;
 
         loop {
             let mut command_buf = [0u8; MAX_PACKET_SIZE as usize];
@@ -270,17 +1385,459 @@ async fn main(_spawner: Spawner) {
                 Ok(size) => {
                     if let Some(command) = Command::deserialize(&command_buf[..size]) {
                         info!("Received command: {:?}", command);
-                        match command {
-                            Command::SetFrequency { frequency_kHz } => {
+                        // head of the history when DumpHistory came in, for after its reply
+                        let mut history_dump = None;
+                        // points of a ScanFrequency sweep, likewise
+                        let mut scan_dump = None;
+                        // a copy of the phase histogram's bins for DumpPhaseHistogram, likewise
+                        let mut histogram_dump = None;
+                        // the window captured for DumpDemodulation, likewise
+                        #[cfg(feature = "demod-dump")]
+                        let mut demod_dump = None;
+                        let response = match command {
+                            Command::SetFrequency {
+                                frequency_kHz,
+                                adc_sampling_period,
+                            } => {
+                                let pdm_frequency = (frequency_kHz * 1000.) as u32;
+                                if !(MIN_PDM_FREQUENCY_HZ..=MAX_PDM_FREQUENCY_HZ)
+                                    .contains(&pdm_frequency)
+                                {
+                                    warn!("Rejecting out of range frequency: {} Hz", pdm_frequency);
+                                    Response::Error(CommandError::FrequencyOutOfRange)
+                                } else if let Err(e) =
+                                    check_sampling(pdm_frequency, &adc_sampling_period)
+                                {
+                                    Response::Error(e)
+                                } else {
+                                    if let Some(mut t) = pdm_transfer.take() {
+                                        soft_stop(&drive_ramp).await;
+                                        t.request_stop();
+                                        t.await;
+                                    }
+                                    tim.stop();
+                                    drive_pins_low();
+                                    tim.set_frequency(Hertz(pdm_frequency));
+
+                                    adc.smpr2().modify(|w| {
+                                        w.set_smp(
+                                            board::SENSE_ADC_CHANNEL as usize,
+                                            sample_time(&adc_sampling_period),
+                                        )
+                                    });
+
+                                    pdm_transfer = Some(start_pdm());
+                                    update_device_config(|c| {
+                                        c.pdm_frequency_hz = pdm_frequency;
+                                        c.adc_sampling_period = adc_sampling_period;
+                                    });
+                                    // an idle poll ramps up on its own
+                                    if power_state.get() == PowerState::Active {
+                                        ramp_drive_to(Some(gain_level.get()));
+                                    }
+                                    Response::Ack
+                                }
+                            }
+                            Command::SetExcitationMode { mode } => {
+                                info!("Excitation: {}", mode);
+                                // Restart from the first tick, so the new table starts on a whole cycle; fut_demodulate sees the change and starts over like for SetFrequency.
+                                if let Some(mut t) = pdm_transfer.take() {
+                                    soft_stop(&drive_ramp).await;
+                                    t.request_stop();
+                                    t.await;
+                                }
                                 tim.stop();
-                                tim.reset();
+                                drive_pins_low();
+
+                                update_device_config(|c| c.excitation_mode = mode);
+                                pdm_transfer = Some(start_pdm());
+                                // an idle poll picks the new table up when it turns the drive back on
+                                if power_state.get() == PowerState::Active {
+                                    ramp_drive_to(Some(gain_level.get()));
+                                }
+                                Response::Ack
+                            }
+                            Command::SetAdcSampleTime {
+                                adc_sampling_period,
+                            } => {
+                                let pdm_frequency = device_config().pdm_frequency_hz;
+                                match check_sampling(pdm_frequency, &adc_sampling_period) {
+                                    Ok(()) => {
+                                        // The excitation keeps running; fut_demodulate sees the new period and starts a fresh window at the new bin.
+                                        adc.smpr2().modify(|w| {
+                                            w.set_smp(
+                                                board::SENSE_ADC_CHANNEL as usize,
+                                                sample_time(&adc_sampling_period),
+                                            )
+                                        });
+                                        update_device_config(|c| {
+                                            c.adc_sampling_period = adc_sampling_period
+                                        });
+                                        Response::AdcSampleRate {
+                                            sampling_frequency_hz: adc_sampling_period.to_Hz(),
+                                        }
+                                    }
+                                    Err(e) => Response::Error(e),
+                                }
+                            }
+                            Command::GetStatus => {
+                                let config = device_config();
+                                Response::Status(Status {
+                                    adc_overruns: adc_overruns.get(),
+                                    self_test_passed,
+                                    temperature_c: temperature_c.get(),
+                                    gain_level: gain_level.get() as u8,
+                                    phase_std_dev: phase_std_dev.get(),
+                                    pll_phase: pll_phase.get(),
+                                    pll_locked: pll_locked.get(),
+                                    power_state: power_state.get(),
+                                    saturated_samples: saturated_samples.get(),
+                                    rejected_steps: rejected_steps.get(),
+                                    dropped_packets: dropped_packets.get(),
+                                    throttled_packets: throttled_packets.get(),
+                                    direction: direction.get(),
+                                    dma_errors: dma_errors.get(),
+                                    dma_failed: dma_failed.get(),
+                                    unit: config.unit,
+                                })
+                            }
+                            Command::GetReading => {
+                                let reading = reading.get();
+                                let config = device_config();
+                                let position = reading.position - config.tare;
+                                let displayed = display_position.get() - config.tare;
+                                Response::Reading(Reading {
+                                    position,
+                                    position_um: counts_to_um(displayed, config.pitch_um),
+                                    value: match config.unit {
+                                        Unit::Millimeters => {
+                                            counts_to_um(displayed, config.pitch_um)
+                                        }
+                                        Unit::Inches => counts_to_ten_thousandths_inch(
+                                            displayed,
+                                            config.pitch_um,
+                                        ),
+                                        Unit::Counts => displayed,
+                                    },
+                                    unit: config.unit,
+                                    position_fine: reading.position_fine
+                                        - (config.tare << POSITION_FRACTION_BITS),
+                                    ..reading
+                                })
+                            }
+                            Command::Tare => {
+                                update_device_config(|c| c.tare = reading.get().position);
+                                Response::Ack
+                            }
+                            Command::ClearTare => {
+                                update_device_config(|c| c.tare = 0);
+                                Response::Ack
+                            }
+                            Command::CalibrateIqOffset => {
+                                calibration_request.signal(());
+                                let offset = calibration_result.wait().await;
 
-                                tim.set_frequency(Hertz((frequency_kHz * 1000.) as u32));
-                                tim.start();
+                                let (_, magnitude) =
+                                    phase_magnitude((offset.sum_sine, offset.sum_cosine), 0);
+                                if magnitude > MAX_IQ_OFFSET_MAGNITUDE {
+                                    warn!("Rejecting I/Q offset with magnitude {}", magnitude);
+                                    Response::Error(CommandError::CalibrationMagnitudeTooHigh)
+                                } else {
+                                    info!("I/Q offset: {}", offset);
+                                    update_device_config(|c| c.iq_offset = offset);
+                                    Response::IqOffset(offset)
+                                }
+                            }
+                            Command::MeasureNoise => {
+                                noise_request.signal(());
+                                let stats = noise_result.wait().await;
+                                match millivolts_per_count(vrefint_sample) {
+                                    Some(mv_per_count) => {
+                                        let noise = NoiseMeasurement {
+                                            samples: stats.count(),
+                                            mean_mv: stats.mean() * mv_per_count,
+                                            rms_mv: stats.rms() * mv_per_count,
+                                            peak_to_peak_mv: stats.peak_to_peak() as f32
+                                                * mv_per_count,
+                                        };
+                                        info!("Noise: {}", noise);
+                                        Response::Noise(noise)
+                                    }
+                                    None => Response::Error(CommandError::NoVoltageReference),
+                                }
+                            }
+                            Command::SetFilterAlpha { alpha } => {
+                                if alpha > 0.0 && alpha <= 1.0 {
+                                    update_device_config(|c| c.filter_alpha = alpha);
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting filter alpha: {}", alpha);
+                                    Response::Error(CommandError::FilterAlphaOutOfRange)
+                                }
+                            }
+                            Command::SetDisplayDeadband { deadband_um } => {
+                                if deadband_um <= MAX_DISPLAY_DEADBAND_UM {
+                                    update_device_config(|c| c.display_deadband_um = deadband_um);
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting display deadband: {}um", deadband_um);
+                                    Response::Error(CommandError::DeadbandOutOfRange)
+                                }
+                            }
+                            Command::SetUnit { unit } => {
+                                info!("Reading out in {}", unit);
+                                update_device_config(|c| c.unit = unit);
+                                Response::Ack
+                            }
+                            Command::GetConfig => Response::Config(device_config()),
+                            Command::SetStreamMode { mode } => {
+                                info!("Streaming {}", mode);
+                                update_device_config(|c| c.stream_mode = mode);
+                                Response::Ack
+                            }
+                            Command::SetPositionRate { rate_hz } => {
+                                if rate_hz > 0 {
+                                    update_device_config(|c| c.position_rate_hz = rate_hz);
+                                    let interval = position_interval(
+                                        rate_hz,
+                                        &device_config().adc_sampling_period,
+                                    );
+                                    let rate_hz =
+                                        embassy_time::TICK_HZ as f32 / interval.as_ticks() as f32;
+                                    info!("Streaming positions at {} Hz", rate_hz);
+                                    Response::PositionRate { rate_hz }
+                                } else {
+                                    warn!("Rejecting position rate: {} Hz", rate_hz);
+                                    Response::Error(CommandError::PositionRateOutOfRange)
+                                }
+                            }
+                            Command::AddStreamCredits { packets } => {
+                                let available =
+                                    stream_credits.get().unwrap_or(0).saturating_add(packets);
+                                stream_credits.set(Some(available));
+                                Response::StreamCredits { available }
+                            }
+                            Command::SetSampleFormat { format } => {
+                                info!("Streaming samples as {}", format);
+                                update_device_config(|c| c.sample_format = format);
+                                Response::Ack
+                            }
+                            Command::DumpHistory => {
+                                let head = POSITION_HISTORY.lock(|h| h.borrow().head);
+                                let entries = head.min(HISTORY_LEN);
+                                history_dump = Some((head, entries));
+                                Response::History {
+                                    entries: entries as u16,
+                                }
+                            }
+                            #[cfg(feature = "demod-dump")]
+                            Command::DumpDemodulation => {
+                                demod_dump_request.signal(());
+                                demod_dump = Some(demod_dump_result.wait().await);
+                                Response::Demodulation {
+                                    entries: NUM_SAMPLES as u16,
+                                }
+                            }
+                            Command::ScanFrequency {
+                                start_kHz,
+                                stop_kHz,
+                                steps,
+                            } => {
+                                let steps = steps as usize;
+                                let adc_sampling_period = device_config().adc_sampling_period;
+                                let frequency = |i: usize| {
+                                    let step = if steps > 1 {
+                                        (stop_kHz - start_kHz) / (steps - 1) as f64
+                                    } else {
+                                        0.0
+                                    };
+                                    ((start_kHz + step * i as f64) * 1000.) as u32
+                                };
+                                let checked = (0..steps).try_for_each(|i| {
+                                    let pdm_frequency = frequency(i);
+                                    if (MIN_PDM_FREQUENCY_HZ..=MAX_PDM_FREQUENCY_HZ)
+                                        .contains(&pdm_frequency)
+                                    {
+                                        check_sampling(pdm_frequency, &adc_sampling_period)
+                                    } else {
+                                        warn!(
+                                            "Rejecting out of range frequency: {} Hz",
+                                            pdm_frequency
+                                        );
+                                        Err(CommandError::FrequencyOutOfRange)
+                                    }
+                                });
+                                if !(1..=MAX_SCAN_POINTS).contains(&steps) {
+                                    warn!("Rejecting a scan of {} steps", steps);
+                                    Response::Error(CommandError::ScanOutOfRange)
+                                } else if let Err(e) = checked {
+                                    Response::Error(e)
+                                } else if power_state.get() != PowerState::Active {
+                                    Response::Error(CommandError::DeviceIdle)
+                                } else {
+                                    let original = device_config().pdm_frequency_hz;
+                                    let mut points = [ScanPoint::default(); MAX_SCAN_POINTS];
+                                    let mut chosen = original;
+                                    // one pass per point, then a last one onto the winner
+                                    for i in 0..=steps {
+                                        let pdm_frequency = if i < steps {
+                                            frequency(i)
+                                        } else {
+                                            chosen = best_scan_point(&points[..steps])
+                                                .map_or(original, |p| p.pdm_frequency_hz);
+                                            chosen
+                                        };
+                                        if let Some(mut t) = pdm_transfer.take() {
+                                            soft_stop(&drive_ramp).await;
+                                            t.request_stop();
+                                            t.await;
+                                        }
+                                        tim.stop();
+                                        drive_pins_low();
+                                        tim.set_frequency(Hertz(pdm_frequency));
+                                        pdm_transfer = Some(start_pdm());
+                                        update_device_config(|c| {
+                                            c.pdm_frequency_hz = pdm_frequency
+                                        });
+                                        ramp_drive_to(Some(gain_level.get()));
+                                        if i == steps {
+                                            break;
+                                        }
+
+                                        // a point that timed out may have finished since
+                                        scan_result.reset();
+                                        scan_request.signal(());
+                                        points[i] = match with_timeout(
+                                            SCAN_POINT_TIMEOUT,
+                                            scan_result.wait(),
+                                        )
+                                        .await
+                                        {
+                                            Ok(point) => point,
+                                            Err(_) => ScanPoint {
+                                                pdm_frequency_hz: pdm_frequency,
+                                                magnitude: reading.get().magnitude,
+                                                phase_std_dev: phase_std_dev.get(),
+                                                gain_level: gain_level.get() as u8,
+                                                settled: false,
+                                            },
+                                        };
+                                        info!("Scan point: {}", points[i]);
+                                    }
+                                    info!("Scan settled on {} Hz", chosen);
+                                    scan_dump = Some((points, steps));
+                                    Response::FrequencyScan {
+                                        points: steps as u16,
+                                        pdm_frequency_hz: chosen,
+                                    }
+                                }
+                            }
+                            Command::ResetPeakHold => {
+                                peak_hold.set(PeakHold::new());
+                                Response::Ack
+                            }
+                            Command::GetPeakHold => {
+                                let tare = device_config().tare;
+                                // nothing to hold yet, straight after a reset
+                                let (min, max) = peak_hold
+                                    .get()
+                                    .range()
+                                    .map_or((0, 0), |(min, max)| (min - tare, max - tare));
+                                Response::PeakHold {
+                                    position_min: min,
+                                    position_max: max,
+                                    position_range: max - min,
+                                }
+                            }
+                            Command::SetIdle {
+                                timeout_ms,
+                                poll_interval_ms,
+                            } => {
+                                if (1..=MAX_IDLE_POLL_INTERVAL_MS).contains(&poll_interval_ms) {
+                                    update_device_config(|c| {
+                                        c.idle_timeout_ms = timeout_ms;
+                                        c.idle_poll_interval_ms = poll_interval_ms;
+                                    });
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting idle poll interval: {}ms", poll_interval_ms);
+                                    Response::Error(CommandError::IdleOutOfRange)
+                                }
+                            }
+                            #[cfg(feature = "adc-lut")]
+                            Command::SetAdcLut {
+                                offset,
+                                corrections,
+                            } => {
+                                let offset = offset as usize;
+                                if offset + ADC_LUT_CHUNK_LEN <= ADC_LUT_LEN {
+                                    ADC_LUT.lock(|lut| lut.borrow_mut().set(offset, &corrections));
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting ADC LUT chunk at {}", offset);
+                                    Response::Error(CommandError::AdcLutOutOfRange)
+                                }
+                            }
+                            Command::SetPhaseCorrection {
+                                offset,
+                                corrections,
+                            } => {
+                                let offset = offset as usize;
+                                if offset + PHASE_CORRECTION_CHUNK_LEN <= PHASE_CORRECTION_LEN {
+                                    PHASE_CORRECTION
+                                        .lock(|c| c.borrow_mut().set(offset, &corrections));
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting phase correction chunk at {}", offset);
+                                    Response::Error(CommandError::PhaseCorrectionOutOfRange)
+                                }
+                            }
+                            Command::SetPitch { pitch_um } => {
+                                if pitch_um > 0 {
+                                    info!("Pitch: {}um", pitch_um);
+                                    update_device_config(|c| c.pitch_um = pitch_um);
+                                    Response::Ack
+                                } else {
+                                    warn!("Rejecting pitch: {}um", pitch_um);
+                                    Response::Error(CommandError::PitchOutOfRange)
+                                }
+                            }
+                            Command::ResetPhaseHistogram => {
+                                PHASE_HISTOGRAM.lock(|h| {
+                                    *h.borrow_mut() = (PhaseHistogram::new(), Instant::now())
+                                });
+                                Response::Ack
+                            }
+                            Command::DumpPhaseHistogram => {
+                                let (bins, windows, started) = PHASE_HISTOGRAM.lock(|h| {
+                                    let (histogram, started) = &*h.borrow();
+                                    (*histogram.bins(), histogram.total, *started)
+                                });
+                                histogram_dump = Some(bins);
+                                Response::PhaseHistogram {
+                                    bins: PHASE_HISTOGRAM_BINS as u16,
+                                    windows,
+                                    elapsed_ms: started.elapsed().as_millis() as u32,
+                                }
                             }
                             x => {
-                                defmt::todo!("Can't handle: {}", x)
+                                warn!("Can't handle: {}", x);
+                                Response::Error(CommandError::Unsupported)
                             }
+                        };
+                        respond(&mut response_ep, &response).await;
+                        #[cfg(feature = "demod-dump")]
+                        if let Some(capture) = demod_dump {
+                            write_demodulation(&mut response_ep, &capture).await;
+                        }
+                        if let Some((points, count)) = scan_dump {
+                            write_scan(&mut response_ep, &points[..count]).await;
+                        }
+                        if let Some((head, entries)) = history_dump {
+                            write_history(&mut response_ep, head, entries).await;
+                        }
+                        if let Some(bins) = histogram_dump {
+                            write_phase_histogram(&mut response_ep, &bins).await;
                         }
                     } else {
                         error!("Failed to deserialize command");
@@ -288,154 +1845,265 @@ async fn main(_spawner: Spawner) {
                 }
                 Err(e) => {
                     error!("Failed to read USB packet: {:?}", e);
+                    // Reads fail straight away while disconnected, so wait for the host rather than spin.
+                    // DEVICE_CONFIG carries over to the new connection.
+                    read_ep.wait_enabled().await;
+                    respond(&mut response_ep, &Response::Handshake(adc_calibration)).await;
                 }
             };
         }
     };
 
+    let fut_zero_button = async {
+        let mut ticker = Ticker::every(ZERO_BUTTON_POLL_INTERVAL);
+        let mut button = Debouncer::new(
+            false,
+            (ZERO_BUTTON_STABLE_MS / ZERO_BUTTON_POLL_INTERVAL.as_millis()) as u32,
+        );
+        loop {
+            ticker.next().await;
+            if button.update(zero_button.is_low()) == Some(true) {
+                let position = reading.get().position;
+                update_device_config(|c| c.tare = position);
+                info!("Zero button pressed, tare {}", position);
+            }
+        }
+    };
+
+    let fut_status_led = async {
+        let mut ticker = Ticker::every(STATUS_LED_TICK);
+        let mut elapsed_ms: u64 = 0;
+        loop {
+            ticker.next().await;
+            elapsed_ms += STATUS_LED_TICK.as_millis();
+            // the same state GetStatus and GetReading report
+            let reading = reading.get();
+            let pattern = if power_state.get() == PowerState::Idle {
+                &LED_IDLE
+            } else if dma_failed.get() || reading.magnitude < MAX_IQ_OFFSET_MAGNITUDE {
+                &LED_INVALID
+            } else if reading.settling
+                || reading.saturated
+                || reading.magnitude < GAIN_LOW_MAGNITUDE
+            {
+                &LED_DEGRADED
+            } else {
+                &LED_GOOD
+            };
+            let lit = elapsed_ms % pattern.period_ms < pattern.on_ms;
+            status_led.set_level((lit != STATUS_LED_ACTIVE_LOW).into());
+        }
+    };
+
     // Pinning and using join_array saves 1kB of flash compared to join3. (Presumably reduced code size.)
     // embassy_futures::join::join3(fut_commands, fut_usb, fut_stream_adc).await;
 
     let fut_commands = core::pin::pin!(fut_commands);
     let fut_usb = core::pin::pin!(fut_usb);
+    let fut_demodulate = core::pin::pin!(fut_demodulate);
     let fut_stream_adc = core::pin::pin!(fut_stream_adc);
+    let fut_zero_button = core::pin::pin!(fut_zero_button);
+    let fut_stream_positions = core::pin::pin!(fut_stream_positions);
+    let fut_status_led = core::pin::pin!(fut_status_led);
 
-    let futures: [core::pin::Pin<&mut dyn core::future::Future<Output = _>>; 3] =
-        [fut_commands, fut_usb, fut_stream_adc];
+    let futures: [core::pin::Pin<&mut dyn core::future::Future<Output = _>>; 7] = [
+        fut_commands,
+        fut_usb,
+        fut_demodulate,
+        fut_stream_adc,
+        fut_zero_button,
+        fut_stream_positions,
+        fut_status_led,
+    ];
     embassy_futures::join::join_array(futures).await;
 }
 
-static SIGNAL: [u32; 132] = [
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000011010100000000010010101,
-    0b00000000011010100000000010010101,
-    0b00000000010101010000000010101010,
-    0b00000000100101010000000001101010,
-    0b00000000011010100000000010010101,
-    0b00000000011010100000000010010101,
-    0b00000000010101010000000010101010,
-    0b00000000100101010000000001101010,
-    0b00000000011010100000000010010101,
-    0b00000000011010100000000010010101,
-    0b00000000100101010000000001101010,
-    0b00000000100101010000000001101010,
-    0b00000000010110100000000010100101,
-    0b00000000011010100000000010010101,
-    0b00000000100101010000000001101010,
-    0b00000000100101010000000001101010,
-    0b00000000010110100000000010100101,
-    0b00000000010110100000000010100101,
-    0b00000000100101010000000001101010,
-    0b00000000101001010000000001011010,
-    0b00000000010110100000000010100101,
-    0b00000000010110100000000010100101,
-    0b00000000100101010000000001101010,
-    0b00000000101001010000000001011010,
-    0b00000000010110100000000010100101,
-    0b00000000010110100000000010100101,
-    0b00000000101001010000000001011010,
-    0b00000000101001010000000001011010,
-    0b00000000010101100000000010101001,
-    0b00000000010110100000000010100101,
-    0b00000000101001010000000001011010,
-    0b00000000101001010000000001011010,
-    0b00000000010101100000000010101001,
-    0b00000000010101100000000010101001,
-    0b00000000101001010000000001011010,
-    0b00000000101010010000000001010110,
-    0b00000000010101100000000010101001,
-    0b00000000010101100000000010101001,
-    0b00000000101001010000000001011010,
-    0b00000000101010010000000001010110,
-    0b00000000010101100000000010101001,
-    0b00000000010101100000000010101001,
-    0b00000000101010010000000001010110,
-    0b00000000101010010000000001010110,
-    0b00000000010101010000000010101010,
-    0b00000000010101100000000010101001,
-    0b00000000101010010000000001010110,
-    0b00000000101010010000000001010110,
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000101010010000000001010110,
-    0b00000000101010100000000001010101,
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000101010010000000001010110,
-    0b00000000101010100000000001010101,
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000100101010000000001101010,
-    0b00000000010101010000000010101010,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000100101010000000001101010,
-    0b00000000100101010000000001101010,
-    0b00000000101010100000000001010101,
-    0b00000000011010100000000010010101,
-    0b00000000100101010000000001101010,
-    0b00000000100101010000000001101010,
-    0b00000000101010100000000001010101,
-    0b00000000011010100000000010010101,
-    0b00000000100101010000000001101010,
-    0b00000000100101010000000001101010,
-    0b00000000011010100000000010010101,
-    0b00000000011010100000000010010101,
-    0b00000000101001010000000001011010,
-    0b00000000100101010000000001101010,
-    0b00000000011010100000000010010101,
-    0b00000000011010100000000010010101,
-    0b00000000101001010000000001011010,
-    0b00000000101001010000000001011010,
-    0b00000000011010100000000010010101,
-    0b00000000010110100000000010100101,
-    0b00000000101001010000000001011010,
-    0b00000000101001010000000001011010,
-    0b00000000011010100000000010010101,
-    0b00000000010110100000000010100101,
-    0b00000000101001010000000001011010,
-    0b00000000101001010000000001011010,
-    0b00000000010110100000000010100101,
-    0b00000000010110100000000010100101,
-    0b00000000101010010000000001010110,
-    0b00000000101001010000000001011010,
-    0b00000000010110100000000010100101,
-    0b00000000010110100000000010100101,
-    0b00000000101010010000000001010110,
-    0b00000000101010010000000001010110,
-    0b00000000010110100000000010100101,
-    0b00000000010101100000000010101001,
-    0b00000000101010010000000001010110,
-    0b00000000101010010000000001010110,
-    0b00000000010110100000000010100101,
-    0b00000000010101100000000010101001,
-    0b00000000101010010000000001010110,
-    0b00000000101010010000000001010110,
-    0b00000000010101100000000010101001,
-    0b00000000010101100000000010101001,
-    0b00000000101010100000000001010101,
-    0b00000000101010010000000001010110,
-    0b00000000010101100000000010101001,
-    0b00000000010101100000000010101001,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000010101100000000010101001,
-    0b00000000010101010000000010101010,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000010101100000000010101001,
-    0b00000000010101010000000010101010,
-    0b00000000101010100000000001010101,
-    0b00000000101010100000000001010101,
-    0b00000000010101010000000010101010,
-    0b00000000010101010000000010101010,
-    0b00000000011010100000000010010101,
-    0b00000000101010100000000001010101,
-];
+/// Fills in the header of the first `len` bytes of `packet` and, with SAMPLE_PACKET_CRC, the CRC at the end; the body must already be in place.
+fn finish_packet(packet: &mut [u8], len: usize, sequence: u16, timestamp_us: u32) {
+    SamplePacketHeader {
+        sequence,
+        timestamp_us,
+    }
+    .write(packet);
+    if SAMPLE_PACKET_CRC {
+        let body_len = len - SAMPLE_PACKET_CRC_SIZE;
+        let crc = crc16_ccitt(&packet[..body_len]);
+        packet[body_len..len].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+async fn respond(ep: &mut impl EndpointIn, response: &Response) {
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    match response.serialize(&mut buf) {
+        Ok(bs) => {
+            if let Err(e) = ep.write(bs).await {
+                error!("USB Error: {:?}", e);
+            }
+        }
+        Err(_) => error!("Failed to serialize response"),
+    }
+}
+
+/// Sends the `entries` history entries before `head` as history packets, see `HISTORY_ENTRIES_PER_PACKET`.
+/// Entries are read one packet at a time, so the demodulation loop may overwrite the oldest ones before they go out; that's left for the host to spot from the timestamps.
+async fn write_history(ep: &mut impl EndpointIn, head: usize, entries: usize) {
+    let tare = device_config().tare;
+    let first = head.wrapping_sub(entries);
+
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    for (sequence, start) in (0..entries).step_by(HISTORY_ENTRIES_PER_PACKET).enumerate() {
+        buf[0..2].copy_from_slice(&(sequence as u16).to_le_bytes());
+        let count = HISTORY_ENTRIES_PER_PACKET.min(entries - start);
+        POSITION_HISTORY.lock(|h| {
+            let h = h.borrow();
+            for k in 0..count {
+                let mut entry = h.entries[first.wrapping_add(start + k) % HISTORY_LEN];
+                entry.position -= tare;
+                let offset = 2 + k * HistoryEntry::SIZE;
+                entry.write(&mut buf[offset..offset + HistoryEntry::SIZE]);
+            }
+        });
+        if let Err(e) = ep.write(&buf[..2 + count * HistoryEntry::SIZE]).await {
+            error!("USB Error: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Sends `capture` as demodulation packets, see `DEMODULATION_ENTRIES_PER_PACKET`.
+/// The products are worked out here rather than in the demodulation loop, which runs the Goertzel filter and never has them; the sums match its output to within float rounding.
+#[cfg(feature = "demod-dump")]
+async fn write_demodulation(ep: &mut impl EndpointIn, capture: &DemodCapture) {
+    // a one-sample window's advance
+    let step = window_phase_advance(capture.bin / NUM_SAMPLES as f64);
+    let (mut sum_sine, mut sum_cosine) = (0.0, 0.0);
+
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    for (sequence, start) in (0..NUM_SAMPLES)
+        .step_by(DEMODULATION_ENTRIES_PER_PACKET)
+        .enumerate()
+    {
+        buf[0..2].copy_from_slice(&(sequence as u16).to_le_bytes());
+        let count = DEMODULATION_ENTRIES_PER_PACKET.min(NUM_SAMPLES - start);
+        for k in 0..count {
+            let i = start + k;
+            let sample = capture.samples[i];
+            // in the excitation's frame, i.e., the Goertzel's sums rotated by the window phase as fut_demodulate does
+            let angle = capture
+                .window_phase
+                .wrapping_add(step.wrapping_mul(i as i32));
+            let (sine, cosine) = angle_to_radians(angle).sin_cos();
+            let (sine_product, cosine_product) = (sample as f32 * sine, sample as f32 * cosine);
+            sum_sine += sine_product;
+            sum_cosine += cosine_product;
+            let offset = 2 + k * DemodulationEntry::SIZE;
+            DemodulationEntry {
+                sample,
+                sine_product,
+                cosine_product,
+                sum_sine,
+                sum_cosine,
+            }
+            .write(&mut buf[offset..offset + DemodulationEntry::SIZE]);
+        }
+        if let Err(e) = ep.write(&buf[..2 + count * DemodulationEntry::SIZE]).await {
+            error!("USB Error: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Sends `points` as scan packets, see `SCAN_POINTS_PER_PACKET`.
+async fn write_scan(ep: &mut impl EndpointIn, points: &[ScanPoint]) {
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    for (sequence, chunk) in points.chunks(SCAN_POINTS_PER_PACKET).enumerate() {
+        buf[0..2].copy_from_slice(&(sequence as u16).to_le_bytes());
+        for (k, point) in chunk.iter().enumerate() {
+            let offset = 2 + k * ScanPoint::SIZE;
+            point.write(&mut buf[offset..offset + ScanPoint::SIZE]);
+        }
+        if let Err(e) = ep.write(&buf[..2 + chunk.len() * ScanPoint::SIZE]).await {
+            error!("USB Error: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Sends a copy of the phase histogram's bins as histogram packets, see `PHASE_HISTOGRAM_BINS_PER_PACKET`.
+async fn write_phase_histogram(ep: &mut impl EndpointIn, bins: &[u32; PHASE_HISTOGRAM_BINS]) {
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    for (sequence, chunk) in bins.chunks(PHASE_HISTOGRAM_BINS_PER_PACKET).enumerate() {
+        buf[0..2].copy_from_slice(&(sequence as u16).to_le_bytes());
+        for (k, count) in chunk.iter().enumerate() {
+            let offset = 2 + k * 4;
+            buf[offset..offset + 4].copy_from_slice(&count.to_le_bytes());
+        }
+        if let Err(e) = ep.write(&buf[..2 + chunk.len() * 4]).await {
+            error!("USB Error: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// The settled point with the least phase noise, if any settled.
+fn best_scan_point(points: &[ScanPoint]) -> Option<&ScanPoint> {
+    points
+        .iter()
+        .filter(|p| p.settled)
+        .min_by(|a, b| a.phase_std_dev.total_cmp(&b.phase_std_dev))
+}
+
+/// Goertzel bin for the excitation: signal cycles per `NUM_SAMPLES` window.
+fn excitation_bin(pdm_frequency: u32, adc_sampling_period: &AdcSamplingPeriod) -> f64 {
+    let signal_frequency = pdm_frequency as f64 / PDM_SIGNAL.len() as f64;
+    signal_frequency * NUM_SAMPLES as f64 / adc_sampling_period.to_Hz()
+}
+
+/// Rejects an excitation and sample rate that NUM_SAMPLES windows can't demodulate.
+/// The window phase correction copes with any fraction of a cycle per window, but under one cycle the bin runs into DC, and from half a cycle per sample up the excitation aliases.
+/// Below 8 samples per cycle, some ratios also fold a harmonic back onto the excitation, see `aliased_harmonic`.
+fn check_sampling(
+    pdm_frequency: u32,
+    adc_sampling_period: &AdcSamplingPeriod,
+) -> Result<(), CommandError> {
+    let bin = excitation_bin(pdm_frequency, adc_sampling_period);
+    if !(1.0..NUM_SAMPLES as f64 / 2.0).contains(&bin) {
+        warn!(
+            "Rejecting {} Hz PDM sampled at {}: {} cycles per window",
+            pdm_frequency, adc_sampling_period, bin
+        );
+        Err(CommandError::SamplingIncoherent)
+    } else if let Some(h) = aliased_harmonic(bin, NUM_SAMPLES) {
+        warn!(
+            "Rejecting {} Hz PDM sampled at {}: the {}x harmonic aliases onto the excitation",
+            pdm_frequency, adc_sampling_period, h
+        );
+        Err(CommandError::SamplingIncoherent)
+    } else {
+        Ok(())
+    }
+}
+
+/// Time between demodulation windows, in seconds.
+fn window_period(adc_sampling_period: &AdcSamplingPeriod) -> f32 {
+    (NUM_SAMPLES as f64 / adc_sampling_period.to_Hz()) as f32
+}
+
+/// Ticker interval for StreamMode::Positions at `rate_hz`, but no shorter than a window, since faster would only resend the same reading.
+fn position_interval(rate_hz: u32, adc_sampling_period: &AdcSamplingPeriod) -> Duration {
+    let window = Duration::from_micros((window_period(adc_sampling_period) * 1e6) as u64);
+    Duration::from_hz(rate_hz as u64).max(window)
+}
+
+fn sample_time(period: &AdcSamplingPeriod) -> adc::SampleTime {
+    match period {
+        AdcSamplingPeriod::CYCLES1_5 => adc::SampleTime::CYCLES1_5,
+        AdcSamplingPeriod::CYCLES7_5 => adc::SampleTime::CYCLES7_5,
+        AdcSamplingPeriod::CYCLES13_5 => adc::SampleTime::CYCLES13_5,
+        AdcSamplingPeriod::CYCLES28_5 => adc::SampleTime::CYCLES28_5,
+        AdcSamplingPeriod::CYCLES41_5 => adc::SampleTime::CYCLES41_5,
+        AdcSamplingPeriod::CYCLES55_5 => adc::SampleTime::CYCLES55_5,
+        AdcSamplingPeriod::CYCLES71_5 => adc::SampleTime::CYCLES71_5,
+        AdcSamplingPeriod::CYCLES239_5 => adc::SampleTime::CYCLES239_5,
+    }
+}
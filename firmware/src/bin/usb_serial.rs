@@ -1,19 +1,47 @@
 #![no_std]
 #![no_main]
 
+// Two serial ports over USB: text commands, with a line of reply each, on one, and raw ADC samples streaming on the other, so neither has to be muxed into the other.
+//
+// The host tells them apart by USB interface number, commands on interface 0 and data on interface 2, rather than by the port names it hands out, which depend on enumeration order:
+//
+//     Linux    /dev/serial/by-id/...-if00 for commands, ...-if02 for data
+//     macOS    /dev/cu.usbmodem<location>1 for commands, ...3 for data
+//     Windows  the COM port whose device instance path has MI_00 for commands, MI_02 for data
+//
+// The data port streams while it's open (DTR set, which terminals and pyserial do on open); see `data_loop` for the packet layout.
+
+use calipertron_core::{counts_to_um, PositionTracker, DEFAULT_PITCH_UM};
+
+use core::fmt::Write;
+
 use defmt::{panic, *};
 use embassy_executor::Spawner;
-use embassy_futures::join::join;
+use embassy_futures::join::join3;
 use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::peripherals::TIM2;
 use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::low_level::Timer as PdmTimer;
 use embassy_stm32::usb::{Driver, Instance};
 use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Instant, Timer};
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::Builder;
 use {defmt_rtt as _, panic_probe as _};
 
+include!(concat!(env!("OUT_DIR"), "/constants.rs"));
+
+#[macro_use]
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../common.rs"]
+mod common;
+
+use common::measure_phase;
+
 bind_interrupts!(struct Irqs {
     USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
 });
@@ -24,6 +52,26 @@ bind_interrupts!(struct AdcIrqs {
 
 const MAX_PACKET_SIZE: u8 = 64;
 
+// Each ACM function is an interface association (8 bytes), its communication interface (9) with header, ACM, union and call management descriptors (5 + 4 + 5 + 5) and interrupt endpoint (7), then its data interface (9) with two bulk endpoints (7 each).
+const CDC_ACM_DESCRIPTOR_LEN: usize = 8 + 9 + 5 + 4 + 5 + 5 + 7 + 9 + 2 * 7;
+// the configuration descriptor's own 9 bytes, then both functions
+const CONFIG_DESCRIPTOR_LEN: usize = 9 + 2 * CDC_ACM_DESCRIPTOR_LEN;
+const _: () = assert!(CONFIG_DESCRIPTOR_LEN <= 256);
+// Packet memory is 512 bytes, the buffer table's 64 included: control takes 2 * 64, and each function 2 * 64 for bulk and 8 for interrupt, 464 in all.
+const _: () =
+    assert!(64 + 2 * MAX_PACKET_SIZE as usize + 2 * (2 * MAX_PACKET_SIZE as usize + 8) <= 512);
+
+// A data packet's timestamp, then as many samples as fill the rest.
+const DATA_HEADER_SIZE: usize = 4;
+const DATA_SAMPLES_PER_PACKET: usize = (MAX_PACKET_SIZE as usize - DATA_HEADER_SIZE) / 2;
+
+// Longest command line accepted; anything longer is thrown away up to the next newline.
+const MAX_LINE_LENGTH: usize = 32;
+
+// Same range usb_custom allows for SetFrequency.
+const MIN_PDM_FREQUENCY_HZ: u32 = 1_000;
+const MAX_PDM_FREQUENCY_HZ: u32 = 500_000;
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let mut config = Config::default();
@@ -44,6 +92,11 @@ async fn main(_spawner: Spawner) {
         config.rcc.apb2_pre = APBPrescaler::DIV1;
     }
     let mut p = embassy_stm32::init(config);
+    let board = take_board!(p);
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
+    // cycle counter times `pos` windows, see measure_phase; embassy-time's 32 kHz tick is too coarse
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
 
     info!("Hello World!");
 
@@ -54,18 +107,33 @@ async fn main(_spawner: Spawner) {
         Timer::after_millis(10).await;
     }
 
+    ////////////////////////
+    // Signal emission setup
+
+    // Runs for as long as the firmware does; `freq` only changes the timer under it.
+    let excitation =
+        common::start_excitation(board.electrodes, board.test_point, board.pdm_dma, p.TIM2);
+
     let driver = Driver::new(p.USB, Irqs, p.PA12, p.PA11);
     let (vid, pid) = (0xc0de, 0xcafe);
     let mut config = embassy_usb::Config::new(vid, pid);
     config.max_packet_size_0 = MAX_PACKET_SIZE;
+    config.product = Some("Calipertron serial");
+    // With two functions, each one's pair of interfaces is grouped by an interface association descriptor, which this device class tells the host to look for.
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
 
     // Create embassy-usb DeviceBuilder using the driver and config.
-    // It needs some buffers for building the descriptors.
+    // It needs some buffers for building the descriptors; see CONFIG_DESCRIPTOR_LEN.
     let mut config_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
+    // SET_LINE_CODING's 7 bytes are the most either function takes
     let mut control_buf = [0; 7];
 
-    let mut state = State::new();
+    let mut command_state = State::new();
+    let mut data_state = State::new();
 
     let mut builder = Builder::new(
         driver,
@@ -76,26 +144,46 @@ async fn main(_spawner: Spawner) {
         &mut control_buf,
     );
 
-    let mut class = CdcAcmClass::new(&mut builder, &mut state, MAX_PACKET_SIZE as u16);
+    // Interfaces are numbered in the order the functions are added: commands get 0 and 1, data 2 and 3.
+    let mut command_class =
+        CdcAcmClass::new(&mut builder, &mut command_state, MAX_PACKET_SIZE as u16);
+    let mut data_class = CdcAcmClass::new(&mut builder, &mut data_state, MAX_PACKET_SIZE as u16);
     let mut usb = builder.build();
     let usb_fut = usb.run();
 
     let mut adc = Adc::new(p.ADC1);
-    let mut pin = p.PB1;
+    adc.set_sample_time(adc::SampleTime::CYCLES71_5);
+    // shared by `pos` and the data stream, which take turns a window or a packet at a time
+    let adc = Mutex::<NoopRawMutex, _>::new((adc, board.sense_pin));
+
+    let mut caliper = Caliper {
+        tracker: PositionTracker::new(),
+        zero: 0,
+        pdm_frequency_hz: PDM_FREQUENCY,
+    };
 
-    let fut = async {
+    let fut_commands = async {
         loop {
-            class.wait_connection().await;
-            info!("Connected");
-            //let _ = echo(&mut class).await;
-            let _ = stream_adc(&mut class, &mut adc, &mut pin).await;
-            info!("Disconnected");
+            command_class.wait_connection().await;
+            info!("Command port connected");
+            //let _ = echo(&mut command_class).await;
+            let _ = command_loop(&mut command_class, &adc, &excitation.tim, &mut caliper).await;
+            info!("Command port disconnected");
+        }
+    };
+
+    let fut_data = async {
+        loop {
+            data_class.wait_connection().await;
+            info!("Data port connected");
+            let _ = data_loop(&mut data_class, &adc).await;
+            info!("Data port disconnected");
         }
     };
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, fut).await;
+    join3(usb_fut, fut_commands, fut_data).await;
 }
 
 struct Disconnected {}
@@ -122,31 +210,163 @@ async fn echo<'d, T: Instance + 'd>(
 }
 
 use embassy_stm32::adc;
-use embassy_stm32::adc::Adc;
+use embassy_stm32::adc::{Adc, AdcChannel};
 use embassy_stm32::peripherals::ADC1;
 
-async fn stream_adc<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
-    adc: &mut Adc<'d, ADC1>,
-    pin: &mut impl embassy_stm32::adc::AdcChannel<ADC1>,
-) -> Result<(), Disconnected> {
-    let mut vrefint = adc.enable_vref();
-    let vrefint_sample = adc.read(&mut vrefint).await;
-    let convert_to_millivolts = |sample| {
-        const VREFINT_MV: u32 = 1200;
-        (u32::from(sample) * VREFINT_MV / u32::from(vrefint_sample)) as u16
+/// State the text commands act on; carries over between connections.
+struct Caliper {
+    tracker: PositionTracker,
+    /// Untared position reported as zero, in counts.
+    zero: i64,
+    pdm_frequency_hz: u32,
+}
+
+enum TextCommand {
+    /// `pos`: measure a window and report position.
+    Position,
+    /// `zero`: make the current position the origin.
+    Zero,
+    /// `freq <Hz>`: set the PDM tick rate.
+    Frequency(u32),
+}
+
+fn parse_command(line: &str) -> Result<TextCommand, &'static str> {
+    let mut words = line.split_ascii_whitespace();
+    let command = match words.next() {
+        Some("pos") => TextCommand::Position,
+        Some("zero") => TextCommand::Zero,
+        Some("freq") => {
+            let frequency = words.next().ok_or("usage: freq <Hz>")?;
+            TextCommand::Frequency(
+                frequency
+                    .parse()
+                    .map_err(|_| "frequency must be an integer")?,
+            )
+        }
+        _ => return Err("unknown command, try pos, zero, or freq <Hz>"),
     };
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(command)
+}
 
+/// Reads lines like `freq 100000` from a serial terminal and answers each with one line of text.
+async fn command_loop<'d, T: Instance + 'd, P: AdcChannel<ADC1>>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    adc: &Mutex<NoopRawMutex, (Adc<'d, ADC1>, P)>,
+    tim: &PdmTimer<'d, TIM2>,
+    caliper: &mut Caliper,
+) -> Result<(), Disconnected> {
     let mut buf = [0u8; MAX_PACKET_SIZE as usize];
-    let samples_per_packet = (MAX_PACKET_SIZE as usize) / 2; // 2 bytes per sample
+    let mut line = heapless::Vec::<u8, MAX_LINE_LENGTH>::new();
+    // set once the line has overflowed, so the rest of it is dropped rather than parsed as a new command
+    let mut discarding = false;
 
     loop {
-        for i in 0..samples_per_packet {
-            let v = adc.read(pin).await;
-            let mv = convert_to_millivolts(v);
-            buf[i * 2] = (mv >> 8) as u8;
-            buf[i * 2 + 1] = mv as u8;
+        // a command can arrive split across packets, or several in one
+        let n = class.read_packet(&mut buf).await?;
+        for &byte in &buf[..n] {
+            // terminals send \r, \n, or both
+            if byte != b'\r' && byte != b'\n' {
+                if !discarding && line.push(byte).is_err() {
+                    warn!(
+                        "Command line longer than {} bytes, dropping it",
+                        MAX_LINE_LENGTH
+                    );
+                    line.clear();
+                    discarding = true;
+                }
+                continue;
+            }
+
+            let mut reply = heapless::String::<{ MAX_PACKET_SIZE as usize }>::new();
+            if discarding {
+                let _ = core::write!(reply, "error: line too long\r\n");
+                discarding = false;
+            } else if line.is_empty() {
+                continue;
+            } else {
+                match core::str::from_utf8(&line)
+                    .map_err(|_| "not text")
+                    .and_then(parse_command)
+                {
+                    Ok(command) => run_command(command, adc, tim, caliper, &mut reply).await,
+                    Err(e) => {
+                        let _ = core::write!(reply, "error: {}\r\n", e);
+                    }
+                }
+                line.clear();
+            }
+            class.write_packet(reply.as_bytes()).await?;
+        }
+    }
+}
+
+async fn run_command<'d, P: AdcChannel<ADC1>>(
+    command: TextCommand,
+    adc: &Mutex<NoopRawMutex, (Adc<'d, ADC1>, P)>,
+    tim: &PdmTimer<'d, TIM2>,
+    caliper: &mut Caliper,
+    reply: &mut impl Write,
+) {
+    // replies are a line each, well under a packet, so formatting can't run out of room
+    let _ = match command {
+        TextCommand::Position => {
+            let (angle, magnitude) = {
+                let (adc, pin) = &mut *adc.lock().await;
+                measure_phase(adc, pin, caliper.pdm_frequency_hz).await
+            };
+            // Windows are as far apart as the host's commands, so a move of more than half a pitch between them goes unnoticed.
+            let position = caliper.tracker.update_angle(angle) - caliper.zero;
+            let um = counts_to_um(position, DEFAULT_PITCH_UM);
+            core::write!(
+                reply,
+                "{:.3} mm ({} counts, magnitude {:.0})\r\n",
+                um as f32 / 1000.0,
+                position,
+                magnitude
+            )
+        }
+        TextCommand::Zero => {
+            caliper.zero = caliper.tracker.position();
+            core::write!(reply, "ok\r\n")
+        }
+        TextCommand::Frequency(pdm_frequency) => {
+            if (MIN_PDM_FREQUENCY_HZ..=MAX_PDM_FREQUENCY_HZ).contains(&pdm_frequency) {
+                tim.set_frequency(Hertz(pdm_frequency));
+                caliper.pdm_frequency_hz = pdm_frequency;
+                core::write!(reply, "ok\r\n")
+            } else {
+                core::write!(
+                    reply,
+                    "error: frequency must be {}..={} Hz\r\n",
+                    MIN_PDM_FREQUENCY_HZ,
+                    MAX_PDM_FREQUENCY_HZ
+                )
+            }
+        }
+    };
+}
+
+/// Streams samples for as long as the host holds the data port open, one packet at a time: a little-endian u32 timestamp in µs of the first sample, then DATA_SAMPLES_PER_PACKET little-endian u16 raw 12-bit samples.
+/// Samples within a packet are back to back, but there's a gap between packets wherever a `pos` window or a host slow to read got in, which the timestamps show.
+async fn data_loop<'d, T: Instance + 'd, P: AdcChannel<ADC1>>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    adc: &Mutex<NoopRawMutex, (Adc<'d, ADC1>, P)>,
+) -> Result<(), Disconnected> {
+    let mut packet = [0u8; DATA_HEADER_SIZE + 2 * DATA_SAMPLES_PER_PACKET];
+    // closing the port drops DTR but leaves the endpoints up, so that's the end of a connection here
+    while class.dtr() {
+        {
+            let (adc, pin) = &mut *adc.lock().await;
+            let timestamp_us = Instant::now().as_micros() as u32;
+            packet[..DATA_HEADER_SIZE].copy_from_slice(&timestamp_us.to_le_bytes());
+            for bytes in packet[DATA_HEADER_SIZE..].chunks_exact_mut(2) {
+                bytes.copy_from_slice(&adc.read(pin).await.to_le_bytes());
+            }
         }
-        class.write_packet(&buf).await?;
+        class.write_packet(&packet).await?;
     }
+    Ok(())
 }
@@ -0,0 +1,63 @@
+//! Pins, ADC channels and DMA channels of the board the firmware is built for, selected with one `board-*` cargo feature.
+//! Each binary pulls this in with `#[path]`, since they're separate crates with no firmware library between them, and uses whichever parts it needs.
+//!
+//! To add a board:
+//!
+//! 1. Copy `v1_1.rs` to a new module and change what differs; keep every name, since the binaries use them all between them.
+//! 2. Add a `board-<name>` feature to Cargo.toml, and a `#[cfg]` module and re-export for it below.
+//! 3. Add the feature to the `compile_error!` below, along with one for it being enabled together with another board, and build every binary with `--no-default-features --features board-<name>`.
+//!
+//! The binaries still assume an STM32F103: ADC1, TIM2 driving the excitation through DMA, and the clock tree in each main.
+//! A board can move the electrodes and sense pins, but not onto peripherals or DMA requests the F103 doesn't route that way.
+//! What the binaries share beyond that, such as reading back the clock tree and the startup log, is in `common.rs` rather than here.
+
+// each binary uses a different subset
+#![allow(dead_code, unused_macros)]
+
+#[cfg(feature = "board-v1-1")]
+#[macro_use]
+mod v1_1;
+#[cfg(feature = "board-v1-1")]
+pub use v1_1::*;
+
+#[cfg(not(any(feature = "board-v1-1")))]
+compile_error!("select a board with one of the board-* features, e.g. board-v1-1");
+
+/// Moved out of `embassy_stm32::Peripherals` by the board's `take_board!`, leaving the rest for the binary.
+pub struct Board {
+    /// Electrode drive pins, bit `i` of `PDM_PIN_MASK` and the BSRR words in `PDM_SIGNAL` being `electrodes[i]`.
+    pub electrodes: [embassy_stm32::gpio::AnyPin; 8],
+    /// Pickup electrode into the ADC, on `SENSE_ADC_CHANNEL`.
+    pub sense_pin: SensePin,
+    /// Second pickup electrode for local's differential mode, on `RETURN_ADC_CHANNEL`.
+    pub return_pin: ReturnPin,
+    /// Logic analyzer test point, `TEST_POINT_PIN` on ELECTRODE_PORT, for the tables to copy a phase onto; see `test_point`.
+    pub test_point: TestPointPin,
+    /// Carries ADC1 conversions, the request fixed by the F103 (reference manual table 78); `ADC_DMA_CHANNEL` in the PAC's numbering.
+    pub adc_dma: AdcDma,
+    /// Carries PDM_SIGNAL to the electrodes on TIM2 updates, likewise; `PDM_DMA_CHANNEL` in the PAC's numbering.
+    pub pdm_dma: PdmDma,
+}
+
+// The binaries set sample times through SMPR2, which covers channels 0 to 9.
+const _: () = assert!(SENSE_ADC_CHANNEL <= 9 && RETURN_ADC_CHANNEL <= 9);
+
+/// Drives the test point when build.rs was asked to copy a phase onto it (`test_point` being its `TEST_POINT`), so it shows what the DMA writes to BSRR; keep the pin for as long as the excitation runs.
+/// See `test_point_from_env` in build.rs for reading a capture against the table.
+pub fn test_point(
+    pin: TestPointPin,
+    test_point: Option<(u8, usize)>,
+) -> Option<embassy_stm32::gpio::Output<'static>> {
+    let (number, phase) = test_point?;
+    defmt::assert_eq!(
+        number,
+        TEST_POINT_PIN,
+        "build.rs puts the test point on a different pin from the board's"
+    );
+    defmt::info!("Test point PA{} mirrors phase {}", number, phase);
+    Some(embassy_stm32::gpio::Output::new(
+        pin,
+        embassy_stm32::gpio::Level::Low,
+        embassy_stm32::gpio::Speed::Low,
+    ))
+}
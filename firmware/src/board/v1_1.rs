@@ -0,0 +1,45 @@
+//! The v1.1 PCB, an STM32F103C8 with the electrodes on PA0--PA7 and the pickup on PB1; the layout every binary was written against.
+
+use embassy_stm32::peripherals;
+
+pub type SensePin = peripherals::PB1;
+pub const SENSE_ADC_CHANNEL: u8 = 9;
+pub type ReturnPin = peripherals::PB0;
+pub const RETURN_ADC_CHANNEL: u8 = 8;
+/// Free on every binary, and on ELECTRODE_PORT, as the test point has to be; build.rs's TEST_POINT_PIN.
+pub type TestPointPin = peripherals::PA10;
+pub const TEST_POINT_PIN: u8 = 10;
+
+// DMA1 channels, numbered from 0 as in the PAC: ADC1 requests are wired to channel 1 and TIM2_UP to channel 2 (reference manual table 78).
+pub type AdcDma = peripherals::DMA1_CH1;
+pub const ADC_DMA_CHANNEL: usize = 0;
+pub type PdmDma = peripherals::DMA1_CH2;
+pub const PDM_DMA_CHANNEL: usize = 1;
+
+/// The crystal on HSE; the clock setup in each main assumes it.
+pub const HSE_HZ: u32 = 8_000_000;
+
+/// The GPIO port the electrodes are on; PDM_SIGNAL is written to its BSRR.
+pub const ELECTRODE_PORT: embassy_stm32::pac::gpio::Gpio = embassy_stm32::pac::GPIOA;
+
+macro_rules! take_board {
+    ($p:ident) => {
+        board::Board {
+            electrodes: [
+                embassy_stm32::gpio::Pin::degrade($p.PA0),
+                embassy_stm32::gpio::Pin::degrade($p.PA1),
+                embassy_stm32::gpio::Pin::degrade($p.PA2),
+                embassy_stm32::gpio::Pin::degrade($p.PA3),
+                embassy_stm32::gpio::Pin::degrade($p.PA4),
+                embassy_stm32::gpio::Pin::degrade($p.PA5),
+                embassy_stm32::gpio::Pin::degrade($p.PA6),
+                embassy_stm32::gpio::Pin::degrade($p.PA7),
+            ],
+            sense_pin: $p.PB1,
+            return_pin: $p.PB0,
+            test_point: $p.PA10,
+            adc_dma: $p.DMA1_CH1,
+            pdm_dma: $p.DMA1_CH2,
+        }
+    };
+}
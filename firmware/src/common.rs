@@ -0,0 +1,343 @@
+//! What the binaries share that isn't about which board they're built for.
+//! Pulled in with `#[path]` like `board`, which it expects alongside it, and uses the constants.rs from build.rs that every binary includes at its root.
+
+// each binary uses a different subset
+#![allow(dead_code)]
+
+use crate::board;
+use calipertron_core::dsp::{cordic_atan2, Goertzel};
+use embassy_stm32::adc::{Adc, AdcChannel};
+use embassy_stm32::dma::{Transfer, TransferOptions};
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+use embassy_stm32::peripherals::{ADC1, TIM2};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::low_level::Timer;
+use num_traits::Float;
+
+// ADC reads per measure_phase window; at CYCLES71_5 that's a few excitation cycles at the default PDM frequency.
+pub const MEASURE_SAMPLES: usize = 256;
+// SYSCLK as usb_serial, flash_logger and display configure RCC, which measure_phase times its window against.
+pub const CORE_CLOCK_HZ: u32 = 72_000_000;
+// Below this received amplitude (ADC counts, peak) measure_phase's phase is noise.
+pub const MIN_SIGNAL_AMPLITUDE: f32 = 16.0;
+pub const MIN_MEASURE_MAGNITUDE: f32 = MIN_SIGNAL_AMPLITUDE * MEASURE_SAMPLES as f32 / 2.0;
+
+/// PDM_SIGNAL on the electrodes in PDM_PIN_MASK, a tick per TIM2 update through circular DMA, as usb_serial, flash_logger and display drive them.
+/// Runs for as long as this is kept; `tim` changes the tick rate under it.
+pub struct Excitation {
+    pub tim: Timer<'static, TIM2>,
+    _pins: heapless::Vec<Output<'static>, 8>,
+    _test_point: Option<Output<'static>>,
+    _transfer: Transfer<'static>,
+}
+
+/// Starts the excitation at PDM_FREQUENCY, from the board's electrodes, test point and PDM DMA channel.
+pub fn start_excitation(
+    electrodes: [AnyPin; 8],
+    test_point: board::TestPointPin,
+    pdm_dma: board::PdmDma,
+    tim2: TIM2,
+) -> Excitation {
+    let pins: heapless::Vec<Output<'static>, 8> = electrodes
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| crate::PDM_PIN_MASK & (1 << idx) != 0)
+        .map(|(_, pin)| Output::new(pin, Level::Low, Speed::Low))
+        .collect();
+    let test_point = board::test_point(test_point, crate::TEST_POINT);
+
+    let tim = Timer::new(tim2);
+    let timer_registers = tim.regs_gp16();
+    timer_registers
+        .cr2()
+        .modify(|w| w.set_ccds(embassy_stm32::pac::timer::vals::Ccds::ONUPDATE));
+    timer_registers.dier().modify(|w| w.set_ude(true)); // update DMA request
+    tim.set_frequency(Hertz(crate::PDM_FREQUENCY));
+
+    let transfer = unsafe {
+        let mut opts = TransferOptions::default();
+        opts.circular = true;
+
+        let request = embassy_stm32::timer::UpDma::request(&pdm_dma);
+        tim.reset();
+        let t = Transfer::new_write(
+            pdm_dma,
+            request,
+            &crate::PDM_SIGNAL,
+            board::ELECTRODE_PORT.bsrr().as_ptr() as *mut u32,
+            opts,
+        );
+        tim.start();
+        t
+    };
+
+    Excitation {
+        tim,
+        _pins: pins,
+        _test_point: test_point,
+        _transfer: transfer,
+    }
+}
+
+/// Reads a window of MEASURE_SAMPLES and returns their phase relative to the excitation, and magnitude.
+/// Reads are one at a time, so rather than assume a sample rate the window is timed, on the DWT cycle counter the binary has to have enabled, and the Goertzel bin worked out from that.
+pub async fn measure_phase<'d>(
+    adc: &mut Adc<'d, ADC1>,
+    pin: &mut impl AdcChannel<ADC1>,
+    pdm_frequency_hz: u32,
+) -> (i32, f32) {
+    let mut samples = [0u16; MEASURE_SAMPLES];
+    let pdm_signal_len = crate::PDM_SIGNAL.len();
+
+    // excitation phase at the start of the window, from how far the PDM DMA is through PDM_SIGNAL
+    let remaining = embassy_stm32::pac::DMA1
+        .ch(board::PDM_DMA_CHANNEL)
+        .ndtr()
+        .read()
+        .ndt() as usize;
+    let tick = (pdm_signal_len - remaining) % pdm_signal_len;
+    let start_phase = ((tick as u64) << 32).div_euclid(pdm_signal_len as u64) as u32 as i32;
+    let start = cortex_m::peripheral::DWT::cycle_count();
+
+    for x in samples.iter_mut() {
+        *x = adc.read(pin).await;
+    }
+
+    let elapsed = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start);
+    let window_seconds = elapsed as f32 / CORE_CLOCK_HZ as f32;
+    let signal_frequency = pdm_frequency_hz as f32 / pdm_signal_len as f32;
+
+    // The window doesn't span a whole number of cycles, so take out DC before it leaks into the bin.
+    let mean = samples.iter().map(|x| *x as u32).sum::<u32>() / MEASURE_SAMPLES as u32;
+    let mut goertzel = Goertzel::new(MEASURE_SAMPLES, signal_frequency * window_seconds);
+    for x in samples.iter() {
+        goertzel.push((*x as i32 - mean as i32) as i16);
+    }
+    let (sum_sine, sum_cosine) = goertzel.iq();
+    let magnitude = (sum_sine * sum_sine + sum_cosine * sum_cosine).sqrt();
+    let angle = cordic_atan2(sum_sine as i32, sum_cosine as i32).wrapping_add(start_phase);
+    (angle, magnitude)
+}
+
+// The internal temperature sensor's typical figures (datasheet section 5.3.19): 1.43V at 25°C, falling 4.3mV/°C.
+const TEMPERATURE_SENSOR_V25_MV: f32 = 1430.0;
+const TEMPERATURE_SENSOR_SLOPE_MV_PER_C: f32 = 4.3;
+
+/// Converts the internal temperature sensor's voltage to °C.
+/// Parts vary by several degrees from the typical figures, but the slope is what temperature compensation depends on.
+pub fn sensor_temperature_c(millivolts: u16) -> f32 {
+    25.0 + (TEMPERATURE_SENSOR_V25_MV - millivolts as f32) / TEMPERATURE_SENSOR_SLOPE_MV_PER_C
+}
+
+// The F103's internal RC oscillator, fixed by the chip (datasheet section 5.3.7).
+const HSI_HZ: u32 = 8_000_000;
+
+/// The clock tree as RCC is actually running it, decoded from CFGR rather than taken from the `Config` a main passed to `embassy_stm32::init`.
+pub struct Clocks {
+    pub sys_hz: u32,
+    pub ahb_hz: u32,
+    pub apb1_hz: u32,
+    pub apb2_hz: u32,
+    /// TIM2 to TIM4, which run at twice APB1 whenever APB1 is divided (reference manual section 7.2).
+    pub apb1_timer_hz: u32,
+    pub adc_hz: u32,
+}
+
+/// Reads back the clock tree; HSE is taken to be the board's `HSE_HZ`, the one frequency the registers can't tell.
+pub fn clocks() -> Clocks {
+    let cfgr = embassy_stm32::pac::RCC.cfgr().read();
+    // encodings from the CFGR description, reference manual section 7.3.2
+    let pll_input_hz = if cfgr.pllsrc().to_bits() == 0 {
+        HSI_HZ / 2
+    } else {
+        board::HSE_HZ >> cfgr.pllxtpre().to_bits()
+    };
+    let pll_mul = (cfgr.pllmul().to_bits() as u32 + 2).min(16);
+    let sys_hz = match cfgr.sws().to_bits() {
+        0 => HSI_HZ,
+        1 => board::HSE_HZ,
+        _ => pll_input_hz * pll_mul,
+    };
+    let ahb_shift = match cfgr.hpre().to_bits() {
+        0..=7 => 0,
+        // there's no divide by 32
+        bits @ 8..=11 => bits - 7,
+        bits => bits - 6,
+    };
+    let apb_shift = |bits: u8| bits.saturating_sub(3);
+    let ahb_hz = sys_hz >> ahb_shift;
+    let apb1_hz = ahb_hz >> apb_shift(cfgr.ppre1().to_bits());
+    let apb2_hz = ahb_hz >> apb_shift(cfgr.ppre2().to_bits());
+    Clocks {
+        sys_hz,
+        ahb_hz,
+        apb1_hz,
+        apb2_hz,
+        apb1_timer_hz: if apb1_hz == ahb_hz {
+            apb1_hz
+        } else {
+            2 * apb1_hz
+        },
+        adc_hz: apb2_hz / (2 * (cfgr.adcpre().to_bits() as u32 + 1)),
+    }
+}
+
+// Transfer errors on a channel within DMA_ERROR_INTERVAL of each other before it's given up on rather than restarted.
+const MAX_DMA_RESTARTS: u32 = 3;
+const DMA_ERROR_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+/// Call after (re)configuring a transfer on `channel`, which turns its error interrupt back on; see `DmaErrors`.
+pub fn mask_dma_error_interrupt(channel: usize) {
+    embassy_stm32::pac::DMA1
+        .ch(channel)
+        .cr()
+        .modify(|w| w.set_teie(false));
+}
+
+/// Transfer errors (TEIF) on one DMA1 channel, which the hardware answers by disabling the channel.
+/// Embassy's DMA interrupt panics on one, so the error interrupt is masked once the transfer is set up and the flag polled here instead.
+/// A one-off, such as a glitch on the bus, is recovered from by restarting the channel at the start of its buffer; one that keeps coming back is a misconfiguration or a fault that restarting won't fix, so the channel is left off.
+pub struct DmaErrors {
+    name: &'static str,
+    channel: usize,
+    /// Transfers per pass through the channel's buffer.
+    len: u16,
+    recent: u32,
+    last: Option<embassy_time::Instant>,
+    pub total: u32,
+    pub failed: bool,
+}
+
+impl DmaErrors {
+    pub fn new(name: &'static str, channel: usize, len: usize) -> Self {
+        DmaErrors {
+            name,
+            channel,
+            len: len as u16,
+            recent: 0,
+            last: None,
+            total: 0,
+            failed: false,
+        }
+    }
+
+    /// Checks for a transfer error since the last call, restarting the channel or giving up on it; returns whether there was one.
+    pub fn poll(&mut self) -> bool {
+        let dma = embassy_stm32::pac::DMA1;
+        if !dma.isr().read().teif(self.channel) {
+            return false;
+        }
+        dma.ifcr().write(|w| w.set_teif(self.channel, true));
+        self.total += 1;
+        self.recent = match self.last {
+            Some(last) if last.elapsed() < DMA_ERROR_INTERVAL => self.recent + 1,
+            _ => 1,
+        };
+        self.last = Some(embassy_time::Instant::now());
+
+        if self.recent > MAX_DMA_RESTARTS {
+            self.failed = true;
+            defmt::error!(
+                "{} DMA transfer error {} times in a row, leaving the channel off; check for a bus fault or a bad address",
+                self.name, self.recent
+            );
+            return true;
+        }
+        defmt::warn!(
+            "{} DMA transfer error, {} since boot; restarting the channel, position may jump",
+            self.name,
+            self.total
+        );
+        self.restart();
+        true
+    }
+
+    /// Restarts the channel from the start of its buffer, so the transfer lines up with it again.
+    pub fn restart(&self) {
+        let ch = embassy_stm32::pac::DMA1.ch(self.channel);
+        // NDTR is only writable while the channel is off
+        ch.cr().modify(|w| w.set_en(false));
+        ch.ndtr().write(|w| w.set_ndt(self.len));
+        ch.cr().modify(|w| w.set_en(true));
+    }
+}
+
+// Everything below logs at info level with a "Startup:" prefix, a line per fact in a fixed order, so two boots' logs can be diffed line for line.
+
+pub fn log_clocks(clocks: &Clocks) {
+    defmt::info!(
+        "Startup: SYSCLK {} Hz, AHB {} Hz, APB1 {} Hz (timers {} Hz), APB2 {} Hz, ADC {} Hz",
+        clocks.sys_hz,
+        clocks.ahb_hz,
+        clocks.apb1_hz,
+        clocks.apb1_timer_hz,
+        clocks.apb2_hz,
+        clocks.adc_hz
+    );
+}
+
+/// Logs how ADC1 was left by `embassy_stm32::adc::Adc::new` and the binary's configuration: calibration, the regular sequence and `channel`'s sample time.
+pub fn log_adc(vrefint_sample: u16, channel: u8) {
+    let adc = embassy_stm32::pac::ADC1;
+    let cr2 = adc.cr2().read();
+    defmt::info!(
+        "Startup: ADC1 on {}, calibration {}, VREFINT sample {}",
+        cr2.adon(),
+        if cr2.cal() || cr2.rstcal() {
+            "still running"
+        } else {
+            "done"
+        },
+        vrefint_sample
+    );
+    defmt::info!(
+        "Startup: ADC1 {} regular conversions, first on channel {}; channel {} sample time code {}, DMA {}, continuous {}, external trigger {}",
+        adc.sqr1().read().l() + 1,
+        adc.sqr3().read().sq(0),
+        channel,
+        adc.smpr2().read().smp(channel as usize).to_bits(),
+        cr2.dma(),
+        cr2.cont(),
+        cr2.exttrig()
+    );
+}
+
+/// Logs a DMA1 channel, numbered from 0 as in the PAC and logged from 1 as in the reference manual.
+pub fn log_dma_channel(name: &str, channel: usize) {
+    let ch = embassy_stm32::pac::DMA1.ch(channel);
+    let cr = ch.cr().read();
+    defmt::info!(
+        "Startup: {} on DMA1 channel {}, peripheral address {:#010x}, {} transfers left, enabled {}, circular {}",
+        name,
+        channel + 1,
+        ch.par().read(),
+        ch.ndtr().read().ndt(),
+        cr.en(),
+        cr.circ()
+    );
+}
+
+/// Logs TIM2's update rate, which steps the PDM DMA, and the excitation frequency that gives over the `pdm_signal_len` ticks of PDM_SIGNAL.
+/// Counting an external reference on ETR, the rate depends on a frequency the registers don't hold, so it's logged in reference cycles instead.
+pub fn log_excitation(clocks: &Clocks, pdm_signal_len: usize) {
+    let tim = embassy_stm32::pac::TIM2;
+    let prescaler = tim.psc().read().psc() as u32 + 1;
+    let reload = tim.arr().read().arr() as u32 + 1;
+    if tim.smcr().read().ece() {
+        defmt::info!(
+            "Startup: TIM2 counting ETR, {} reference cycles per PDM tick, {} ticks per excitation cycle",
+            prescaler * reload,
+            pdm_signal_len
+        );
+        return;
+    }
+    let tick_hz = clocks.apb1_timer_hz as f32 / (prescaler * reload) as f32;
+    defmt::info!(
+        "Startup: TIM2 prescaler {}, reload {}, PDM tick {} Hz, {} ticks per excitation cycle, excitation {} Hz",
+        prescaler,
+        reload,
+        tick_hz,
+        pdm_signal_len,
+        tick_hz / pdm_signal_len as f32
+    );
+}
@@ -0,0 +1,380 @@
+// Reference host for the usb_custom firmware's stream: picks a stream mode, then decodes the packets to stdout.
+// Usage: capture <samples|iq|positions|runs> [csv|live] [rate_hz] [threshold_counts]
+//
+// runs is positions run-length encoded on the device, with positions that stay within threshold_counts of the last one sent only counted; they're expanded back to one per tick here, see RunDecoder.
+// csv writes one row per sample, window or position; live overwrites a single line with the latest position, and needs the positions or runs stream.
+// Dropped packets, from sequence gaps, are reported on stderr so they don't end up in the CSV, as are positions that don't unwrap from their phase.
+
+use calipertron_core::{COUNTS_PER_PITCH, POSITION_FRACTION_BITS};
+use nusb::transfer::{ControlIn, ControlType, Queue, Recipient, RequestBuffer};
+use schema::*;
+use std::io::Write;
+use std::time::Duration;
+use tokio::time::timeout;
+
+const MAX_PACKET_SIZE: usize = 64;
+// Reads kept in flight on the stream endpoint, so packets keep landing while we're busy printing.
+const IN_FLIGHT_TRANSFERS: usize = 8;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+// for runs without a threshold given: only an unchanged position counts as a repeat
+const DEFAULT_RUN_THRESHOLD_COUNTS: u16 = 0;
+
+#[derive(PartialEq)]
+enum Output {
+    Csv,
+    Live,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: capture <samples|iq|positions|runs> [csv|live] [rate_hz] [threshold_counts]");
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let mode = match args.get(1).map(String::as_str) {
+        Some("samples") => StreamMode::Samples,
+        Some("iq") => StreamMode::IqWindows,
+        Some("positions") => StreamMode::Positions,
+        Some("runs") => StreamMode::PositionRuns {
+            threshold: match args.get(4) {
+                None => DEFAULT_RUN_THRESHOLD_COUNTS,
+                Some(s) => s.parse().unwrap_or_else(|_| usage()),
+            },
+        },
+        _ => usage(),
+    };
+    let positions = matches!(
+        mode,
+        StreamMode::Positions | StreamMode::PositionRuns { .. }
+    );
+    let output = match args.get(2).map(String::as_str) {
+        None | Some("csv") => Output::Csv,
+        Some("live") => Output::Live,
+        _ => usage(),
+    };
+    if output == Output::Live && !positions {
+        eprintln!("Error: live output needs the positions or runs stream");
+        std::process::exit(1);
+    }
+    let rate_hz: u32 = match args.get(3) {
+        None => 100,
+        Some(s) => s.parse().unwrap_or_else(|_| usage()),
+    };
+
+    let di = nusb::list_devices()?
+        .find(|d| d.vendor_id() == 0xc0de && d.product_id() == 0xcafe)
+        .expect("device should be connected");
+    let device = di.open()?;
+    let interface = device.claim_interface(0)?;
+
+    // Firmware from before the request stalls it, which only costs the printout.
+    match interface
+        .control_in(ControlIn {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Interface,
+            request: DeviceInfo::REQUEST,
+            value: 0,
+            index: 0,
+            length: MAX_PACKET_SIZE as u16,
+        })
+        .await
+        .into_result()
+    {
+        Ok(data) => match DeviceInfo::deserialize(&data) {
+            Some(info) => eprintln!(
+                "Firmware {}.{}.{} at {}{}, features {:#x}",
+                info.version[0],
+                info.version[1],
+                info.version[2],
+                String::from_utf8_lossy(&info.git_hash),
+                if info.git_dirty { " (dirty)" } else { "" },
+                info.features
+            ),
+            None => eprintln!("Couldn't decode device info from {} bytes", data.len()),
+        },
+        Err(e) => eprintln!("No device info: {e}"),
+    }
+
+    let endpoint_addr = 1;
+    let mut out_queue = interface.bulk_out_queue(endpoint_addr);
+    let mut stream_queue = interface.bulk_in_queue(0x80 + endpoint_addr);
+    let mut response_queue = interface.bulk_in_queue(0x80 + endpoint_addr + 1);
+
+    // The handshake only goes out when the firmware sees a new connection, so it's waiting for us straight after a replug but long gone on a second run.
+    let mut calibration = None;
+    let config = match command(
+        &mut out_queue,
+        &mut response_queue,
+        &mut calibration,
+        Command::GetConfig,
+    )
+    .await?
+    {
+        Response::Config(config) => config,
+        r => return Err(format!("expected config, got {r:?}").into()),
+    };
+    eprintln!("Device config: {config:?}");
+    match calibration {
+        Some(c) if !c.plausible => {
+            eprintln!("Warning: implausible VREFINT reading {c:?}, millivolts will be off")
+        }
+        Some(_) => {}
+        None if mode == StreamMode::IqWindows => {
+            eprintln!("No handshake since the device was plugged in, so I/Q sums stay in raw ADC units; replug to get millivolts")
+        }
+        None => {}
+    }
+
+    if positions {
+        match command(
+            &mut out_queue,
+            &mut response_queue,
+            &mut calibration,
+            Command::SetPositionRate { rate_hz },
+        )
+        .await?
+        {
+            Response::PositionRate { rate_hz } => eprintln!("Streaming positions at {rate_hz} Hz"),
+            r => return Err(format!("device rejected position rate: {r:?}").into()),
+        }
+    }
+    match command(
+        &mut out_queue,
+        &mut response_queue,
+        &mut calibration,
+        Command::SetStreamMode { mode },
+    )
+    .await?
+    {
+        Response::Ack => {}
+        r => return Err(format!("device rejected stream mode: {r:?}").into()),
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    match (&mode, &output) {
+        (StreamMode::Samples, _) => writeln!(out, "sequence,timestamp_us,millivolts")?,
+        (StreamMode::IqWindows, _) => writeln!(out, "sequence,timestamp_us,sum_sine,sum_cosine")?,
+        (_, Output::Csv) if positions => writeln!(
+            out,
+            "sequence,timestamp_us,position,position_mm,settling,saturated,phase_rad,position_fine"
+        )?,
+        _ => {}
+    }
+
+    // raw ADC units to millivolts, for the I/Q sums; samples arrive in millivolts already, or as INVALID_MILLIVOLTS below the plausible range
+    let mv_per_count = calibration
+        .filter(|c| c.vrefint_sample > 0)
+        .map_or(1.0, |c| c.vref_int_mv as f64 / c.vrefint_sample as f64);
+    // Packets the firmware queued before the switch come through in the old mode; each mode's packets are a different length, so those get skipped.
+    let body_len = match mode {
+        StreamMode::Samples => config.sample_format.body_len(),
+        StreamMode::IqWindows => 8 * IQ_PAIRS_PER_PACKET,
+        StreamMode::Positions => PositionSample::SIZE,
+        StreamMode::PositionRuns { .. } => PositionRun::SIZE,
+    };
+    let mut runs = RunDecoder::default();
+    let mut last_sequence: Option<u16> = None;
+    let mut dropped: u64 = 0;
+    let mut samples = [0u16; 64];
+
+    loop {
+        while stream_queue.pending() < IN_FLIGHT_TRANSFERS {
+            stream_queue.submit(RequestBuffer::new(MAX_PACKET_SIZE));
+        }
+        let completion = stream_queue.next_complete().await;
+        completion.status?;
+
+        let Some((header, body)) = SamplePacketHeader::parse(&completion.data) else {
+            eprintln!(
+                "Discarding malformed packet of {} bytes",
+                completion.data.len()
+            );
+            stream_queue.submit(RequestBuffer::reuse(completion.data, MAX_PACKET_SIZE));
+            continue;
+        };
+        if body.len() != body_len {
+            stream_queue.submit(RequestBuffer::reuse(completion.data, MAX_PACKET_SIZE));
+            continue;
+        }
+        if let Some(last) = last_sequence {
+            let gap = header.sequence.wrapping_sub(last).wrapping_sub(1);
+            if gap > 0 {
+                dropped += gap as u64;
+                eprintln!("Dropped {gap} packets, {dropped} total");
+            }
+        }
+        last_sequence = Some(header.sequence);
+        let (sequence, timestamp_us) = (header.sequence, header.timestamp_us);
+
+        match mode {
+            StreamMode::Samples => {
+                let n = decode_samples(body, &config.sample_format, &mut samples);
+                for mv in &samples[..n] {
+                    writeln!(out, "{sequence},{timestamp_us},{mv}")?;
+                }
+            }
+            StreamMode::IqWindows => {
+                for pair in body.chunks_exact(8) {
+                    let sum_sine = i32::from_le_bytes(pair[0..4].try_into().unwrap());
+                    let sum_cosine = i32::from_le_bytes(pair[4..8].try_into().unwrap());
+                    writeln!(
+                        out,
+                        "{sequence},{timestamp_us},{},{}",
+                        sum_sine as f64 * mv_per_count,
+                        sum_cosine as f64 * mv_per_count
+                    )?;
+                }
+            }
+            StreamMode::Positions => {
+                if let Some(p) = PositionSample::read(body) {
+                    check_unwrap(&config, sequence, &p);
+                    write_position(&mut out, &output, &config, sequence, timestamp_us, &p)?;
+                }
+            }
+            StreamMode::PositionRuns { .. } => {
+                if let Some(run) = PositionRun::read(body) {
+                    check_unwrap(&config, sequence, &run.sample);
+                    for (sequence, timestamp_us, p) in runs.decode(sequence, timestamp_us, &run) {
+                        write_position(&mut out, &output, &config, sequence, timestamp_us, &p)?;
+                    }
+                }
+            }
+        }
+
+        stream_queue.submit(RequestBuffer::reuse(completion.data, MAX_PACKET_SIZE));
+    }
+}
+
+/// Reports a position that doesn't unwrap from its phase on stderr.
+fn check_unwrap(config: &DeviceConfig, sequence: u16, p: &PositionSample) {
+    // see Reading::tracked_phase; a rejected step also holds the position, so the odd one isn't necessarily the unwrap's fault
+    let pitch_fine = COUNTS_PER_PITCH << POSITION_FRACTION_BITS;
+    let within_pitch =
+        (p.position_fine + (config.tare << POSITION_FRACTION_BITS)).rem_euclid(pitch_fine);
+    // a pitch is a turn of phase, 2^32
+    let phase_fine = (p.phase as u32 >> (32 - pitch_fine.trailing_zeros())) as i64;
+    if !p.settling && within_pitch != phase_fine {
+        eprintln!(
+            "Sequence {sequence}: position_fine {} doesn't unwrap from phase {}",
+            p.position_fine, p.phase
+        );
+    }
+}
+
+/// Writes one position as a CSV row or the live line.
+fn write_position(
+    out: &mut impl Write,
+    output: &Output,
+    config: &DeviceConfig,
+    sequence: u16,
+    timestamp_us: u32,
+    p: &PositionSample,
+) -> std::io::Result<()> {
+    let mm = p.position as f64 * config.pitch_um as f64 / COUNTS_PER_PITCH as f64 / 1000.0;
+    let phase_rad = p.phase as f64 * std::f64::consts::TAU / 4_294_967_296.0;
+    match output {
+        Output::Csv => writeln!(
+            out,
+            "{sequence},{timestamp_us},{},{mm:.3},{},{},{phase_rad:.6},{}",
+            p.position, p.settling, p.saturated, p.position_fine
+        )?,
+        Output::Live => {
+            let flags = match (p.settling, p.saturated) {
+                (true, _) => " settling",
+                (false, true) => " saturated",
+                (false, false) => "",
+            };
+            write!(out, "\r{mm:10.3} mm{flags:<10}")?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `command` and waits for its reply, noting the handshake if it turns up first.
+async fn command(
+    out_queue: &mut Queue<Vec<u8>>,
+    response_queue: &mut Queue<RequestBuffer>,
+    calibration: &mut Option<AdcCalibration>,
+    command: Command,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let bs = command
+        .serialize(&mut buf)
+        .map_err(|_| "failed to serialize command")?;
+    out_queue.submit(bs.to_vec());
+    timeout(RESPONSE_TIMEOUT, out_queue.next_complete())
+        .await?
+        .status?;
+
+    loop {
+        response_queue.submit(RequestBuffer::new(MAX_PACKET_SIZE));
+        let completion = timeout(RESPONSE_TIMEOUT, response_queue.next_complete()).await?;
+        completion.status?;
+        match Response::deserialize(&completion.data) {
+            Some(Response::Handshake(c)) => *calibration = Some(c),
+            Some(response) => return Ok(response),
+            None => return Err("failed to deserialize response".into()),
+        }
+    }
+}
+
+/// Unpacks a sample packet's body in `format` into `out`, see `SampleWidth` for the byte layouts.
+fn decode_samples(body: &[u8], format: &SampleFormat, out: &mut [u16]) -> usize {
+    let big_endian = format.endianness == Endianness::Big;
+    let mut n = 0;
+    match format.width {
+        SampleWidth::Bits16 => {
+            for bytes in body.chunks_exact(2) {
+                let bytes = [bytes[0], bytes[1]];
+                out[n] = if big_endian {
+                    u16::from_be_bytes(bytes)
+                } else {
+                    u16::from_le_bytes(bytes)
+                };
+                n += 1;
+            }
+        }
+        SampleWidth::Packed12 => {
+            for bytes in body.chunks_exact(3) {
+                let (b0, b1, b2) = (bytes[0] as u16, bytes[1] as u16, bytes[2] as u16);
+                let (a, b) = if big_endian {
+                    (b0 << 4 | b1 >> 4, (b1 & 0xF) << 8 | b2)
+                } else {
+                    (b0 | (b1 & 0xF) << 8, b1 >> 4 | b2 << 4)
+                };
+                out[n] = a;
+                out[n + 1] = b;
+                n += 2;
+            }
+        }
+    }
+    n
+}
+
+/// Expands `StreamMode::PositionRuns` packets back to one position per tick, see `PositionRun`.
+#[derive(Default)]
+struct RunDecoder {
+    /// The last packet's sample, along with its sequence and timestamp.
+    held: Option<(u16, u32, PositionSample)>,
+}
+
+impl RunDecoder {
+    /// Returns the held sample once for each tick that wasn't sent, then the new one.
+    /// The first packet's repeats have nothing to repeat, so they're left out.
+    fn decode(
+        &mut self,
+        sequence: u16,
+        timestamp_us: u32,
+        run: &PositionRun,
+    ) -> impl Iterator<Item = (u16, u32, PositionSample)> {
+        let repeats = run.repeats as usize;
+        let held = self.held.replace((sequence, timestamp_us, run.sample));
+        held.into_iter()
+            .flat_map(move |held| std::iter::repeat(held).take(repeats))
+            .chain(std::iter::once((sequence, timestamp_us, run.sample)))
+    }
+}
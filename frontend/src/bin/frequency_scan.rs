@@ -0,0 +1,95 @@
+#![allow(non_snake_case)]
+
+// Runs a ScanFrequency sweep on the usb_custom firmware and writes its points to stdout as CSV, for plotting the response.
+// Usage: frequency_scan <start_kHz> <stop_kHz> <steps>
+//
+// Hold the slider still on the scale while it runs. The device stays on whichever frequency had the least phase noise, reported on stderr.
+
+use nusb::transfer::{Queue, RequestBuffer};
+use schema::*;
+use std::time::Duration;
+use tokio::time::timeout;
+
+const MAX_PACKET_SIZE: usize = 64;
+// Per point, comfortably more than the firmware's SCAN_POINT_TIMEOUT plus the ramps either side.
+const POINT_TIMEOUT: Duration = Duration::from_secs(3);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn usage() -> ! {
+    eprintln!("Usage: frequency_scan <start_kHz> <stop_kHz> <steps>");
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        usage();
+    }
+    let start_kHz: f64 = args[1].parse().unwrap_or_else(|_| usage());
+    let stop_kHz: f64 = args[2].parse().unwrap_or_else(|_| usage());
+    let steps: u16 = args[3].parse().unwrap_or_else(|_| usage());
+
+    let di = nusb::list_devices()?
+        .find(|d| d.vendor_id() == 0xc0de && d.product_id() == 0xcafe)
+        .expect("device should be connected");
+    let device = di.open()?;
+    let interface = device.claim_interface(0)?;
+
+    let endpoint_addr = 1;
+    let mut out_queue = interface.bulk_out_queue(endpoint_addr);
+    let mut response_queue = interface.bulk_in_queue(0x80 + endpoint_addr + 1);
+
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let bs = Command::ScanFrequency {
+        start_kHz,
+        stop_kHz,
+        steps,
+    }
+    .serialize(&mut buf)
+    .map_err(|_| "failed to serialize command")?;
+    out_queue.submit(bs.to_vec());
+    timeout(RESPONSE_TIMEOUT, out_queue.next_complete())
+        .await?
+        .status?;
+
+    let sweep_timeout = POINT_TIMEOUT * (steps as u32 + 1);
+    let (points, pdm_frequency_hz) = loop {
+        match Response::deserialize(&read(&mut response_queue, sweep_timeout).await?) {
+            // sent on every connection, ahead of the first reply
+            Some(Response::Handshake(_)) => continue,
+            Some(Response::FrequencyScan {
+                points,
+                pdm_frequency_hz,
+            }) => break (points as usize, pdm_frequency_hz),
+            r => return Err(format!("expected a scan, got {r:?}").into()),
+        }
+    };
+
+    println!("pdm_frequency_hz,magnitude,phase_std_dev,gain_level,settled");
+    let mut received = 0;
+    while received < points {
+        let data = read(&mut response_queue, RESPONSE_TIMEOUT).await?;
+        let body = data.get(2..).ok_or("short scan packet")?;
+        for chunk in body.chunks_exact(ScanPoint::SIZE) {
+            let p = ScanPoint::read(chunk).unwrap();
+            println!(
+                "{},{},{},{},{}",
+                p.pdm_frequency_hz, p.magnitude, p.phase_std_dev, p.gain_level, p.settled
+            );
+            received += 1;
+        }
+    }
+    eprintln!("Device settled on {pdm_frequency_hz} Hz");
+    Ok(())
+}
+
+async fn read(
+    queue: &mut Queue<RequestBuffer>,
+    wait: Duration,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    queue.submit(RequestBuffer::new(MAX_PACKET_SIZE));
+    let completion = timeout(wait, queue.next_complete()).await?;
+    completion.status?;
+    Ok(completion.data)
+}
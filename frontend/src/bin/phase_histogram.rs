@@ -0,0 +1,117 @@
+// Downloads the usb_custom firmware's phase histogram and writes it to stdout as CSV, for looking at the shape of the phase noise.
+// Usage: phase_histogram [seconds]
+//
+// With seconds, starts the histogram over and waits that long before downloading it; without, downloads whatever's accumulated since the last reset or boot.
+// Hold the slider still for a noise measurement, or the histogram is of the motion.
+
+use nusb::transfer::{Queue, RequestBuffer};
+use schema::*;
+use std::f64::consts::{PI, TAU};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const MAX_PACKET_SIZE: usize = 64;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn usage() -> ! {
+    eprintln!("Usage: phase_histogram [seconds]");
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let capture: Option<f64> = match args.len() {
+        1 => None,
+        2 => Some(args[1].parse().unwrap_or_else(|_| usage())),
+        _ => usage(),
+    };
+
+    let di = nusb::list_devices()?
+        .find(|d| d.vendor_id() == 0xc0de && d.product_id() == 0xcafe)
+        .expect("device should be connected");
+    let device = di.open()?;
+    let interface = device.claim_interface(0)?;
+
+    let endpoint_addr = 1;
+    let mut out_queue = interface.bulk_out_queue(endpoint_addr);
+    let mut response_queue = interface.bulk_in_queue(0x80 + endpoint_addr + 1);
+
+    if let Some(seconds) = capture {
+        match command(
+            &mut out_queue,
+            &mut response_queue,
+            Command::ResetPhaseHistogram,
+        )
+        .await?
+        {
+            Response::Ack => {}
+            r => return Err(format!("device rejected reset: {r:?}").into()),
+        }
+        tokio::time::sleep(Duration::from_secs_f64(seconds)).await;
+    }
+
+    let (bins, windows, elapsed_ms) = match command(
+        &mut out_queue,
+        &mut response_queue,
+        Command::DumpPhaseHistogram,
+    )
+    .await?
+    {
+        Response::PhaseHistogram {
+            bins,
+            windows,
+            elapsed_ms,
+        } => (bins as usize, windows, elapsed_ms),
+        r => return Err(format!("expected a histogram, got {r:?}").into()),
+    };
+
+    let mut counts = Vec::with_capacity(bins);
+    while counts.len() < bins {
+        let data = read(&mut response_queue).await?;
+        let body = data.get(2..).ok_or("short histogram packet")?;
+        counts.extend(
+            body.chunks_exact(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap())),
+        );
+    }
+
+    println!("bin,start_rad,end_rad,count");
+    let width = TAU / bins as f64;
+    for (i, count) in counts.iter().take(bins).enumerate() {
+        let start = -PI + i as f64 * width;
+        println!("{},{:.6},{:.6},{}", i, start, start + width, count);
+    }
+    eprintln!("{windows} windows over {:.1}s", elapsed_ms as f64 / 1000.0);
+    Ok(())
+}
+
+async fn command(
+    out_queue: &mut Queue<Vec<u8>>,
+    response_queue: &mut Queue<RequestBuffer>,
+    command: Command,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let bs = command
+        .serialize(&mut buf)
+        .map_err(|_| "failed to serialize command")?;
+    out_queue.submit(bs.to_vec());
+    timeout(RESPONSE_TIMEOUT, out_queue.next_complete())
+        .await?
+        .status?;
+    loop {
+        match Response::deserialize(&read(response_queue).await?) {
+            // sent on every connection, ahead of the first reply
+            Some(Response::Handshake(_)) => continue,
+            Some(r) => return Ok(r),
+            None => return Err("failed to deserialize response".into()),
+        }
+    }
+}
+
+async fn read(queue: &mut Queue<RequestBuffer>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    queue.submit(RequestBuffer::new(MAX_PACKET_SIZE));
+    let completion = timeout(RESPONSE_TIMEOUT, queue.next_complete()).await?;
+    completion.status?;
+    Ok(completion.data)
+}
@@ -2,7 +2,7 @@ use eframe::egui;
 use egui_plot::{Line, Plot, PlotPoints};
 use flume::{Receiver, Sender};
 use nusb::transfer::{Queue, RequestBuffer};
-use schema::Command;
+use schema::{AdcSamplingPeriod, Command, Response, SamplePacketHeader};
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -24,6 +24,7 @@ fn main() -> Result<(), eframe::Error> {
     let endpoint_addr = 1;
     let in_queue = interface.bulk_in_queue(0x80 + endpoint_addr);
     let out_queue = interface.bulk_out_queue(endpoint_addr);
+    let response_queue = interface.bulk_in_queue(0x80 + endpoint_addr + 1);
 
     let samples = Arc::new(Mutex::new(VecDeque::with_capacity(MAX_SAMPLES)));
     let samples_clone = Arc::clone(&samples);
@@ -35,7 +36,14 @@ fn main() -> Result<(), eframe::Error> {
 
     // Start USB reading thread
     thread::spawn(move || {
-        usb_reading_thread(in_queue, out_queue, samples_clone, threshold_clone, rx);
+        usb_reading_thread(
+            in_queue,
+            out_queue,
+            response_queue,
+            samples_clone,
+            threshold_clone,
+            rx,
+        );
     });
 
     let options = eframe::NativeOptions::default();
@@ -55,6 +63,7 @@ fn main() -> Result<(), eframe::Error> {
 fn usb_reading_thread(
     mut in_queue: Queue<RequestBuffer>,
     mut out_queue: Queue<Vec<u8>>,
+    mut response_queue: Queue<RequestBuffer>,
     samples: Arc<Mutex<VecDeque<u16>>>,
     threshold: Arc<Mutex<Option<u16>>>,
     rx: Receiver<Command>, // Add this parameter
@@ -68,6 +77,13 @@ fn usb_reading_thread(
             let mut buf = [0u8; MAX_PACKET_SIZE];
             if let Ok(serialized) = command.serialize(&mut buf) {
                 out_queue.submit(serialized.into());
+
+                // firmware replies to every command and stalls until we've read the reply
+                response_queue.submit(RequestBuffer::new(MAX_PACKET_SIZE));
+                let completion = futures_lite::future::block_on(response_queue.next_complete());
+                if let Some(Response::Error(e)) = Response::deserialize(&completion.data) {
+                    eprintln!("Device rejected command: {e:?}");
+                }
             }
         }
 
@@ -76,7 +92,9 @@ fn usb_reading_thread(
         }
 
         let completion = futures_lite::future::block_on(in_queue.next_complete());
-        let data = completion.data.as_slice();
+        let data = SamplePacketHeader::parse(&completion.data)
+            .map(|(_, samples)| samples)
+            .unwrap_or_default();
 
         let threshold = *threshold.lock().unwrap();
         let mut samples = samples.lock().unwrap();
@@ -146,6 +164,7 @@ impl eframe::App for ADCApp {
                 self.tx
                     .send(Command::SetFrequency {
                         frequency_kHz: self.frequency_kHz,
+                        adc_sampling_period: AdcSamplingPeriod::CYCLES239_5,
                     })
                     .unwrap()
             }
@@ -1,6 +1,6 @@
 #![allow(non_snake_case)]
 
-use schema::Command;
+use schema::{AdcSamplingPeriod, Command, Response, SamplePacketHeader};
 
 fn main() {
     // Parse command-line argument for frequency
@@ -20,11 +20,13 @@ fn main() {
     // Send frequency command to firmware
     let endpoint_addr = 1;
     let mut out_queue = interface.bulk_out_queue(endpoint_addr);
-    send_frequency_command(&mut out_queue, frequency_kHz);
+    let mut response_queue = interface.bulk_in_queue(0x80 + endpoint_addr + 1);
+    send_frequency_command(&mut out_queue, &mut response_queue, frequency_kHz);
 
     // Read and print ADC values
     let mut queue = interface.bulk_in_queue(0x80 + endpoint_addr);
     let transfer_size = 64;
+    let mut expected_sequence = None;
 
     loop {
         while queue.pending() < 1 {
@@ -34,7 +36,24 @@ fn main() {
         let completion = futures_lite::future::block_on(queue.next_complete());
 
         let data = completion.data.as_slice();
-        for chunk in data.chunks_exact(2) {
+        let Some((header, samples)) = SamplePacketHeader::parse(data) else {
+            eprintln!(
+                "Warning: Dropping short or corrupt packet of {} bytes",
+                data.len()
+            );
+            continue;
+        };
+        if let Some(expected) = expected_sequence {
+            if header.sequence != expected {
+                eprintln!(
+                    "Warning: Expected packet {expected} but got {}, samples were dropped",
+                    header.sequence
+                );
+            }
+        }
+        expected_sequence = Some(header.sequence.wrapping_add(1));
+
+        for chunk in samples.chunks_exact(2) {
             if let [low, high] = chunk {
                 let adc_value = u16::from_le_bytes([*low, *high]);
                 //println!("ADC value: {} mV", adc_value);
@@ -64,8 +83,15 @@ fn parse_frequency_arg() -> f64 {
     }
 }
 
-fn send_frequency_command(out_queue: &mut nusb::transfer::Queue<Vec<u8>>, frequency_kHz: f64) {
-    let command = Command::SetFrequency { frequency_kHz };
+fn send_frequency_command(
+    out_queue: &mut nusb::transfer::Queue<Vec<u8>>,
+    response_queue: &mut nusb::transfer::Queue<nusb::transfer::RequestBuffer>,
+    frequency_kHz: f64,
+) {
+    let command = Command::SetFrequency {
+        frequency_kHz,
+        adc_sampling_period: AdcSamplingPeriod::CYCLES239_5,
+    };
     let mut buf = [0u8; 64]; // Assuming MAX_PACKET_SIZE is 64
     if let Ok(serialized) = command.serialize(&mut buf) {
         out_queue.submit(serialized.into());
@@ -73,4 +99,14 @@ fn send_frequency_command(out_queue: &mut nusb::transfer::Queue<Vec<u8>>, freque
         eprintln!("Error: Failed to serialize frequency command");
         std::process::exit(1);
     }
+
+    response_queue.submit(nusb::transfer::RequestBuffer::new(64));
+    let completion = futures_lite::future::block_on(response_queue.next_complete());
+    match Response::deserialize(&completion.data) {
+        Some(Response::Ack) => {}
+        response => {
+            eprintln!("Error: Device rejected frequency command: {response:?}");
+            std::process::exit(1);
+        }
+    }
 }
@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, defmt::Format)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
 pub enum AdcSamplingPeriod {
     CYCLES1_5,
     CYCLES7_5,
@@ -43,6 +43,102 @@ pub enum Command {
         adc_sampling_period: AdcSamplingPeriod,
     },
     Record,
+    GetStatus,
+    /// Latest demodulated position, for hosts that poll rather than parse the sample stream.
+    GetReading,
+    /// Make the current position the origin for subsequent readings.
+    Tare,
+    ClearTare,
+    /// Smoothing of reported position, see `calipertron_core::dsp::OnePole`; 1.0 turns it off.
+    SetFilterAlpha {
+        alpha: f32,
+    },
+    /// Measure the I/Q offset from stray coupling and ADC bias, and subtract it from subsequent windows.
+    /// Run with the slider off the scale, so there's no real signal to measure.
+    CalibrateIqOffset,
+    GetConfig,
+    /// Download the most recent positions in one burst, see `HistoryEntry`.
+    DumpHistory,
+    SetStreamMode {
+        mode: StreamMode,
+    },
+    /// Change the ADC sample time without touching the excitation; answered with `Response::AdcSampleRate`.
+    SetAdcSampleTime {
+        adc_sampling_period: AdcSamplingPeriod,
+    },
+    /// Electrode pitch of the scale, for converting position to micrometers.
+    SetPitch {
+        pitch_um: u32,
+    },
+    /// Start the peak hold over from the current position, see `GetPeakHold`.
+    ResetPeakHold,
+    SetExcitationMode {
+        mode: ExcitationMode,
+    },
+    /// Load `corrections` into entries `offset..offset + ADC_LUT_CHUNK_LEN` of the ADC nonlinearity table, see `ADC_LUT_LEN`.
+    /// Firmware built without the adc-lut feature answers `CommandError::Unsupported`.
+    SetAdcLut {
+        offset: u16,
+        corrections: [i16; ADC_LUT_CHUNK_LEN],
+    },
+    /// Erase flash_logger's position log.
+    EraseLog,
+    /// Download flash_logger's position log, see `LogPageHeader`.
+    DumpLog,
+    /// Drop into `PowerState::Idle` after the slider has been still for `timeout_ms`, 0 to stay active; see `PowerState`.
+    SetIdle {
+        timeout_ms: u32,
+        poll_interval_ms: u32,
+    },
+    /// Load `corrections` into entries `offset..offset + PHASE_CORRECTION_CHUNK_LEN` of the phase-to-position correction table, see `PHASE_CORRECTION_LEN`.
+    SetPhaseCorrection {
+        offset: u16,
+        corrections: [i16; PHASE_CORRECTION_CHUNK_LEN],
+    },
+    /// How to pack samples in `StreamMode::Samples`; a partly filled packet in the old format is dropped.
+    SetSampleFormat {
+        format: SampleFormat,
+    },
+    /// Turn the excitation off, measure the ADC's noise floor, and turn it back on; see `NoiseMeasurement`.
+    /// Position readings hold off while it runs, then settle again as for any reconfiguration.
+    MeasureNoise,
+    /// Ticks per second in `StreamMode::Positions` and `StreamMode::PositionRuns`; answered with `Response::PositionRate`.
+    SetPositionRate {
+        rate_hz: u32,
+    },
+    /// Allow `packets` more stream packets, for pacing the stream to the host; answered with `Response::StreamCredits`.
+    /// Until the first grant after connecting the stream is unpaced, as fast as the queue allows. After it, each packet spends a credit, and packets with none left are skipped (see `Status::throttled_packets`) while demodulation carries on.
+    /// Skipped packets still take a sequence number, so they show up as gaps like any other drop. Grant ahead of what's been read, e.g., topping up to the number of reads kept in flight as each completes.
+    AddStreamCredits {
+        packets: u32,
+    },
+    /// Capture the next whole window and download how each sample went into its I/Q sums, see `DemodulationEntry`.
+    /// Firmware built without the demod-dump feature answers `CommandError::Unsupported`.
+    DumpDemodulation,
+    /// Step the excitation through `steps` frequencies from `start_kHz` to `stop_kHz`, at the current sample time, and settle on the one with the least phase noise.
+    /// Answered with `Response::FrequencyScan` and its scan packets once the sweep is done, which takes a fraction of a second per step; see `ScanPoint`.
+    /// Hold the slider still on the scale while it runs, so the phase noise is the measurement's.
+    ScanFrequency {
+        start_kHz: f64,
+        stop_kHz: f64,
+        steps: u16,
+    },
+    /// Hold `Reading::position_um` still until the position moves more than `deadband_um` from it, for a display whose last digit shouldn't flicker; 0 turns it off.
+    /// It follows freely once moving, see `calipertron_core::DeadZone`; other positions aren't affected.
+    SetDisplayDeadband {
+        deadband_um: u32,
+    },
+    /// What `Reading::value` reads out in, like a caliper's mm/inch button; every other field keeps its own units.
+    SetUnit {
+        unit: Unit,
+    },
+    /// Empty the phase histogram and start its capture period over, see `DumpPhaseHistogram`.
+    ResetPhaseHistogram,
+    /// Download how the wrapped phase of every window since the last `ResetPhaseHistogram` (or boot) is distributed, see `PHASE_HISTOGRAM_BINS`.
+    /// Answered with `Response::PhaseHistogram` and its histogram packets; it keeps counting while they go out.
+    DumpPhaseHistogram,
+    /// Lowest and highest position since boot or the last `ResetPeakHold`, for measuring travel or runout; answered with `Response::PeakHold`.
+    GetPeakHold,
 }
 
 impl Command {
@@ -54,3 +150,892 @@ impl Command {
         postcard::from_bytes(bs).ok()
     }
 }
+
+/// Entries in usb_custom's ADC nonlinearity table, each added to the raw samples in a run of `4096 / ADC_LUT_LEN` codes; see `calipertron_core::dsp::AdcLut`.
+pub const ADC_LUT_LEN: usize = 256;
+/// Corrections per `Command::SetAdcLut`; at up to three bytes each in postcard, a chunk fits one 64 byte packet.
+pub const ADC_LUT_CHUNK_LEN: usize = 16;
+
+/// Entries in usb_custom's phase-to-position correction table, in counts at evenly spaced phases across a pitch; see `calipertron_core::PhaseCorrection` for making one.
+pub const PHASE_CORRECTION_LEN: usize = 64;
+/// Corrections per `Command::SetPhaseCorrection`, sized like `ADC_LUT_CHUNK_LEN`.
+pub const PHASE_CORRECTION_CHUNK_LEN: usize = 16;
+
+/// What usb_custom streams on its bulk IN endpoint.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub enum StreamMode {
+    /// Raw ADC samples in millivolts, see `SamplePacketHeader`.
+    Samples,
+    /// Correlation sums of each demodulation window, for doing the phase math on the host; see `IQ_PAIRS_PER_PACKET`.
+    IqWindows,
+    /// The latest position at a fixed rate, whatever the demodulation rate; see `PositionSample`.
+    Positions,
+    /// `Positions` run-length encoded: ticks whose position is within `threshold` counts of the last one sent, and flagged the same, are only counted; see `PositionRun`.
+    /// Takes the rate from `SetPositionRate` like `Positions`, so it's the same stream while the slider moves and next to nothing while it's still.
+    PositionRuns { threshold: u16 },
+}
+
+/// Width of each sample in `StreamMode::Samples` packets.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub enum SampleWidth {
+    /// u16 each.
+    Bits16,
+    /// Pairs `(a, b)` of 12-bit samples in 3 bytes, with `a` the earlier; see `calipertron_core::pack_12`.
+    ///
+    /// ```text
+    /// little-endian  byte 0 = a[7:0],   byte 1 = b[3:0] << 4 | a[11:8],  byte 2 = b[11:4]
+    /// big-endian     byte 0 = a[11:4],  byte 1 = a[3:0] << 4 | b[11:8],  byte 2 = b[7:0]
+    /// ```
+    ///
+    /// That is, the 24-bit integer `a | b << 12` little-endian, or `a << 12 | b` big-endian.
+    Packed12,
+}
+
+/// Byte order of samples in `StreamMode::Samples` packets; the header stays little-endian either way.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct SampleFormat {
+    pub width: SampleWidth,
+    pub endianness: Endianness,
+}
+
+impl SampleFormat {
+    /// What usb_custom streams until told otherwise, and what older hosts expect.
+    pub const DEFAULT: SampleFormat = SampleFormat {
+        width: SampleWidth::Bits16,
+        endianness: Endianness::Little,
+    };
+
+    /// Samples in each full-size packet: 29 as u16 and 38 packed (28 and 36 with `SAMPLE_PACKET_CRC`).
+    pub const fn samples_per_packet(&self) -> usize {
+        let body_len = 64 - SamplePacketHeader::SIZE - SAMPLE_PACKET_CRC_SIZE; // 64 byte full-speed bulk packets
+        match self.width {
+            SampleWidth::Bits16 => body_len / 2,
+            SampleWidth::Packed12 => body_len / 3 * 2,
+        }
+    }
+
+    /// Bytes taken by `samples_per_packet` samples, which is at most a packet's body.
+    pub const fn body_len(&self) -> usize {
+        match self.width {
+            SampleWidth::Bits16 => self.samples_per_packet() * 2,
+            SampleWidth::Packed12 => self.samples_per_packet() / 2 * 3,
+        }
+    }
+}
+
+/// Waveform driven onto the electrodes, see `firmware/build.rs`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub enum ExcitationMode {
+    /// Sigma-delta PDM of a sinusoid per phase; the only one automatic gain control can scale.
+    Sine,
+    /// Each pin fully on for half the cycle, for debugging; its harmonics couple too, so positions read differently than with `Sine`.
+    Square,
+}
+
+/// Whether the usb_custom firmware is measuring continuously or saving power while the slider sits still.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub enum PowerState {
+    Active,
+    /// Excitation is off except for a short burst every `DeviceConfig::idle_poll_interval_ms` to check for motion, so readings only update that often.
+    /// Motion wakes it straight back up, but a slider moved more than half a pitch between polls loses track of whole pitches.
+    Idle,
+}
+
+/// Units of `Reading::value`, all fixed point so the reading is exact at the display's last digit.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub enum Unit {
+    /// Micrometers, i.e., millimeters with three decimals; the same as `Reading::position_um`.
+    #[default]
+    Millimeters,
+    /// Ten-thousandths of an inch, i.e., inches with four decimals, converted with the exact 25.4mm to the inch; see `calipertron_core::counts_to_ten_thousandths_inch`.
+    Inches,
+    /// `calipertron_core::COUNTS_PER_PITCH` per pitch, like `Reading::position` but held through the display deadband too.
+    Counts,
+}
+
+/// Which way the slider is moving, from the smoothed velocity; forward is increasing position.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub enum Direction {
+    #[default]
+    Stationary,
+    Forward,
+    Backward,
+}
+
+/// Reply to a `Command`.
+/// The usb_custom firmware answers every command with exactly one `Response` on its own bulk IN endpoint, so replies never interleave with streamed samples.
+/// `History`, `Demodulation` and `FrequencyScan` are the exceptions: their packets follow on the same endpoint before the next reply.
+/// `Handshake` isn't a reply at all; it's sent unprompted on every connection, before the reply to the first command.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, defmt::Format)]
+pub enum Response {
+    Ack,
+    Error(CommandError),
+    Status(Status),
+    Reading(Reading),
+    IqOffset(IqOffset),
+    Config(DeviceConfig),
+    Handshake(AdcCalibration),
+    /// Number of entries in the history packets that follow.
+    History {
+        entries: u16,
+    },
+    /// Number of log pages that follow, `LOG_PAGE_SIZE` bytes each in full-size packets, oldest first.
+    Log {
+        pages: u16,
+    },
+    Noise(NoiseMeasurement),
+    /// Conversions per second at the new sample time, see `AdcSamplingPeriod::to_Hz`.
+    AdcSampleRate {
+        sampling_frequency_hz: f64,
+    },
+    /// Packets per second `StreamMode::Positions` will actually send at the current sample time, or ticks per second in `StreamMode::PositionRuns`.
+    /// That's the requested rate to within the timer's resolution, or the demodulation rate if the request was faster; a later change of sample time clamps again.
+    PositionRate {
+        rate_hz: f32,
+    },
+    /// Credits left after a grant, counting any unspent from before.
+    StreamCredits {
+        available: u32,
+    },
+    /// Number of entries in the demodulation packets that follow, one per sample of the window.
+    Demodulation {
+        entries: u16,
+    },
+    /// Number of points in the scan packets that follow, and the PDM frequency the device has settled on.
+    /// If no point settled, that's the frequency it was on before the scan.
+    FrequencyScan {
+        points: u16,
+        pdm_frequency_hz: u32,
+    },
+    /// Number of bins in the histogram packets that follow, the windows counted across them, and how long they've been counted for.
+    PhaseHistogram {
+        bins: u16,
+        windows: u32,
+        elapsed_ms: u32,
+    },
+    /// In the same counts as `Reading::position`, and both 0 straight after a reset.
+    /// Its own response rather than part of `Status`, which with three more i64s wouldn't fit a packet.
+    PeakHold {
+        position_min: i64,
+        position_max: i64,
+        /// `position_max - position_min`, i.e., total travel or runout.
+        position_range: i64,
+    },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default, defmt::Format)]
+pub struct Status {
+    /// Times the ADC DMA lapped the ring buffer before firmware drained it, dropping samples.
+    pub adc_overruns: u32,
+    /// Whether demodulating a synthetic signal at boot recovered its phase, see `firmware/build.rs`.
+    pub self_test_passed: bool,
+    /// Internal temperature sensor, in °C, refreshed about once a second; accurate to several degrees but good for logging drift.
+    pub temperature_c: f32,
+    /// Index into the firmware's excitation strengths, weakest first, currently chosen by automatic gain control.
+    pub gain_level: u8,
+    /// Standard deviation of the phase over the last 64 windows, in radians; with the slider still, this is the measurement noise.
+    pub phase_std_dev: f32,
+    /// Phase of the latest window through a phase-locked loop of about 100 windows, in radians; averages away jitter on a still slider, but lags a move.
+    pub pll_phase: f32,
+    /// Whether the loop has caught up with the phase; `pll_phase` is only better than the raw phase while this is set.
+    pub pll_locked: bool,
+    pub power_state: PowerState,
+    /// Samples in the latest window at 0 or 4095, i.e., clipping; a high count with a high magnitude means the coupling is too strong.
+    pub saturated_samples: u32,
+    /// Windows since boot whose phase implied an impossibly fast move, and were held at the previous position instead; see `PositionTracker::set_max_step`.
+    pub rejected_steps: u32,
+    /// Stream packets dropped since the host connected because the queue to the bulk endpoint was full, i.e., the host isn't reading fast enough.
+    pub dropped_packets: u32,
+    /// Stream packets skipped since the host connected because it had no credits left, see `Command::AddStreamCredits`.
+    pub throttled_packets: u32,
+    /// With a deadband on `Reading::velocity` so it doesn't flicker while the slider sits still; always `Stationary` while `PowerState::Idle`.
+    pub direction: Direction,
+    /// DMA transfer errors since boot, on either the ADC or the excitation channel; each one restarts the channel, so position may jump.
+    pub dma_errors: u32,
+    /// A DMA channel kept erroring after restarts and has been left off, so readings have stopped updating until the device is reset.
+    pub dma_failed: bool,
+    /// What `Reading::value` is currently in, see `Command::SetUnit`.
+    pub unit: Unit,
+}
+
+impl Status {
+    /// Postcard encoding's worst case, field by field: up to 5 bytes for a varint u32, 1 for a bool, a u8 or a unit-only enum.
+    /// Replies go out in a single 64 byte packet, so anything more goes in its own `Response`, as `PeakHold` does.
+    pub const MAX_SIZE: usize = 5 + 1 + 4 + 1 + 4 + 4 + 1 + 1 + 5 + 5 + 5 + 5 + 1 + 5 + 1 + 1;
+}
+
+/// Output of the most recent demodulation window.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct Reading {
+    /// Unwrapped position in counts, `calipertron_core::COUNTS_PER_PITCH` per electrode pitch, relative to the last `Tare`.
+    pub position: i64,
+    /// Wrapped phase as an `i32` fraction of a full turn, so 2π maps to 2^32.
+    pub phase: i32,
+    /// `sqrt(sum_sine² + sum_cosine²)` of the window, in raw ADC units; near zero means no coupling.
+    pub magnitude: f32,
+    /// Counts per second, smoothed.
+    pub velocity: f32,
+    /// `position` converted with `DeviceConfig::pitch_um`, i.e., fixed-point millimeters with three decimals, and held through `DeviceConfig::display_deadband_um`.
+    pub position_um: i64,
+    /// Also relative to the last `Tare`, but unfiltered and keeping the sub-count part of the phase: fixed-point counts with `calipertron_core::POSITION_FRACTION_BITS` (16) fractional bits.
+    pub position_fine: i64,
+    /// Set from boot or a change of excitation, sample time, or gain until consecutive windows agree; the other fields are transient garbage until it clears.
+    pub settling: bool,
+    /// More than a couple of the window's samples clipped, so the phase is distorted.
+    pub saturated: bool,
+    /// The phase `position_fine` was unwrapped from: `phase` after the firmware's phase correction, in the same turn units.
+    /// From the same window as the positions, so adding back the tare, `position_fine` modulo a pitch is this as a u32 shifted right by 4, unless the window's step was rejected (see `Status::rejected_steps`); anything else is an unwrap error.
+    pub tracked_phase: i32,
+    /// `position_um` in `unit`, from the same held position, so a display can show it as is.
+    pub value: i64,
+    /// As set with `Command::SetUnit`, carried in every reading so `value` can't be misread across a change.
+    pub unit: Unit,
+}
+
+/// Everything the host can set on the usb_custom firmware.
+/// Defaults apply at power-on only, so a host reconnecting after a USB hiccup can read this back rather than resending it.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct DeviceConfig {
+    pub pdm_frequency_hz: u32,
+    pub adc_sampling_period: AdcSamplingPeriod,
+    /// Position reported as zero, in counts.
+    pub tare: i64,
+    pub filter_alpha: f32,
+    pub iq_offset: IqOffset,
+    /// Micrometers per electrode pitch.
+    pub pitch_um: u32,
+    pub stream_mode: StreamMode,
+    pub sample_format: SampleFormat,
+    pub excitation_mode: ExcitationMode,
+    /// Time without motion before going idle, 0 for never.
+    pub idle_timeout_ms: u32,
+    pub idle_poll_interval_ms: u32,
+    /// As requested with `SetPositionRate`, before clamping to the demodulation rate.
+    pub position_rate_hz: u32,
+    /// See `Command::SetDisplayDeadband`.
+    pub display_deadband_um: u32,
+    pub unit: Unit,
+}
+
+/// ADC reference measured at boot, which scales everything usb_custom reports in millivolts.
+/// A raw conversion `x` is `x * vref_int_mv / vrefint_sample` millivolts.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct AdcCalibration {
+    /// Raw conversion of the internal reference.
+    pub vrefint_sample: u16,
+    /// Nominal voltage of the internal reference the firmware assumes.
+    pub vref_int_mv: u16,
+    /// Whether `vrefint_sample` is within what the reference's tolerance and the supply's range allow, see `calipertron_core::dsp::MIN_VREFINT_SAMPLE`.
+    /// Below the range there's no conversion at all: samples stream as `INVALID_MILLIVOLTS` and `MeasureNoise` fails with `NoVoltageReference`. Above it, the millivolts are off.
+    pub plausible: bool,
+}
+
+/// What usb_custom is and can do, for a host to check before streaming.
+/// Answered outside the bulk protocol, to a vendor IN control request with `bRequest` `DeviceInfo::REQUEST` (any recipient, value and index), so it works whatever state the command endpoint is in.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct DeviceInfo {
+    /// Firmware crate's major, minor and patch version.
+    pub version: [u8; 3],
+    /// First 8 hex digits of the commit the firmware was built from, in ASCII; all zero if it wasn't built from a git checkout.
+    pub git_hash: [u8; 8],
+    /// Built with uncommitted changes on top of `git_hash`.
+    pub git_dirty: bool,
+    /// ADC conversions per second at the current sample time, see `AdcSamplingPeriod::to_Hz`.
+    pub sampling_frequency_hz: f32,
+    pub pitch_um: u32,
+    /// `FEATURE_*` bits for the Cargo features the firmware was built with.
+    pub features: u32,
+}
+
+impl DeviceInfo {
+    pub const REQUEST: u8 = 0x01;
+    /// Postcard encoding's worst case, under the 64 byte control buffer.
+    pub const MAX_SIZE: usize = 3 + 8 + 1 + 4 + 5 + 5;
+
+    pub const FEATURE_ADC_LUT: u32 = 1 << 0;
+    pub const FEATURE_QUADRATURE: u32 = 1 << 1;
+    pub const FEATURE_DUAL_FREQUENCY: u32 = 1 << 2;
+    pub const FEATURE_DEMOD_DUMP: u32 = 1 << 3;
+
+    pub fn serialize<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn deserialize(bs: &[u8]) -> Option<Self> {
+        postcard::from_bytes(bs).ok()
+    }
+}
+
+/// Raw samples with the excitation off, i.e., the front end's noise plus any interference, in millivolts.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct NoiseMeasurement {
+    pub samples: u32,
+    /// DC level; far from mid-supply means a bias problem in the front end.
+    pub mean_mv: f32,
+    /// RMS about the mean.
+    pub rms_mv: f32,
+    pub peak_to_peak_mv: f32,
+}
+
+/// Average correlation sums of an uncoupled window, at sample scale.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct IqOffset {
+    pub sum_sine: i32,
+    pub sum_cosine: i32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, defmt::Format)]
+pub enum CommandError {
+    FrequencyOutOfRange,
+    FilterAlphaOutOfRange,
+    /// Measured offset was too large to be stray coupling; the slider is probably on the scale or moving.
+    CalibrationMagnitudeTooHigh,
+    PitchOutOfRange,
+    /// The excitation would span less than one cycle per demodulation window, or alias at the requested sample rate, itself or through one of its harmonics.
+    SamplingIncoherent,
+    IdleOutOfRange,
+    /// `SetAdcLut` chunk runs past the end of the table.
+    AdcLutOutOfRange,
+    /// `SetPhaseCorrection` chunk runs past the end of the table.
+    PhaseCorrectionOutOfRange,
+    PositionRateOutOfRange,
+    Unsupported,
+    /// `ScanFrequency` with no steps, or more than the firmware has room for.
+    ScanOutOfRange,
+    /// The drive is off in `PowerState::Idle`, so there's nothing to measure; move the slider to wake it.
+    DeviceIdle,
+    /// `SetDisplayDeadband` wider than usb_custom's `MAX_DISPLAY_DEADBAND_UM`.
+    DeadbandOutOfRange,
+    /// The VREFINT reading at boot failed, so there's nothing to convert to millivolts by; see `AdcCalibration::plausible`.
+    NoVoltageReference,
+}
+
+impl Response {
+    pub fn serialize<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn deserialize(bs: &[u8]) -> Option<Self> {
+        postcard::from_bytes(bs).ok()
+    }
+}
+
+/// End raw sample packets with `crc16_ccitt` of everything before it, so the host can discard corrupted frames.
+/// Costs one sample per packet.
+pub const SAMPLE_PACKET_CRC: bool = false;
+pub const SAMPLE_PACKET_CRC_SIZE: usize = if SAMPLE_PACKET_CRC { 2 } else { 0 };
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021 (x^16 + x^12 + x^5 + 1), initial value 0xFFFF, MSB first with no reflection and no final XOR.
+/// Check value, for other implementations: 0x29B1 over the ASCII bytes "123456789".
+/// Bitwise rather than table-driven, to keep firmware small.
+pub fn crc16_ccitt(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in bytes {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Windows per packet in `StreamMode::IqWindows`, a few hundred times less data than the samples they came from.
+///
+/// Packets have a `SamplePacketHeader`, so `SamplePacketHeader::parse` applies, and the CRC if `SAMPLE_PACKET_CRC` is set.
+/// The sums are the ones the firmware takes `atan2` of: rotated into the excitation's frame and with the I/Q offset subtracted, at sample scale.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..6  header   SamplePacketHeader; timestamp_us is when the packet's first window started, the rest following a window period apart
+/// bytes 6..   windows  (sum_sine i32, sum_cosine i32), oldest first
+/// last 2      crc      u16, only with SAMPLE_PACKET_CRC
+/// ```
+pub const IQ_PAIRS_PER_PACKET: usize = (64 - SamplePacketHeader::SIZE - SAMPLE_PACKET_CRC_SIZE) / 8; // 64 byte full-speed bulk packets
+
+/// Sent in place of every millivolt sample when the VREFINT reading at boot failed, see `AdcCalibration::plausible`.
+/// Above anything a 3.6V supply can read, and still 12 bits, so `SampleWidth::Packed12` carries it too.
+pub const INVALID_MILLIVOLTS: u16 = 4095;
+
+/// Header at the start of every raw sample packet streamed by usb_custom.
+/// The rest of the packet is millivolt samples in the `SampleFormat` set with `Command::SetSampleFormat`, u16 little-endian by default, then the CRC if `SAMPLE_PACKET_CRC` is set.
+/// Packets are `SampleFormat::samples_per_packet` samples each, so packed ones come up a byte or two short of 64.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  sequence      u16, incremented per packet (wrapping) so the host can detect dropped packets
+/// bytes 2..6  timestamp_us  u32, microseconds since boot (wrapping) when the ADC converted the packet's first sample
+/// bytes 6..   samples       see SampleFormat
+/// last 2      crc           u16 over all preceding bytes, only with SAMPLE_PACKET_CRC
+/// ```
+///
+/// Timestamps are worked back from how far behind the DMA the firmware read each buffer, so they say when samples were taken however late USB got them out.
+/// The firmware's clock ticks at 32.768 kHz, so each one is quantized to about 31us, but with no further jitter; a fit of timestamp against sequence over many packets gives the true sample or window rate.
+#[derive(PartialEq, Debug, Clone, defmt::Format)]
+pub struct SamplePacketHeader {
+    pub sequence: u16,
+    pub timestamp_us: u32,
+}
+
+impl SamplePacketHeader {
+    pub const SIZE: usize = 6;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[2..6].copy_from_slice(&self.timestamp_us.to_le_bytes());
+    }
+
+    /// Splits a sample packet into its header and sample bytes.
+    /// Returns `None` if the packet is too short or, with `SAMPLE_PACKET_CRC`, fails its CRC.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        let header = Self::read(packet)?;
+        let body_len = packet.len().checked_sub(SAMPLE_PACKET_CRC_SIZE)?;
+        let (body, crc) = packet.split_at(body_len);
+        if SAMPLE_PACKET_CRC && crc16_ccitt(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+        Some((header, &body[Self::SIZE..]))
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(SamplePacketHeader {
+            sequence: u16::from_le_bytes([bs[0], bs[1]]),
+            timestamp_us: u32::from_le_bytes([bs[2], bs[3], bs[4], bs[5]]),
+        })
+    }
+}
+
+/// Position history entries packed into each packet of a `DumpHistory` burst.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  sequence  u16, from 0 for the first packet of each dump
+/// bytes 2..   entries   HistoryEntry::SIZE each, oldest first; only the last packet can be short
+/// ```
+pub const HISTORY_ENTRIES_PER_PACKET: usize = (64 - 2) / HistoryEntry::SIZE; // 64 byte full-speed bulk packets
+
+/// One demodulated window, as recorded for `DumpHistory`.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..4   timestamp_us  u32, microseconds since boot (wrapping) when the window started, as in SamplePacketHeader
+/// bytes 4..12  position      i64, counts relative to the tare at the time of the dump
+/// ```
+///
+/// The firmware keeps recording while it dumps, so on a long dump the oldest entries may be overwritten by newer ones before they're sent; timestamps show where.
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct HistoryEntry {
+    pub timestamp_us: u32,
+    pub position: i64,
+}
+
+impl HistoryEntry {
+    pub const SIZE: usize = 12;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        buf[4..12].copy_from_slice(&self.position.to_le_bytes());
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(HistoryEntry {
+            timestamp_us: u32::from_le_bytes(bs[0..4].try_into().unwrap()),
+            position: i64::from_le_bytes(bs[4..12].try_into().unwrap()),
+        })
+    }
+}
+
+/// Demodulation entries packed into each packet of a `DumpDemodulation` burst.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  sequence  u16, from 0 for the first packet of each dump
+/// bytes 2..   entries   DemodulationEntry::SIZE each, in sample order; only the last packet can be short
+/// ```
+pub const DEMODULATION_ENTRIES_PER_PACKET: usize = (64 - 2) / DemodulationEntry::SIZE; // 64 byte full-speed bulk packets
+
+/// One sample of a captured window and its contribution to the window's correlation sums, for lining the firmware's view up against a scope trace.
+/// The sine and cosine are of the excitation's phase at the sample, so the last entry's sums are the window's I/Q as streamed in `StreamMode::IqWindows`, before the `IqOffset` is subtracted, to within float rounding.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2    sample          u16, raw ADC counts after any ADC LUT correction
+/// bytes 2..6    sine_product    f32, sample × sin
+/// bytes 6..10   cosine_product  f32, sample × cos
+/// bytes 10..14  sum_sine        f32, running Σ x sin up to and including this sample
+/// bytes 14..18  sum_cosine      f32, running Σ x cos
+/// ```
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct DemodulationEntry {
+    pub sample: u16,
+    pub sine_product: f32,
+    pub cosine_product: f32,
+    pub sum_sine: f32,
+    pub sum_cosine: f32,
+}
+
+impl DemodulationEntry {
+    pub const SIZE: usize = 18;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.sample.to_le_bytes());
+        buf[2..6].copy_from_slice(&self.sine_product.to_le_bytes());
+        buf[6..10].copy_from_slice(&self.cosine_product.to_le_bytes());
+        buf[10..14].copy_from_slice(&self.sum_sine.to_le_bytes());
+        buf[14..18].copy_from_slice(&self.sum_cosine.to_le_bytes());
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(DemodulationEntry {
+            sample: u16::from_le_bytes([bs[0], bs[1]]),
+            sine_product: f32::from_le_bytes(bs[2..6].try_into().unwrap()),
+            cosine_product: f32::from_le_bytes(bs[6..10].try_into().unwrap()),
+            sum_sine: f32::from_le_bytes(bs[10..14].try_into().unwrap()),
+            sum_cosine: f32::from_le_bytes(bs[14..18].try_into().unwrap()),
+        })
+    }
+}
+
+/// Bins of usb_custom's phase histogram, evenly spaced across a turn of wrapped phase: bin `i` counts windows whose phase fell in `-π + i * 2π / PHASE_HISTOGRAM_BINS` and up, before any phase correction; see `calipertron_core::dsp::PhaseHistogram`.
+pub const PHASE_HISTOGRAM_BINS: usize = 64;
+
+/// Histogram bins packed into each packet of a `DumpPhaseHistogram` burst.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  sequence  u16, from 0 for the first packet of each dump
+/// bytes 2..   counts    u32 each, from bin 0 up; only the last packet can be short
+/// ```
+///
+/// The bins are copied in one go when the dump starts, so they add up to `Response::PhaseHistogram::windows` whatever comes in while it's sent.
+pub const PHASE_HISTOGRAM_BINS_PER_PACKET: usize = (64 - 2) / 4; // 64 byte full-speed bulk packets
+
+/// Scan points packed into each packet of a `ScanFrequency` burst.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  sequence  u16, from 0 for the first packet of each scan
+/// bytes 2..   points    ScanPoint::SIZE each, in the order they were measured; only the last packet can be short
+/// ```
+pub const SCAN_POINTS_PER_PACKET: usize = (64 - 2) / ScanPoint::SIZE; // 64 byte full-speed bulk packets
+
+/// One excitation frequency of a `ScanFrequency` sweep.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..4    pdm_frequency_hz  u32
+/// bytes 4..8    magnitude         f32, mean over the measured windows, as in `Reading::magnitude`
+/// bytes 8..12   phase_std_dev     f32, radians, as in `Status::phase_std_dev`
+/// byte 12       gain_level        u8, as in `Status::gain_level`
+/// byte 13       settled           u8, 1 if the readings settled in time, 0 if not, in which case the rest is a snapshot as the point gave up
+/// ```
+///
+/// The phase noise is the figure of merit: it falls as the magnitude rises against the ADC noise, and rises where interference lands near the excitation.
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct ScanPoint {
+    pub pdm_frequency_hz: u32,
+    pub magnitude: f32,
+    pub phase_std_dev: f32,
+    pub gain_level: u8,
+    pub settled: bool,
+}
+
+impl ScanPoint {
+    pub const SIZE: usize = 14;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.pdm_frequency_hz.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.magnitude.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.phase_std_dev.to_le_bytes());
+        buf[12] = self.gain_level;
+        buf[13] = self.settled as u8;
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(ScanPoint {
+            pdm_frequency_hz: u32::from_le_bytes(bs[0..4].try_into().unwrap()),
+            magnitude: f32::from_le_bytes(bs[4..8].try_into().unwrap()),
+            phase_std_dev: f32::from_le_bytes(bs[8..12].try_into().unwrap()),
+            gain_level: bs[12],
+            settled: bs[13] != 0,
+        })
+    }
+}
+
+/// Body of each packet streamed in `StreamMode::Positions`.
+/// Packets have a `SamplePacketHeader`, so `SamplePacketHeader::parse` applies, and the CRC if `SAMPLE_PACKET_CRC` is set.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..6    header         SamplePacketHeader; timestamp_us is when the position's window started, not when the packet was sent
+/// bytes 6..14   position       i64, counts relative to the tare, as in `Reading::position`
+/// byte 14       flags          u8, bit 0 `Reading::settling`, bit 1 `Reading::saturated`
+/// bytes 15..19  phase          i32, turn units, as in `Reading::tracked_phase`
+/// bytes 19..27  position_fine  i64, as in `Reading::position_fine`
+/// last 2        crc            u16, only with SAMPLE_PACKET_CRC
+/// ```
+///
+/// All three come from one window, so a host can check the unwrap by comparing `phase` against `position_fine`.
+///
+/// Packets go out on a timer rather than per window, so one may repeat the last one's window, timestamp and all, or skip a window when the two rates beat.
+/// Readings hold while the firmware is idle or measuring noise, so packets keep coming with a stale timestamp.
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct PositionSample {
+    pub position: i64,
+    pub settling: bool,
+    pub saturated: bool,
+    pub phase: i32,
+    pub position_fine: i64,
+}
+
+impl PositionSample {
+    pub const SIZE: usize = 21;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..8].copy_from_slice(&self.position.to_le_bytes());
+        buf[8] = self.settling as u8 | (self.saturated as u8) << 1;
+        buf[9..13].copy_from_slice(&self.phase.to_le_bytes());
+        buf[13..21].copy_from_slice(&self.position_fine.to_le_bytes());
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(PositionSample {
+            position: i64::from_le_bytes(bs[0..8].try_into().unwrap()),
+            settling: bs[8] & 1 != 0,
+            saturated: bs[8] & 2 != 0,
+            phase: i32::from_le_bytes(bs[9..13].try_into().unwrap()),
+            position_fine: i64::from_le_bytes(bs[13..21].try_into().unwrap()),
+        })
+    }
+}
+
+/// Body of each packet streamed in `StreamMode::PositionRuns`, sent whenever the position moves or its flags change and otherwise once per `u16::MAX` ticks.
+/// Packets have a `SamplePacketHeader` and optional CRC as in `StreamMode::Positions`, with `sequence` counting packets sent rather than ticks.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..6    header   SamplePacketHeader, of `sample`
+/// bytes 6..27   sample   PositionSample
+/// bytes 27..29  repeats  u16, ticks since the previous packet that held at its sample, not sent
+/// last 2        crc      u16, only with SAMPLE_PACKET_CRC
+/// ```
+///
+/// So the per-tick stream is the previous packet's sample `repeats` more times, then this one's. After a sequence gap the previous sample is the one that went missing, so the repeats before the next one received are unknown.
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct PositionRun {
+    pub sample: PositionSample,
+    pub repeats: u16,
+}
+
+impl PositionRun {
+    pub const SIZE: usize = PositionSample::SIZE + 2;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        self.sample.write(buf);
+        buf[PositionSample::SIZE..Self::SIZE].copy_from_slice(&self.repeats.to_le_bytes());
+    }
+
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        Some(PositionRun {
+            sample: PositionSample::read(bs)?,
+            repeats: u16::from_le_bytes([bs[PositionSample::SIZE], bs[PositionSample::SIZE + 1]]),
+        })
+    }
+}
+
+/// Size of each page of flash_logger's log, the erase size of the STM32F103C8's flash.
+pub const LOG_PAGE_SIZE: usize = 1024;
+/// `LogRecord` slots after each page's header.
+pub const LOG_RECORDS_PER_PAGE: usize = (LOG_PAGE_SIZE - LogPageHeader::SIZE) / LogRecord::SIZE;
+
+/// Start of a page of flash_logger's position log, followed by `LOG_RECORDS_PER_PAGE` `LogRecord`s.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..4   sequence      u32, one more than the previous page's; erased pages read u32::MAX
+/// bytes 4..8   timestamp_ms  u32, milliseconds since the boot that opened the page
+/// bytes 8..16  position      i64, counts, untared
+/// ```
+///
+/// Every boot opens a new page, so timestamps only count from a common start within a page.
+/// `sequence` is written last, so a header cut short by power loss reads as erased.
+#[derive(PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct LogPageHeader {
+    pub sequence: u32,
+    pub timestamp_ms: u32,
+    pub position: i64,
+}
+
+impl LogPageHeader {
+    pub const SIZE: usize = 16;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.position.to_le_bytes());
+    }
+
+    /// `None` for an erased or half-written header.
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        let header = LogPageHeader {
+            sequence: u32::from_le_bytes(bs[0..4].try_into().unwrap()),
+            timestamp_ms: u32::from_le_bytes(bs[4..8].try_into().unwrap()),
+            position: i64::from_le_bytes(bs[8..16].try_into().unwrap()),
+        };
+        (header.sequence != u32::MAX).then_some(header)
+    }
+}
+
+/// Change since the previous record (or the page header), for packing as many positions as possible into flash.
+/// Changes too big for one record are split over several, some with `dt_ms` 0.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// bytes 0..2  dt_ms      u16, at most 0xFFFE; 0xFFFF marks an unwritten slot, and the end of the page
+/// bytes 2..4  dposition  i16, counts
+/// ```
+///
+/// `dposition` is written first, so a record cut short by power loss still reads as unwritten.
+#[derive(PartialEq, Debug, Clone, Copy, defmt::Format)]
+pub struct LogRecord {
+    pub dt_ms: u16,
+    pub dposition: i16,
+}
+
+impl LogRecord {
+    pub const SIZE: usize = 4;
+    pub const MAX_DT_MS: u16 = 0xFFFE;
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.dt_ms.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.dposition.to_le_bytes());
+    }
+
+    /// `None` for an unwritten or half-written slot.
+    pub fn read(bs: &[u8]) -> Option<Self> {
+        let bs = bs.get(..Self::SIZE)?;
+        let record = LogRecord {
+            dt_ms: u16::from_le_bytes([bs[0], bs[1]]),
+            dposition: i16::from_le_bytes([bs[2], bs[3]]),
+        };
+        (record.dt_ms != 0xFFFF).then_some(record)
+    }
+}
+
+/// Register map the local firmware serves as an I2C slave, with `I2C_OUTPUT` set.
+/// The master writes a one-byte register address, then reads on from there.
+///
+/// Layout, all little-endian:
+///
+/// ```text
+/// 0x00  position      i64, counts relative to the last zeroing
+/// 0x08  magnitude     f32, `sqrt(sum_sine² + sum_cosine²)` of the latest window, in raw ADC units
+/// 0x0C  status        u32, `I2cRegisters` flag bits
+/// 0x10  phase         i32, of the latest window, in turn units
+/// 0x14  second_phase  i32, of the second tone with the `dual-frequency` feature, 0 without
+/// 0x18  stalls        u32, times the ADC was found to have stopped converting without a timeout and restarted, since boot
+/// 0x1C  channel_gain[0]               f32, writable, see below
+/// 0x20  channel_offset[0].sum_sine    i32, writable
+/// 0x24  channel_offset[0].sum_cosine  i32, writable
+/// 0x28  channel_gain[1]               f32, writable
+/// 0x2C  channel_offset[1].sum_sine    i32, writable
+/// 0x30  channel_offset[1].sum_cosine  i32, writable
+/// 0x34  calibrate     f32, write-only, see `CALIBRATE`; reads as 0xFF like the rest past the end
+/// ```
+///
+/// The whole map is latched when a read is addressed, so a multi-byte read never mixes two windows.
+///
+/// In differential mode each channel's correlation sums are corrected to `(sums - channel_offset) * channel_gain` before the second is subtracted from the first (see `calipertron_core::ChannelCalibration`), 1 and 0 until calibrated.
+/// Writes after the register address land from there on, and take effect at the STOP; writes to the read-only registers are ignored, as is a gain that isn't positive, which leaves all six as they were.
+/// To save a calibration, read the six back and write them again after the next boot.
+///
+/// With two tones, both measure the same spatial phase, so `second_phase` is reported minus the tones' difference on the first window and reads the same as `phase` while both are clean.
+/// Interference close to one tone pulls only that tone's phase, so while `FREQUENCY_DISAGREEMENT` is set the host should follow whichever phase has been steadier over its recent reads.
+/// While it's clear, averaging the two (as `phase + (second_phase - phase) / 2` with a wrapping difference) cuts the noise by about √2.
+#[derive(PartialEq, Debug, Clone, Copy, Default, defmt::Format)]
+pub struct I2cRegisters {
+    pub position: i64,
+    pub magnitude: f32,
+    pub status: u32,
+    pub phase: i32,
+    pub second_phase: i32,
+    pub stalls: u32,
+    pub channel_gain: [f32; 2],
+    pub channel_offset: [IqOffset; 2],
+}
+
+impl I2cRegisters {
+    pub const SIZE: usize = 0x34;
+    /// Start of `channel_gain` and `channel_offset`, a channel's three after another.
+    pub const CHANNEL_CALIBRATION: usize = 0x1C;
+    pub const CHANNEL_CALIBRATION_SIZE: usize = 12;
+    /// Write 0.0 here with no input on either channel to capture their offsets, then with an input the same on both, the magnitude a channel should read for it to set their gains; see `calipertron_core::ChannelCalibration::new`.
+    /// Each capture averages a couple of seconds of windows, which `CALIBRATING` is set for; channel_gain and channel_offset only change once the second is done.
+    /// The magnitude is per channel at sample scale, e.g., A * N/4 for an input of amplitude A counts over an N-sample window, since each channel gets half of it; the pair's own magnitude with channel_gain at 1 is a good choice, since it keeps MIN_MAGNITUDE where it was.
+    pub const CALIBRATE: usize = 0x34;
+
+    /// The latest window's magnitude was too low to trust, so position holds its last good value.
+    pub const LOW_MAGNITUDE: u32 = 1 << 0;
+    /// The ADC watchdog tripped on the latest window, see `ADC_WATCHDOG_LOW` in local.rs.
+    pub const ADC_OUT_OF_RANGE: u32 = 1 << 1;
+    /// The latest step was too large to unwrap reliably, so position may be off by a pitch.
+    pub const ALIASED: u32 = 1 << 2;
+    /// Alongside `ADC_OUT_OF_RANGE`: enough samples sat on the rails that the input is clipping, i.e., coupling is too strong rather than an electrode floating.
+    pub const SATURATED: u32 = 1 << 3;
+    /// The two tones' phases differ by more than `MAX_FREQUENCY_DISAGREEMENT` in local.rs, i.e., narrowband interference is pulling one of them.
+    pub const FREQUENCY_DISAGREEMENT: u32 = 1 << 4;
+    /// A DMA channel kept erroring after restarts and has been left off, so the other registers have stopped updating; stays set until reset.
+    pub const DMA_FAILED: u32 = 1 << 5;
+    /// A write to `CALIBRATE` is capturing windows.
+    pub const CALIBRATING: u32 = 1 << 6;
+    /// The position is more than halfway to where it saturates, see `calipertron_core::PositionTracker::MAX_WRAPS`; zeroing with the button then re-zeroes the accumulator too.
+    pub const POSITION_NEAR_LIMIT: u32 = 1 << 7;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bs = [0u8; Self::SIZE];
+        bs[0..8].copy_from_slice(&self.position.to_le_bytes());
+        bs[8..12].copy_from_slice(&self.magnitude.to_le_bytes());
+        bs[12..16].copy_from_slice(&self.status.to_le_bytes());
+        bs[16..20].copy_from_slice(&self.phase.to_le_bytes());
+        bs[20..24].copy_from_slice(&self.second_phase.to_le_bytes());
+        bs[24..28].copy_from_slice(&self.stalls.to_le_bytes());
+        for channel in 0..2 {
+            let at = Self::CHANNEL_CALIBRATION + channel * Self::CHANNEL_CALIBRATION_SIZE;
+            bs[at..at + 4].copy_from_slice(&self.channel_gain[channel].to_le_bytes());
+            bs[at + 4..at + 8]
+                .copy_from_slice(&self.channel_offset[channel].sum_sine.to_le_bytes());
+            bs[at + 8..at + 12]
+                .copy_from_slice(&self.channel_offset[channel].sum_cosine.to_le_bytes());
+        }
+        bs
+    }
+}